@@ -0,0 +1,70 @@
+//! Compares the ways of encoding a request, allocating a new buffer or
+//! reusing the caller's one, and decoding the reply.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use p2p_handshake::feature::{ModeFeature, SessionFeature};
+use p2p_handshake::framing::Network;
+use p2p_handshake::spec::MAX_HANDSHAKE_SIZE;
+use p2p_handshake::{HandshakeBuilder, HandshakeMessage, Version};
+
+/// A request as sent by a crawler, with the features of the reference
+/// node.
+fn request() -> HandshakeBuilder {
+    HandshakeBuilder::new()
+        .agent_name("ergoref")
+        .peer_name("ergo-mainnet-crawler")
+        .version(Version([5, 0, 21]))
+        .declared_address("203.0.113.7:9030".parse().unwrap())
+        .feature(ModeFeature::archive().into())
+        .feature(SessionFeature::new(Network::Mainnet.magic(), 42).into())
+        .timestamp(1_700_000_000_000)
+}
+
+fn encode(c: &mut Criterion) {
+    let request = request();
+    let message = request.build().unwrap();
+
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("builder_encode", |b| {
+        b.iter(|| black_box(&request).encode().unwrap())
+    });
+    group.bench_function("builder_encode_into", |b| {
+        let mut buf = Vec::new();
+        b.iter(|| {
+            buf.clear();
+            black_box(&request).encode_into(&mut buf).unwrap();
+            buf.len()
+        })
+    });
+    group.bench_function("builder_encode_to_slice", |b| {
+        let mut buf = [0u8; MAX_HANDSHAKE_SIZE];
+        b.iter(|| black_box(&request).encode_to_slice(&mut buf).unwrap())
+    });
+    group.bench_function("message_encode_with_timestamp", |b| {
+        b.iter(|| {
+            black_box(&message)
+                .encode_with_timestamp(1_700_000_000_000)
+                .unwrap()
+        })
+    });
+    group.bench_function("message_encode_into_writer", |b| {
+        let mut buf = Vec::new();
+        b.iter(|| {
+            buf.clear();
+            black_box(&message).encode_into(&mut buf).unwrap();
+            buf.len()
+        })
+    });
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let data = request().encode().unwrap();
+    c.bench_function("decode", |b| {
+        b.iter(|| HandshakeMessage::decode_from_response(black_box(data.clone())).unwrap())
+    });
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+//! Decoding attacker controlled bytes must fail cleanly, never panic, and
+//! the decoders must agree.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use p2p_handshake::{HandshakeMessage, HandshakeMessageRef};
+
+fuzz_target!(|data: &[u8]| {
+    let owned = HandshakeMessage::decode_from_response(data.to_vec());
+    let borrowed = HandshakeMessageRef::decode(data);
+    assert_eq!(owned.is_ok(), borrowed.is_ok());
+    match HandshakeMessage::try_decode_any(data) {
+        Ok(_) => assert!(owned.is_ok()),
+        Err(err) => {
+            assert!(owned.is_err());
+            assert!(err.offset <= data.len());
+        }
+    }
+});
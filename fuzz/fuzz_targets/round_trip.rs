@@ -0,0 +1,19 @@
+//! Every message that encodes must decode back to the same fields.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use p2p_handshake::HandshakeMessage;
+
+fuzz_target!(|message: HandshakeMessage| {
+    let Ok(encoded) = message.encode_for_request() else {
+        return;
+    };
+    let decoded =
+        HandshakeMessage::decode_from_response(encoded).expect("encoded messages should decode");
+    assert_eq!(decoded.agent_name, message.agent_name);
+    assert_eq!(decoded.version, message.version);
+    assert_eq!(decoded.peer_name, message.peer_name);
+    assert_eq!(decoded.declared_address, message.declared_address);
+    assert_eq!(decoded.features, message.features);
+});
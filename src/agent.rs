@@ -0,0 +1,122 @@
+//! This module implements parsing the agent name of a node into the
+//! client and release it runs, so that every consumer normalizes them the
+//! same way.
+//!
+//! Nodes commonly write their client, release and a few flags in the
+//! agent name, separated by dashes or slashes, ex. `ergoref-5.0.14`,
+//! `ergo-mainnet-5.0.14` or `ergoref/v5.1.0-RC1`. The parts before the
+//! release name the client, the ones following it and the network names
+//! are flags.
+//!
+
+use std::fmt;
+
+use crate::encoder::{HandshakeMessage, TinyString, Version};
+
+/// The network names recognized as flags wherever they appear.
+const NETWORKS: [&str; 3] = ["mainnet", "testnet", "devnet"];
+
+/// The client, release and flags written in an agent name.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NodeInfo {
+    /// The parts before the release, lowercase and joined with dashes,
+    /// ex. `ergoref`.
+    pub client: String,
+    /// The release written in the agent name, which may differ from the
+    /// version of the handshake.
+    pub version: Option<Version>,
+    /// The other parts, lowercase, ex. `mainnet` or `rc1`.
+    pub flags: Vec<String>,
+}
+
+impl NodeInfo {
+    /// Parses the agent name, it never fails: an agent name without
+    /// release is a client without version.
+    pub fn parse(agent: &TinyString) -> Self {
+        let agent = agent.trim().to_ascii_lowercase();
+        let parts: Vec<&str> = agent
+            .split(['-', '/', ' '])
+            .filter(|part| !part.is_empty())
+            .collect();
+        let release = parts.iter().position(|part| parse_release(part).is_some());
+
+        let mut client = vec![];
+        let mut flags = vec![];
+        for (index, part) in parts.iter().enumerate() {
+            let is_flag = NETWORKS.contains(part) || release.is_some_and(|release| index > release);
+            if is_flag {
+                flags.push(part.to_string());
+            } else if Some(index) != release {
+                client.push(*part);
+            }
+        }
+        Self {
+            client: client.join("-"),
+            version: release.and_then(|release| parse_release(parts[release])),
+            flags,
+        }
+    }
+
+    /// Whether the flag is set, ex. `mainnet`.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|set| set.eq_ignore_ascii_case(flag))
+    }
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.client)?;
+        if let Some(Version([major, minor, patch])) = &self.version {
+            write!(f, " {major}.{minor}.{patch}")?;
+        }
+        for flag in &self.flags {
+            write!(f, " +{flag}")?;
+        }
+        Ok(())
+    }
+}
+
+impl HandshakeMessage {
+    /// The client and release written in the agent name, see [`NodeInfo`].
+    pub fn node_info(&self) -> NodeInfo {
+        NodeInfo::parse(&self.agent_name)
+    }
+}
+
+/// Parses a release such as `5.0.14` or `v5.0.14`.
+fn parse_release(part: &str) -> Option<Version> {
+    part.strip_prefix('v').unwrap_or(part).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(agent: &str) -> NodeInfo {
+        NodeInfo::parse(&TinyString::new(agent).unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        let info = parse("ergoref-5.0.14");
+        assert_eq!(info.client, "ergoref");
+        assert_eq!(info.version, Some(Version([5, 0, 14])));
+        assert!(info.flags.is_empty());
+
+        let info = parse("Ergo-Mainnet-5.0.14");
+        assert_eq!(info.client, "ergo");
+        assert_eq!(info.version, Some(Version([5, 0, 14])));
+        assert!(info.has_flag("mainnet"));
+
+        let info = parse("ergo-node/v5.1.0-RC1");
+        assert_eq!(info.client, "ergo-node");
+        assert_eq!(info.version, Some(Version([5, 1, 0])));
+        assert_eq!(info.flags, ["rc1"]);
+        assert_eq!(info.to_string(), "ergo-node 5.1.0 +rc1");
+
+        let info = parse("ergoref");
+        assert_eq!(info.client, "ergoref");
+        assert_eq!(info.version, None);
+        assert_eq!(parse(""), NodeInfo::default());
+    }
+}
@@ -0,0 +1,206 @@
+//! This module implements aggregating the nodes of a scan or a crawl by
+//! network location, to measure how concentrated the network is.
+//!
+//! Nodes are grouped by /24 subnet (/48 in IPv6) and, with the `geoip`
+//! feature and a MaxMind ASN database, by autonomous system. Each grouping
+//! reports the share of the largest group, the Herfindahl-Hirschman index
+//! and the Nakamoto coefficient of the nodes.
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Number of groups listed by the [`Concentration`] display.
+const DISPLAYED_GROUPS: usize = 10;
+
+/// The /24 subnet of an IPv4 address or the /48 subnet of an IPv6 address.
+pub fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::from([a, b, c, 0, 0, 0, 0, 0])
+        }
+    }
+}
+
+/// Nodes counted by group, ex. by subnet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Concentration {
+    /// The groups and their node count, by decreasing count.
+    pub groups: Vec<(String, usize)>,
+    /// Total number of nodes.
+    pub total: usize,
+}
+
+impl Concentration {
+    /// Counts the nodes by group, given the group of every node.
+    pub fn new<I, K>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: ToString,
+    {
+        let mut counts = BTreeMap::<String, usize>::new();
+        for key in keys {
+            *counts.entry(key.to_string()).or_default() += 1;
+        }
+        let total = counts.values().sum();
+        let mut groups: Vec<(String, usize)> = counts.into_iter().collect();
+        // Stable, groups of equal count stay ordered by key.
+        groups.sort_by(|(_, first), (_, second)| second.cmp(first));
+        Self { groups, total }
+    }
+
+    /// Counts the nodes by /24 subnet, /48 in IPv6.
+    pub fn by_subnet<I: IntoIterator<Item = IpAddr>>(ips: I) -> Self {
+        Self::new(ips.into_iter().map(|ip| {
+            let prefix = if ip.is_ipv4() { 24 } else { 48 };
+            format!("{}/{prefix}", subnet(ip))
+        }))
+    }
+
+    /// Counts the nodes by autonomous system, nodes missing from the
+    /// database are grouped as `unknown`.
+    #[cfg(feature = "geoip")]
+    pub fn by_asn<I: IntoIterator<Item = IpAddr>>(ips: I, database: &AsnDatabase) -> Self {
+        Self::new(ips.into_iter().map(|ip| match database.lookup(ip) {
+            Some(asn) => asn.to_string(),
+            None => "unknown".to_string(),
+        }))
+    }
+
+    /// Share of the largest group, between 0 and 1.
+    pub fn top_share(&self) -> f64 {
+        match self.groups.first() {
+            Some((_, count)) => *count as f64 / self.total as f64,
+            None => 0.0,
+        }
+    }
+
+    /// The Herfindahl-Hirschman index, the sum of the squared shares of the
+    /// groups: `1 / n` for nodes spread evenly over `n` groups, up to 1 when
+    /// they all share a single group.
+    pub fn hhi(&self) -> f64 {
+        self.groups
+            .iter()
+            .map(|(_, count)| (*count as f64 / self.total as f64).powi(2))
+            .sum()
+    }
+
+    /// The Nakamoto coefficient, the smallest number of groups holding more
+    /// than half of the nodes.
+    pub fn nakamoto(&self) -> usize {
+        let mut held = 0;
+        for (index, (_, count)) in self.groups.iter().enumerate() {
+            held += count;
+            if held * 2 > self.total {
+                return index + 1;
+            }
+        }
+        0
+    }
+}
+
+impl fmt::Display for Concentration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} nodes in {} groups, largest {:.1}%, HHI {:.3}, Nakamoto coefficient {}.",
+            self.total,
+            self.groups.len(),
+            self.top_share() * 100.0,
+            self.hhi(),
+            self.nakamoto()
+        )?;
+        for (key, count) in self.groups.iter().take(DISPLAYED_GROUPS) {
+            let share = *count as f64 / self.total as f64 * 100.0;
+            writeln!(f, "  {key}: {count} ({share:.1}%)")?;
+        }
+        Ok(())
+    }
+}
+
+/// An autonomous system.
+#[cfg(feature = "geoip")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asn {
+    pub number: u32,
+    pub organization: Option<String>,
+}
+
+#[cfg(feature = "geoip")]
+impl fmt::Display for Asn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.organization {
+            Some(organization) => write!(f, "AS{} {organization}", self.number),
+            None => write!(f, "AS{}", self.number),
+        }
+    }
+}
+
+/// A MaxMind ASN database, ex. GeoLite2-ASN.mmdb.
+#[cfg(feature = "geoip")]
+pub struct AsnDatabase {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl AsnDatabase {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path).map_err(std::io::Error::other)?;
+        Ok(Self { reader })
+    }
+
+    /// The autonomous system announcing the address, if known.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Asn> {
+        let asn: maxminddb::geoip2::Asn = self.reader.lookup(ip).ok()?;
+        Some(Asn {
+            number: asn.autonomous_system_number?,
+            organization: asn.autonomous_system_organization.map(str::to_string),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        assert_eq!(subnet(ip), "1.2.3.0".parse::<IpAddr>().unwrap());
+        let ip: IpAddr = "2a01:4f8:1:2:3::1".parse().unwrap();
+        assert_eq!(subnet(ip), "2a01:4f8:1::".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_concentration() {
+        let ips: Vec<IpAddr> = ["1.2.3.4", "1.2.3.5", "1.2.3.6", "5.6.7.8", "9.9.9.9"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let concentration = Concentration::by_subnet(ips);
+        assert_eq!(concentration.total, 5);
+        assert_eq!(concentration.groups[0], ("1.2.3.0/24".to_string(), 3));
+        assert_eq!(concentration.top_share(), 0.6);
+        assert!((concentration.hhi() - 0.44).abs() < 1e-9);
+        assert_eq!(concentration.nakamoto(), 1);
+        assert_eq!(
+            concentration.to_string(),
+            "5 nodes in 3 groups, largest 60.0%, HHI 0.440, Nakamoto coefficient 1.\n  \
+             1.2.3.0/24: 3 (60.0%)\n  5.6.7.0/24: 1 (20.0%)\n  9.9.9.0/24: 1 (20.0%)\n"
+        );
+
+        let even = Concentration::new(["a", "b", "c", "d"]);
+        assert_eq!(even.hhi(), 0.25);
+        assert_eq!(even.nakamoto(), 3);
+
+        let empty = Concentration::new(Vec::<String>::new());
+        assert_eq!(empty.top_share(), 0.0);
+        assert_eq!(empty.nakamoto(), 0);
+    }
+}
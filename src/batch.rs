@@ -0,0 +1,817 @@
+//! This module implements probing many nodes concurrently.
+//!
+//! Unlike the single [`handshake`](crate::handshake) call, a batch is
+//! expected to meet unresponsive nodes, so each probe can be bounded by
+//! a timeout configured in [`HandshakeConfig`].
+//!
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::cache::Lookup;
+use crate::config::{HandshakeConfig, ScanStrategy};
+use crate::dial::CONNECTION_ATTEMPT_DELAY;
+use crate::dial::{connect_any_with, interleave};
+use crate::encoder::{get_current_unix_timestamp, HandshakeMessage};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::observer::{exchange_observed, Observation};
+use crate::route::Target;
+use crate::stream::PeerStream;
+use crate::wire::exchange_captured;
+
+/// The result of probing a single target.
+#[derive(Debug)]
+pub struct ProbeResult<A> {
+    pub target: A,
+    /// The address the connection was established with.
+    pub address: Option<SocketAddr>,
+    pub outcome: ProtocolResult<HandshakeMessage>,
+    /// Time spent resolving and connecting to the target.
+    pub connect_time: Option<Duration>,
+    /// Time spent sending the request and reading the reply.
+    pub handshake_time: Option<Duration>,
+    /// Number of attempts made, the timings are those of the last one.
+    pub attempts: u32,
+    /// Unix timestamp in milliseconds at which the last attempt started.
+    pub started_at: u64,
+    /// Whether the outcome was shared from the
+    /// [`outcome_cache`](HandshakeConfig::outcome_cache) of the
+    /// configuration, no attempt was then made.
+    pub cached: bool,
+}
+
+impl<A> ProbeResult<A> {
+    /// A result without any attempt made yet.
+    pub fn new(target: A) -> Self {
+        Self {
+            target,
+            address: None,
+            outcome: Err(ProtocolError::TimedOut),
+            connect_time: None,
+            handshake_time: None,
+            attempts: 0,
+            started_at: 0,
+            cached: false,
+        }
+    }
+
+    /// The same result for another target, ex. the host name an address
+    /// was resolved from.
+    pub fn with_target<B>(self, target: B) -> ProbeResult<B> {
+        ProbeResult {
+            target,
+            address: self.address,
+            outcome: self.outcome,
+            connect_time: self.connect_time,
+            handshake_time: self.handshake_time,
+            attempts: self.attempts,
+            started_at: self.started_at,
+            cached: self.cached,
+        }
+    }
+}
+
+/// Performs a handshake with every target, running at most
+/// `concurrency_limit` of them at the same time.
+///
+/// The results are returned in the order of the targets. Connections are
+/// closed once the handshake completes. With the
+/// [`TwoPhase`](ScanStrategy::TwoPhase) strategy of the configuration,
+/// only the targets accepting a first connection are handshaken.
+pub async fn handshake_many<A, I>(
+    targets: I,
+    config: &HandshakeConfig,
+    concurrency_limit: usize,
+) -> Vec<ProbeResult<A>>
+where
+    I: IntoIterator<Item = A>,
+    A: Target + Clone + Send + 'static,
+{
+    let targets: Vec<A> = targets.into_iter().collect();
+    match config.strategy {
+        ScanStrategy::Direct => {
+            run_concurrently(targets, concurrency_limit, |target| {
+                let config = config.clone();
+                async move { probe(target, &config).await }
+            })
+            .await
+        }
+        ScanStrategy::TwoPhase => two_phase(targets, config, concurrency_limit).await,
+    }
+}
+
+/// Sweeps the targets with bare connections, then handshakes the ones
+/// which accepted it. The unresponsive targets are reported with the
+/// connection failure of the sweep.
+async fn two_phase<A>(
+    targets: Vec<A>,
+    config: &HandshakeConfig,
+    concurrency_limit: usize,
+) -> Vec<ProbeResult<A>>
+where
+    A: Target + Clone + Send + 'static,
+{
+    let swept = run_concurrently(targets, concurrency_limit, |target| {
+        let config = config.clone();
+        async move { sweep(target, &config).await }
+    })
+    .await;
+
+    let responsive: Vec<A> = swept
+        .iter()
+        .filter_map(|swept| swept.as_ref().ok().cloned())
+        .collect();
+    let mut probes = run_concurrently(responsive, concurrency_limit, |target| {
+        let config = config.clone();
+        async move { probe(target, &config).await }
+    })
+    .await
+    .into_iter();
+
+    swept
+        .into_iter()
+        .filter_map(|swept| match swept {
+            Ok(_) => probes.next(),
+            Err(result) => Some(result),
+        })
+        .collect()
+}
+
+/// Connects to the target and closes the connection at once, in a single
+/// attempt bounded by the connection timeout. Returns the target when it
+/// accepted the connection.
+async fn sweep<A: Target + Clone>(
+    target: A,
+    config: &HandshakeConfig,
+) -> Result<A, ProbeResult<A>> {
+    let mut result = ProbeResult::new(target.clone());
+    let _permit = match &config.rate_limiter {
+        Some(limiter) => Some(limiter.acquire(&target).await),
+        None => None,
+    };
+    result.attempts = 1;
+    result.started_at = get_current_unix_timestamp().unwrap_or_default();
+    let timeout = config.connect_timeout.or(config.timeout);
+    let mut observation = Observation::new(config.observer.as_ref());
+    let connecting = with_timeout(
+        timeout,
+        connect_target(target.clone(), config, &mut observation),
+    );
+    let outcome = tokio::select! {
+        biased;
+        _ = config.cancelled() => Err(ProtocolError::Cancelled),
+        outcome = connecting => outcome,
+    };
+    match outcome {
+        Ok(stream) => {
+            observation.connected(stream.peer_addr().ok());
+            Ok(target)
+        }
+        Err(err) => {
+            observation.failed(&err);
+            result.outcome = Err(err);
+            Err(result)
+        }
+    }
+}
+
+/// Runs the task of every target, at most `concurrency_limit` at the same
+/// time, returning the outputs in the order of the targets.
+pub(crate) async fn run_concurrently<A, T, F, Fut>(
+    targets: Vec<A>,
+    concurrency_limit: usize,
+    task: F,
+) -> Vec<T>
+where
+    F: Fn(A) -> Fut,
+    Fut: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(concurrency_limit.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, target) in targets.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        let running = task(target);
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            (index, running.await)
+        });
+    }
+
+    let mut outputs = Vec::with_capacity(tasks.len());
+    while let Some(joined) = tasks.join_next().await {
+        // Probes never panic by themselves, a failure to join means
+        // the runtime is shutting down.
+        if let Ok(output) = joined {
+            outputs.push(output);
+        }
+    }
+    outputs.sort_by_key(|(index, _)| *index);
+    outputs.into_iter().map(|(_, output)| output).collect()
+}
+
+pub(crate) async fn probe<A: Target + Clone>(
+    target: A,
+    config: &HandshakeConfig,
+) -> ProbeResult<A> {
+    let mut result = ProbeResult::new(target.clone());
+    // Held until the probe is done, the probes of the node waiting for it
+    // then find its outcome in the cache.
+    let _claim = match lookup(target.address(), config).await {
+        Lookup::Hit(cached) => {
+            result.address = target.address();
+            result.outcome = match &config.policy {
+                Some(policy) => policy.check(&cached.reply).map(|_| cached.reply),
+                None => Ok(cached.reply),
+            };
+            result.connect_time = cached.connect_time;
+            result.handshake_time = cached.handshake_time;
+            result.started_at = cached.started_at;
+            result.cached = true;
+            return result;
+        }
+        Lookup::Miss(claim) => claim,
+    };
+    result.outcome = connect_and_exchange(target, config, &mut result)
+        .await
+        .map(|(_stream, reply)| reply);
+    result
+}
+
+/// Looks the target up in the cache, if it is given as an address.
+async fn lookup(address: Option<SocketAddr>, config: &HandshakeConfig) -> Lookup {
+    let (Some(cache), Some(address)) = (&config.outcome_cache, address) else {
+        return Lookup::Miss(None);
+    };
+    tokio::select! {
+        biased;
+        // The probe is then cancelled right away.
+        _ = config.cancelled() => Lookup::Miss(None),
+        lookup = cache.lookup(address, config) => lookup,
+    }
+}
+
+/// Connects to the target and exchanges the handshakes, retrying transient
+/// failures as configured. The timings of the last attempt are recorded in
+/// `result`.
+pub(crate) async fn connect_and_exchange<A: Target + Clone, T>(
+    target: A,
+    config: &HandshakeConfig,
+    result: &mut ProbeResult<T>,
+) -> ProtocolResult<(PeerStream, HandshakeMessage)> {
+    tokio::select! {
+        biased;
+        _ = config.cancelled() => Err(ProtocolError::Cancelled),
+        outcome = exchange_with_retries(target, config, result) => outcome,
+    }
+}
+
+async fn exchange_with_retries<A: Target + Clone, T>(
+    target: A,
+    config: &HandshakeConfig,
+    result: &mut ProbeResult<T>,
+) -> ProtocolResult<(PeerStream, HandshakeMessage)> {
+    // Every attempt sends a fresh timestamp, encoded into the same buffer.
+    let mut data = Vec::new();
+    loop {
+        data.clear();
+        config.request.encode_into(&mut data)?;
+        // Acquired before the attempt starts, waiting for the limiter isn't
+        // bounded by the timeout.
+        let _permit = match &config.rate_limiter {
+            Some(limiter) => Some(limiter.acquire(&target).await),
+            None => None,
+        };
+        result.address = None;
+        result.connect_time = None;
+        result.handshake_time = None;
+        result.started_at = get_current_unix_timestamp().unwrap_or_default();
+        // Counted upfront so that cancelled attempts are reported too.
+        let retry = result.attempts;
+        result.attempts += 1;
+        let mut observation = Observation::new(config.observer.as_ref());
+        let task = attempt(target.clone(), &data, config, result, &mut observation);
+        let outcome = with_timeout(config.timeout, task).await;
+        if let Err(err) = &outcome {
+            observation.failed(err);
+        }
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_attempt(&outcome);
+        match outcome {
+            Err(err) if retry < config.retries && err.is_transient() => {
+                tokio::time::sleep(config.backoff.delay(retry)).await;
+            }
+            outcome => return outcome,
+        }
+    }
+}
+
+async fn attempt<A: Target, T>(
+    target: A,
+    data: &[u8],
+    config: &HandshakeConfig,
+    result: &mut ProbeResult<T>,
+    observation: &mut Observation<'_>,
+) -> ProtocolResult<(PeerStream, HandshakeMessage)> {
+    let started = Instant::now();
+    #[cfg(any(test, feature = "test-util"))]
+    if let Some(chaos) = &config.chaos {
+        chaos.inject_connect_failure()?;
+    }
+    // The TLS session, if any, is negotiated within the connection timeout.
+    let connecting = async {
+        let stream = connect_target(target, config, observation).await?;
+        PeerStream::negotiate(stream, &config.request).await
+    };
+    let mut stream = with_timeout(config.connect_timeout, connecting).await?;
+    result.connect_time = Some(started.elapsed());
+    #[cfg(feature = "metrics")]
+    crate::telemetry::record_connect(started.elapsed());
+    result.address = stream.peer_addr().ok();
+    observation.connected(result.address);
+
+    let started = Instant::now();
+    #[cfg(any(test, feature = "test-util"))]
+    if let Some(chaos) = &config.chaos {
+        chaos.inject_handshake_failure()?;
+    }
+    let reply = match (&config.wire_capture, result.address) {
+        (Some(capture), Some(address)) => {
+            let exchanging = exchange_captured(&mut stream, data, address, capture, observation);
+            with_timeout(config.read_timeout, exchanging).await?
+        }
+        _ => {
+            let exchanging = exchange_observed(&mut stream, data, observation);
+            with_timeout(config.read_timeout, exchanging).await?
+        }
+    };
+    result.handshake_time = Some(started.elapsed());
+    #[cfg(feature = "metrics")]
+    crate::telemetry::record_round_trip(started.elapsed());
+    config.request.check_self_connection(&reply)?;
+    // Cached before the policy is checked, the configurations sharing the
+    // cache may follow different ones.
+    if let (Some(cache), Some(address)) = (&config.outcome_cache, result.address) {
+        let (connect_time, handshake_time) = (result.connect_time, result.handshake_time);
+        let reply = reply.clone();
+        cache.insert(
+            address,
+            config,
+            reply,
+            connect_time,
+            handshake_time,
+            result.started_at,
+        );
+    }
+    if let Some(policy) = &config.policy {
+        policy.check(&reply)?;
+    }
+    Ok((stream, reply))
+}
+
+/// Connects with the handler of the target if it is routed, or resolves
+/// and dials it.
+async fn connect_target<A: Target>(
+    target: A,
+    config: &HandshakeConfig,
+    observation: &mut Observation<'_>,
+) -> ProtocolResult<TcpStream> {
+    // The target isn't borrowed across the await, it needs not be `Sync`.
+    let routed = config.router.as_ref().and_then(|router| {
+        let (host, port) = target.host_port()?;
+        Some((router.find(host)?, host.to_string(), port))
+    });
+    if let Some((handler, host, port)) = routed {
+        return handler.connect(&host, port).await;
+    }
+    let attempt_delay = config
+        .connection_attempt_delay
+        .unwrap_or(CONNECTION_ATTEMPT_DELAY);
+    let addresses = interleave(crate::resolve(target).await?);
+    observation.resolved(&addresses);
+    let pre_connect = config.pre_connect.clone();
+    Ok(connect_any_with(addresses, attempt_delay, config.local_address, pre_connect).await?)
+}
+
+/// Bounds the task by the timeout, if any.
+pub(crate) async fn with_timeout<T, F>(timeout: Option<Duration>, task: F) -> ProtocolResult<T>
+where
+    F: Future<Output = ProtocolResult<T>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, task)
+            .await
+            .unwrap_or(Err(ProtocolError::TimedOut)),
+        None => task.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::cache::OutcomeCache;
+    use crate::config::Backoff;
+    use crate::encoder::{read_handshake, Version};
+    use crate::observer::{HandshakeObserver, Phase};
+    use crate::policy::HandshakePolicy;
+    use crate::ratelimit::RateLimiter;
+    use crate::spec;
+    use crate::testing::Chaos;
+    use crate::wire::Direction;
+
+    #[tokio::test]
+    async fn test_handshake_many() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE).await?;
+                    let reply = HandshakeBuilder::new().agent_name("ergoref").encode()?;
+                    tokio::io::AsyncWriteExt::write_all(&mut stream, &reply).await?;
+                    ProtocolResult::Ok(())
+                });
+            }
+        });
+
+        // A listener that never replies.
+        let silent = TcpListener::bind("127.0.0.1:0").await?;
+        let silent_address = silent.local_addr()?;
+
+        let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"))
+            .timeout(Duration::from_millis(200));
+        let targets = vec![node_address, silent_address, node_address];
+        let results = handshake_many(targets.clone(), &config, 2).await;
+
+        assert_eq!(results.len(), 3);
+        for (result, target) in results.iter().zip(targets) {
+            assert_eq!(result.target, target);
+        }
+        assert_eq!(
+            results[0].outcome.as_ref().unwrap().agent_name.as_str(),
+            "ergoref"
+        );
+        assert!(results[0].connect_time.is_some());
+        assert!(results[0].handshake_time.is_some());
+        assert!(matches!(results[1].outcome, Err(ProtocolError::TimedOut)));
+        assert!(results[1].handshake_time.is_none());
+        assert!(results[2].outcome.is_ok());
+        drop(silent);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_two_phase() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                tokio::spawn(async move {
+                    read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE).await?;
+                    let reply = HandshakeBuilder::new().agent_name("ergoref").encode()?;
+                    tokio::io::AsyncWriteExt::write_all(&mut stream, &reply).await?;
+                    ProtocolResult::Ok(())
+                });
+            }
+        });
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+        let config = HandshakeConfig::new(HandshakeBuilder::new())
+            .timeout(Duration::from_secs(5))
+            .strategy(ScanStrategy::TwoPhase);
+        let targets = vec![node_address, closed, node_address];
+        let results = handshake_many(targets.clone(), &config, 2).await;
+        for (result, target) in results.iter().zip(targets) {
+            assert_eq!(result.target, target);
+        }
+        assert!(results[0].outcome.is_ok());
+        assert!(matches!(results[1].outcome, Err(ProtocolError::Io(_))));
+        assert_eq!(results[1].attempts, 1);
+        assert!(results[2].outcome.is_ok());
+        // The responsive targets are connected to twice, once per phase.
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::Relaxed), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outcome_cache() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        // The mock node answers a single connection.
+        let node = crate::testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let cache = OutcomeCache::new(Duration::from_secs(60));
+        let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("monitor"))
+            .timeout(Duration::from_millis(500))
+            .outcome_cache(cache.clone());
+
+        let results = handshake_many(vec![node.address()], &config, 1).await;
+        assert!(results[0].outcome.is_ok());
+        assert!(!results[0].cached);
+        assert_eq!(cache.len(), 1);
+
+        // Another subsystem sending the same request shares the outcome,
+        // and checks it against its own policy.
+        let target = node.address().to_string();
+        let results = handshake_many(vec![target.clone()], &config.clone(), 1).await;
+        assert!(results[0].cached);
+        assert_eq!(results[0].attempts, 0);
+        assert_eq!(results[0].address, Some(node.address()));
+        assert_eq!(
+            results[0].outcome.as_ref().unwrap().agent_name.as_str(),
+            "ergoref"
+        );
+        let strict = config
+            .clone()
+            .policy(HandshakePolicy::new().min_version(Version([9, 0, 0])));
+        let results = handshake_many(vec![target.clone()], &strict, 1).await;
+        assert!(results[0].cached);
+        assert!(matches!(
+            results[0].outcome,
+            Err(ProtocolError::PolicyViolation(_))
+        ));
+
+        // Another request doesn't, and connects again.
+        let other = HandshakeConfig {
+            request: HandshakeBuilder::new().agent_name("crawler"),
+            ..config
+        };
+        let results = handshake_many(vec![target], &other, 1).await;
+        assert!(!results[0].cached);
+        assert!(results[0].outcome.is_err());
+
+        cache.clear();
+        assert!(cache.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outcome_cache_coalesces_probes() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        // The mock node answers a single connection.
+        let node = crate::testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("monitor"))
+            .timeout(Duration::from_millis(500))
+            .outcome_cache(OutcomeCache::new(Duration::from_secs(60)));
+
+        // The probes started together connect once.
+        let results = handshake_many(vec![node.address(); 3], &config, 3).await;
+        assert!(results.iter().all(|result| result.outcome.is_ok()));
+        let cached = results.iter().filter(|result| result.cached).count();
+        assert_eq!(cached, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wire_capture() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let reply = HandshakeBuilder::new().agent_name("ergoref").encode()?;
+        let truncated = reply[..reply.len() - 2].to_vec();
+        tokio::spawn(async move {
+            // The reply is cut short, the peer then goes silent.
+            let (mut stream, _) = listener.accept().await?;
+            read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE).await?;
+            tokio::io::AsyncWriteExt::write_all(&mut stream, &truncated).await?;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            ProtocolResult::Ok(())
+        });
+
+        let captured = Arc::new(std::sync::Mutex::new(vec![]));
+        let log = captured.clone();
+        let config = HandshakeConfig::new(HandshakeBuilder::new())
+            .timeout(Duration::from_millis(200))
+            .on_wire(move |peer, direction, data: &[u8]| {
+                log.lock().unwrap().push((peer, direction, data.to_vec()));
+            });
+        let results = handshake_many(vec![address], &config, 1).await;
+        assert!(matches!(results[0].outcome, Err(ProtocolError::TimedOut)));
+
+        // The bytes received before the timeout are captured too.
+        let captured = captured.lock().unwrap();
+        assert_eq!(captured.len(), 2);
+        assert_eq!((captured[0].0, captured[0].1), (address, Direction::Sent));
+        assert_eq!(
+            (captured[1].0, captured[1].1),
+            (address, Direction::Received)
+        );
+        assert_eq!(captured[1].2, reply[..reply.len() - 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pre_connect() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        let node = crate::testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let knocked = Arc::new(std::sync::Mutex::new(vec![]));
+        let log = knocked.clone();
+        let config = HandshakeConfig::new(HandshakeBuilder::new()).pre_connect(
+            move |address: SocketAddr| {
+                log.lock().unwrap().push(address);
+                async move { Ok(()) }
+            },
+        );
+        let results = handshake_many(vec![node.address()], &config, 1).await;
+        assert!(results[0].outcome.is_ok());
+        assert_eq!(*knocked.lock().unwrap(), [node.address()]);
+
+        let config = HandshakeConfig::new(HandshakeBuilder::new())
+            .pre_connect(|_: SocketAddr| async { Err(std::io::Error::other("knock refused")) });
+        let results = handshake_many(vec![node.address()], &config, 1).await;
+        let Err(ProtocolError::Io(err)) = &results[0].outcome else {
+            panic!("expected the hook to fail the connection");
+        };
+        assert_eq!(err.to_string(), "knock refused");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_observer() -> ProtocolResult<()> {
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+
+        impl HandshakeObserver for Recorder {
+            fn on_resolved(&self, addresses: &[SocketAddr]) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("resolved {}", addresses.len()));
+            }
+
+            fn on_connected(&self, _address: SocketAddr) {
+                self.0.lock().unwrap().push("connected".to_string());
+            }
+
+            fn on_request_sent(&self, _address: SocketAddr, data: &[u8]) {
+                self.0.lock().unwrap().push(format!("sent {}", data.len()));
+            }
+
+            fn on_response_received(&self, _address: SocketAddr, message: &HandshakeMessage) {
+                let received = format!("received {}", message.agent_name);
+                self.0.lock().unwrap().push(received);
+            }
+
+            fn on_error(&self, error: &ProtocolError, phase: Phase, _address: Option<SocketAddr>) {
+                let failed = format!("failed {} {phase}", error.kind());
+                self.0.lock().unwrap().push(failed);
+            }
+        }
+
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        let node = crate::testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let recorder = Arc::new(Recorder::default());
+        let request = HandshakeBuilder::new();
+        let sent = format!("sent {}", request.encode()?.len());
+        let config = HandshakeConfig::new(request)
+            .observer(recorder.clone())
+            .policy(HandshakePolicy::new().min_version(Version([9, 0, 0])));
+        let results = handshake_many(vec![node.address()], &config, 1).await;
+        assert!(results[0].outcome.is_err());
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "resolved 1",
+                "connected",
+                &sent,
+                "received ergoref",
+                "failed policy_violation validate",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        let server = crate::testing::MockErgoNode::builder()
+            .then_wait(Duration::from_millis(50))
+            .respond_handshake(reply)
+            .serve()
+            .await?;
+        // The handshakes with the same host run one after the other,
+        // whatever the concurrency.
+        let config = HandshakeConfig::new(HandshakeBuilder::new())
+            .timeout(Duration::from_millis(80))
+            .rate_limiter(RateLimiter::new().per_host(1));
+        let started = Instant::now();
+        let results = handshake_many(vec![server.address(); 4], &config, 4).await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        // Waiting for the limiter doesn't count in the timeout.
+        for result in results {
+            assert!(result.outcome.is_ok());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_many_with_chaos() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+
+        let chaos = Chaos::new(1).connect_failure_rate(1.0);
+        let config = HandshakeConfig::new(HandshakeBuilder::new()).chaos(chaos);
+        let results = handshake_many(vec![node_address; 4], &config, 2).await;
+        for result in results {
+            let Err(ProtocolError::Io(err)) = result.outcome else {
+                panic!("expected an injected failure");
+            };
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+        }
+        drop(listener);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retries() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+        };
+
+        let chaos = Chaos::new(1).connect_failure_rate(1.0);
+        let config = HandshakeConfig::new(HandshakeBuilder::new())
+            .retries(2, backoff)
+            .chaos(chaos);
+        let results = handshake_many(vec![node_address], &config, 1).await;
+        assert!(results[0].outcome.is_err());
+        assert_eq!(results[0].attempts, 3);
+
+        // Peers breaking the policy are not retried.
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE).await?;
+            let reply = HandshakeBuilder::new().agent_name("evil").encode()?;
+            tokio::io::AsyncWriteExt::write_all(&mut stream, &reply).await?;
+            ProtocolResult::Ok(())
+        });
+        let config = HandshakeConfig::new(HandshakeBuilder::new())
+            .read_timeout(Duration::from_secs(5))
+            .retries(2, backoff)
+            .policy(HandshakePolicy::new().allow_agent("ergoref"));
+        let results = handshake_many(vec![node_address], &config, 1).await;
+        assert!(matches!(
+            results[0].outcome,
+            Err(ProtocolError::PolicyViolation(_))
+        ));
+        assert_eq!(results[0].attempts, 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "cancellation")]
+    #[tokio::test]
+    async fn test_cancellation() -> ProtocolResult<()> {
+        // The node accepts the connections but never replies.
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+        let node = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let token = tokio_util::sync::CancellationToken::new();
+        let config = HandshakeConfig::new(HandshakeBuilder::new())
+            .read_timeout(Duration::from_secs(60))
+            .cancellation(token.clone());
+        let canceller = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            token.cancel();
+        });
+        let results = handshake_many(vec![node_address; 3], &config, 1).await;
+        for result in &results {
+            assert!(matches!(result.outcome, Err(ProtocolError::Cancelled)));
+        }
+        // Only the first handshake started, the pending ones fail without
+        // connecting.
+        let attempts: u32 = results.iter().map(|result| result.attempts).sum();
+        assert_eq!(attempts, 1);
+        canceller.abort();
+        node.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! This module implements the handshake over synchronous I/O using
+//! [`std::net::TcpStream`], for tools that don't run an async runtime.
+//!
+//! As for the async version, no timeout is set by this module. When the
+//! call needs to be bounded, connect the stream with the desired timeouts
+//! and run [`read_handshake`] on it.
+//!
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::builder::HandshakeBuilder;
+use crate::encoder::{HandshakeMessage, Version};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::spec::{self, DecodeLimits};
+
+/// Handshake implements the p2p handshake portion of Ergo platform protocol
+/// over a blocking TCP stream.
+///
+/// * `target_address` - The address and port of this target node (ex. 127.0.0.1:9030).
+/// * `agent_name` - The name of this client making the request
+/// * `version` - The version of this client making the request
+/// * `on_accept` - A callback that gets called when the handshake is successful.
+///
+pub fn handshake<A: ToSocketAddrs, F>(
+    target_address: A,
+    agent_name: &str,
+    version: Version,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
+{
+    let request = HandshakeBuilder::new()
+        .agent_name(agent_name)
+        .version(version);
+    handshake_with_builder(target_address, &request, on_accept)
+}
+
+/// Same as [`handshake`] but sends the request composed by the given builder.
+pub fn handshake_with_builder<A: ToSocketAddrs, F>(
+    target_address: A,
+    request: &HandshakeBuilder,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
+{
+    request.check_plaintext()?;
+    // Encoded on the stack, every request fits in the documented size.
+    let mut data = [0u8; spec::MAX_HANDSHAKE_SIZE];
+    let len = request.encode_to_slice(&mut data)?;
+    let mut stream = TcpStream::connect(target_address)?;
+    stream.write_all(&data[..len])?;
+    let response = read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE)?;
+    request.check_self_connection(&response)?;
+    crate::callback::call(|| on_accept(stream, response))
+}
+
+/// Reads a complete handshake message from the reader without consuming
+/// any byte past its end. An error is returned as soon as the message
+/// grows beyond `max_size` bytes.
+pub fn read_handshake<R: Read>(
+    reader: &mut R,
+    max_size: usize,
+) -> ProtocolResult<HandshakeMessage> {
+    read_handshake_with_limits(reader, &DecodeLimits::with_max_message(max_size))
+}
+
+/// Same as [`read_handshake`] but enforces the given limits, ex. shorter
+/// agent and peer names.
+pub fn read_handshake_with_limits<R: Read>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> ProtocolResult<HandshakeMessage> {
+    let max_size = limits.message_limit();
+    let mut limited = reader.take(max_size as u64);
+    match HandshakeMessage::decode_from_reader_with(&mut limited, limits) {
+        Err(err) if limited.limit() == 0 && is_unexpected_eof(&err) => {
+            Err(ProtocolError::MessageTooLarge(max_size))
+        }
+        Err(err) if limited.limit() == max_size as u64 && is_unexpected_eof(&err) => {
+            Err(ProtocolError::HandshakeTimedOutByPeer)
+        }
+        result => result,
+    }
+}
+
+fn is_unexpected_eof(err: &ProtocolError) -> bool {
+    match err {
+        ProtocolError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        ProtocolError::TruncatedMessage { .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::encoder::TinyString;
+
+    #[test]
+    fn test_blocking_handshake() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let node = thread::spawn(move || -> ProtocolResult<HandshakeMessage> {
+            let (mut stream, _) = listener.accept()?;
+            let request = read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE)?;
+            let reply = HandshakeBuilder::new().agent_name("ergoref").encode()?;
+            stream.write_all(&reply)?;
+            Ok(request)
+        });
+
+        let mut reply = None;
+        handshake(address, "paul", Version([5, 0, 0]), |_stream, message| {
+            reply = Some(message);
+            Ok(())
+        })?;
+
+        let request = node.join().expect("node thread should not panic")?;
+        assert_eq!(request.agent_name, TinyString::new("paul").unwrap());
+        assert_eq!(
+            reply.unwrap().agent_name,
+            TinyString::new("ergoref").unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_handshake_limits() -> ProtocolResult<()> {
+        let data = HandshakeBuilder::new().agent_name("ergoref").encode()?;
+        let error = read_handshake(&mut data.as_slice(), data.len() - 1).unwrap_err();
+        assert!(matches!(error, ProtocolError::MessageTooLarge(_)));
+
+        let error = read_handshake(&mut &data[..data.len() - 1], data.len()).unwrap_err();
+        assert!(matches!(error, ProtocolError::TruncatedMessage { .. }));
+
+        let error = read_handshake(&mut [].as_slice(), data.len()).unwrap_err();
+        assert!(matches!(error, ProtocolError::HandshakeTimedOutByPeer));
+
+        let limits = DecodeLimits {
+            max_string: 4,
+            ..Default::default()
+        };
+        let error = read_handshake_with_limits(&mut data.as_slice(), &limits).unwrap_err();
+        assert!(matches!(error, ProtocolError::StringTooLong { len: 7, .. }));
+        Ok(())
+    }
+}
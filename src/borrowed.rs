@@ -0,0 +1,261 @@
+//! This module implements decoding handshake messages without copying,
+//! the decoded strings and feature payloads borrow the received bytes.
+//!
+//! It suits consumers decoding many handshakes that only inspect a few
+//! fields, a [`HandshakeMessageRef`] can still be turned into an owned
+//! [`HandshakeMessage`] when it must outlive the received bytes.
+//!
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::core::read_vlq;
+use crate::encoder::{HandshakeMessage, TinyString, Version};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::feature::PeerFeature;
+use crate::spec;
+
+/// A handshake message borrowing the bytes it was decoded from.
+///
+/// ```
+/// use p2p_handshake::{HandshakeBuilder, HandshakeMessageRef};
+///
+/// let data = HandshakeBuilder::new().agent_name("ergoref").encode()?;
+/// let message = HandshakeMessageRef::decode(&data)?;
+/// assert_eq!(message.agent_name, "ergoref");
+/// # Ok::<(), p2p_handshake::ProtocolError>(())
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct HandshakeMessageRef<'a> {
+    pub timestamp: u64,
+    pub agent_name: &'a str,
+    pub version: Version,
+    pub peer_name: &'a str,
+    pub declared_address: Option<SocketAddr>,
+    pub features: FeaturesRef<'a>,
+}
+
+impl<'a> HandshakeMessageRef<'a> {
+    /// Decodes the message at the start of `data`, trailing bytes are
+    /// ignored as in [`HandshakeMessage::decode_from_response`].
+    pub fn decode(mut data: &'a [u8]) -> ProtocolResult<Self> {
+        let data = &mut data;
+        let timestamp = read_vlq(data)?;
+        let agent_name = read_str(data, spec::Field::AgentName)?;
+        let mut version = [0u8; 3];
+        version.copy_from_slice(take(data, spec::VERSION_LEN)?);
+        let peer_name = read_str(data, spec::Field::PeerName)?;
+        let declared_address = read_declared_address(data)?;
+
+        // The features are checked now, so iterating them can't fail.
+        let count = read_byte(data)?;
+        let start = *data;
+        for _ in 0..count {
+            read_feature(data)?;
+        }
+        let features = FeaturesRef {
+            count,
+            data: &start[..start.len() - data.len()],
+        };
+
+        Ok(Self {
+            timestamp,
+            agent_name,
+            version: Version(version),
+            peer_name,
+            declared_address,
+            features,
+        })
+    }
+}
+
+/// Fails when a name is longer than 255 bytes, which only happens to
+/// messages built by hand rather than decoded.
+impl TryFrom<&HandshakeMessageRef<'_>> for HandshakeMessage {
+    type Error = ProtocolError;
+
+    fn try_from(message: &HandshakeMessageRef<'_>) -> ProtocolResult<Self> {
+        Ok(Self {
+            timestamp: message.timestamp,
+            agent_name: TinyString::for_field(message.agent_name, spec::Field::AgentName)?,
+            version: message.version.clone(),
+            peer_name: TinyString::for_field(message.peer_name, spec::Field::PeerName)?,
+            declared_address: message.declared_address,
+            features: message.features.iter().map(PeerFeature::from).collect(),
+        })
+    }
+}
+
+/// A peer feature borrowing its payload.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PeerFeatureRef<'a> {
+    pub id: u8,
+    pub payload: &'a [u8],
+}
+
+impl From<PeerFeatureRef<'_>> for PeerFeature {
+    fn from(feature: PeerFeatureRef<'_>) -> Self {
+        PeerFeature::new(feature.id, feature.payload.to_vec())
+    }
+}
+
+/// The features of a [`HandshakeMessageRef`], decoded while iterating.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FeaturesRef<'a> {
+    count: u8,
+    data: &'a [u8],
+}
+
+impl<'a> FeaturesRef<'a> {
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn iter(&self) -> FeaturesIter<'a> {
+        FeaturesIter {
+            remaining: self.count,
+            data: self.data,
+        }
+    }
+
+    /// Returns the first feature with the given identifier.
+    pub fn get(&self, id: u8) -> Option<PeerFeatureRef<'a>> {
+        self.iter().find(|feature| feature.id == id)
+    }
+}
+
+impl<'a> IntoIterator for FeaturesRef<'a> {
+    type Item = PeerFeatureRef<'a>;
+    type IntoIter = FeaturesIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over [`FeaturesRef`].
+#[derive(Debug, Clone)]
+pub struct FeaturesIter<'a> {
+    remaining: u8,
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for FeaturesIter<'a> {
+    type Item = PeerFeatureRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        read_feature(&mut self.data).ok()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for FeaturesIter<'_> {}
+
+/// Splits `len` bytes off the front of `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> ProtocolResult<&'a [u8]> {
+    if data.len() < len {
+        return Err(ProtocolError::TruncatedMessage {
+            expected: len,
+            got: data.len(),
+        });
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+fn read_byte(data: &mut &[u8]) -> ProtocolResult<u8> {
+    Ok(take(data, 1)?[0])
+}
+
+fn read_str<'a>(data: &mut &'a [u8], field: spec::Field) -> ProtocolResult<&'a str> {
+    let len = read_byte(data)?;
+    spec::check_string_len(field, len as usize)?;
+    let bytes = take(data, len as usize)?;
+    std::str::from_utf8(bytes).map_err(|_| {
+        // Copied on the error path only, to report the same error as the
+        // owned decoding.
+        match String::from_utf8(bytes.to_vec()) {
+            Err(err) => ProtocolError::Utf8Error(err),
+            Ok(_) => ProtocolError::SpecViolation(format!("invalid {field}")),
+        }
+    })
+}
+
+fn read_declared_address(data: &mut &[u8]) -> ProtocolResult<Option<SocketAddr>> {
+    if read_byte(data)? == 0 {
+        return Ok(None);
+    }
+
+    let size = read_byte(data)?;
+    spec::check_address_size(size)?;
+    let octets = take(data, size as usize - 4)?;
+    let ip = match <[u8; 4]>::try_from(octets) {
+        Ok(ipv4) => IpAddr::from(ipv4),
+        Err(_) => {
+            let mut ipv6 = [0u8; 16];
+            ipv6.copy_from_slice(octets);
+            IpAddr::from(ipv6)
+        }
+    };
+    let port = read_vlq(data)?;
+    let port = u16::try_from(port).map_err(|_| ProtocolError::InvalidPort(port))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn read_feature<'a>(data: &mut &'a [u8]) -> ProtocolResult<PeerFeatureRef<'a>> {
+    let id = read_byte(data)?;
+    let len = read_vlq(data)?;
+    spec::check_feature_len(id, len)?;
+    let payload = take(data, len as usize)?;
+    Ok(PeerFeatureRef { id, payload })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::FieldDiff;
+
+    #[test]
+    fn test_borrowed_decoding() -> ProtocolResult<()> {
+        let message = HandshakeMessage {
+            timestamp: 42,
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version([5, 0, 21]),
+            peer_name: TinyString::new("node").unwrap(),
+            declared_address: Some("[2001:db8::1]:9030".parse().unwrap()),
+            features: vec![
+                PeerFeature::new(16, vec![0, 1, 0, 1]),
+                PeerFeature::new(2, vec![]),
+            ],
+        };
+        let data = message.encode_with_timestamp(42)?;
+
+        let borrowed = HandshakeMessageRef::decode(&data)?;
+        assert_eq!(borrowed.agent_name, "ergoref");
+        assert_eq!(borrowed.timestamp, 42);
+        assert_eq!(borrowed.features.len(), 2);
+        assert_eq!(borrowed.features.get(16).unwrap().payload, &[0, 1, 0, 1]);
+        let owned = HandshakeMessage::try_from(&borrowed)?;
+        assert_eq!(owned.diff(&message), Vec::<FieldDiff>::new());
+        assert_eq!(owned.timestamp, 42);
+
+        // Every truncation fails the same way as the owned decoding.
+        for len in 0..data.len() {
+            let borrowed = HandshakeMessageRef::decode(&data[..len]).map(|_| ());
+            let owned = HandshakeMessage::decode_from_response(data[..len].to_vec()).map(|_| ());
+            assert_eq!(format!("{borrowed:?}"), format!("{owned:?}"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+//! This module implements a builder for the handshake request,
+//! giving users full control over what goes on the wire.
+//!
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::clock::{Clock, FixedClock, SystemClock};
+use crate::encoder::{HandshakeFields, HandshakeMessage, TinyString, Version};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::feature::{PeerFeature, SessionFeature, SESSION_FEATURE_ID};
+use crate::spec::Field;
+
+/// Default peer name used when none is provided.
+pub const DEFAULT_PEER_NAME: &str = "evan-testnet";
+
+/// HandshakeBuilder composes the handshake request sent to a target node.
+///
+/// ```
+/// use p2p_handshake::{HandshakeBuilder, Version};
+///
+/// let request = HandshakeBuilder::new()
+///     .agent_name("agent-name")
+///     .peer_name("my-node")
+///     .version(Version([5, 0, 21]))
+///     .declared_address("1.2.3.4:9030".parse().unwrap())
+///     .build()
+///     .unwrap();
+/// assert_eq!(request.peer_name.as_str(), "my-node");
+/// ```
+///
+/// The names and features are reference counted, so cloning a builder to
+/// share it between tasks doesn't copy them.
+#[derive(Clone)]
+pub struct HandshakeBuilder {
+    agent_name: Arc<str>,
+    peer_name: Arc<str>,
+    version: Version,
+    declared_address: Option<SocketAddr>,
+    features: Arc<Vec<PeerFeature>>,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<crate::tls::TlsSettings>,
+}
+
+impl Default for HandshakeBuilder {
+    fn default() -> Self {
+        Self {
+            agent_name: Arc::from(""),
+            peer_name: Arc::from(DEFAULT_PEER_NAME),
+            version: Version::default(),
+            declared_address: None,
+            features: Arc::default(),
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl fmt::Debug for HandshakeBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("HandshakeBuilder");
+        debug
+            .field("agent_name", &self.agent_name)
+            .field("peer_name", &self.peer_name)
+            .field("version", &self.version)
+            .field("declared_address", &self.declared_address)
+            .field("features", &self.features);
+        #[cfg(feature = "tls")]
+        debug.field("tls", &self.tls);
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl HandshakeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn agent_name(mut self, agent_name: impl Into<Arc<str>>) -> Self {
+        self.agent_name = agent_name.into();
+        self
+    }
+
+    pub fn peer_name(mut self, peer_name: impl Into<Arc<str>>) -> Self {
+        self.peer_name = peer_name.into();
+        self
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn declared_address(mut self, address: SocketAddr) -> Self {
+        self.declared_address = Some(address);
+        self
+    }
+
+    pub fn feature(mut self, feature: PeerFeature) -> Self {
+        Arc::make_mut(&mut self.features).push(feature);
+        self
+    }
+
+    /// Adds a session feature on the network with a random session id,
+    /// so that connections looping back to ourselves fail with
+    /// [`ProtocolError::SelfConnection`](crate::ProtocolError::SelfConnection).
+    pub fn session<N: Into<[u8; 4]>>(self, network: N) -> Self {
+        self.feature(SessionFeature::random(network.into()).into())
+    }
+
+    /// The session id of the request, if it holds a session feature.
+    pub(crate) fn session_id(&self) -> Option<i64> {
+        self.features
+            .iter()
+            .find(|feature| feature.id == SESSION_FEATURE_ID)
+            .and_then(|feature| SessionFeature::parse(feature).ok())
+            .map(|session| session.session_id)
+    }
+
+    /// Fails when the peer answered with the session id of the request,
+    /// meaning the connection looped back to ourselves.
+    pub(crate) fn check_self_connection(&self, reply: &HandshakeMessage) -> ProtocolResult<()> {
+        match (self.session_id(), SessionFeature::find(reply)) {
+            (Some(ours), Some(theirs)) if ours == theirs.session_id => {
+                Err(ProtocolError::SelfConnection)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails when the request must be sent over TLS, for the functions
+    /// handing a plain `TcpStream` over. The scans negotiate the session
+    /// instead.
+    #[cfg(feature = "net")]
+    pub(crate) fn check_plaintext(&self) -> ProtocolResult<()> {
+        #[cfg(feature = "tls")]
+        if let Some(settings) = &self.tls {
+            return Err(ProtocolError::Tls(format!(
+                "the request for {:?} must be sent with the tls module or a scan",
+                settings.server_name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sets the clock giving the timestamp written in the request, the
+    /// system clock by default.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Writes a fixed timestamp (unix milliseconds) in the request.
+    pub fn timestamp(self, timestamp: u64) -> Self {
+        self.clock(FixedClock(timestamp))
+    }
+
+    /// Sets the function called to get the timestamp (unix milliseconds)
+    /// written in the request.
+    pub fn timestamp_provider<F>(self, provider: F) -> Self
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        self.clock(provider)
+    }
+
+    /// Validates the configured fields and produces the request message,
+    /// stamped with the clock.
+    pub fn build(&self) -> ProtocolResult<HandshakeMessage> {
+        let agent_name = TinyString::for_field(&*self.agent_name, Field::AgentName)?;
+        let peer_name = TinyString::for_field(&*self.peer_name, Field::PeerName)?;
+
+        let timestamp = self.clock.now_millis()?;
+        Ok(HandshakeMessage {
+            timestamp,
+            agent_name,
+            version: self.version.clone(),
+            peer_name,
+            declared_address: self.declared_address,
+            features: self.features.to_vec(),
+        })
+    }
+
+    #[cfg(feature = "net")]
+    pub(crate) fn version_ref(&self) -> &Version {
+        &self.version
+    }
+
+    #[cfg(all(feature = "net", test))]
+    pub(crate) fn features_ref(&self) -> &Arc<Vec<PeerFeature>> {
+        &self.features
+    }
+
+    /// Encodes the request message, using the configured clock. The names
+    /// and features are written from the builder in a single allocation,
+    /// without building the message first.
+    pub fn encode(&self) -> ProtocolResult<Vec<u8>> {
+        Ok(self.fields()?.encode()?)
+    }
+
+    /// Same as [`HandshakeBuilder::encode`] but appends the message to
+    /// `buf`, ex. a buffer cleared and reused for every connection, which
+    /// only grows when it can't hold the message yet.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> ProtocolResult<()> {
+        Ok(self.fields()?.append(buf)?)
+    }
+
+    /// Same as [`HandshakeBuilder::encode`] but writes the message at the
+    /// start of `buf`, without allocating, and returns the number of bytes
+    /// written. Fails with [`ProtocolError::BufferTooSmall`] when `buf`
+    /// can't hold the message. [`MAX_HANDSHAKE_SIZE`](crate::spec::MAX_HANDSHAKE_SIZE)
+    /// bytes always do, the requests exceeding it fail with
+    /// [`ProtocolError::MessageTooLarge`] whatever the buffer.
+    ///
+    /// ```
+    /// use p2p_handshake::HandshakeBuilder;
+    ///
+    /// let request = HandshakeBuilder::new().agent_name("ergoref").timestamp(42);
+    /// let mut buf = [0u8; 64];
+    /// let len = request.encode_to_slice(&mut buf).unwrap();
+    /// assert_eq!(&buf[..len], request.encode().unwrap());
+    /// ```
+    pub fn encode_to_slice(&self, buf: &mut [u8]) -> ProtocolResult<usize> {
+        Ok(self.fields()?.write_to_slice(buf)?)
+    }
+
+    /// The fields of the request, timestamped with the configured clock.
+    fn fields(&self) -> ProtocolResult<HandshakeFields<'_>> {
+        Ok(HandshakeFields {
+            timestamp: self.clock.now_millis()?,
+            agent_name: &self.agent_name,
+            version: &self.version,
+            peer_name: &self.peer_name,
+            declared_address: self.declared_address.as_ref(),
+            features: &self.features,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_encoding() -> ProtocolResult<()> {
+        let data = HandshakeBuilder::new()
+            .agent_name("ab")
+            .peer_name("c")
+            .version(Version([1, 2, 3]))
+            .declared_address("10.0.0.1:9030".parse().unwrap())
+            .feature(PeerFeature::new(2, vec![7, 7]))
+            .timestamp(1)
+            .encode()?;
+
+        #[rustfmt::skip]
+        let expected = vec![
+            1, // timestamp
+            2, b'a', b'b', // agent name
+            1, 2, 3, // version
+            1, b'c', // peer name
+            1, 8, 10, 0, 0, 1, 0xc6, 0x46, // declared address
+            1, 2, 2, 7, 7, // features
+        ];
+        assert_eq!(data, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_into_buffers() -> ProtocolResult<()> {
+        let request = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .feature(PeerFeature::new(2, vec![7; 300]))
+            .timestamp(1);
+        let expected = request.encode()?;
+
+        // The message is appended to the bytes already in the buffer.
+        let mut buf = vec![9];
+        request.encode_into(&mut buf)?;
+        assert_eq!(buf[0], 9);
+        assert_eq!(&buf[1..], expected);
+
+        let mut slice = [0u8; 512];
+        let len = request.encode_to_slice(&mut slice)?;
+        assert_eq!(&slice[..len], expected);
+        assert!(matches!(
+            request.encode_to_slice(&mut slice[..len - 1]),
+            Err(ProtocolError::BufferTooSmall { needed, got }) if needed == len && got == len - 1
+        ));
+
+        // Nothing is appended when the request breaks the spec.
+        let invalid = request.agent_name("x".repeat(256));
+        assert!(invalid.encode_into(&mut buf).is_err());
+        assert_eq!(buf.len(), expected.len() + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_rejects_long_names() {
+        let result = HandshakeBuilder::new().agent_name("x".repeat(256)).build();
+        assert!(matches!(
+            result,
+            Err(crate::error::ProtocolError::StringTooLong {
+                field: Field::AgentName,
+                len: 256,
+                max: 255
+            })
+        ));
+    }
+}
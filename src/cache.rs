@@ -0,0 +1,242 @@
+//! This module implements sharing the recent handshake outcomes between
+//! the subsystems of a process probing the same nodes, ex. a monitor and
+//! a crawler, so that a node isn't connected to twice in a short time.
+//!
+//! The outcomes are keyed by the address of the node and a fingerprint of
+//! the request, only configurations sending the same request share them.
+//! Only the completed handshakes are cached, failures are always retried.
+//! The probes of a node started while another with the same request is
+//! in flight wait for its outcome rather than connecting too.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use std::time::Duration;
+//! use p2p_handshake::cache::OutcomeCache;
+//! use p2p_handshake::{handshake_many, HandshakeBuilder, HandshakeConfig};
+//!
+//! let cache = OutcomeCache::new(Duration::from_secs(30));
+//! let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"))
+//!     .outcome_cache(cache.clone());
+//! let first = handshake_many(vec!["1.2.3.4:9030"], &config, 1).await;
+//! // Answered from the cache, without connecting again.
+//! let second = handshake_many(vec!["1.2.3.4:9030"], &config, 1).await;
+//! assert!(second[0].cached);
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use crate::config::HandshakeConfig;
+use crate::encoder::HandshakeMessage;
+use crate::error::ProtocolResult;
+use crate::sync::lock;
+
+/// A completed handshake shared through an [`OutcomeCache`].
+#[derive(Debug, Clone)]
+pub struct CachedOutcome {
+    pub reply: HandshakeMessage,
+    /// Time spent resolving and connecting to the node.
+    pub connect_time: Option<Duration>,
+    /// Time spent sending the request and reading the reply.
+    pub handshake_time: Option<Duration>,
+    /// Unix timestamp in milliseconds at which the handshake started.
+    pub started_at: u64,
+    stored: Instant,
+}
+
+type Key = (SocketAddr, u64);
+
+/// The recent outcomes of the handshakes, shared by the configurations
+/// holding a clone of it. Cloning a cache only bumps a reference count.
+#[derive(Clone)]
+pub struct OutcomeCache {
+    ttl: Duration,
+    entries: Arc<Mutex<Entries>>,
+}
+
+#[derive(Default)]
+struct Entries {
+    outcomes: HashMap<Key, CachedOutcome>,
+    /// The outcomes in the order they were stored, thus expire in.
+    expiries: VecDeque<(Instant, Key)>,
+    /// The probes in flight, closed once they are done.
+    in_flight: HashMap<Key, watch::Receiver<()>>,
+}
+
+/// The result of looking a node up, see [`OutcomeCache::lookup`].
+pub(crate) enum Lookup {
+    Hit(CachedOutcome),
+    /// The caller probes the node, holding the claim until it is done if
+    /// the probes are coalesced.
+    Miss(Option<Claim>),
+}
+
+/// Marks a probe in flight, the probes of the node waiting for it resume
+/// once it is dropped.
+pub(crate) struct Claim {
+    key: Key,
+    entries: Arc<Mutex<Entries>>,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        lock(&self.entries).in_flight.remove(&self.key);
+    }
+}
+
+impl fmt::Debug for OutcomeCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutcomeCache")
+            .field("ttl", &self.ttl)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl OutcomeCache {
+    /// A cache whose outcomes expire `ttl` after the handshake completed.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::default(),
+        }
+    }
+
+    /// The outcome of the last handshake with the node made with the same
+    /// request, unless it expired.
+    pub fn get(&self, address: SocketAddr, config: &HandshakeConfig) -> Option<CachedOutcome> {
+        let key = (address, fingerprint(config).ok()?);
+        self.get_key(&key)
+    }
+
+    fn get_key(&self, key: &Key) -> Option<CachedOutcome> {
+        let mut entries = lock(&self.entries);
+        match entries.outcomes.get(key) {
+            Some(entry) if entry.stored.elapsed() < self.ttl => Some(entry.clone()),
+            Some(_) => {
+                entries.outcomes.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The outcome of the node, waiting for the probe of it in flight with
+    /// the same request if any. On a miss the caller probes the node,
+    /// claiming it if no other probe is.
+    pub(crate) async fn lookup(&self, address: SocketAddr, config: &HandshakeConfig) -> Lookup {
+        let Ok(fingerprint) = fingerprint(config) else {
+            return Lookup::Miss(None);
+        };
+        let key = (address, fingerprint);
+        if let Some(outcome) = self.get_key(&key) {
+            return Lookup::Hit(outcome);
+        }
+        let mut in_flight = {
+            let mut entries = lock(&self.entries);
+            match entries.in_flight.get(&key) {
+                Some(in_flight) => in_flight.clone(),
+                None => {
+                    let (done, in_flight) = watch::channel(());
+                    entries.in_flight.insert(key, in_flight);
+                    return Lookup::Miss(Some(Claim {
+                        key,
+                        entries: self.entries.clone(),
+                        _done: done,
+                    }));
+                }
+            }
+        };
+        // Fails once the claim is dropped, however the probe ended. A
+        // failed probe isn't cached, the caller then probes the node too.
+        let _ = in_flight.changed().await;
+        match self.get_key(&key) {
+            Some(outcome) => Lookup::Hit(outcome),
+            None => Lookup::Miss(None),
+        }
+    }
+
+    /// Records the outcome of a handshake with the node, replacing the
+    /// previous one. The outcomes expired meanwhile are dropped, so that
+    /// the cache doesn't grow with the nodes probed once.
+    pub(crate) fn insert(
+        &self,
+        address: SocketAddr,
+        config: &HandshakeConfig,
+        reply: HandshakeMessage,
+        connect_time: Option<Duration>,
+        handshake_time: Option<Duration>,
+        started_at: u64,
+    ) {
+        let Ok(fingerprint) = fingerprint(config) else {
+            return;
+        };
+        let mut entries = lock(&self.entries);
+        // All the outcomes live as long, they expire in the order they were
+        // stored.
+        while let Some(&(stored, key)) = entries.expiries.front() {
+            if stored.elapsed() < self.ttl {
+                break;
+            }
+            entries.expiries.pop_front();
+            // Unless it was replaced since.
+            if entries
+                .outcomes
+                .get(&key)
+                .is_some_and(|entry| entry.stored == stored)
+            {
+                entries.outcomes.remove(&key);
+            }
+        }
+        let key = (address, fingerprint);
+        let stored = Instant::now();
+        entries.expiries.push_back((stored, key));
+        entries.outcomes.insert(
+            key,
+            CachedOutcome {
+                reply,
+                connect_time,
+                handshake_time,
+                started_at,
+                stored,
+            },
+        );
+    }
+
+    /// Number of outcomes held, expired ones included.
+    pub fn len(&self) -> usize {
+        lock(&self.entries).outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every outcome, ex. when the nodes are known to have changed.
+    pub fn clear(&self) {
+        let mut entries = lock(&self.entries);
+        entries.outcomes.clear();
+        entries.expiries.clear();
+    }
+}
+
+/// Identifies the request of the configuration, its timestamp aside, and
+/// the local address it is sent from, which both may change the reply.
+fn fingerprint(config: &HandshakeConfig) -> ProtocolResult<u64> {
+    let request = config.request.build()?.encode_with_timestamp(0)?;
+    let mut hasher = DefaultHasher::new();
+    request.hash(&mut hasher);
+    config.local_address.hash(&mut hasher);
+    Ok(hasher.finish())
+}
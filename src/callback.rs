@@ -0,0 +1,93 @@
+//! This module implements the boundary between the library and the
+//! `on_accept` callbacks of the users.
+//!
+//! A panic in a callback is caught and reported as a
+//! [`ProtocolError::CallbackPanicked`] rather than unwinding through the
+//! caller, so that a faulty callback fails its own handshake without
+//! bringing down the probes running alongside it. Binaries built with
+//! `panic = "abort"` still abort, there is nothing to catch then.
+//!
+
+use std::any::Any;
+#[cfg(feature = "net")]
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+#[cfg(feature = "net")]
+use std::task::Poll;
+
+use crate::error::{ProtocolError, ProtocolResult};
+
+/// Runs the callback, turning its panic into an error.
+pub(crate) fn call<T>(callback: impl FnOnce() -> ProtocolResult<T>) -> ProtocolResult<T> {
+    // The values the callback captured are dropped with it, the caller
+    // never observes them in a broken state.
+    catch_unwind(AssertUnwindSafe(callback)).unwrap_or_else(|payload| Err(panicked(payload)))
+}
+
+/// Same as [`call`] for a callback returning a future, a panic while
+/// polling the future is turned into an error too.
+#[cfg(feature = "net")]
+pub(crate) async fn call_async<T, Fut>(callback: impl FnOnce() -> Fut) -> ProtocolResult<T>
+where
+    Fut: Future<Output = ProtocolResult<T>>,
+{
+    let future = match catch_unwind(AssertUnwindSafe(callback)) {
+        Ok(future) => future,
+        Err(payload) => return Err(panicked(payload)),
+    };
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(
+        |cx| match catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => Poll::Ready(Err(panicked(payload))),
+        },
+    )
+    .await
+}
+
+fn panicked(payload: Box<dyn Any + Send>) -> ProtocolError {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic payload".to_string(),
+        },
+    };
+    ProtocolError::CallbackPanicked(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call() {
+        assert!(matches!(call(|| Ok(7)), Ok(7)));
+        let outcome: ProtocolResult<()> = call(|| panic!("boom {}", 1));
+        let Err(ProtocolError::CallbackPanicked(message)) = outcome else {
+            panic!("expected a caught panic");
+        };
+        assert_eq!(message, "boom 1");
+    }
+
+    #[cfg(feature = "net")]
+    #[tokio::test]
+    async fn test_call_async() {
+        let outcome = call_async(|| async {
+            tokio::task::yield_now().await;
+            Ok(7)
+        })
+        .await;
+        assert!(matches!(outcome, Ok(7)));
+
+        let outcome: ProtocolResult<()> = call_async(|| async {
+            tokio::task::yield_now().await;
+            panic!("boom")
+        })
+        .await;
+        let Err(ProtocolError::CallbackPanicked(message)) = outcome else {
+            panic!("expected a caught panic");
+        };
+        assert_eq!(message, "boom");
+    }
+}
@@ -0,0 +1,183 @@
+//! This module implements exchanging the network messages over a
+//! handshaken connection from independent tasks, ex. one task answering
+//! the requests of the node while another one sends its own.
+//!
+//! [`into_channel`] splits the connection into a [`MessageSink`] and a
+//! [`MessageStream`] framing the messages for the given network:
+//!
+//! ```no_run
+//! # async fn run(stream: tokio::io::DuplexStream) -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::channel::into_channel;
+//! use p2p_handshake::framing::Network;
+//! use p2p_handshake::peers::{decode_peers, get_peers_message, PEERS_CODE};
+//!
+//! let (mut sink, mut messages) = into_channel(stream, Network::Mainnet);
+//! tokio::spawn(async move { sink.send(&get_peers_message()).await });
+//! while let Some(message) = messages.recv().await? {
+//!     if message.code == PEERS_CODE {
+//!         println!("{} peers", decode_peers(&message)?.len());
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+use crate::error::ProtocolResult;
+use crate::framing::{self, NetworkMessage, DEFAULT_MAX_BODY_SIZE, HEADER_LEN};
+
+/// Splits the stream into the halves sending and receiving the messages
+/// of the network.
+pub fn into_channel<S, N>(
+    stream: S,
+    network: N,
+) -> (MessageSink<WriteHalf<S>>, MessageStream<ReadHalf<S>>)
+where
+    S: AsyncRead + AsyncWrite,
+    N: Into<[u8; 4]>,
+{
+    let magic = network.into();
+    let (reader, writer) = tokio::io::split(stream);
+    (
+        MessageSink::new(writer, magic),
+        MessageStream::new(reader, magic),
+    )
+}
+
+/// The half of a connection sending the messages.
+#[derive(Debug)]
+pub struct MessageSink<W> {
+    writer: W,
+    magic: [u8; 4],
+}
+
+impl<W: AsyncWrite + Unpin> MessageSink<W> {
+    /// Frames the messages written to `writer`, ex. the write half of a
+    /// [`tokio::io::split`] or of `TcpStream::into_split`.
+    pub fn new<N: Into<[u8; 4]>>(writer: W, network: N) -> Self {
+        Self {
+            writer,
+            magic: network.into(),
+        }
+    }
+
+    /// Frames and writes the message.
+    pub async fn send(&mut self, message: &NetworkMessage) -> ProtocolResult<()> {
+        framing::write_message(&mut self.writer, self.magic, message).await?;
+        Ok(self.writer.flush().await?)
+    }
+
+    /// Shuts the writing down, the node then sees the end of the stream.
+    pub async fn close(&mut self) -> ProtocolResult<()> {
+        Ok(self.writer.shutdown().await?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// The half of a connection receiving the messages.
+#[derive(Debug)]
+pub struct MessageStream<R> {
+    reader: R,
+    magic: [u8; 4],
+    max_body_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> MessageStream<R> {
+    /// Decodes the messages read from `reader`, rejecting the bodies
+    /// larger than [`DEFAULT_MAX_BODY_SIZE`].
+    pub fn new<N: Into<[u8; 4]>>(reader: R, network: N) -> Self {
+        Self {
+            reader,
+            magic: network.into(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    /// Rejects the bodies larger than `size` bytes instead.
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    /// Reads the next message, `None` once the node closed the connection
+    /// between two messages.
+    pub async fn recv(&mut self) -> ProtocolResult<Option<NetworkMessage>> {
+        let mut header = [0u8; HEADER_LEN];
+        let read = self.reader.read(&mut header).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut header[read..]).await?;
+        let message =
+            framing::read_body(&mut self.reader, &header, self.magic, self.max_body_size).await?;
+        Ok(Some(message))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProtocolError;
+    use crate::framing::Network;
+    use crate::peers::{encode_peers, get_peers_message, GET_PEERS_CODE};
+
+    #[tokio::test]
+    async fn test_channel() -> ProtocolResult<()> {
+        let (local, remote) = tokio::io::duplex(64);
+        let (mut sink, mut messages) = into_channel(local, Network::Mainnet);
+        let (mut remote_sink, mut remote_messages) = into_channel(remote, Network::Mainnet);
+
+        // The remote node answers every request from its own task.
+        let node = tokio::spawn(async move {
+            while let Some(message) = remote_messages.recv().await? {
+                assert_eq!(message.code, GET_PEERS_CODE);
+                remote_sink.send(&encode_peers(&[])?).await?;
+            }
+            remote_sink.close().await
+        });
+
+        let sender = tokio::spawn(async move {
+            for _ in 0..3 {
+                sink.send(&get_peers_message()).await?;
+            }
+            sink.close().await
+        });
+        let mut replies = 0;
+        while let Some(message) = messages.recv().await? {
+            assert_eq!(message, encode_peers(&[])?);
+            replies += 1;
+        }
+        assert_eq!(replies, 3);
+        sender.await.unwrap()?;
+        node.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_recv_errors() -> ProtocolResult<()> {
+        let (local, remote) = tokio::io::duplex(64);
+        let (_, mut messages) = into_channel(local, Network::Mainnet);
+        let (mut sink, _) = into_channel(remote, Network::Testnet);
+        sink.send(&get_peers_message()).await?;
+        assert!(matches!(
+            messages.recv().await,
+            Err(ProtocolError::WrongNetwork { .. })
+        ));
+
+        // A connection closed within a message is an error.
+        let (local, mut remote) = tokio::io::duplex(64);
+        let (_, mut messages) = into_channel(local, Network::Mainnet);
+        remote.write_all(&Network::Mainnet.magic()).await?;
+        drop(remote);
+        assert!(matches!(messages.recv().await, Err(ProtocolError::Io(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+//! Measures the throughput and latency of loopback handshakes against an
+//! in-process responder, failing below the configured budget so that
+//! regressions of the encoder or the I/O path show up in automation.
+//!
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use p2p_handshake::framing::Network;
+use p2p_handshake::{handshake_with_builder, respond_handshake};
+
+use crate::cli::selftest::message_builder;
+
+#[derive(Debug, Clone, Default)]
+pub struct BenchOptions {
+    /// Number of handshakes to perform.
+    pub count: usize,
+    /// Number of handshakes running at the same time.
+    pub concurrency: usize,
+    pub budget: Budget,
+}
+
+/// The performance a run must reach, unchecked when unset.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    /// Minimum number of handshakes per second.
+    pub min_throughput: Option<f64>,
+    /// Maximum 99th percentile of the handshake latency.
+    pub max_p99: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub count: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BenchReport {
+    /// Summarizes the latencies of the handshakes of a run.
+    pub fn new(mut latencies: Vec<Duration>, elapsed: Duration) -> Self {
+        latencies.sort();
+        let percentile = |p: f64| {
+            // Nearest rank, as the monitor percentiles.
+            let rank = (p / 100.0 * latencies.len() as f64).ceil() as usize;
+            latencies
+                .get(rank.saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+        Self {
+            count: latencies.len(),
+            elapsed,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: latencies.last().copied().unwrap_or_default(),
+        }
+    }
+
+    /// Handshakes completed per second.
+    pub fn throughput(&self) -> f64 {
+        self.count as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Fails with every exceeded limit of the budget.
+    pub fn check(&self, budget: &Budget) -> Result<()> {
+        let mut breaches = vec![];
+        if let Some(min) = budget.min_throughput {
+            if self.throughput() < min {
+                breaches.push(format!(
+                    "throughput {:.0}/s is below {min:.0}/s",
+                    self.throughput()
+                ));
+            }
+        }
+        if let Some(max) = budget.max_p99 {
+            if self.p99 > max {
+                breaches.push(format!(
+                    "p99 latency {} is above {}",
+                    millis(self.p99),
+                    millis(max)
+                ));
+            }
+        }
+        if !breaches.is_empty() {
+            bail!("latency budget exceeded: {}", breaches.join(", "));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} handshakes in {:.3}s: {:.0}/s, p50 {}, p90 {}, p99 {}, max {}",
+            self.count,
+            self.elapsed.as_secs_f64(),
+            self.throughput(),
+            millis(self.p50),
+            millis(self.p90),
+            millis(self.p99),
+            millis(self.max)
+        )
+    }
+}
+
+fn millis(latency: Duration) -> String {
+    format!("{:.3}ms", latency.as_secs_f64() * 1000.0)
+}
+
+/// Performs the handshakes against an in-process responder over
+/// loopback. Any failed handshake fails the run.
+pub async fn bench(options: &BenchOptions) -> Result<BenchReport> {
+    let request = message_builder("bench-initiator", Network::Mainnet, 1)?;
+    let reply = message_builder("bench-responder", Network::Mainnet, 2)?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let responder = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let reply = reply.clone();
+            tokio::spawn(async move { respond_handshake(&mut stream, &reply).await });
+        }
+    });
+
+    let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let started = Instant::now();
+    for _ in 0..options.count {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let request = request.clone();
+        tasks.spawn(async move {
+            let _permit = permit;
+            let started = Instant::now();
+            let task = handshake_with_builder(address, &request, |_stream, _reply| Ok(()));
+            tokio::time::timeout(crate::HANDSHAKE_TIMEOUT, task).await??;
+            anyhow::Ok(started.elapsed())
+        });
+    }
+    let mut latencies = Vec::with_capacity(options.count);
+    while let Some(latency) = tasks.join_next().await {
+        latencies.push(latency??);
+    }
+    let elapsed = started.elapsed();
+    responder.abort();
+    Ok(BenchReport::new(latencies, elapsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bench() -> Result<()> {
+        let options = BenchOptions {
+            count: 20,
+            concurrency: 4,
+            ..Default::default()
+        };
+        let report = bench(&options).await?;
+        assert_eq!(report.count, 20);
+        assert!(report.p50 <= report.p99 && report.p99 <= report.max);
+        report.check(&options.budget)
+    }
+
+    #[test]
+    fn test_budget() {
+        let latencies = (1..=100).map(Duration::from_millis).collect();
+        let report = BenchReport::new(latencies, Duration::from_secs(1));
+        assert_eq!(report.p50, Duration::from_millis(50));
+        assert_eq!(report.p99, Duration::from_millis(99));
+        assert_eq!(report.throughput(), 100.0);
+
+        let budget = Budget {
+            min_throughput: Some(50.0),
+            max_p99: Some(Duration::from_millis(100)),
+        };
+        assert!(report.check(&budget).is_ok());
+        let budget = Budget {
+            min_throughput: Some(200.0),
+            max_p99: Some(Duration::from_millis(10)),
+        };
+        assert_eq!(
+            report.check(&budget).unwrap_err().to_string(),
+            "latency budget exceeded: throughput 100/s is below 200/s, \
+             p99 latency 99.000ms is above 10.000ms"
+        );
+    }
+}
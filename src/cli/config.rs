@@ -0,0 +1,369 @@
+//! The configuration of a probe run, read from a TOML file with the
+//! `config-file` feature and overridden by the command line options.
+//!
+//! [`RunConfig::resolve`] applies the defaults and cross-checks the
+//! options, so mistakes surface before a long run starts.
+//!
+
+use anyhow::{bail, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use p2p_handshake::framing::Network;
+use p2p_handshake::peers::AddressFilter;
+use p2p_handshake::ports::PortList;
+use p2p_handshake::ratelimit::RateLimiter;
+use p2p_handshake::{HandshakePolicy, HumanDuration, Version};
+
+pub const DEFAULT_VERSION: Version = Version([3, 3, 6]);
+pub const DEFAULT_CONCURRENCY: usize = 64;
+pub const DEFAULT_TIMEOUT: HumanDuration = HumanDuration::from_secs(30);
+
+/// The options of a run, unset options take their default value.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RunConfig {
+    pub name: Option<String>,
+    pub version: Option<Version>,
+    pub declared_address: Option<SocketAddr>,
+    /// Local address the outbound connections originate from.
+    pub bind: Option<SocketAddr>,
+    pub concurrency: Option<usize>,
+    /// Timeout of each handshake, ex. `10s`.
+    pub timeout: Option<HumanDuration>,
+    pub retries: Option<u32>,
+    /// Connections started per second over all the targets.
+    pub rate: Option<f64>,
+    /// Handshakes running at the same time with a single host.
+    pub per_host: Option<usize>,
+    /// Handshakes running at the same time within a single /24 subnet.
+    pub per_subnet: Option<usize>,
+    pub network: Option<Network>,
+    pub targets: Vec<String>,
+    pub targets_file: Option<PathBuf>,
+    /// Also probe the peers resolved from the default seeds of the
+    /// network.
+    pub use_seeds: bool,
+    pub policy: PolicyConfig,
+}
+
+/// The rules the replies must follow.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct PolicyConfig {
+    pub min_version: Option<Version>,
+    pub allowed_agents: Vec<String>,
+}
+
+/// The options of a run once the defaults are applied.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "config-file", derive(serde::Serialize))]
+pub struct EffectiveConfig {
+    pub name: String,
+    pub version: Version,
+    pub declared_address: Option<SocketAddr>,
+    pub bind: Option<SocketAddr>,
+    pub concurrency: usize,
+    pub timeout: HumanDuration,
+    pub retries: u32,
+    pub rate: Option<f64>,
+    pub per_host: Option<usize>,
+    pub per_subnet: Option<usize>,
+    pub network: Option<Network>,
+    pub targets: Vec<String>,
+    pub targets_file: Option<PathBuf>,
+    pub use_seeds: bool,
+    pub policy: PolicyConfig,
+}
+
+impl RunConfig {
+    /// Reads the configuration file at `path`.
+    #[cfg(feature = "config-file")]
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        use anyhow::Context;
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Returns this configuration with the options set in `other` taking
+    /// precedence.
+    #[cfg(feature = "config-file")]
+    pub fn overridden_by(self, other: RunConfig) -> RunConfig {
+        let list = |value: Vec<String>, other: Vec<String>| match other.is_empty() {
+            true => value,
+            false => other,
+        };
+        RunConfig {
+            name: other.name.or(self.name),
+            version: other.version.or(self.version),
+            declared_address: other.declared_address.or(self.declared_address),
+            bind: other.bind.or(self.bind),
+            concurrency: other.concurrency.or(self.concurrency),
+            timeout: other.timeout.or(self.timeout),
+            retries: other.retries.or(self.retries),
+            rate: other.rate.or(self.rate),
+            per_host: other.per_host.or(self.per_host),
+            per_subnet: other.per_subnet.or(self.per_subnet),
+            network: other.network.or(self.network),
+            targets: list(self.targets, other.targets),
+            targets_file: other.targets_file.or(self.targets_file),
+            use_seeds: other.use_seeds || self.use_seeds,
+            policy: PolicyConfig {
+                min_version: other.policy.min_version.or(self.policy.min_version),
+                allowed_agents: list(self.policy.allowed_agents, other.policy.allowed_agents),
+            },
+        }
+    }
+
+    /// Applies the defaults and checks the options, returning the effective
+    /// configuration along with warnings about options that are valid but
+    /// likely mistaken. Every invalid option is reported at once.
+    pub fn resolve(self) -> Result<(EffectiveConfig, Vec<String>)> {
+        let config = EffectiveConfig {
+            name: self.name.unwrap_or_default(),
+            version: self.version.unwrap_or(DEFAULT_VERSION),
+            declared_address: self.declared_address,
+            bind: self.bind,
+            concurrency: self.concurrency.unwrap_or(DEFAULT_CONCURRENCY),
+            timeout: self.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            retries: self.retries.unwrap_or_default(),
+            rate: self.rate,
+            per_host: self.per_host,
+            per_subnet: self.per_subnet,
+            network: self.network,
+            targets: self.targets,
+            targets_file: self.targets_file,
+            use_seeds: self.use_seeds,
+            policy: self.policy,
+        };
+
+        let mut errors = Vec::new();
+        if config.name.is_empty() {
+            errors.push("a name is required".to_string());
+        } else if config.name.len() > 255 {
+            errors.push("the name is longer than 255 bytes".to_string());
+        }
+        if config.concurrency == 0 {
+            errors.push("the concurrency must be at least 1".to_string());
+        }
+        if config.rate.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+            errors.push("the rate must be a positive number of connections per second".to_string());
+        }
+        if config.per_host == Some(0) || config.per_subnet == Some(0) {
+            errors.push("the per host and per subnet caps must be at least 1".to_string());
+        }
+        if config.timeout.is_zero() {
+            errors.push("the timeout must be at least 1ms".to_string());
+        }
+        if config.targets.is_empty() && config.targets_file.is_none() && !config.use_seeds {
+            errors.push("no target, targets file nor seeds are given".to_string());
+        }
+        for target in &config.targets {
+            if !is_host_port(target) {
+                errors.push(format!(
+                    "invalid target `{target}`, expected host:port or host:[port,...]"
+                ));
+            }
+        }
+        if let Some(path) = &config.targets_file {
+            if !path.is_file() {
+                errors.push(format!("targets file {} is not a file", path.display()));
+            }
+        }
+        if !errors.is_empty() {
+            bail!("invalid configuration:\n  - {}", errors.join("\n  - "));
+        }
+
+        let mut warnings = Vec::new();
+        if let Some(min_version) = &config.policy.min_version {
+            if config.version < *min_version {
+                warnings.push(format!(
+                    "the declared version {} is below policy.min_version {min_version}, \
+                     peers running it would be rejected",
+                    config.version
+                ));
+            }
+        }
+        if let Some(address) = &config.declared_address {
+            if !AddressFilter::default().accepts(address) {
+                warnings.push(format!(
+                    "the declared address {address} is private or reserved, peers won't dial it"
+                ));
+            }
+        }
+        Ok((config, warnings))
+    }
+}
+
+impl EffectiveConfig {
+    /// The limiter pacing the connections, if any limit is set.
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        if self.rate.is_none() && self.per_host.is_none() && self.per_subnet.is_none() {
+            return None;
+        }
+        let mut limiter = RateLimiter::new();
+        if let Some(rate) = self.rate {
+            limiter = limiter.per_second(rate);
+        }
+        if let Some(limit) = self.per_host {
+            limiter = limiter.per_host(limit);
+        }
+        if let Some(limit) = self.per_subnet {
+            limiter = limiter.per_subnet(limit);
+        }
+        Some(limiter)
+    }
+
+    /// The policy of the replies, if any rule is set.
+    pub fn handshake_policy(&self) -> Option<HandshakePolicy> {
+        let rules = &self.policy;
+        if self.network.is_none() && rules.min_version.is_none() && rules.allowed_agents.is_empty()
+        {
+            return None;
+        }
+        let mut policy = HandshakePolicy::new();
+        if let Some(network) = self.network {
+            policy = policy.network(network);
+        }
+        if let Some(min_version) = &rules.min_version {
+            policy = policy.min_version(min_version.clone());
+        }
+        for agent_name in &rules.allowed_agents {
+            policy = policy.allow_agent(agent_name.clone());
+        }
+        Some(policy)
+    }
+}
+
+/// Whether the target is a `host:port` or a `host:[port,...]` entry.
+fn is_host_port(target: &str) -> bool {
+    target.parse::<PortList>().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minimal() -> RunConfig {
+        RunConfig {
+            name: Some("paul".to_string()),
+            targets: vec!["1.2.3.4:9030".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let (config, warnings) = minimal().resolve()?;
+        assert_eq!(config.version, DEFAULT_VERSION);
+        assert_eq!(config.concurrency, DEFAULT_CONCURRENCY);
+        assert!(warnings.is_empty());
+        assert!(config.handshake_policy().is_none());
+        assert!(config.rate_limiter().is_none());
+
+        let error = RunConfig {
+            concurrency: Some(0),
+            targets: vec!["node".to_string()],
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("a name is required"));
+        assert!(error.contains("the concurrency must be at least 1"));
+        assert!(error.contains("invalid target `node`"));
+
+        let error = RunConfig {
+            rate: Some(0.0),
+            per_subnet: Some(0),
+            ..minimal()
+        }
+        .resolve()
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("the rate must be a positive number"));
+        assert!(error.contains("caps must be at least 1"));
+        let (limited, _) = RunConfig {
+            rate: Some(20.0),
+            ..minimal()
+        }
+        .resolve()?;
+        assert!(limited
+            .rate_limiter()
+            .is_some_and(|limiter| limiter.is_limited()));
+
+        let seeded = RunConfig {
+            targets: vec![],
+            use_seeds: true,
+            ..minimal()
+        };
+        assert!(seeded.resolve().is_ok());
+
+        let (_, warnings) = RunConfig {
+            declared_address: Some("192.168.1.1:9030".parse().unwrap()),
+            policy: PolicyConfig {
+                min_version: Some(Version([5, 0, 0])),
+                ..Default::default()
+            },
+            ..minimal()
+        }
+        .resolve()?;
+        assert_eq!(warnings.len(), 2);
+        Ok(())
+    }
+
+    #[cfg(feature = "config-file")]
+    #[test]
+    fn test_overridden_by() {
+        let file = RunConfig {
+            concurrency: Some(8),
+            targets: vec!["1.2.3.4:9030".to_string()],
+            ..minimal()
+        };
+        let args = RunConfig {
+            name: Some("jane".to_string()),
+            ..Default::default()
+        };
+        let merged = file.overridden_by(args);
+        assert_eq!(merged.name.as_deref(), Some("jane"));
+        assert_eq!(merged.concurrency, Some(8));
+        assert_eq!(merged.targets, ["1.2.3.4:9030"]);
+    }
+
+    #[cfg(feature = "config-file")]
+    #[test]
+    fn test_parse_toml() -> Result<()> {
+        let config: RunConfig = toml::from_str(
+            r#"
+            name = "paul"
+            version = "5.0.21"
+            network = "testnet"
+            timeout = "2m"
+            targets = ["1.2.3.4:9030"]
+
+            [policy]
+            min_version = "5.0.0"
+            "#,
+        )?;
+        assert_eq!(config.network, Some(Network::Testnet));
+        let (config, _) = config.resolve()?;
+        let printed = toml::to_string(&config)?;
+        assert!(printed.contains("timeout = \"2m\""));
+        assert!(printed.contains("min_version = \"5.0.0\""));
+
+        assert!(toml::from_str::<RunConfig>("nmae = \"paul\"").is_err());
+        // The unit of a duration is never implied.
+        assert!(toml::from_str::<RunConfig>("timeout = \"30\"").is_err());
+        Ok(())
+    }
+}
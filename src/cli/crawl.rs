@@ -0,0 +1,288 @@
+//! Crawls the network from seed nodes and renders the discovered
+//! topology.
+//!
+
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+#[cfg(feature = "json")]
+use p2p_handshake::clock::{Clock, SystemClock};
+use p2p_handshake::crawler::{CrawlResult, Crawler, CrawlerConfig};
+use p2p_handshake::framing::Network;
+use p2p_handshake::HandshakeConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CrawlFormat {
+    /// The nodes and the edges between them, as a JSON document
+    #[cfg(feature = "json")]
+    Json,
+    /// A Graphviz graph, the failed nodes dashed
+    Dot,
+}
+
+impl Default for CrawlFormat {
+    fn default() -> Self {
+        #[cfg(feature = "json")]
+        return CrawlFormat::Json;
+        #[cfg(not(feature = "json"))]
+        return CrawlFormat::Dot;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CrawlOptions {
+    pub network: Network,
+    pub max_depth: Option<usize>,
+    pub max_nodes: Option<usize>,
+    pub max_duration: Option<Duration>,
+    /// File keeping the outcomes between runs, keyed by the address of
+    /// the nodes.
+    #[cfg(feature = "json")]
+    pub history: Option<std::path::PathBuf>,
+}
+
+/// A crawled node.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct TopologyNode {
+    pub address: SocketAddr,
+    pub depth: usize,
+    pub referrer: Option<SocketAddr>,
+    pub agent_name: Option<String>,
+    pub version: Option<String>,
+    pub latency_ms: Option<u128>,
+    pub error: Option<String>,
+    /// Number of peers the node announced.
+    pub peers: usize,
+}
+
+/// The crawled nodes and the announcements between them.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct Topology {
+    pub nodes: Vec<TopologyNode>,
+    /// The announcements of the crawled nodes by each other.
+    pub edges: Vec<TopologyEdge>,
+}
+
+/// A node announcing another one in its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct TopologyEdge {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+}
+
+impl Topology {
+    pub fn new(results: &[CrawlResult]) -> Self {
+        let crawled: HashSet<SocketAddr> = results.iter().map(|result| result.address).collect();
+        let mut edges = BTreeSet::new();
+        for result in results {
+            let announced = result.peers.iter().filter_map(|peer| peer.declared_address);
+            for address in announced.filter(|address| crawled.contains(address)) {
+                edges.insert(TopologyEdge {
+                    from: result.address,
+                    to: address,
+                });
+            }
+        }
+        let nodes = results
+            .iter()
+            .map(|result| {
+                let reply = result.reply.as_ref().ok();
+                TopologyNode {
+                    address: result.address,
+                    depth: result.depth,
+                    referrer: result.referrer,
+                    agent_name: reply.map(|reply| reply.agent_name.to_string()),
+                    version: reply.map(|reply| reply.version.to_string()),
+                    latency_ms: result.latency.map(|latency| latency.as_millis()),
+                    error: result.reply.as_ref().err().map(|err| err.to_string()),
+                    peers: result.peers.len(),
+                }
+            })
+            .collect();
+        Self {
+            nodes,
+            edges: edges.into_iter().collect(),
+        }
+    }
+
+    pub fn render(&self, format: CrawlFormat) -> Result<String> {
+        match format {
+            #[cfg(feature = "json")]
+            CrawlFormat::Json => {
+                let mut out = serde_json::to_string_pretty(self)?;
+                out.push('\n');
+                Ok(out)
+            }
+            CrawlFormat::Dot => Ok(self.dot()),
+        }
+    }
+
+    fn dot(&self) -> String {
+        let mut out = String::from("digraph ergo {\n");
+        for node in &self.nodes {
+            let mut label = node.address.to_string();
+            if let (Some(agent_name), Some(version)) = (&node.agent_name, &node.version) {
+                label.push_str(&format!("\\n{agent_name} {version}"));
+            }
+            if let Some(latency) = node.latency_ms {
+                label.push_str(&format!("\\n{latency}ms"));
+            }
+            let style = match node.error {
+                Some(_) => ", style=dashed",
+                None => "",
+            };
+            let _ = writeln!(
+                out,
+                "  \"{}\" [label=\"{}\"{style}];",
+                node.address,
+                escape(&label)
+            );
+        }
+        for TopologyEdge { from, to } in &self.edges {
+            let _ = writeln!(out, "  \"{from}\" -> \"{to}\";");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Escapes the quotes of the agent names, line breaks being written as
+/// `\n` already.
+fn escape(label: &str) -> String {
+    label.replace('"', "\\\"")
+}
+
+/// Crawls the network from the seeds, the first resolved address of each
+/// of them.
+pub async fn crawl(
+    seeds: Vec<String>,
+    handshake: &HandshakeConfig,
+    concurrency: usize,
+    options: CrawlOptions,
+) -> Result<Topology> {
+    let mut addresses = Vec::with_capacity(seeds.len());
+    for seed in &seeds {
+        match tokio::net::lookup_host(seed.as_str())
+            .await
+            .map(|mut found| found.next())
+        {
+            Ok(Some(address)) => addresses.push(address),
+            _ => eprintln!("warning: cannot resolve the seed {seed}, skipped"),
+        }
+    }
+    if addresses.is_empty() {
+        bail!("none of the seeds could be resolved");
+    }
+
+    let mut crawler = Crawler::new(CrawlerConfig {
+        handshake: handshake.clone(),
+        network: options.network,
+        concurrency,
+        max_nodes: options.max_nodes,
+        max_depth: options.max_depth,
+        max_duration: options.max_duration,
+        ..Default::default()
+    });
+    crawler.seed(addresses);
+    #[cfg(feature = "json")]
+    let history = options
+        .history
+        .as_deref()
+        .map(crate::cli::history::HistoryFile::open)
+        .transpose()?;
+    #[cfg(feature = "json")]
+    let started_at = SystemClock.now_millis()?;
+    let results = crawler.run().await;
+    #[cfg(feature = "json")]
+    if let Some(mut history) = history {
+        history.record(results.iter().map(|result| {
+            let outcome = result.reply.as_ref();
+            (result.address.to_string(), outcome, started_at)
+        }))?;
+    }
+    Ok(Topology::new(&results))
+}
+
+#[cfg(test)]
+mod tests {
+    use p2p_handshake::{HandshakeMessage, PeerSpec, ProtocolError, TinyString};
+
+    use super::*;
+
+    fn result(address: &str, agent_name: Option<&str>, peers: &[&str]) -> CrawlResult {
+        let reply = match agent_name {
+            Some(agent_name) => Ok(HandshakeMessage {
+                agent_name: TinyString::new(agent_name).unwrap(),
+                ..Default::default()
+            }),
+            None => Err(ProtocolError::TimedOut),
+        };
+        let peers = peers
+            .iter()
+            .map(|peer| PeerSpec {
+                declared_address: Some(peer.parse().unwrap()),
+                ..PeerSpec::from(HandshakeMessage::default())
+            })
+            .collect();
+        CrawlResult {
+            address: address.parse().unwrap(),
+            reply,
+            peers,
+            depth: 0,
+            referrer: None,
+            discovered: 0,
+            latency: agent_name.map(|_| Duration::from_millis(12)),
+        }
+    }
+
+    #[test]
+    fn test_topology() -> Result<()> {
+        let results = [
+            result(
+                "1.1.1.1:9030",
+                Some("ergo\"ref"),
+                &["2.2.2.2:9030", "9.9.9.9:9030"],
+            ),
+            result("2.2.2.2:9030", None, &[]),
+        ];
+        let topology = Topology::new(&results);
+        // Announced nodes which weren't crawled have no edge.
+        assert_eq!(
+            topology.edges,
+            [TopologyEdge {
+                from: "1.1.1.1:9030".parse()?,
+                to: "2.2.2.2:9030".parse()?,
+            }]
+        );
+        assert_eq!(topology.nodes[0].peers, 2);
+        assert_eq!(
+            topology.nodes[1].error.as_deref(),
+            Some("The operation timed out")
+        );
+
+        let dot = topology.render(CrawlFormat::Dot)?;
+        assert!(dot.starts_with("digraph ergo {\n"));
+        assert!(
+            dot.contains("\"1.1.1.1:9030\" [label=\"1.1.1.1:9030\\nergo\\\"ref 0.0.0\\n12ms\"];")
+        );
+        assert!(dot.contains("\"2.2.2.2:9030\" [label=\"2.2.2.2:9030\", style=dashed];"));
+        assert!(dot.contains("\"1.1.1.1:9030\" -> \"2.2.2.2:9030\";"));
+
+        #[cfg(feature = "json")]
+        {
+            let json: serde_json::Value =
+                serde_json::from_str(&topology.render(CrawlFormat::Json)?)?;
+            assert_eq!(json["nodes"][0]["agent_name"], "ergo\"ref");
+            assert_eq!(json["edges"][0]["to"], "2.2.2.2:9030");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! Classifies the failures of a run into distinct exit codes, so that
+//! automation can tell them apart without parsing the messages.
+//!
+
+use std::fmt;
+use std::process::ExitCode;
+
+use p2p_handshake::{ProbeResult, ProtocolError};
+
+/// The exit status of the binary, by class of failure. Invalid command
+/// line arguments exit with 2, as reported by clap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Success = 0,
+    /// A failure of no other class, ex. an unreadable targets file.
+    Failure = 1,
+    /// The target could not be resolved or connected to, or dropped the
+    /// connection.
+    Connect = 3,
+    /// The connection or the handshake timed out.
+    Timeout = 4,
+    /// The reply could not be decoded or broke the protocol.
+    Decode = 5,
+    /// The peer was rejected by the policy.
+    Policy = 6,
+}
+
+impl ExitStatus {
+    /// Classifies a failed handshake.
+    pub fn of_protocol_error(err: &ProtocolError) -> Self {
+        match err {
+            ProtocolError::Io(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                ExitStatus::Timeout
+            }
+            ProtocolError::Io(_)
+            | ProtocolError::InvalidTarget(_)
+            | ProtocolError::InvalidOnionAddress(_)
+            | ProtocolError::Proxy(_)
+            | ProtocolError::Disconnected
+            | ProtocolError::SelfConnection
+            | ProtocolError::Tls(_) => ExitStatus::Connect,
+            ProtocolError::TimedOut | ProtocolError::HandshakeTimedOutByPeer => ExitStatus::Timeout,
+            ProtocolError::Utf8Error(_)
+            | ProtocolError::LEB128Error(_)
+            | ProtocolError::SpecViolation(_)
+            | ProtocolError::StringTooLong { .. }
+            | ProtocolError::InvalidVersion(_)
+            | ProtocolError::InvalidAddressSize(_)
+            | ProtocolError::InvalidPort(_)
+            | ProtocolError::FeatureTooLong { .. }
+            | ProtocolError::TooManyFeatures(_)
+            | ProtocolError::TooManyPeers(_)
+            | ProtocolError::TruncatedMessage { .. }
+            | ProtocolError::TrailingBytes(_)
+            | ProtocolError::UnexpectedMessage(_)
+            | ProtocolError::MessageTooLarge(_)
+            | ProtocolError::FeatureCountExceeded { .. }
+            | ProtocolError::InvalidMagic(_)
+            | ProtocolError::WrongNetwork { .. }
+            | ProtocolError::InvalidChecksum => ExitStatus::Decode,
+            ProtocolError::PolicyViolation(_) | ProtocolError::Plugin(_) => ExitStatus::Policy,
+            _ => ExitStatus::Failure,
+        }
+    }
+
+    /// Classifies the error a run failed with, after the first protocol
+    /// error or timeout in its chain of causes.
+    pub fn of_error(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(failed) = cause.downcast_ref::<TargetsFailed>() {
+                return failed.status;
+            }
+            if let Some(err) = cause.downcast_ref::<ProtocolError>() {
+                return Self::of_protocol_error(err);
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return ExitStatus::Timeout;
+            }
+        }
+        ExitStatus::Failure
+    }
+
+    /// A short and stable name of the class, ex. `timeout`.
+    pub fn class(self) -> &'static str {
+        match self {
+            ExitStatus::Success => "success",
+            ExitStatus::Failure => "failure",
+            ExitStatus::Connect => "connect",
+            ExitStatus::Timeout => "timeout",
+            ExitStatus::Decode => "decode",
+            ExitStatus::Policy => "policy",
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status.code())
+    }
+}
+
+/// Some targets of the run failed, the status is the one of the first
+/// failed target in the order of the targets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetsFailed {
+    pub status: ExitStatus,
+    pub failed: usize,
+    pub total: usize,
+}
+
+impl TargetsFailed {
+    /// Fails when any of the probes failed.
+    pub fn check(results: &[ProbeResult<String>]) -> Result<(), TargetsFailed> {
+        let mut failures = results
+            .iter()
+            .filter_map(|result| result.outcome.as_ref().err());
+        let Some(first) = failures.next() else {
+            return Ok(());
+        };
+        Err(TargetsFailed {
+            status: ExitStatus::of_protocol_error(first),
+            failed: 1 + failures.count(),
+            total: results.len(),
+        })
+    }
+}
+
+impl fmt::Display for TargetsFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} targets failed", self.failed, self.total)
+    }
+}
+
+impl std::error::Error for TargetsFailed {}
+
+/// Renders the error a run failed with as a JSON object on a single line.
+#[cfg(feature = "json")]
+pub fn error_json(err: &anyhow::Error, status: ExitStatus) -> String {
+    let causes: Vec<String> = err.chain().skip(1).map(ToString::to_string).collect();
+    serde_json::json!({
+        "error": err.to_string(),
+        "causes": causes,
+        "class": status.class(),
+        "exit_code": status.code(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use p2p_handshake::HandshakeMessage;
+
+    use super::*;
+
+    fn result(outcome: Result<HandshakeMessage, ProtocolError>) -> ProbeResult<String> {
+        let mut result = ProbeResult::new("1.2.3.4:9030".to_string());
+        result.outcome = outcome;
+        result
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            ExitStatus::of_protocol_error(&ProtocolError::Io(refused)),
+            ExitStatus::Connect
+        );
+        assert_eq!(
+            ExitStatus::of_protocol_error(&ProtocolError::InvalidChecksum),
+            ExitStatus::Decode
+        );
+
+        let err = anyhow::Error::new(ProtocolError::TimedOut).context("cannot probe the node");
+        assert_eq!(ExitStatus::of_error(&err), ExitStatus::Timeout);
+        let err = std::fs::read("/nonexistent/targets.txt")
+            .context("cannot read the targets")
+            .unwrap_err();
+        assert_eq!(ExitStatus::of_error(&err), ExitStatus::Failure);
+    }
+
+    #[test]
+    fn test_targets_failed() {
+        let results = [
+            result(Ok(HandshakeMessage::default())),
+            result(Err(ProtocolError::TimedOut)),
+            result(Err(ProtocolError::InvalidChecksum)),
+        ];
+        assert!(TargetsFailed::check(&results[..1]).is_ok());
+        let failed = TargetsFailed::check(&results).unwrap_err();
+        assert_eq!(failed.status, ExitStatus::Timeout);
+        assert_eq!(failed.to_string(), "2 of 3 targets failed");
+        assert_eq!(
+            ExitStatus::of_error(&anyhow::Error::new(failed)),
+            ExitStatus::Timeout
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_error_json() -> anyhow::Result<()> {
+        let err = anyhow::Error::new(ProtocolError::TimedOut).context("cannot probe the node");
+        let json: serde_json::Value =
+            serde_json::from_str(&error_json(&err, ExitStatus::of_error(&err)))?;
+        assert_eq!(json["error"], "cannot probe the node");
+        assert_eq!(json["causes"][0], "The operation timed out");
+        assert_eq!(json["class"], "timeout");
+        assert_eq!(json["exit_code"], 4);
+        Ok(())
+    }
+}
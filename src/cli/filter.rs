@@ -0,0 +1,156 @@
+//! Filters the probe reports with a [rhai](https://rhai.rs) expression,
+//! ex. `version >= "5.0" && agent.contains("ergoref")`.
+//!
+//! The expression sees the following variables:
+//!
+//! * `ok` - whether the handshake succeeded.
+//! * `target`, `address`, `agent`, `peer_name`, `declared_address`,
+//!   `error` - strings, empty when unknown.
+//! * `version` - compares with dotted strings, `"5.0"` matches every
+//!   `5.0.x` version. Failed probes have version `0.0.0`.
+//! * `latency_ms` - an integer, `-1` when unknown.
+//!
+
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, Result};
+use rhai::{Engine, EvalAltResult, Scope, AST};
+
+use p2p_handshake::Version;
+
+use super::output::ProbeReport;
+
+/// A compiled filter expression.
+pub struct Filter {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Filter {
+    pub fn new(expression: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        register_version(&mut engine);
+        let ast = engine
+            .compile_expression(expression)
+            .map_err(|err| anyhow!("invalid filter `{expression}`: {err}"))?;
+        Ok(Self { engine, ast })
+    }
+
+    /// Evaluates the expression on the report, it must return a boolean.
+    pub fn matches(&self, report: &ProbeReport) -> Result<bool> {
+        let version = report
+            .version
+            .as_deref()
+            .and_then(|version| version.parse().ok())
+            .unwrap_or_default();
+        let text = |value: &Option<String>| value.clone().unwrap_or_default();
+
+        let mut scope = Scope::new();
+        scope.push_constant("ok", report.error.is_none());
+        scope.push_constant("target", report.target.clone());
+        scope.push_constant("address", text(&report.address));
+        scope.push_constant("agent", text(&report.agent_name));
+        scope.push_constant("peer_name", text(&report.peer_name));
+        scope.push_constant("declared_address", text(&report.declared_address));
+        scope.push_constant("error", text(&report.error));
+        scope.push_constant("version", FilterVersion(version));
+        scope.push_constant(
+            "latency_ms",
+            report
+                .latency_ms
+                .and_then(|latency| i64::try_from(latency).ok())
+                .unwrap_or(-1),
+        );
+        self.engine
+            .eval_ast_with_scope::<bool>(&mut scope, &self.ast)
+            .map_err(|err| anyhow!("cannot evaluate the filter on {}: {err}", report.target))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FilterVersion(Version);
+
+impl FilterVersion {
+    /// Compares the version components given in `other` (ex. `5.0`) with
+    /// the same number of leading components.
+    fn compare(&self, other: &str) -> Result<Ordering, Box<EvalAltResult>> {
+        let mut ordering = Ordering::Equal;
+        for (index, part) in other.split('.').enumerate() {
+            let (Some(component), Ok(part)) = (self.0 .0.get(index), part.parse::<u8>()) else {
+                return Err(format!("invalid version `{other}`").into());
+            };
+            ordering = ordering.then(component.cmp(&part));
+        }
+        Ok(ordering)
+    }
+}
+
+fn register_version(engine: &mut Engine) {
+    type Comparison = fn(Ordering) -> bool;
+    const OPERATORS: [(&str, Comparison); 6] = [
+        ("==", Ordering::is_eq),
+        ("!=", Ordering::is_ne),
+        ("<", Ordering::is_lt),
+        ("<=", Ordering::is_le),
+        (">", Ordering::is_gt),
+        (">=", Ordering::is_ge),
+    ];
+
+    engine.register_type_with_name::<FilterVersion>("Version");
+    engine.register_fn("to_string", |version: &mut FilterVersion| {
+        version.0.to_string()
+    });
+    for (operator, comparison) in OPERATORS {
+        engine.register_fn(
+            operator,
+            move |version: FilterVersion, other: &str| -> Result<bool, Box<EvalAltResult>> {
+                Ok(comparison(version.compare(other)?))
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(agent_name: &str, version: &str) -> ProbeReport {
+        ProbeReport {
+            target: "1.2.3.4:9030".to_string(),
+            address: Some("1.2.3.4:9030".to_string()),
+            agent_name: Some(agent_name.to_string()),
+            version: Some(version.to_string()),
+            peer_name: Some("node".to_string()),
+            declared_address: None,
+            latency_ms: Some(42),
+            error: None,
+            error_class: None,
+            node_info: None,
+        }
+    }
+
+    #[test]
+    fn test_filter() -> Result<()> {
+        let filter = Filter::new(r#"version >= "5.0" && agent.contains("ergoref")"#)?;
+        assert!(filter.matches(&report("ergoref", "5.0.21"))?);
+        assert!(filter.matches(&report("ergoref", "10.0.0"))?);
+        assert!(!filter.matches(&report("ergoref", "4.0.100"))?);
+        assert!(!filter.matches(&report("ergo-sync", "5.0.21"))?);
+
+        let failed = ProbeReport {
+            error: Some("The operation timed out".to_string()),
+            ..report("", "")
+        };
+        assert!(Filter::new("!ok && latency_ms < 100")?.matches(&failed)?);
+        assert!(Filter::new(r#"version == "5.0""#)?.matches(&report("ergoref", "5.0.21"))?);
+
+        assert!(Filter::new("version >=").is_err());
+        assert!(Filter::new(r#"version > "x""#)?
+            .matches(&report("ergoref", "5.0.21"))
+            .is_err());
+        assert!(Filter::new("agent")?
+            .matches(&report("ergoref", "5.0.21"))
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+//! Keeps the outcomes of the probes between runs, printing what changed
+//! since the previous run.
+//!
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use p2p_handshake::history::{ProbeHistory, TargetChange};
+use p2p_handshake::{HandshakeMessage, ProtocolError};
+
+/// The history saved at a path.
+#[derive(Debug)]
+pub struct HistoryFile {
+    path: PathBuf,
+    history: ProbeHistory,
+    /// Whether the history was empty, every target is then new.
+    first_run: bool,
+}
+
+impl HistoryFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let history = ProbeHistory::load(path)
+            .with_context(|| format!("cannot read the history {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            first_run: history.is_empty(),
+            history,
+        })
+    }
+
+    /// Drops the targets probed less than `max_age` before `now`, a unix
+    /// timestamp in milliseconds, and returns the number of targets dropped.
+    pub fn skip_fresh(&self, targets: &mut Vec<String>, max_age: Duration, now: u64) -> usize {
+        let count = targets.len();
+        targets.retain(|target| !self.history.is_fresh(target, max_age, now));
+        count - targets.len()
+    }
+
+    /// Records the outcomes, prints the changes on the standard error and
+    /// saves the history. The new targets aren't reported on the first run.
+    pub fn record<'a, I>(&mut self, outcomes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, Result<&'a HandshakeMessage, &'a ProtocolError>, u64)>,
+    {
+        for (target, outcome, probed_at) in outcomes {
+            for change in self.history.record(&target, outcome, probed_at) {
+                if !(self.first_run && change == TargetChange::New) {
+                    eprintln!("change: {target} {change}");
+                }
+            }
+        }
+        self.first_run = false;
+        self.history
+            .save(&self.path)
+            .with_context(|| format!("cannot write the history {}", self.path.display()))
+    }
+}
@@ -0,0 +1,60 @@
+//! Runs a shell command before connecting to the targets.
+//!
+
+use std::io;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::sync::Arc;
+
+use p2p_handshake::dial::PreConnect;
+use p2p_handshake::route::BoxFuture;
+
+/// A pre-connect hook running the command with `sh -c`, the address to
+/// connect to in the `TARGET_IP` and `TARGET_PORT` environment variables.
+/// The connection attempt fails when the command exits with an error.
+pub struct CommandHook {
+    command: Arc<str>,
+}
+
+impl CommandHook {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: Arc::from(command),
+        }
+    }
+}
+
+impl PreConnect for CommandHook {
+    fn before_connect(&self, address: SocketAddr) -> BoxFuture<'static, io::Result<()>> {
+        let command = self.command.clone();
+        let running = tokio::task::spawn_blocking(move || {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(&*command)
+                .env("TARGET_IP", address.ip().to_string())
+                .env("TARGET_PORT", address.port().to_string())
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "the pre-connect command failed for {address}: {status}"
+                )));
+            }
+            Ok(())
+        });
+        Box::pin(async move { running.await.map_err(io::Error::other)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_command_hook() {
+        let address: SocketAddr = "127.0.0.1:9030".parse().unwrap();
+        let hook = CommandHook::new(r#"test "$TARGET_IP:$TARGET_PORT" = 127.0.0.1:9030"#);
+        assert!(hook.before_connect(address).await.is_ok());
+        let hook = CommandHook::new("exit 1");
+        assert!(hook.before_connect(address).await.is_err());
+    }
+}
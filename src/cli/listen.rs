@@ -0,0 +1,94 @@
+//! Acts as the responder of incoming handshakes, printing the peer spec
+//! of every initiator.
+//!
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use p2p_handshake::listener::HandshakeListener;
+use p2p_handshake::{HandshakeBuilder, HandshakeMessage, ProtocolError};
+
+/// The protections of the listener against abusive initiators.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenOptions {
+    pub handshake_timeout: Duration,
+    pub max_connections_per_ip: usize,
+    pub max_message_size: usize,
+}
+
+/// Accepts connections on `address` forever, answering every handshake
+/// with the reply composed by the builder.
+pub async fn listen(
+    address: SocketAddr,
+    reply: HandshakeBuilder,
+    options: ListenOptions,
+) -> Result<()> {
+    let listener = HandshakeListener::bind(address, reply)
+        .await?
+        .handshake_timeout(options.handshake_timeout)
+        .max_connections_per_ip(options.max_connections_per_ip)
+        .max_message_size(options.max_message_size);
+    println!("Listening on {}", listener.local_addr()?);
+    loop {
+        let incoming = match listener.accept().await {
+            Ok(incoming) => incoming,
+            Err(err @ ProtocolError::TooManyConnections { .. }) => {
+                eprintln!("{err}, connection refused");
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let remote = incoming.remote_addr();
+        tokio::spawn(async move {
+            match incoming.handshake().await {
+                Ok(peer) => println!("{}", describe(remote, &peer.request)),
+                Err(err) => eprintln!("{remote}: {err}"),
+            }
+        });
+    }
+}
+
+/// Describes the peer spec sent by an initiator on a single line.
+fn describe(remote: SocketAddr, request: &HandshakeMessage) -> String {
+    let declared_address = request
+        .declared_address
+        .map(|address| address.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let features: Vec<String> = request
+        .features
+        .iter()
+        .map(|feature| feature.id.to_string())
+        .collect();
+    format!(
+        "{remote}: agent={} version={} peer_name={} declared_address={} features=[{}]",
+        request.agent_name,
+        request.version,
+        request.peer_name,
+        declared_address,
+        features.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use p2p_handshake::{PeerFeature, TinyString, Version};
+
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let request = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version([5, 0, 21]),
+            peer_name: TinyString::new("node").unwrap(),
+            features: vec![PeerFeature::new(16, vec![]), PeerFeature::new(3, vec![])],
+            ..Default::default()
+        };
+        assert_eq!(
+            describe("1.2.3.4:5000".parse().unwrap(), &request),
+            "1.2.3.4:5000: agent=ergoref version=5.0.21 peer_name=node declared_address=- features=[16,3]"
+        );
+    }
+}
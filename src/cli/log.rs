@@ -0,0 +1,30 @@
+//! Writes the events of the library to the standard error, ex. for the
+//! log pipeline of a service manager.
+//!
+
+use std::io::IsTerminal;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use tracing_subscriber::filter::LevelFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Pretty,
+    /// One JSON object per event, with the fields of its spans
+    Json,
+}
+
+/// Installs the subscriber writing the events up to the level.
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr);
+    let result = match format {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+    result.map_err(|err| anyhow!("cannot install the logger: {err}"))
+}
@@ -0,0 +1,20 @@
+//! Building blocks of the command line tool.
+//!
+
+pub mod bench;
+pub mod config;
+#[cfg(feature = "crawl")]
+pub mod crawl;
+pub mod exit;
+#[cfg(feature = "filter")]
+pub mod filter;
+#[cfg(feature = "json")]
+pub mod history;
+pub mod hook;
+pub mod listen;
+#[cfg(feature = "tracing")]
+pub mod log;
+pub mod monitor;
+pub mod output;
+pub mod selftest;
+pub mod targets;
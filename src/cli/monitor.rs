@@ -0,0 +1,276 @@
+//! Repeatedly probes the targets, tracking their uptime, latency
+//! percentiles and identity changes over the rounds.
+//!
+
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use tokio::time::MissedTickBehavior;
+
+use p2p_handshake::monitor::{ChangeDetector, TargetStats};
+use p2p_handshake::{HandshakeConfig, ProbeResult};
+
+use crate::cli::output::write_table;
+use crate::cli::targets::probe_targets;
+
+/// Clears the terminal and moves the cursor to the top left corner.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MonitorFormat {
+    /// A table refreshed every round, for humans
+    Table,
+    /// One JSON object per target and round
+    #[cfg(feature = "json")]
+    Ndjson,
+}
+
+#[derive(Debug, Clone)]
+pub struct MonitorOptions {
+    /// Delay between the start of two rounds.
+    pub interval: Duration,
+    /// Number of rounds to run, forever if unset.
+    pub rounds: Option<u64>,
+    pub format: MonitorFormat,
+    /// File keeping the outcomes between runs, updated after every round.
+    #[cfg(feature = "json")]
+    pub history: Option<std::path::PathBuf>,
+}
+
+/// The state of a target after a round.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct MonitorRow {
+    pub round: u64,
+    pub target: String,
+    pub up: bool,
+    pub agent_name: Option<String>,
+    pub version: Option<String>,
+    /// Connection and handshake time of this round, in milliseconds.
+    pub latency_ms: Option<u128>,
+    /// Share of the rounds the target completed the handshake.
+    pub uptime: f64,
+    pub p50_ms: Option<u128>,
+    pub p90_ms: Option<u128>,
+    pub p99_ms: Option<u128>,
+    pub error: Option<String>,
+    /// Changes of the identity or features of the target in this round.
+    pub changes: Vec<String>,
+    /// The most recent change, kept over the rounds.
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub last_change: Option<String>,
+}
+
+/// The history of the monitored targets.
+#[derive(Debug, Default)]
+pub struct Monitor {
+    stats: HashMap<String, TargetStats>,
+    last_changes: HashMap<String, String>,
+    detector: ChangeDetector,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the results of a round and returns the state of every
+    /// target, in the order of the results.
+    pub fn observe(&mut self, round: u64, results: &[ProbeResult<String>]) -> Vec<MonitorRow> {
+        results
+            .iter()
+            .map(|result| self.observe_one(round, result))
+            .collect()
+    }
+
+    fn observe_one(&mut self, round: u64, result: &ProbeResult<String>) -> MonitorRow {
+        let target = &result.target;
+        let reply = result.outcome.as_ref().ok();
+        let latency = match (reply, result.connect_time, result.handshake_time) {
+            (Some(_), Some(connect_time), Some(handshake_time)) => {
+                Some(connect_time + handshake_time)
+            }
+            _ => None,
+        };
+        let stats = self.stats.entry(target.clone()).or_default();
+        stats.record(latency);
+        let millis = |latency: Option<Duration>| latency.map(|latency| latency.as_millis());
+
+        let changes: Vec<String> = reply
+            .and_then(|reply| self.detector.observe(target, reply.clone()))
+            .map(|event| event.diffs.iter().map(ToString::to_string).collect())
+            .unwrap_or_default();
+        if !changes.is_empty() {
+            self.last_changes.insert(
+                target.clone(),
+                format!("round {round}: {}", changes.join(", ")),
+            );
+        }
+
+        MonitorRow {
+            round,
+            target: target.clone(),
+            up: reply.is_some(),
+            agent_name: reply.map(|reply| reply.agent_name.to_string()),
+            version: reply.map(|reply| reply.version.to_string()),
+            latency_ms: millis(latency),
+            uptime: stats.uptime(),
+            p50_ms: millis(stats.latency_percentile(50.0)),
+            p90_ms: millis(stats.latency_percentile(90.0)),
+            p99_ms: millis(stats.latency_percentile(99.0)),
+            error: result.outcome.as_ref().err().map(|err| err.to_string()),
+            changes,
+            last_change: self.last_changes.get(target).cloned(),
+        }
+    }
+}
+
+/// Probes the targets every interval and prints their state after each
+/// round, until the requested number of rounds ran.
+pub async fn monitor(
+    targets: Vec<String>,
+    config: &HandshakeConfig,
+    concurrency: usize,
+    options: MonitorOptions,
+) -> Result<()> {
+    let live = std::io::stdout().is_terminal();
+    let mut monitor = Monitor::new();
+    #[cfg(feature = "json")]
+    let mut history = options
+        .history
+        .as_deref()
+        .map(crate::cli::history::HistoryFile::open)
+        .transpose()?;
+    let mut ticks = tokio::time::interval(options.interval);
+    // A round outlasting the interval delays the next one instead of
+    // starting a burst of rounds.
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for round in 1.. {
+        ticks.tick().await;
+        let results = probe_targets(targets.clone(), config, concurrency).await;
+        let rows = monitor.observe(round, &results);
+        #[cfg(feature = "json")]
+        if let Some(history) = &mut history {
+            history.record(results.iter().map(|result| {
+                let outcome = result.outcome.as_ref();
+                (result.target.clone(), outcome, result.started_at)
+            }))?;
+        }
+        let mut stdout = std::io::stdout().lock();
+        if live && options.format == MonitorFormat::Table {
+            stdout.write_all(CLEAR_SCREEN.as_bytes())?;
+        }
+        stdout.write_all(render(&rows, options.format)?.as_bytes())?;
+        stdout.flush()?;
+        if options.rounds == Some(round) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Renders the rows of a round, the text ends with a new line.
+pub fn render(rows: &[MonitorRow], format: MonitorFormat) -> Result<String> {
+    let mut out = String::new();
+    match format {
+        MonitorFormat::Table => {
+            let up = rows.iter().filter(|row| row.up).count();
+            let round = rows.first().map(|row| row.round).unwrap_or_default();
+            out.push_str(&format!("Round {round}, {up}/{} targets up\n", rows.len()));
+            let headers = [
+                "TARGET",
+                "STATUS",
+                "UPTIME",
+                "LATENCY",
+                "P50",
+                "P90",
+                "P99",
+                "VERSION",
+                "LAST CHANGE",
+            ];
+            let rows: Vec<Vec<String>> = rows.iter().map(cells).collect();
+            write_table(&headers, &rows, &mut out)?;
+        }
+        #[cfg(feature = "json")]
+        MonitorFormat::Ndjson => {
+            for row in rows {
+                out.push_str(&serde_json::to_string(row)?);
+                out.push('\n');
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn cells(row: &MonitorRow) -> Vec<String> {
+    let millis = |latency: Option<u128>| match latency {
+        Some(latency) => format!("{latency}ms"),
+        None => "-".to_string(),
+    };
+    vec![
+        row.target.clone(),
+        if row.up { "up" } else { "down" }.to_string(),
+        format!("{:.1}%", row.uptime * 100.0),
+        millis(row.latency_ms),
+        millis(row.p50_ms),
+        millis(row.p90_ms),
+        millis(row.p99_ms),
+        row.version.clone().unwrap_or_else(|| "-".to_string()),
+        row.last_change.clone().unwrap_or_else(|| "-".to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use p2p_handshake::{HandshakeMessage, ProtocolError, Version};
+
+    use super::*;
+
+    fn result(version: Option<[u8; 3]>, latency_ms: u64) -> ProbeResult<String> {
+        let mut result = ProbeResult::new("1.2.3.4:9030".to_string());
+        if let Some(version) = version {
+            result.outcome = Ok(HandshakeMessage {
+                version: Version(version),
+                ..Default::default()
+            });
+            result.connect_time = Some(Duration::from_millis(latency_ms / 2));
+            result.handshake_time = Some(Duration::from_millis(latency_ms / 2));
+        } else {
+            result.outcome = Err(ProtocolError::TimedOut);
+        }
+        result
+    }
+
+    #[test]
+    fn test_monitor_rounds() -> Result<()> {
+        let mut monitor = Monitor::new();
+        monitor.observe(1, &[result(Some([5, 0, 20]), 20)]);
+        monitor.observe(2, &[result(None, 0)]);
+        let rows = monitor.observe(3, &[result(Some([5, 0, 21]), 40)]);
+        let row = &rows[0];
+        assert!(row.up);
+        assert!((row.uptime - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(row.latency_ms, Some(40));
+        assert_eq!(row.p50_ms, Some(20));
+        assert_eq!(row.p99_ms, Some(40));
+        assert_eq!(row.changes, ["version: 5.0.20 -> 5.0.21"]);
+
+        let rows = monitor.observe(4, &[result(Some([5, 0, 21]), 30)]);
+        assert!(rows[0].changes.is_empty());
+        assert_eq!(
+            rows[0].last_change.as_deref(),
+            Some("round 3: version: 5.0.20 -> 5.0.21")
+        );
+
+        let expected = "\
+Round 4, 1/1 targets up
+TARGET        STATUS  UPTIME  LATENCY  P50   P90   P99   VERSION  LAST CHANGE
+1.2.3.4:9030  up      75.0%   30ms     30ms  40ms  40ms  5.0.21   round 3: version: 5.0.20 -> 5.0.21
+";
+        assert_eq!(render(&rows, MonitorFormat::Table)?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+//! Renders the outcome of the probes in the requested output format.
+//!
+
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use p2p_handshake::{NodeInfo, ProbeResult};
+
+use crate::cli::exit::ExitStatus;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// An aligned table, for humans
+    Table,
+    /// A single JSON array
+    #[cfg(feature = "json")]
+    Json,
+    /// One JSON object per line
+    #[cfg(feature = "json")]
+    Ndjson,
+}
+
+/// The outcome of a probe as reported by the command line tool.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProbeReport {
+    pub target: String,
+    pub address: Option<String>,
+    pub agent_name: Option<String>,
+    pub version: Option<String>,
+    pub peer_name: Option<String>,
+    /// The public address the peer advertised.
+    pub declared_address: Option<String>,
+    /// Connection and handshake time, in milliseconds.
+    pub latency_ms: Option<u128>,
+    pub error: Option<String>,
+    /// The class of the error, as the exit status it leads to, ex. `timeout`.
+    pub error_class: Option<&'static str>,
+    /// The client and release parsed from the agent name.
+    pub node_info: Option<NodeInfo>,
+}
+
+impl From<&ProbeResult<String>> for ProbeReport {
+    fn from(result: &ProbeResult<String>) -> Self {
+        let reply = result.outcome.as_ref().ok();
+        let latency = match (result.connect_time, result.handshake_time) {
+            (Some(connect_time), Some(handshake_time)) => {
+                Some((connect_time + handshake_time).as_millis())
+            }
+            _ => None,
+        };
+        Self {
+            target: result.target.clone(),
+            address: result.address.map(|address| address.to_string()),
+            agent_name: reply.map(|reply| reply.agent_name.to_string()),
+            node_info: reply.map(|reply| reply.node_info()),
+            version: reply.map(|reply| reply.version.to_string()),
+            peer_name: reply.map(|reply| reply.peer_name.to_string()),
+            declared_address: reply
+                .and_then(|reply| reply.declared_address)
+                .map(|address| address.to_string()),
+            latency_ms: latency,
+            error: result.outcome.as_ref().err().map(|err| err.to_string()),
+            error_class: result
+                .outcome
+                .as_ref()
+                .err()
+                .map(|err| ExitStatus::of_protocol_error(err).class()),
+        }
+    }
+}
+
+/// A column of the table, or a key of the JSON objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum Field {
+    Target,
+    Address,
+    AgentName,
+    Version,
+    PeerName,
+    DeclaredAddress,
+    LatencyMs,
+    Error,
+    ErrorClass,
+    Client,
+}
+
+impl Field {
+    /// The fields rendered when none are selected, the table leaves the
+    /// address out to stay readable.
+    pub fn defaults(format: OutputFormat) -> &'static [Field] {
+        match format {
+            OutputFormat::Table => &[
+                Field::Target,
+                Field::AgentName,
+                Field::Version,
+                Field::PeerName,
+                Field::LatencyMs,
+                Field::Error,
+            ],
+            #[cfg(feature = "json")]
+            OutputFormat::Json | OutputFormat::Ndjson => &[
+                Field::Target,
+                Field::Address,
+                Field::AgentName,
+                Field::Version,
+                Field::PeerName,
+                Field::DeclaredAddress,
+                Field::LatencyMs,
+                Field::Error,
+                Field::ErrorClass,
+            ],
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            Field::Target => "TARGET",
+            Field::Address => "ADDRESS",
+            Field::AgentName => "AGENT",
+            Field::Version => "VERSION",
+            Field::PeerName => "PEER NAME",
+            Field::DeclaredAddress => "DECLARED ADDRESS",
+            Field::LatencyMs => "LATENCY",
+            Field::Error => "ERROR",
+            Field::ErrorClass => "ERROR CLASS",
+            Field::Client => "CLIENT",
+        }
+    }
+
+    #[cfg(feature = "json")]
+    fn key(self) -> &'static str {
+        match self {
+            Field::Target => "target",
+            Field::Address => "address",
+            Field::AgentName => "agent_name",
+            Field::Version => "version",
+            Field::PeerName => "peer_name",
+            Field::DeclaredAddress => "declared_address",
+            Field::LatencyMs => "latency_ms",
+            Field::Error => "error",
+            Field::ErrorClass => "error_class",
+            Field::Client => "client",
+        }
+    }
+
+    fn cell(self, report: &ProbeReport) -> String {
+        let value = match self {
+            Field::Target => Some(report.target.clone()),
+            Field::Address => report.address.clone(),
+            Field::AgentName => report.agent_name.clone(),
+            Field::Version => report.version.clone(),
+            Field::PeerName => report.peer_name.clone(),
+            Field::DeclaredAddress => report.declared_address.clone(),
+            Field::LatencyMs => report.latency_ms.map(|latency| format!("{latency}ms")),
+            Field::Error => report.error.clone(),
+            Field::ErrorClass => report.error_class.map(str::to_string),
+            Field::Client => report.node_info.as_ref().map(NodeInfo::to_string),
+        };
+        value.unwrap_or_else(|| "-".to_string())
+    }
+}
+
+/// The selected fields of a report, serialized in the selection order.
+#[cfg(feature = "json")]
+struct Selection<'a> {
+    report: &'a ProbeReport,
+    fields: &'a [Field],
+}
+
+#[cfg(feature = "json")]
+impl serde::Serialize for Selection<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let report = self.report;
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for field in self.fields {
+            match field {
+                Field::Target => map.serialize_entry(field.key(), &report.target)?,
+                Field::Address => map.serialize_entry(field.key(), &report.address)?,
+                Field::AgentName => map.serialize_entry(field.key(), &report.agent_name)?,
+                Field::Version => map.serialize_entry(field.key(), &report.version)?,
+                Field::PeerName => map.serialize_entry(field.key(), &report.peer_name)?,
+                Field::DeclaredAddress => {
+                    map.serialize_entry(field.key(), &report.declared_address)?
+                }
+                Field::LatencyMs => map.serialize_entry(field.key(), &report.latency_ms)?,
+                Field::Error => map.serialize_entry(field.key(), &report.error)?,
+                Field::ErrorClass => map.serialize_entry(field.key(), &report.error_class)?,
+                Field::Client => {
+                    let client = report.node_info.as_ref().map(|info| {
+                        serde_json::json!({
+                            "name": info.client,
+                            "version": info.version.as_ref().map(ToString::to_string),
+                            "flags": info.flags,
+                        })
+                    });
+                    map.serialize_entry(field.key(), &client)?
+                }
+            }
+        }
+        map.end()
+    }
+}
+
+/// Renders the given fields of the reports, or the default ones of the
+/// format when `fields` is empty. The returned text ends with a new line.
+pub fn render(
+    reports: &[ProbeReport],
+    format: OutputFormat,
+    fields: &[Field],
+) -> anyhow::Result<String> {
+    let fields = match fields {
+        [] => Field::defaults(format),
+        fields => fields,
+    };
+    let mut out = String::new();
+    #[cfg(feature = "json")]
+    let selections = reports.iter().map(|report| Selection { report, fields });
+    match format {
+        OutputFormat::Table => render_table(reports, fields, &mut out)?,
+        #[cfg(feature = "json")]
+        OutputFormat::Json => {
+            let selections: Vec<Selection> = selections.collect();
+            out.push_str(&serde_json::to_string_pretty(&selections)?);
+            out.push('\n');
+        }
+        #[cfg(feature = "json")]
+        OutputFormat::Ndjson => {
+            for selection in selections {
+                out.push_str(&serde_json::to_string(&selection)?);
+                out.push('\n');
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn render_table(reports: &[ProbeReport], fields: &[Field], out: &mut String) -> std::fmt::Result {
+    let headers: Vec<&str> = fields.iter().map(|field| field.header()).collect();
+    let rows: Vec<Vec<String>> = reports
+        .iter()
+        .map(|report| fields.iter().map(|field| field.cell(report)).collect())
+        .collect();
+    write_table(&headers, &rows, out)
+}
+
+/// Writes the rows below the headers, with the columns aligned.
+pub fn write_table(headers: &[&str], rows: &[Vec<String>], out: &mut String) -> std::fmt::Result {
+    let headers: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+    let mut widths: Vec<usize> = headers.iter().map(String::len).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in std::iter::once(&headers).chain(rows) {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(&widths) {
+            write!(line, "{cell:width$}  ")?;
+        }
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reports() -> Vec<ProbeReport> {
+        vec![
+            ProbeReport {
+                target: "1.2.3.4:9030".to_string(),
+                address: Some("1.2.3.4:9030".to_string()),
+                agent_name: Some("ergoref".to_string()),
+                version: Some("5.0.21".to_string()),
+                peer_name: Some("node".to_string()),
+                declared_address: Some("1.2.3.4:9030".to_string()),
+                latency_ms: Some(42),
+                error: None,
+                error_class: None,
+                node_info: Some(NodeInfo {
+                    client: "ergoref".to_string(),
+                    version: Some(p2p_handshake::Version([5, 0, 21])),
+                    flags: vec!["mainnet".to_string()],
+                }),
+            },
+            ProbeReport {
+                target: "5.6.7.8:9030".to_string(),
+                address: None,
+                agent_name: None,
+                version: None,
+                peer_name: None,
+                declared_address: None,
+                latency_ms: None,
+                error: Some("The operation timed out".to_string()),
+                error_class: Some("timeout"),
+                node_info: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_render_table() -> anyhow::Result<()> {
+        let expected = "\
+TARGET        AGENT    VERSION  PEER NAME  LATENCY  ERROR
+1.2.3.4:9030  ergoref  5.0.21   node       42ms     -
+5.6.7.8:9030  -        -        -          -        The operation timed out
+";
+        assert_eq!(render(&reports(), OutputFormat::Table, &[])?, expected);
+
+        let expected = "\
+VERSION  TARGET
+5.0.21   1.2.3.4:9030
+-        5.6.7.8:9030
+";
+        let fields = [Field::Version, Field::Target];
+        assert_eq!(render(&reports(), OutputFormat::Table, &fields)?, expected);
+
+        let expected = "\
+CLIENT
+ergoref 5.0.21 +mainnet
+-
+";
+        assert_eq!(
+            render(&reports(), OutputFormat::Table, &[Field::Client])?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_render_ndjson() -> anyhow::Result<()> {
+        let out = render(&reports(), OutputFormat::Ndjson, &[])?;
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(lines[0])?;
+        assert_eq!(first["agent_name"], "ergoref");
+        assert_eq!(first["latency_ms"], 42);
+        assert_eq!(first["declared_address"], "1.2.3.4:9030");
+
+        let out = render(&reports(), OutputFormat::Json, &[])?;
+        let all: serde_json::Value = serde_json::from_str(&out)?;
+        assert_eq!(all[1]["error"], "The operation timed out");
+        assert_eq!(all[1]["error_class"], "timeout");
+
+        let fields = [Field::Target, Field::LatencyMs];
+        let out = render(&reports(), OutputFormat::Ndjson, &fields)?;
+        assert_eq!(
+            out.lines().next(),
+            Some(r#"{"target":"1.2.3.4:9030","latency_ms":42}"#)
+        );
+
+        let out = render(&reports(), OutputFormat::Ndjson, &[Field::Client])?;
+        assert_eq!(
+            out.lines().next(),
+            Some(r#"{"client":{"flags":["mainnet"],"name":"ergoref","version":"5.0.21"}}"#)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Performs a handshake with an in-process responder over loopback, to
+//! validate a build or a deployment without touching real peers.
+//!
+
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, bail, Result};
+use tokio::net::TcpListener;
+
+use p2p_handshake::feature::{SessionFeature, MODE_FEATURE_ID};
+use p2p_handshake::framing::Network;
+use p2p_handshake::{
+    handshake_with_builder, respond_handshake, HandshakeBuilder, HandshakeMessage, PeerFeature,
+    Version,
+};
+
+/// Fixed timestamp of the exchanged messages, so that they can be
+/// compared after the round trip.
+const TIMESTAMP: u64 = 1_700_000_000_000;
+
+/// Handshakes the in-process responder and checks that both peers
+/// received the exact messages sent by the other, returning a line per
+/// passed check.
+pub async fn self_test() -> Result<Vec<String>> {
+    let request = message_builder("self-test-initiator", Network::Mainnet, 8192)?;
+    let reply = message_builder("self-test-responder", Network::Mainnet, -8192)?;
+    let mut checks = vec![];
+
+    // The encoding round trip alone, without the network.
+    let sent = request.build()?;
+    let decoded = HandshakeMessage::decode_from_response(request.encode()?)?;
+    compare("encoding round trip", &sent, &decoded)?;
+    checks.push("encoding round trip".to_string());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let responder = {
+        let reply = reply.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            respond_handshake(&mut stream, &reply).await
+        })
+    };
+    let received_reply = initiate(address, &request).await;
+    // The responder fails in turn when the initiator does, the error of
+    // the initiator tells more.
+    let received_request = responder.await?;
+    let received_reply = received_reply?;
+    checks.push(format!("loopback handshake with {address}"));
+
+    compare(
+        "request received by the responder",
+        &sent,
+        &received_request?,
+    )?;
+    checks.push("request received by the responder".to_string());
+    compare(
+        "reply received by the initiator",
+        &reply.build()?,
+        &received_reply,
+    )?;
+    checks.push("reply received by the initiator".to_string());
+    Ok(checks)
+}
+
+/// A message exercising every field of the handshake. The peers of a
+/// handshake need distinct session ids, else the initiator takes the
+/// responder for itself.
+pub fn message_builder(name: &str, network: Network, session_id: i64) -> Result<HandshakeBuilder> {
+    let session = SessionFeature::new(network.magic(), session_id);
+    Ok(HandshakeBuilder::new()
+        .agent_name("ergoref")
+        .peer_name(name)
+        .version(Version([5, 0, 21]))
+        .declared_address("127.0.0.1:9030".parse()?)
+        .feature(session.into())
+        .feature(PeerFeature::new(MODE_FEATURE_ID, vec![0, 1, 0, 1]))
+        .timestamp(TIMESTAMP))
+}
+
+async fn initiate(address: SocketAddr, request: &HandshakeBuilder) -> Result<HandshakeMessage> {
+    let mut reply = None;
+    let task = handshake_with_builder(address, request, |_stream, message| {
+        reply = Some(message);
+        Ok(())
+    });
+    tokio::time::timeout(crate::HANDSHAKE_TIMEOUT, task).await??;
+    reply.ok_or_else(|| anyhow!("the handshake completed without a reply"))
+}
+
+/// Fails with the differences between the sent and received messages.
+fn compare(check: &str, sent: &HandshakeMessage, received: &HandshakeMessage) -> Result<()> {
+    let mut diffs: Vec<String> = sent
+        .diff(received)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    if sent.timestamp != received.timestamp {
+        diffs.push(format!(
+            "timestamp: {} -> {}",
+            sent.timestamp, received.timestamp
+        ));
+    }
+    if !diffs.is_empty() {
+        bail!(
+            "{check} differs from the sent message: {}",
+            diffs.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_self_test() -> Result<()> {
+        let checks = self_test().await?;
+        assert_eq!(checks.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare() -> Result<()> {
+        let sent = message_builder("a", Network::Mainnet, 1)?.build()?;
+        let received = message_builder("b", Network::Testnet, 2)?.build()?;
+        let err = compare("check", &sent, &received).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "check differs from the sent message: peer name: a -> b, feature changed: 3"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+//! Collects the targets given on the command line.
+//!
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use p2p_handshake::ports::{handshake_many_ports, PortList};
+use p2p_handshake::{handshake_many, HandshakeConfig, ProbeResult};
+
+/// Reads a targets file, one `host:port` per line. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn read_targets_file(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("cannot read targets file {}", path.display()))?;
+    Ok(parse_targets(&content))
+}
+
+/// Maximum number of targets a single entry expands to, a /12 IPv4 range.
+const MAX_EXPANDED_TARGETS: u128 = 1 << 20;
+
+/// Expands the entries giving a range of addresses in CIDR notation, ex.
+/// `10.0.0.0/24:9030` or `[2001:db8::/120]:9030`, and the entries giving
+/// comma separated ports, ex. `node.example.org:9030,9053`, into a target
+/// per address and port. The network and broadcast addresses of the IPv4
+/// ranges are left out, a range must give its port. The other entries are
+/// kept as given.
+pub fn expand_targets(targets: Vec<String>) -> Result<Vec<String>> {
+    let mut expanded = Vec::with_capacity(targets.len());
+    for target in targets {
+        expand_target(&target, &mut expanded)
+            .with_context(|| format!("invalid target {target}"))?;
+    }
+    Ok(expanded)
+}
+
+fn expand_target(target: &str, expanded: &mut Vec<String>) -> Result<()> {
+    let split = target.rsplit_once(':');
+    let Some((host, ports)) = split.filter(|(_, ports)| !ports.contains('/')) else {
+        if target.contains('/') {
+            bail!("missing port after the address range");
+        }
+        expanded.push(target.to_string());
+        return Ok(());
+    };
+    // Brackets list the ports tried in order, they stay a single target.
+    let is_port_list = ports.contains(',') && !ports.starts_with('[');
+    if !host.contains('/') && !is_port_list {
+        expanded.push(target.to_string());
+        return Ok(());
+    }
+
+    let ports: Vec<String> = if is_port_list {
+        ports
+            .split(',')
+            .map(|port| Ok(port.trim().parse::<u16>()?.to_string()))
+            .collect::<Result<_>>()?
+    } else {
+        vec![ports.to_string()]
+    };
+    let (first, count) = match host.split_once('/') {
+        Some((ip, prefix)) => {
+            let ip = ip.trim_start_matches('[');
+            let prefix = prefix.trim_end_matches(']');
+            address_range(ip.parse()?, prefix.parse()?)?
+        }
+        None => {
+            for port in &ports {
+                expanded.push(format!("{host}:{port}"));
+            }
+            return Ok(());
+        }
+    };
+    if count.saturating_mul(ports.len() as u128) > MAX_EXPANDED_TARGETS {
+        bail!("the entry expands to more than {MAX_EXPANDED_TARGETS} targets");
+    }
+    for offset in 0..count {
+        let host = match first {
+            IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) + offset as u32).to_string(),
+            IpAddr::V6(ip) => format!("[{}]", Ipv6Addr::from(u128::from(ip) + offset)),
+        };
+        for port in &ports {
+            expanded.push(format!("{host}:{port}"));
+        }
+    }
+    Ok(())
+}
+
+/// The first address and the number of addresses of the range.
+fn address_range(ip: IpAddr, prefix: u32) -> Result<(IpAddr, u128)> {
+    let bits = match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    if prefix > bits {
+        bail!("the prefix length {prefix} exceeds {bits}");
+    }
+    let host_bits = bits - prefix;
+    if host_bits > MAX_EXPANDED_TARGETS.ilog2() {
+        bail!("the range /{prefix} holds more than {MAX_EXPANDED_TARGETS} addresses");
+    }
+    let count = 1u128 << host_bits;
+    Ok(match ip {
+        IpAddr::V4(ip) => {
+            let first = u32::from(ip) & !(u32::MAX.checked_shr(prefix).unwrap_or(0));
+            // The network and broadcast addresses aren't hosts.
+            if prefix < 31 {
+                (Ipv4Addr::from(first + 1).into(), count - 2)
+            } else {
+                (Ipv4Addr::from(first).into(), count)
+            }
+        }
+        IpAddr::V6(ip) => {
+            let first = u128::from(ip) & !(u128::MAX.checked_shr(prefix).unwrap_or(0));
+            (Ipv6Addr::from(first).into(), count)
+        }
+    })
+}
+
+/// Probes the targets, resolving their host names with hickory instead of
+/// the C library when the `hickory-dns` feature is enabled. The entries
+/// listing several ports, ex. `host:[9030,9020]`, try them in order.
+pub async fn probe_targets(
+    targets: Vec<String>,
+    config: &HandshakeConfig,
+    concurrency: usize,
+) -> Vec<ProbeResult<String>> {
+    let (multiple, single): (Vec<_>, Vec<_>) = targets
+        .into_iter()
+        .enumerate()
+        .partition(|(_, target)| is_port_list(target));
+    let (single_indexes, single): (Vec<usize>, Vec<String>) = single.into_iter().unzip();
+    let (multiple_indexes, multiple): (Vec<usize>, Vec<PortList>) = multiple
+        .into_iter()
+        .filter_map(|(index, target)| Some((index, target.parse().ok()?)))
+        .unzip();
+
+    let multiple = handshake_many_ports(multiple, config, concurrency).await;
+    let single = probe_single(single, config, concurrency).await;
+    let mut results: Vec<(usize, ProbeResult<String>)> = single_indexes
+        .into_iter()
+        .zip(single)
+        .chain(multiple_indexes.into_iter().zip(multiple))
+        .collect();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Whether the entry lists several ports.
+fn is_port_list(target: &str) -> bool {
+    target
+        .parse::<PortList>()
+        .is_ok_and(|entry| entry.is_multiple())
+}
+
+async fn probe_single(
+    targets: Vec<String>,
+    config: &HandshakeConfig,
+    concurrency: usize,
+) -> Vec<ProbeResult<String>> {
+    #[cfg(feature = "hickory-dns")]
+    return probe_resolved(targets, config, concurrency).await;
+    #[cfg(not(feature = "hickory-dns"))]
+    handshake_many(targets, config, concurrency).await
+}
+
+/// Resolves the targets first, then probes the first address of each. The
+/// targets routed to a handler are probed as given.
+#[cfg(feature = "hickory-dns")]
+async fn probe_resolved(
+    targets: Vec<String>,
+    config: &HandshakeConfig,
+    concurrency: usize,
+) -> Vec<ProbeResult<String>> {
+    use std::io;
+    use std::sync::Arc;
+
+    use p2p_handshake::dns::DnsResolver;
+    use tokio::sync::Semaphore;
+    use tokio::task::JoinSet;
+
+    let resolver = DnsResolver::system_or_cloudflare();
+    let router = config.router.as_ref();
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut lookups = JoinSet::new();
+    for (index, target) in targets.iter().enumerate() {
+        let (resolver, semaphore, target) = (resolver.clone(), semaphore.clone(), target.clone());
+        let routed = router.is_some_and(|router| router.routes(&target));
+        lookups.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            // The routed targets are dialed by their handler as given.
+            if routed {
+                return (index, Ok(target));
+            }
+            let addresses = resolver.lookup(&target).await;
+            let address = addresses.and_then(|addresses| {
+                let no_address = || io::Error::other("could not resolve to any address");
+                let address = addresses.first().ok_or_else(no_address)?;
+                Ok(address.to_string())
+            });
+            (index, address)
+        });
+    }
+    let mut resolved = Vec::with_capacity(targets.len());
+    while let Some(Ok(lookup)) = lookups.join_next().await {
+        resolved.push(lookup);
+    }
+    resolved.sort_by_key(|(index, _)| *index);
+
+    let addresses = resolved
+        .iter()
+        .filter_map(|(_, address)| address.as_ref().ok().cloned());
+    let mut probes = handshake_many(addresses, config, concurrency)
+        .await
+        .into_iter();
+    let mut results = Vec::with_capacity(targets.len());
+    for (target, (_, address)) in targets.into_iter().zip(resolved) {
+        let probe = address.and_then(|_| {
+            // Probes only go missing when the runtime shuts down.
+            probes
+                .next()
+                .ok_or_else(|| io::Error::other("probe cancelled").into())
+        });
+        results.push(match probe {
+            Ok(probe) => probe.with_target(target),
+            Err(err) => {
+                let mut result = ProbeResult::new(target);
+                result.outcome = Err(err);
+                result
+            }
+        });
+    }
+    results
+}
+
+fn parse_targets(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_targets() -> Result<()> {
+        let targets =
+            |entries: &[&str]| expand_targets(entries.iter().map(|e| e.to_string()).collect());
+        assert_eq!(
+            targets(&["node.example.org:9030", "node.example.org:[9030,9020]"])?,
+            vec!["node.example.org:9030", "node.example.org:[9030,9020]"]
+        );
+        assert_eq!(
+            targets(&["node.example.org:9030,9053"])?,
+            vec!["node.example.org:9030", "node.example.org:9053"]
+        );
+
+        let expanded = targets(&["10.0.0.7/24:9030"])?;
+        assert_eq!(expanded.len(), 254);
+        assert_eq!(expanded[0], "10.0.0.1:9030");
+        assert_eq!(expanded[253], "10.0.0.254:9030");
+        assert_eq!(
+            targets(&["10.0.0.0/31:9030,9053"])?,
+            vec![
+                "10.0.0.0:9030",
+                "10.0.0.0:9053",
+                "10.0.0.1:9030",
+                "10.0.0.1:9053"
+            ]
+        );
+        assert_eq!(
+            targets(&["[2001:db8::/127]:9030"])?,
+            vec!["[2001:db8::]:9030", "[2001:db8::1]:9030"]
+        );
+        assert_eq!(targets(&["10.0.0.1/32:9030"])?, vec!["10.0.0.1:9030"]);
+
+        assert!(targets(&["10.0.0.0/8:9030"]).is_err());
+        assert!(targets(&["10.0.0.0/33:9030"]).is_err());
+        assert!(targets(&["node.example.org:9030,x"]).is_err());
+        let err = targets(&["10.0.0.0/24"]).unwrap_err();
+        assert!(format!("{err:#}").contains("missing port"));
+        assert!(targets(&["[2001:db8::/120]"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        let content = "# seeds\n1.2.3.4:9030\n\n  node.example.org:9030  \n";
+        assert_eq!(
+            parse_targets(content),
+            vec!["1.2.3.4:9030", "node.example.org:9030"]
+        );
+    }
+}
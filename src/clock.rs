@@ -0,0 +1,57 @@
+//! This module defines the time source of the handshake timestamps, so
+//! tests and replay tools can produce deterministic bytes and embedded
+//! users can supply their own time.
+//!
+
+use crate::encoder::get_current_unix_timestamp;
+use crate::error::ProtocolResult;
+
+/// A source of unix timestamps, in milliseconds.
+///
+/// Closures returning a timestamp implement it.
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> ProtocolResult<u64>;
+}
+
+/// The system clock, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> ProtocolResult<u64> {
+        get_current_unix_timestamp()
+    }
+}
+
+/// A clock stopped at a given timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now_millis(&self) -> ProtocolResult<u64> {
+        Ok(self.0)
+    }
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> u64 + Send + Sync,
+{
+    fn now_millis(&self) -> ProtocolResult<u64> {
+        Ok(self())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clocks() -> ProtocolResult<()> {
+        assert_eq!(FixedClock(42).now_millis()?, 42);
+        assert_eq!((|| 7).now_millis()?, 7);
+        // Any date after 2024.
+        assert!(SystemClock.now_millis()? > 1_700_000_000_000);
+        Ok(())
+    }
+}
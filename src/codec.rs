@@ -0,0 +1,201 @@
+//! This module implements [`tokio_util::codec`] codecs, so the handshake
+//! and the network messages can be exchanged over [`Framed`](tokio_util::codec::Framed)
+//! streams.
+//!
+
+use std::io::Cursor;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::encoder::{is_truncated, HandshakeMessage};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::framing::{self, NetworkMessage, DEFAULT_MAX_BODY_SIZE, HEADER_LEN};
+use crate::spec;
+
+/// Codec of the handshake message, the first message exchanged on a
+/// connection.
+///
+/// Messages are encoded with their own timestamp, build them with
+/// [`HandshakeBuilder::build`](crate::HandshakeBuilder::build) to stamp
+/// them with the current time.
+#[derive(Debug, Clone)]
+pub struct HandshakeCodec {
+    max_size: usize,
+}
+
+impl HandshakeCodec {
+    pub fn new() -> Self {
+        Self::with_max_size(spec::MAX_HANDSHAKE_SIZE)
+    }
+
+    /// Creates a codec rejecting handshakes larger than `max_size` bytes.
+    pub fn with_max_size(max_size: usize) -> Self {
+        Self { max_size }
+    }
+}
+
+impl Default for HandshakeCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for HandshakeCodec {
+    type Item = HandshakeMessage;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> ProtocolResult<Option<Self::Item>> {
+        let mut cursor = Cursor::new(&src[..]);
+        match HandshakeMessage::decode_from_reader(&mut cursor) {
+            Ok(message) => {
+                let len = cursor.position() as usize;
+                if len > self.max_size {
+                    return Err(ProtocolError::MessageTooLarge(self.max_size));
+                }
+                src.advance(len);
+                Ok(Some(message))
+            }
+            Err(err) if is_truncated(&err) => {
+                if src.len() >= self.max_size {
+                    return Err(ProtocolError::MessageTooLarge(self.max_size));
+                }
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Encoder<HandshakeMessage> for HandshakeCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, item: HandshakeMessage, dst: &mut BytesMut) -> ProtocolResult<()> {
+        // Written in place, the buffer grows at most once.
+        let start = dst.len();
+        dst.resize(start + item.encoded_len(), 0);
+        crate::core::encode_to_slice(&item, item.timestamp, &mut dst[start..])
+            .inspect_err(|_| dst.truncate(start))?;
+        Ok(())
+    }
+}
+
+/// Codec of the network messages exchanged after the handshake.
+#[derive(Debug, Clone)]
+pub struct NetworkMessageCodec {
+    magic: [u8; 4],
+    max_body_size: usize,
+}
+
+impl NetworkMessageCodec {
+    /// Creates a codec for the network identified by `magic`.
+    pub fn new(magic: [u8; 4]) -> Self {
+        Self::with_max_body_size(magic, DEFAULT_MAX_BODY_SIZE)
+    }
+
+    /// Creates a codec rejecting bodies larger than `max_body_size` bytes.
+    pub fn with_max_body_size(magic: [u8; 4], max_body_size: usize) -> Self {
+        Self {
+            magic,
+            max_body_size,
+        }
+    }
+}
+
+impl Decoder for NetworkMessageCodec {
+    type Item = NetworkMessage;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> ProtocolResult<Option<Self::Item>> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        // Reject oversized bodies before buffering them.
+        framing::parse_header(&src[..HEADER_LEN], self.magic, self.max_body_size)?;
+        match NetworkMessage::decode(src, self.magic) {
+            Ok((message, len)) => {
+                src.advance(len);
+                Ok(Some(message))
+            }
+            Err(ProtocolError::TruncatedMessage { expected, .. }) => {
+                src.reserve(expected - src.len());
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Encoder<NetworkMessage> for NetworkMessageCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, item: NetworkMessage, dst: &mut BytesMut) -> ProtocolResult<()> {
+        dst.extend_from_slice(&item.encode(self.magic)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::framing::MAINNET_MAGIC;
+
+    #[test]
+    fn test_handshake_codec() -> ProtocolResult<()> {
+        let message = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .timestamp_provider(|| 1_718_000_000_000)
+            .build()?;
+        let mut codec = HandshakeCodec::new();
+        let mut data = BytesMut::new();
+        codec.encode(message.clone(), &mut data)?;
+        data.extend_from_slice(b"next");
+
+        // Feed the bytes one at a time.
+        let mut src = BytesMut::new();
+        let mut decoded = None;
+        for byte in data.iter() {
+            src.extend_from_slice(&[*byte]);
+            if let Some(message) = codec.decode(&mut src)? {
+                decoded = Some(message);
+                break;
+            }
+        }
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.timestamp, message.timestamp);
+        assert_eq!(decoded.diff(&message), vec![]);
+        assert!(src.is_empty());
+
+        let mut codec = HandshakeCodec::with_max_size(4);
+        let error = codec.decode(&mut data.clone()).unwrap_err();
+        assert!(matches!(error, ProtocolError::MessageTooLarge(4)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_message_codec() -> ProtocolResult<()> {
+        let mut codec = NetworkMessageCodec::new(MAINNET_MAGIC);
+        let mut data = BytesMut::new();
+        codec.encode(NetworkMessage::new(1, vec![]), &mut data)?;
+        codec.encode(NetworkMessage::new(2, vec![1, 2, 3]), &mut data)?;
+
+        let mut src = BytesMut::from(&data[..HEADER_LEN + 2]);
+        assert_eq!(
+            codec.decode(&mut src)?,
+            Some(NetworkMessage::new(1, vec![]))
+        );
+        assert_eq!(codec.decode(&mut src)?, None);
+        src.extend_from_slice(&data[HEADER_LEN + 2..]);
+        assert_eq!(
+            codec.decode(&mut src)?,
+            Some(NetworkMessage::new(2, vec![1, 2, 3]))
+        );
+        assert!(src.is_empty());
+
+        let mut codec = NetworkMessageCodec::with_max_body_size(MAINNET_MAGIC, 2);
+        let error = codec.decode(&mut data.split_off(HEADER_LEN)).unwrap_err();
+        assert!(matches!(error, ProtocolError::MessageTooLarge(2)));
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! This module implements reading and writing result files compressed
+//! according to their extension, as crawl results and monitor histories
+//! grow large quickly.
+//!
+//! Files ending with `.gz` are gzip compressed and files ending with
+//! `.zst` are zstd compressed, other files are left uncompressed. The
+//! compressed formats require the `compression` feature.
+//!
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// The compression applied to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the compression matching the extension of `path`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// A file writer compressing according to the file extension.
+///
+/// [`FileWriter::finish`] must be called once done, to write the end of
+/// the compressed stream and report errors dropping would ignore.
+pub struct FileWriter {
+    inner: WriterKind,
+}
+
+enum WriterKind {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "compression")]
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    #[cfg(feature = "compression")]
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl FileWriter {
+    /// Creates or truncates the file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let compression = Compression::from_path(&path);
+        check_supported(compression)?;
+        let file = BufWriter::new(File::create(path)?);
+        let inner = match compression {
+            #[cfg(feature = "compression")]
+            Compression::Gzip => WriterKind::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "compression")]
+            Compression::Zstd => WriterKind::Zstd(zstd::Encoder::new(file, 0)?),
+            _ => WriterKind::Plain(file),
+        };
+        Ok(Self { inner })
+    }
+
+    /// Completes the compressed stream and flushes the file.
+    #[cfg_attr(
+        not(feature = "compression"),
+        allow(clippy::infallible_destructuring_match)
+    )]
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = match self.inner {
+            WriterKind::Plain(file) => file,
+            #[cfg(feature = "compression")]
+            WriterKind::Gzip(encoder) => encoder.finish()?,
+            #[cfg(feature = "compression")]
+            WriterKind::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            WriterKind::Plain(file) => file.write(buf),
+            #[cfg(feature = "compression")]
+            WriterKind::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "compression")]
+            WriterKind::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            WriterKind::Plain(file) => file.flush(),
+            #[cfg(feature = "compression")]
+            WriterKind::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "compression")]
+            WriterKind::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Opens the file at `path`, decompressing it according to its extension.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read>> {
+    let compression = Compression::from_path(&path);
+    check_supported(compression)?;
+    let file = BufReader::new(File::open(path)?);
+    Ok(match compression {
+        #[cfg(feature = "compression")]
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+        #[cfg(feature = "compression")]
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(file),
+    })
+}
+
+fn check_supported(compression: Compression) -> io::Result<()> {
+    if cfg!(feature = "compression") || compression == Compression::None {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compressed files require the `compression` feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("p2p-compression-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let content = "{\"target\":\"1.2.3.4:9030\"}\n".repeat(100);
+
+        for name in ["results.ndjson", "results.ndjson.gz", "results.ndjson.zst"] {
+            let path = dir.join(name);
+            let written = FileWriter::create(&path).and_then(|mut writer| {
+                writer.write_all(content.as_bytes())?;
+                writer.finish()
+            });
+            if Compression::from_path(&path) != Compression::None
+                && cfg!(not(feature = "compression"))
+            {
+                assert_eq!(written.unwrap_err().kind(), io::ErrorKind::Unsupported);
+                continue;
+            }
+            written?;
+
+            let mut read = String::new();
+            open(&path)?.read_to_string(&mut read)?;
+            assert_eq!(read, content);
+            if name != "results.ndjson" {
+                assert!(std::fs::metadata(&path)?.len() < content.len() as u64);
+            }
+        }
+        std::fs::remove_dir_all(dir)
+    }
+}
@@ -0,0 +1,291 @@
+//! This module implements the configuration shared by the handshakes
+//! of a scan.
+//!
+//! A single configuration is typically shared by thousands of concurrent
+//! probes, cloning it only bumps reference counts.
+//!
+
+use std::net::SocketAddr;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::builder::HandshakeBuilder;
+use crate::cache::OutcomeCache;
+use crate::dial::{PreConnect, PreConnectHook};
+use crate::observer::{HandshakeObserver, ObserverHook};
+use crate::policy::HandshakePolicy;
+use crate::ratelimit::RateLimiter;
+use crate::route::TargetRouter;
+#[cfg(any(test, feature = "test-util"))]
+use crate::testing::Chaos;
+use crate::wire::{WireCapture, WireCaptureHook};
+
+/// Delay before retrying a failed handshake, doubling after every attempt
+/// up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay following the failed attempt number `attempt`, starting
+    /// at 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// How [`handshake_many`](crate::handshake_many) goes through the targets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScanStrategy {
+    /// Every target is handshaken right away.
+    #[default]
+    Direct,
+    /// A first sweep only connects to the targets, closing the connections
+    /// at once, then the targets accepting the connection are handshaken.
+    /// Much cheaper on sparse lists, where most targets don't answer.
+    TwoPhase,
+}
+
+/// Configuration applied to every handshake of a scan.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeConfig {
+    /// The request sent to every target.
+    pub request: HandshakeBuilder,
+    /// Maximum duration of each attempt, connection included.
+    pub timeout: Option<Duration>,
+    /// Maximum duration of the name resolution and connection.
+    pub connect_timeout: Option<Duration>,
+    /// Maximum duration between sending the request and decoding the reply.
+    pub read_timeout: Option<Duration>,
+    /// Delay between the start of two connection attempts to the addresses
+    /// of a target, [`CONNECTION_ATTEMPT_DELAY`](crate::dial::CONNECTION_ATTEMPT_DELAY)
+    /// when unset.
+    pub connection_attempt_delay: Option<Duration>,
+    /// Local address the outbound sockets are bound to, ex. to originate
+    /// the traffic from a given interface of a multi-homed host.
+    pub local_address: Option<SocketAddr>,
+    /// Runs before every connection attempt, see [`PreConnect`].
+    pub pre_connect: Option<PreConnectHook>,
+    /// Number of attempts made after the first one fails with a transient
+    /// error, see [`ProtocolError::is_transient`](crate::ProtocolError::is_transient).
+    pub retries: u32,
+    pub backoff: Backoff,
+    /// Rules the replies must follow, replies breaking them are reported
+    /// as failures.
+    pub policy: Option<HandshakePolicy>,
+    /// Dials the targets with special suffixes, ex. `.onion`, with their
+    /// own transport.
+    pub router: Option<TargetRouter>,
+    pub strategy: ScanStrategy,
+    /// Receives the bytes of the handshakes, see [`WireCapture`].
+    pub wire_capture: Option<WireCaptureHook>,
+    /// Receives the events of every attempt, see [`HandshakeObserver`].
+    pub observer: Option<ObserverHook>,
+    /// Shares the completed handshakes with the other configurations
+    /// holding the same cache, see [`cache`](crate::cache).
+    pub outcome_cache: Option<OutcomeCache>,
+    /// Paces the connection attempts, see [`ratelimit`](crate::ratelimit).
+    pub rate_limiter: Option<RateLimiter>,
+    /// Aborts the handshakes once cancelled, they then fail with
+    /// [`ProtocolError::Cancelled`](crate::ProtocolError::Cancelled).
+    #[cfg(feature = "cancellation")]
+    pub cancellation: Option<tokio_util::sync::CancellationToken>,
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) chaos: Option<Arc<Chaos>>,
+}
+
+/// The durations are given as a [`Duration`] or, ex. when read from a
+/// configuration, a [`HumanDuration`](crate::HumanDuration).
+impl HandshakeConfig {
+    pub fn new(request: HandshakeBuilder) -> Self {
+        Self {
+            request,
+            ..Default::default()
+        }
+    }
+
+    pub fn timeout(mut self, timeout: impl Into<Duration>) -> Self {
+        self.timeout = Some(timeout.into());
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: impl Into<Duration>) -> Self {
+        self.connect_timeout = Some(timeout.into());
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: impl Into<Duration>) -> Self {
+        self.read_timeout = Some(timeout.into());
+        self
+    }
+
+    pub fn connection_attempt_delay(mut self, delay: impl Into<Duration>) -> Self {
+        self.connection_attempt_delay = Some(delay.into());
+        self
+    }
+
+    /// Binds the outbound sockets to the local address before connecting,
+    /// only the addresses of the targets of the same family are dialed.
+    /// A zero port lets the system pick one.
+    pub fn local_address(mut self, address: SocketAddr) -> Self {
+        self.local_address = Some(address);
+        self
+    }
+
+    /// Runs the hook before every connection attempt, ex. to knock the
+    /// ports of the address. Routed targets are dialed by their handler
+    /// without running it.
+    ///
+    /// ```no_run
+    /// use p2p_handshake::{HandshakeBuilder, HandshakeConfig};
+    ///
+    /// let config = HandshakeConfig::new(HandshakeBuilder::new()).pre_connect(|address| async move {
+    ///     println!("knocking {address}");
+    ///     Ok(())
+    /// });
+    /// ```
+    pub fn pre_connect<H: PreConnect + 'static>(mut self, hook: H) -> Self {
+        self.pre_connect = Some(PreConnectHook::new(hook));
+        self
+    }
+
+    /// Hands the request and the bytes received in reply to the hook, for
+    /// every attempt. The reply is handed once complete, or as far as it
+    /// was read when the exchange fails.
+    ///
+    /// ```
+    /// use p2p_handshake::wire::hex_dump;
+    /// use p2p_handshake::{HandshakeBuilder, HandshakeConfig};
+    ///
+    /// let config = HandshakeConfig::new(HandshakeBuilder::new()).on_wire(|address, direction, data: &[u8]| {
+    ///     eprintln!("{direction} {address}\n{}", hex_dump(data));
+    /// });
+    /// ```
+    pub fn on_wire<H: WireCapture + 'static>(mut self, hook: H) -> Self {
+        self.wire_capture = Some(WireCaptureHook::new(hook));
+        self
+    }
+
+    /// Reports every step of the attempts to the observer, along with the
+    /// phase they failed in.
+    pub fn observer<O: HandshakeObserver + 'static>(mut self, observer: O) -> Self {
+        self.observer = Some(ObserverHook::new(observer));
+        self
+    }
+
+    pub fn retries(mut self, retries: u32, backoff: Backoff) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn policy(mut self, policy: HandshakePolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Routes the targets by the suffix of their host name, see
+    /// [`route`](crate::route). Routed targets ignore the local address and
+    /// the connection attempt delay.
+    pub fn router(mut self, router: TargetRouter) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    pub fn strategy(mut self, strategy: ScanStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Answers the probes of the nodes handshaken recently with the same
+    /// request from the cache, instead of connecting again. Only targets
+    /// given as an address are looked up, all the completed handshakes are
+    /// recorded.
+    pub fn outcome_cache(mut self, cache: OutcomeCache) -> Self {
+        self.outcome_cache = Some(cache);
+        self
+    }
+
+    /// Paces the connection attempts made with this configuration, and
+    /// with the other ones holding a clone of the limiter.
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Aborts the in-flight and pending handshakes made with this
+    /// configuration when the token is cancelled, ex. on shutdown.
+    #[cfg(feature = "cancellation")]
+    pub fn cancellation(mut self, token: tokio_util::sync::CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Completes once the handshakes must be aborted, never without a
+    /// cancellation token.
+    pub(crate) async fn cancelled(&self) {
+        #[cfg(feature = "cancellation")]
+        if let Some(token) = &self.cancellation {
+            return token.cancelled().await;
+        }
+        std::future::pending().await
+    }
+
+    /// Injects failures in the handshakes made with this configuration,
+    /// for testing only.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(Arc::new(chaos));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::feature::PeerFeature;
+
+    #[test]
+    fn test_config_is_shareable() {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<HandshakeConfig>();
+
+        let config = HandshakeConfig::new(
+            HandshakeBuilder::new()
+                .agent_name("paul")
+                .feature(PeerFeature::new(16, vec![0; 1024])),
+        );
+        let cloned = config.clone();
+        assert!(Arc::ptr_eq(
+            config.request.features_ref(),
+            cloned.request.features_ref()
+        ));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(400));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+    }
+}
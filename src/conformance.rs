@@ -0,0 +1,378 @@
+//! This module implements checking the coverage of the handshake spec
+//! against the replies of a trusted reference node.
+//!
+//! A reply is decoded, re-encoded and compared byte for byte with the
+//! received bytes: any difference is a part of the message this crate
+//! decodes lossily. The features whose identifier isn't documented are
+//! kept as opaque payloads, they are reported as not represented.
+//!
+//! ```no_run
+//! # #[cfg(feature = "net")]
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::conformance::check_live;
+//! use p2p_handshake::HandshakeBuilder;
+//!
+//! let request = HandshakeBuilder::new().agent_name("paul");
+//! let report = check_live("127.0.0.1:9030", &request).await?;
+//! print!("{report}");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The [`TestVector`]s are messages written independently of the encoder
+//! of this crate, downstream implementations check their own encoder and
+//! decoder against the same bytes. The [captured](TestVector::captured)
+//! ones are replies of reference nodes, recorded with their source.
+//!
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[cfg(feature = "net")]
+use tokio::net::ToSocketAddrs;
+
+#[cfg(feature = "net")]
+use crate::builder::HandshakeBuilder;
+use crate::encoder::{DecodeMode, HandshakeMessage};
+use crate::error::ProtocolResult;
+use crate::wire;
+
+/// The test vectors embedded in the library.
+const VECTORS: &str = include_str!("../data/handshake_vectors.txt");
+
+/// The replies of reference nodes embedded in the library.
+const CAPTURED_VECTORS: &str = include_str!("../data/captured_vectors.txt");
+
+/// Prefix of the comment line giving the source of the vector following it.
+const SOURCE_PREFIX: &str = "# source:";
+
+/// The first difference between the received reply and its re-encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Offset of the first differing byte.
+    pub offset: usize,
+    /// The field of the received reply at the offset, ex. `peer name`.
+    pub field: String,
+    pub received_len: usize,
+    pub reencoded_len: usize,
+}
+
+/// What a reply holds that this crate doesn't represent exactly.
+#[derive(Debug, Clone)]
+pub struct ConformanceReport {
+    /// The exact bytes of the reply.
+    pub raw: Vec<u8>,
+    /// The decoded reply.
+    pub message: HandshakeMessage,
+    /// `None` when the re-encoding is identical to the received bytes.
+    pub mismatch: Option<Mismatch>,
+    /// Identifiers of the features kept as opaque payloads.
+    pub unknown_features: Vec<u8>,
+    /// Known features whose payload doesn't follow the documented layout,
+    /// with the reason.
+    pub invalid_features: Vec<(u8, String)>,
+}
+
+impl ConformanceReport {
+    /// Checks the exact bytes of a reply.
+    pub fn check(raw: &[u8]) -> ProtocolResult<Self> {
+        let message = HandshakeMessage::decode_from_response(raw.to_vec())?;
+        let reencoded = message.encode_with_timestamp(message.timestamp)?;
+        let mismatch = first_difference(raw, &reencoded).map(|offset| Mismatch {
+            offset,
+            field: field_at(raw, offset),
+            received_len: raw.len(),
+            reencoded_len: reencoded.len(),
+        });
+        let unknown_features = message
+            .features
+            .iter()
+            .filter(|feature| !feature.is_known())
+            .map(|feature| feature.id)
+            .collect();
+        let invalid_features = message
+            .features
+            .iter()
+            .filter_map(|feature| {
+                let err = feature.check_payload().err()?;
+                Some((feature.id, err.to_string()))
+            })
+            .collect();
+        Ok(Self {
+            raw: raw.to_vec(),
+            message,
+            mismatch,
+            unknown_features,
+            invalid_features,
+        })
+    }
+
+    /// Whether the reply round trips exactly with valid known features.
+    /// Unknown features don't break conformance, they are kept as is.
+    pub fn is_conformant(&self) -> bool {
+        self.mismatch.is_none() && self.invalid_features.is_empty()
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = &self.message;
+        writeln!(f, "reply: {} {}", message.agent_name, message.version)?;
+        match &self.mismatch {
+            None => writeln!(f, "ok: the reply re-encodes byte for byte")?,
+            Some(mismatch) => writeln!(
+                f,
+                "mismatch: the re-encoding differs at byte {} ({}), {} bytes received, {} re-encoded",
+                mismatch.offset, mismatch.field, mismatch.received_len, mismatch.reencoded_len
+            )?,
+        }
+        for id in &self.unknown_features {
+            writeln!(
+                f,
+                "not represented: feature {id} is kept as an opaque payload"
+            )?;
+        }
+        for (id, reason) in &self.invalid_features {
+            writeln!(f, "invalid: feature {id}, {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Handshakes the trusted node and checks its reply.
+#[cfg(feature = "net")]
+pub async fn check_live<A: ToSocketAddrs>(
+    target: A,
+    request: &HandshakeBuilder,
+) -> ProtocolResult<ConformanceReport> {
+    let mut raw = vec![];
+    crate::handshake_with_raw(target, request, |_stream, response| {
+        raw = response.raw;
+        Ok(())
+    })
+    .await?;
+    ConformanceReport::check(&raw)
+}
+
+/// A handshake message following the spec byte for byte.
+///
+/// ```
+/// use p2p_handshake::conformance::TestVector;
+///
+/// for vector in TestVector::embedded() {
+///     let message = vector.decode().unwrap();
+///     assert_eq!(message.encode_with_timestamp(message.timestamp).unwrap(), vector.bytes);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    /// Where a captured reply comes from, ex. the node address and version.
+    pub source: Option<String>,
+}
+
+impl TestVector {
+    /// The vectors embedded in the library, from `data/handshake_vectors.txt`.
+    pub fn embedded() -> Vec<Self> {
+        // The embedded vectors are checked by the tests.
+        Self::parse(VECTORS).unwrap_or_default()
+    }
+
+    /// The replies captured from reference nodes, from
+    /// `data/captured_vectors.txt`, each with its source.
+    pub fn captured() -> Vec<Self> {
+        // The embedded vectors are checked by the tests.
+        Self::parse(CAPTURED_VECTORS).unwrap_or_default()
+    }
+
+    /// A vector of the captured reply, named after the peer name and the
+    /// timestamp of the node.
+    pub fn from_capture(report: &ConformanceReport, source: &str) -> Self {
+        let message = &report.message;
+        let peer_name = message.peer_name.as_str().replace(char::is_whitespace, "_");
+        Self {
+            name: format!("{peer_name}-{}", message.timestamp),
+            bytes: report.raw.clone(),
+            source: Some(format!(
+                "{source}, node {} {}",
+                message.agent_name, message.version
+            )),
+        }
+    }
+
+    /// The vector in the format of the vectors files, its source line
+    /// first.
+    pub fn to_lines(&self) -> String {
+        let hex: String = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        match &self.source {
+            Some(source) => format!("{SOURCE_PREFIX} {source}\n{} {hex}\n", self.name),
+            None => format!("{} {hex}\n", self.name),
+        }
+    }
+
+    /// Reads the vectors from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Parses the vectors given as a name followed by the message as
+    /// hexadecimal on every line. Empty lines and lines starting with `#`
+    /// are ignored, but a `# source:` line gives the source of the vector
+    /// following it.
+    pub fn parse(content: &str) -> Result<Vec<Self>, String> {
+        let mut vectors = Vec::new();
+        let mut source = None;
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if let Some(given) = line.strip_prefix(SOURCE_PREFIX) {
+                source = Some(given.trim().to_string());
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| format!("line {}: {reason}", index + 1);
+            let mut words = line.split_whitespace();
+            let (Some(name), Some(hex), None) = (words.next(), words.next(), words.next()) else {
+                return Err(invalid("expected a name and the hexadecimal bytes"));
+            };
+            let bytes = parse_hex(hex).ok_or_else(|| invalid("invalid hexadecimal bytes"))?;
+            vectors.push(Self {
+                name: name.to_string(),
+                bytes,
+                source: source.take(),
+            });
+        }
+        Ok(vectors)
+    }
+
+    /// Decodes the message strictly, no byte may follow it.
+    pub fn decode(&self) -> ProtocolResult<HandshakeMessage> {
+        HandshakeMessage::decode_with_mode(&self.bytes, DecodeMode::Strict)
+    }
+
+    /// Checks that the message re-encodes byte for byte.
+    pub fn check(&self) -> ProtocolResult<ConformanceReport> {
+        ConformanceReport::check(&self.bytes)
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn first_difference(received: &[u8], reencoded: &[u8]) -> Option<usize> {
+    let common = received.iter().zip(reencoded).position(|(a, b)| a != b);
+    match common {
+        Some(offset) => Some(offset),
+        None if received.len() != reencoded.len() => Some(received.len().min(reencoded.len())),
+        None => None,
+    }
+}
+
+fn field_at(raw: &[u8], offset: usize) -> String {
+    wire::annotate(raw)
+        .into_iter()
+        .find(|segment| segment.range.contains(&offset))
+        .map(|segment| segment.label)
+        .unwrap_or_else(|| "end of the message".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::feature::{PeerFeature, MODE_FEATURE_ID};
+
+    #[test]
+    fn test_check() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .feature(PeerFeature::new(MODE_FEATURE_ID, vec![0, 1, 0, 1]))
+            .feature(PeerFeature::new(42, vec![7]))
+            .build()?;
+        let raw = reply.encode_with_timestamp(reply.timestamp)?;
+        let report = ConformanceReport::check(&raw)?;
+        assert_eq!(report.mismatch, None);
+        assert_eq!(report.unknown_features, [42]);
+        assert!(report.is_conformant());
+
+        // A timestamp written on more bytes than needed decodes fine but
+        // can't be re-encoded the same way.
+        let mut padded = vec![0x81, 0x80, 0x00];
+        padded.extend_from_slice(&reply.encode_with_timestamp(1)?[1..]);
+        let report = ConformanceReport::check(&padded)?;
+        let mismatch = report.mismatch.as_ref().unwrap();
+        assert_eq!(mismatch.offset, 0);
+        assert_eq!(mismatch.field, "timestamp = 1");
+        assert_eq!(mismatch.received_len, padded.len());
+        assert_eq!(mismatch.reencoded_len, padded.len() - 2);
+        assert!(!report.is_conformant());
+        Ok(())
+    }
+
+    #[test]
+    fn test_vectors() -> ProtocolResult<()> {
+        let vectors = TestVector::parse(VECTORS).unwrap();
+        assert_eq!(vectors.len(), 5);
+        for vector in &vectors {
+            let report = vector.check()?;
+            assert!(report.is_conformant(), "{}: {report}", vector.name);
+            vector.decode()?;
+        }
+
+        assert_eq!(
+            TestVector::parse("# comment\n\nempty 00 00"),
+            Err("line 3: expected a name and the hexadecimal bytes".to_string())
+        );
+        assert!(TestVector::parse("odd 123").is_err());
+
+        for vector in TestVector::captured() {
+            assert!(vector.source.is_some(), "{} has no source", vector.name);
+            assert!(vector.check()?.is_conformant(), "{}", vector.name);
+        }
+        let captured = TestVector::from_capture(&vectors[0].check()?, "127.0.0.1:9030, mainnet");
+        let parsed = TestVector::parse(&captured.to_lines()).unwrap();
+        assert_eq!(parsed, [captured]);
+        assert_eq!(
+            parsed[0].source.as_deref(),
+            Some("127.0.0.1:9030, mainnet, node ergoref 5.0.21")
+        );
+        assert!(TestVector::parse("invalid 0g").is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "net")]
+    #[tokio::test]
+    async fn test_check_live() -> ProtocolResult<()> {
+        use crate::testing::MockErgoNode;
+
+        let reply = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .feature(PeerFeature::new(MODE_FEATURE_ID, vec![0, 1]))
+            .build()?;
+        let node = MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let report = check_live(node.address(), &HandshakeBuilder::new()).await?;
+        assert_eq!(report.mismatch, None);
+        assert_eq!(report.invalid_features.len(), 1);
+        assert!(!report.is_conformant());
+        assert!(report.to_string().contains("invalid: feature 16"));
+        Ok(())
+    }
+}
@@ -0,0 +1,501 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::net::{IpAddr, SocketAddr};
+
+use super::error::CodecError;
+use super::feature::PeerFeature;
+use super::message::{HandshakeMessage, TinyString, Version};
+use super::spec::{self, DecodeLimits, Field};
+
+/// The bytes a message is decoded from, ex. a slice, which it consumes as
+/// the fields are read. Failures of the source, ex. IO errors, are
+/// reported through its own error type, along with the decoding errors.
+pub trait ByteSource {
+    type Error: From<CodecError>;
+
+    /// Reads exactly `len` bytes, failing with
+    /// [`CodecError::Truncated`] when the source ends early. The length
+    /// comes from the peer, implementations shouldn't allocate ahead of
+    /// the bytes actually present.
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, Self::Error>;
+
+    fn read_byte(&mut self) -> Result<u8, Self::Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+}
+
+/// The bytes a message is encoded to, ex. a vector.
+pub trait ByteSink {
+    type Error: From<CodecError>;
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A slice is consumed from its start, the bytes following the message
+/// are left in it. The slice is emptied when it ends early.
+impl ByteSource for &[u8] {
+    type Error = CodecError;
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, CodecError> {
+        let Some((data, rest)) = self.split_at_checked(len) else {
+            let got = self.len();
+            *self = &[];
+            return Err(CodecError::Truncated { expected: len, got });
+        };
+        *self = rest;
+        Ok(data.to_vec())
+    }
+
+    fn read_byte(&mut self) -> Result<u8, CodecError> {
+        let (&byte, rest) = self.split_first().ok_or(CodecError::Truncated {
+            expected: 1,
+            got: 0,
+        })?;
+        *self = rest;
+        Ok(byte)
+    }
+}
+
+impl ByteSink for Vec<u8> {
+    type Error = CodecError;
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), CodecError> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Writes to the start of a slice, which must hold the whole message.
+struct SliceSink<'a>(&'a mut [u8]);
+
+impl ByteSink for SliceSink<'_> {
+    type Error = CodecError;
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), CodecError> {
+        let got = self.0.len();
+        let (head, rest) = core::mem::take(&mut self.0)
+            .split_at_mut_checked(data.len())
+            .ok_or(CodecError::BufferTooSmall {
+                needed: data.len(),
+                got,
+            })?;
+        head.copy_from_slice(data);
+        self.0 = rest;
+        Ok(())
+    }
+}
+
+/// Encodes the message with the given unix timestamp in milliseconds, in
+/// a single allocation. Nothing is encoded when the message breaks the
+/// documented limits.
+pub fn encode(message: &HandshakeMessage, timestamp: u64) -> Result<Vec<u8>, CodecError> {
+    message.fields(timestamp).encode()
+}
+
+/// Same as [`encode`] but writes the message to the sink.
+pub fn encode_into<S: ByteSink + ?Sized>(
+    message: &HandshakeMessage,
+    timestamp: u64,
+    sink: &mut S,
+) -> Result<(), S::Error> {
+    message.fields(timestamp).write(sink)
+}
+
+/// Same as [`encode`] but writes the message at the start of the buffer,
+/// without allocating, and returns the number of bytes written. Nothing
+/// is written when the buffer can't hold the message, see
+/// [`HandshakeMessage::encoded_len`].
+///
+/// ```
+/// use p2p_handshake::core::{encode, encode_to_slice, HandshakeMessage, TinyString};
+///
+/// let message = HandshakeMessage {
+///     agent_name: TinyString::new("ergoref").unwrap(),
+///     ..Default::default()
+/// };
+/// let mut buf = [0u8; 64];
+/// let len = encode_to_slice(&message, 42, &mut buf).unwrap();
+/// assert_eq!(&buf[..len], encode(&message, 42).unwrap());
+/// assert!(encode_to_slice(&message, 42, &mut buf[..len - 1]).is_err());
+/// ```
+pub fn encode_to_slice(
+    message: &HandshakeMessage,
+    timestamp: u64,
+    buf: &mut [u8],
+) -> Result<usize, CodecError> {
+    message.fields(timestamp).write_to_slice(buf)
+}
+
+/// Decodes the message at the start of the bytes, within the limits, and
+/// returns it along with the number of bytes it spans. The bytes
+/// following it are ignored. A message not ending within the maximum
+/// size fails with [`CodecError::MessageTooLarge`].
+pub fn decode(data: &[u8], limits: &DecodeLimits) -> Result<(HandshakeMessage, usize), CodecError> {
+    let limit = limits.message_limit();
+    let max = data.len().min(limit);
+    let mut limited = &data[..max];
+    match decode_from(&mut limited, limits) {
+        Ok(message) => Ok((message, max - limited.len())),
+        // The message didn't end within the limit.
+        Err(_) if limited.is_empty() && data.len() > limit => {
+            Err(CodecError::MessageTooLarge(limit))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Decodes a message from the source, consuming exactly the bytes of the
+/// message.
+pub fn decode_from<S: ByteSource + ?Sized>(
+    source: &mut S,
+    limits: &DecodeLimits,
+) -> Result<HandshakeMessage, S::Error> {
+    let timestamp = read_vlq(source)?;
+    let fields = read_peer_fields(source, limits)?;
+    Ok(HandshakeMessage {
+        timestamp,
+        agent_name: fields.agent_name,
+        version: fields.version,
+        peer_name: fields.peer_name,
+        declared_address: fields.declared_address,
+        features: fields.features,
+    })
+}
+
+/// The fields of a handshake message borrowed from their owner, so that
+/// the message is written straight into a buffer sized upfront, without
+/// copying the names and features first.
+pub(crate) struct HandshakeFields<'a> {
+    pub timestamp: u64,
+    pub agent_name: &'a str,
+    pub version: &'a Version,
+    pub peer_name: &'a str,
+    pub declared_address: Option<&'a SocketAddr>,
+    pub features: &'a [PeerFeature],
+}
+
+impl HandshakeFields<'_> {
+    /// Number of bytes of the encoded message.
+    pub(crate) fn encoded_len(&self) -> usize {
+        vlq_len(self.timestamp)
+            + 1
+            + self.agent_name.len()
+            + spec::VERSION_LEN
+            + 1
+            + self.peer_name.len()
+            + declared_address_len(self.declared_address)
+            + 1
+            + self
+                .features
+                .iter()
+                .map(PeerFeature::encoded_len)
+                .sum::<usize>()
+    }
+
+    /// Encodes the message in a single allocation.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, CodecError> {
+        let mut buf = Vec::new();
+        self.append(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Appends the message to the buffer, growing it at most once. The
+    /// bytes are written in place, which is faster than extending the
+    /// buffer field by field.
+    pub(crate) fn append(&self, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        let start = buf.len();
+        buf.resize(start + self.check()?, 0);
+        match self.write_to_slice(&mut buf[start..]) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                buf.truncate(start);
+                Err(err)
+            }
+        }
+    }
+
+    /// Writes the message at the start of the buffer and returns its length.
+    pub(crate) fn write_to_slice(&self, buf: &mut [u8]) -> Result<usize, CodecError> {
+        let needed = self.check()?;
+        let got = buf.len();
+        let buf = buf
+            .get_mut(..needed)
+            .ok_or(CodecError::BufferTooSmall { needed, got })?;
+        self.write(&mut SliceSink(buf))?;
+        Ok(needed)
+    }
+
+    /// Checks the fields and the size of the message against the spec,
+    /// returning the number of bytes of the encoded message.
+    pub(crate) fn check(&self) -> Result<usize, CodecError> {
+        spec::check_fields(self.agent_name, self.peer_name, self.features)?;
+        let len = self.encoded_len();
+        if len > spec::MAX_HANDSHAKE_SIZE {
+            return Err(CodecError::MessageTooLarge(spec::MAX_HANDSHAKE_SIZE));
+        }
+        Ok(len)
+    }
+
+    pub(crate) fn write<S: ByteSink + ?Sized>(&self, sink: &mut S) -> Result<(), S::Error> {
+        // Nothing is written when the message breaks the spec.
+        self.check()?;
+
+        // The timestamp is encoded in Little Endian Base 128 also referred
+        // VLQ (variable length quantity)
+        write_vlq(sink, self.timestamp)?;
+        write_peer_spec(
+            sink,
+            self.agent_name,
+            self.version,
+            self.peer_name,
+            self.declared_address,
+            self.features,
+        )
+    }
+}
+
+/// The peer spec fields shared by the handshake and the peers messages.
+pub(crate) struct PeerFields {
+    pub agent_name: TinyString,
+    pub version: Version,
+    pub peer_name: TinyString,
+    pub declared_address: Option<SocketAddr>,
+    pub features: Vec<PeerFeature>,
+}
+
+/// Writes the peer spec fields shared by the handshake and the peers messages.
+/// The caller is responsible for checking the fields against the spec limits.
+pub(crate) fn write_peer_spec<S: ByteSink + ?Sized>(
+    sink: &mut S,
+    agent_name: &str,
+    version: &Version,
+    peer_name: &str,
+    declared_address: Option<&SocketAddr>,
+    features: &[PeerFeature],
+) -> Result<(), S::Error> {
+    sink.write_bytes(&[agent_name.len() as u8])?;
+    sink.write_bytes(agent_name.as_bytes())?;
+    sink.write_bytes(&version.0)?;
+    sink.write_bytes(&[peer_name.len() as u8])?;
+    sink.write_bytes(peer_name.as_bytes())?;
+    write_declared_address(sink, declared_address)?;
+
+    sink.write_bytes(&[features.len() as u8])?;
+    for feature in features {
+        feature.encode(sink)?;
+    }
+    Ok(())
+}
+
+/// Reads the peer spec fields shared by the handshake and the peers messages.
+pub(crate) fn read_peer_fields<S: ByteSource + ?Sized>(
+    source: &mut S,
+    limits: &DecodeLimits,
+) -> Result<PeerFields, S::Error> {
+    let agent_name = read_string(source, Field::AgentName, limits)?;
+    let mut raw_version = [0u8; 3];
+    raw_version.copy_from_slice(&source.read_bytes(spec::VERSION_LEN)?);
+    let peer_name = read_string(source, Field::PeerName, limits)?;
+    let declared_address = read_declared_address(source)?;
+
+    let features_count = source.read_byte()?;
+    limits.check_features_count(features_count as usize)?;
+    let mut features = Vec::with_capacity(features_count as usize);
+    for _ in 0..features_count {
+        features.push(PeerFeature::decode(source)?);
+    }
+
+    Ok(PeerFields {
+        agent_name,
+        version: Version(raw_version),
+        peer_name,
+        declared_address,
+        features,
+    })
+}
+
+/// Writes the optional declared address: a presence flag, then the
+/// size of the ip bytes plus 4, the ip bytes and the VLQ encoded port.
+pub(crate) fn write_declared_address<S: ByteSink + ?Sized>(
+    sink: &mut S,
+    address: Option<&SocketAddr>,
+) -> Result<(), S::Error> {
+    let Some(address) = address else {
+        return sink.write_bytes(&[0]);
+    };
+
+    match address {
+        SocketAddr::V4(addr) => {
+            sink.write_bytes(&[1, spec::IPV4_ADDRESS_SIZE])?;
+            sink.write_bytes(&addr.ip().octets())?;
+        }
+        SocketAddr::V6(addr) => {
+            sink.write_bytes(&[1, spec::IPV6_ADDRESS_SIZE])?;
+            sink.write_bytes(&addr.ip().octets())?;
+        }
+    }
+    write_vlq(sink, address.port() as u64)
+}
+
+/// Number of bytes of the encoded optional declared address.
+fn declared_address_len(address: Option<&SocketAddr>) -> usize {
+    match address {
+        None => 1,
+        Some(address) => {
+            let ip_len = match address {
+                SocketAddr::V4(_) => 4,
+                SocketAddr::V6(_) => 16,
+            };
+            2 + ip_len + vlq_len(address.port() as u64)
+        }
+    }
+}
+
+pub(crate) fn read_declared_address<S: ByteSource + ?Sized>(
+    source: &mut S,
+) -> Result<Option<SocketAddr>, S::Error> {
+    if source.read_byte()? == 0 {
+        return Ok(None);
+    }
+
+    let size = source.read_byte()?;
+    spec::check_address_size(size)?;
+    let octets = source.read_bytes(size as usize - 4)?;
+    let ip = if size == spec::IPV4_ADDRESS_SIZE {
+        let mut ipv4 = [0u8; 4];
+        ipv4.copy_from_slice(&octets);
+        IpAddr::from(ipv4)
+    } else {
+        let mut ipv6 = [0u8; 16];
+        ipv6.copy_from_slice(&octets);
+        IpAddr::from(ipv6)
+    };
+    let port = read_vlq(source)?;
+    let port = u16::try_from(port).map_err(|_| CodecError::InvalidPort(port))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+pub(crate) fn read_string<S: ByteSource + ?Sized>(
+    source: &mut S,
+    field: Field,
+    limits: &DecodeLimits,
+) -> Result<TinyString, S::Error> {
+    let len = source.read_byte()? as usize;
+    limits.check_string_len(field, len)?;
+    let value = String::from_utf8(source.read_bytes(len)?).map_err(CodecError::InvalidUtf8)?;
+    Ok(TinyString::for_field(value, field)?)
+}
+
+/// Reads an unsigned VLQ, the Little Endian Base 128 encoding of the
+/// reference node. Values past 64 bits fail with
+/// [`CodecError::VlqOverflow`].
+pub(crate) fn read_vlq<S: ByteSource + ?Sized>(source: &mut S) -> Result<u64, S::Error> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = source.read_byte()?;
+        // Only the lowest bit of the tenth byte fits in 64 bits.
+        if shift == 63 && byte > 1 {
+            return Err(CodecError::VlqOverflow.into());
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+pub(crate) fn write_vlq<S: ByteSink + ?Sized>(
+    sink: &mut S,
+    mut value: u64,
+) -> Result<(), S::Error> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    sink.write_bytes(&buf[..len])
+}
+
+/// Number of bytes of the VLQ encoding of the value.
+pub(crate) fn vlq_len(value: u64) -> usize {
+    let bits = u64::BITS - value.leading_zeros();
+    (bits as usize).div_ceil(7).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_vlq() -> Result<(), CodecError> {
+        for value in [0, 1, 127, 128, 16_383, 16_384, 1_700_000_000_000, u64::MAX] {
+            let mut data = vec![];
+            write_vlq(&mut data, value)?;
+            assert_eq!(data.len(), vlq_len(value));
+            assert_eq!(read_vlq(&mut data.as_slice())?, value);
+        }
+        let mut data = vec![];
+        write_vlq(&mut data, 16_384)?;
+        assert_eq!(data, [0x80, 0x80, 0x01]);
+        // Over-long encodings are accepted, as by the reference node.
+        assert_eq!(read_vlq(&mut [0x81, 0x00].as_slice())?, 1);
+
+        let mut overflow = vec![0xff; 9];
+        overflow.push(0x02);
+        assert_eq!(
+            read_vlq(&mut overflow.as_slice()),
+            Err(CodecError::VlqOverflow)
+        );
+        assert_eq!(
+            read_vlq(&mut [0x80].as_slice()),
+            Err(CodecError::Truncated {
+                expected: 1,
+                got: 0
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode() -> Result<(), CodecError> {
+        let message = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version([5, 0, 21]),
+            declared_address: Some("[2001:db8::1]:9030".parse().unwrap()),
+            features: vec![PeerFeature::new(16, vec![0, 1, 0, 1])],
+            ..Default::default()
+        };
+        let mut data = encode(&message, 42)?;
+        let len = data.len();
+        data.extend_from_slice(&[1, 2, 3]);
+
+        let (decoded, consumed) = decode(&data, &DecodeLimits::default())?;
+        assert_eq!(consumed, len);
+        assert_eq!(decoded.timestamp, 42);
+        assert_eq!(decoded.agent_name, message.agent_name);
+        assert_eq!(decoded.declared_address, message.declared_address);
+        assert_eq!(decoded.features, message.features);
+
+        assert_eq!(
+            decode(&data, &DecodeLimits::with_max_message(len - 1)).unwrap_err(),
+            CodecError::MessageTooLarge(len - 1)
+        );
+        assert!(matches!(
+            decode(&data[..len - 1], &DecodeLimits::default()),
+            Err(CodecError::Truncated { .. })
+        ));
+        Ok(())
+    }
+}
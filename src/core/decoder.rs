@@ -0,0 +1,193 @@
+use alloc::vec::Vec;
+
+use super::codec::decode;
+use super::error::CodecError;
+use super::message::HandshakeMessage;
+use super::spec::DecodeLimits;
+
+/// The outcome of feeding bytes to a [`HandshakeDecoder`].
+#[derive(Debug, Clone)]
+pub enum Decoded {
+    /// The buffered bytes hold a complete message.
+    Message(HandshakeMessage),
+    /// The message continues past the buffered bytes.
+    NeedMoreData,
+}
+
+/// Decodes a message from bytes arriving in chunks of any size, ex. from
+/// an event loop, without a complete buffer up front.
+///
+/// The chunks are buffered until they hold a complete message, the
+/// decoding is only retried once the bytes the field cut short needs
+/// have arrived. The bytes following the message, ex. the first network
+/// messages, are kept in the buffer, see [`HandshakeDecoder::buffered`].
+///
+/// ```
+/// use p2p_handshake::core::{encode, Decoded, HandshakeDecoder, HandshakeMessage, TinyString};
+///
+/// let message = HandshakeMessage {
+///     agent_name: TinyString::new("ergoref").unwrap(),
+///     ..Default::default()
+/// };
+/// let data = encode(&message, 1_700_000_000_000).unwrap();
+/// let mut decoder = HandshakeDecoder::new();
+/// let (head, tail) = data.split_at(5);
+/// assert!(matches!(decoder.feed(head), Ok(Decoded::NeedMoreData)));
+/// let Ok(Decoded::Message(decoded)) = decoder.feed(tail) else {
+///     panic!("expected a complete message");
+/// };
+/// assert_eq!(decoded.agent_name.as_str(), "ergoref");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeDecoder {
+    buffer: Vec<u8>,
+    limits: DecodeLimits,
+    /// Number of buffered bytes needed before decoding again.
+    needed: usize,
+    /// The error the decoding failed with, returned until the reset.
+    failed: Option<CodecError>,
+}
+
+impl HandshakeDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a decoder enforcing the given limits instead of the
+    /// documented ones, only tighter limits have an effect.
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Buffers the bytes and decodes the message once it is complete.
+    ///
+    /// A decoding error is final: the decoder keeps failing with it until
+    /// [`HandshakeDecoder::reset`]. Once a message is returned, the
+    /// decoder starts over with the bytes following it, feed an empty
+    /// slice to decode the next message from them.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Decoded, CodecError> {
+        if let Some(err) = &self.failed {
+            return Err(err.clone());
+        }
+        let outcome = self.decode(data);
+        if let Err(err) = &outcome {
+            self.failed = Some(err.clone());
+        }
+        outcome
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<Decoded, CodecError> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() < self.needed {
+            return Ok(Decoded::NeedMoreData);
+        }
+        match decode(&self.buffer, &self.limits) {
+            Ok((message, len)) => {
+                self.buffer.drain(..len);
+                self.needed = 0;
+                Ok(Decoded::Message(message))
+            }
+            // `got` bytes were left when the field `expected` more.
+            Err(CodecError::Truncated { expected, got }) => {
+                self.needed = self.buffer.len() - got + expected;
+                if self.needed > self.limits.message_limit() {
+                    return Err(CodecError::MessageTooLarge(self.limits.message_limit()));
+                }
+                Ok(Decoded::NeedMoreData)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The bytes buffered and not decoded yet.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Returns the buffered bytes, ex. to hand the bytes following the
+    /// handshake to the decoder of the network messages.
+    pub fn into_buffered(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    /// Drops the buffered bytes, ex. after a decoding error.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.needed = 0;
+        self.failed = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::core::{encode, PeerFeature, TinyString};
+
+    fn message() -> HandshakeMessage {
+        HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            peer_name: TinyString::new("ergo-node").unwrap(),
+            declared_address: Some("10.0.0.1:9030".parse().unwrap()),
+            features: vec![PeerFeature::new(16, vec![0, 1, 0, 1])],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_feed_byte_by_byte() -> Result<(), CodecError> {
+        let mut data = encode(&message(), 1_700_000_000_000)?;
+        let len = data.len();
+        data.extend_from_slice(&[1, 2, 3]);
+
+        let mut decoder = HandshakeDecoder::new();
+        for byte in &data[..len - 1] {
+            assert!(matches!(decoder.feed(&[*byte])?, Decoded::NeedMoreData));
+        }
+        let Decoded::Message(decoded) = decoder.feed(&data[len - 1..])? else {
+            panic!("expected a complete message");
+        };
+        assert_eq!(decoded.timestamp, 1_700_000_000_000);
+        assert_eq!(decoded.peer_name.as_str(), "ergo-node");
+        assert_eq!(decoded.features, message().features);
+        assert_eq!(decoder.buffered(), [1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_errors() -> Result<(), CodecError> {
+        let data = encode(&message(), 42)?;
+
+        // An oversized field fails before its bytes arrive.
+        let limits = DecodeLimits::with_max_message(data.len() - 1);
+        let mut decoder = HandshakeDecoder::with_limits(limits);
+        let outcome = decoder.feed(&data[..data.len() - 2]);
+        assert_eq!(
+            outcome.unwrap_err(),
+            CodecError::MessageTooLarge(data.len() - 1)
+        );
+        // The error is final, the following bytes aren't buffered.
+        let buffered = decoder.buffered().len();
+        assert_eq!(
+            decoder.feed(&data[data.len() - 2..]).unwrap_err(),
+            CodecError::MessageTooLarge(data.len() - 1)
+        );
+        assert_eq!(decoder.buffered().len(), buffered);
+
+        let mut decoder = HandshakeDecoder::new();
+        let invalid = [&data[..2], &[0xff, 0xfe]].concat();
+        assert!(matches!(decoder.feed(&invalid)?, Decoded::NeedMoreData));
+        assert!(matches!(
+            decoder.feed(&[0; 10]),
+            Err(CodecError::InvalidUtf8(_))
+        ));
+        assert!(matches!(decoder.feed(&[]), Err(CodecError::InvalidUtf8(_))));
+        decoder.reset();
+        assert!(matches!(decoder.feed(&data)?, Decoded::Message(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use alloc::string::FromUtf8Error;
+use core::fmt;
+
+use super::spec::Field;
+
+/// A message failed to encode or decode. With the `std` feature, it
+/// converts into the matching [`ProtocolError`](crate::ProtocolError).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CodecError {
+    /// The bytes ended early, `expected` bytes were needed to read the
+    /// next part of the message but only `got` were available.
+    Truncated {
+        expected: usize,
+        got: usize,
+    },
+    /// A variable length integer doesn't fit in 64 bits.
+    VlqOverflow,
+    StringTooLong {
+        field: Field,
+        len: usize,
+        max: usize,
+    },
+    InvalidUtf8(FromUtf8Error),
+    InvalidAddressSize(u8),
+    InvalidPort(u64),
+    FeatureTooLong {
+        id: u8,
+        len: u64,
+    },
+    TooManyFeatures(usize),
+    MessageTooLarge(usize),
+    /// The message holds more features than the
+    /// [`DecodeLimits`](super::DecodeLimits) allow.
+    FeatureCountExceeded {
+        count: usize,
+        max: usize,
+    },
+    /// The buffer the message is encoded to holds `got` bytes but the
+    /// message needs `needed`.
+    BufferTooSmall {
+        needed: usize,
+        got: usize,
+    },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Truncated { expected, got } => write!(
+                f,
+                "The message is truncated, expected {expected} bytes, got {got}"
+            ),
+            CodecError::VlqOverflow => {
+                f.write_str("A variable length integer conversion error occurred")
+            }
+            CodecError::StringTooLong { field, len, max } => write!(
+                f,
+                "The {field} is {len} bytes long, at most {max} bytes are allowed"
+            ),
+            CodecError::InvalidUtf8(_) => f.write_str("A string conversion error occurred"),
+            CodecError::InvalidAddressSize(size) => {
+                write!(f, "Invalid declared address size {size}")
+            }
+            CodecError::InvalidPort(port) => write!(f, "Invalid declared address port {port}"),
+            CodecError::FeatureTooLong { id, len } => write!(
+                f,
+                "Feature {id} body is {len} bytes long, at most 65535 bytes are allowed"
+            ),
+            CodecError::TooManyFeatures(count) => write!(
+                f,
+                "The message holds {count} features, at most 255 are allowed"
+            ),
+            CodecError::MessageTooLarge(max) => {
+                write!(f, "The message exceeds the maximum size of {max} bytes")
+            }
+            CodecError::FeatureCountExceeded { count, max } => write!(
+                f,
+                "The message holds {count} features, at most {max} are allowed by the limits"
+            ),
+            CodecError::BufferTooSmall { needed, got } => write!(
+                f,
+                "The message needs {needed} bytes, the buffer only holds {got}"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            CodecError::InvalidUtf8(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::codec::{read_vlq, vlq_len, write_vlq, ByteSink, ByteSource};
+use super::message::RedactedBytes;
+use super::spec;
+
+/// Identifier of the feature advertising the peer local address.
+pub const LOCAL_ADDRESS_FEATURE_ID: u8 = 2;
+
+/// Identifier of the feature carrying the network magic and session id.
+pub const SESSION_FEATURE_ID: u8 = 3;
+
+/// Identifier of the feature advertising the peer REST API url.
+pub const REST_API_URL_FEATURE_ID: u8 = 4;
+
+/// Identifier of the feature describing the peer operating mode.
+pub const MODE_FEATURE_ID: u8 = 16;
+
+/// The `Debug` output truncates long payloads.
+#[derive(PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct PeerFeature {
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl fmt::Debug for PeerFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerFeature")
+            .field("id", &self.id)
+            .field("payload", &RedactedBytes(&self.payload))
+            .finish()
+    }
+}
+
+impl PeerFeature {
+    pub fn new(id: u8, payload: Vec<u8>) -> Self {
+        Self { id, payload }
+    }
+
+    /// Whether the id is one of the features documented by the reference
+    /// node, see the `*_FEATURE_ID` constants.
+    pub fn is_known(&self) -> bool {
+        matches!(
+            self.id,
+            LOCAL_ADDRESS_FEATURE_ID
+                | SESSION_FEATURE_ID
+                | REST_API_URL_FEATURE_ID
+                | MODE_FEATURE_ID
+        )
+    }
+
+    /// Number of bytes of the encoded feature, its id and length included.
+    pub fn encoded_len(&self) -> usize {
+        1 + vlq_len(self.payload.len() as u64) + self.payload.len()
+    }
+
+    pub(crate) fn encode<S: ByteSink + ?Sized>(&self, sink: &mut S) -> Result<(), S::Error> {
+        sink.write_bytes(&[self.id])?;
+        write_vlq(sink, self.payload.len() as u64)?;
+        sink.write_bytes(&self.payload)
+    }
+
+    pub(crate) fn decode<S: ByteSource + ?Sized>(source: &mut S) -> Result<Self, S::Error> {
+        let id = source.read_byte()?;
+        let len = read_vlq(source)?;
+        spec::check_feature_len(id, len)?;
+        let payload = source.read_bytes(len as usize)?;
+        Ok(Self { id, payload })
+    }
+}
@@ -0,0 +1,266 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::net::SocketAddr;
+use core::ops::Deref;
+use core::str::FromStr;
+
+use super::codec::HandshakeFields;
+use super::error::CodecError;
+use super::feature::PeerFeature;
+use super::spec::{self, Field};
+
+/// The version of a node, ordered by major, minor then patch component.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Version(pub [u8; 3]);
+
+/// A protocol capability introduced by a given version of the reference
+/// node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// The second version of the synchronization messages, from 4.0.16.
+    SyncV2,
+    /// The difficulty adjustment of EIP-37, from 4.0.100.
+    Eip37,
+    /// Serving UTXO set snapshots, from 5.0.12.
+    UtxoSnapshots,
+    /// Serving NiPoPoW proofs, from 5.0.13.
+    Nipopow,
+}
+
+impl Capability {
+    /// Every capability, in the order they were introduced.
+    pub const ALL: [Capability; 4] = [
+        Capability::SyncV2,
+        Capability::Eip37,
+        Capability::UtxoSnapshots,
+        Capability::Nipopow,
+    ];
+
+    /// The first version supporting the capability.
+    pub fn min_version(self) -> Version {
+        match self {
+            Capability::SyncV2 => Version([4, 0, 16]),
+            Capability::Eip37 => Version([4, 0, 100]),
+            Capability::UtxoSnapshots => Version([5, 0, 12]),
+            Capability::Nipopow => Version([5, 0, 13]),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::SyncV2 => "sync-v2",
+            Capability::Eip37 => "eip-37",
+            Capability::UtxoSnapshots => "utxo-snapshots",
+            Capability::Nipopow => "nipopow",
+        })
+    }
+}
+
+impl Version {
+    /// Whether a node running this version supports the capability.
+    pub fn supports(&self, capability: Capability) -> bool {
+        *self >= capability.min_version()
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts = value.split(".");
+        let mut index = 0;
+        let mut raw_version = [0u8; 3];
+        for s in parts {
+            if index >= 3 {
+                return Err("Malformed version specs.".to_string());
+            }
+            let version_part = s
+                .parse::<u8>()
+                .map_err(|_| format!("Error parsing version component: `{}`.", s))?;
+            raw_version[index] = version_part;
+            index += 1;
+        }
+
+        if index != 3 {
+            return Err("Malformed version specs.".to_string());
+        }
+
+        Ok(Version(raw_version))
+    }
+}
+
+/// Number of characters of a peer supplied string shown by the `Debug`
+/// outputs, longer strings are truncated.
+const DEBUG_MAX_CHARS: usize = 64;
+
+/// Number of bytes of a feature payload shown by the `Debug` outputs.
+const DEBUG_MAX_BYTES: usize = 16;
+
+/// Formats a peer supplied string for logs: quoted, truncated and with
+/// the control and bidirectional formatting characters escaped, so that
+/// a malicious reply can't rewrite the terminal or forge log lines.
+pub(crate) struct Redacted<'a>(pub &'a str);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars().take(DEBUG_MAX_CHARS) {
+            match c {
+                '\u{061c}'
+                | '\u{200e}'
+                | '\u{200f}'
+                | '\u{202a}'..='\u{202e}'
+                | '\u{2066}'..='\u{2069}' => write!(f, "{}", c.escape_unicode())?,
+                c => write!(f, "{}", c.escape_debug())?,
+            }
+        }
+        f.write_str("\"")?;
+        if self.0.chars().nth(DEBUG_MAX_CHARS).is_some() {
+            write!(f, "... ({} bytes)", self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a peer supplied payload for logs, truncated.
+pub(crate) struct RedactedBytes<'a>(pub &'a [u8]);
+
+impl fmt::Debug for RedactedBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.0[..self.0.len().min(DEBUG_MAX_BYTES)];
+        write!(f, "{shown:?}")?;
+        if self.0.len() > DEBUG_MAX_BYTES {
+            write!(f, "... ({} bytes)", self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// A string of at most 255 bytes, the most a byte length prefix allows.
+/// Every constructor enforces the limit, so that any tiny string encodes.
+#[derive(PartialEq, Eq, Default, Clone)]
+pub struct TinyString(String);
+
+impl fmt::Debug for TinyString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TinyString")
+            .field(&Redacted(&self.0))
+            .finish()
+    }
+}
+
+impl TryFrom<&str> for TinyString {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl TryFrom<String> for TinyString {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl TinyString {
+    /// A tiny string holding the value, failing when it is longer than 255
+    /// bytes. The other constructors, the decoder included, go through it.
+    pub fn new(value: impl Into<String>) -> Result<Self, String> {
+        let value = value.into();
+        if value.len() > spec::MAX_SHORT_STRING_LEN {
+            return Err("TinyString cannot hold more than 255 bytes.".to_string());
+        }
+        Ok(Self(value))
+    }
+
+    /// Same as [`TinyString::new`] but reports which field is too long.
+    pub(crate) fn for_field(value: impl Into<String>, field: Field) -> Result<Self, CodecError> {
+        let value = value.into();
+        let len = value.len();
+        Self::new(value).map_err(|_| CodecError::StringTooLong {
+            field,
+            len,
+            max: spec::MAX_SHORT_STRING_LEN,
+        })
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl fmt::Display for TinyString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Deref for TinyString {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// The `Debug` output truncates and escapes the strings supplied by the
+/// peer, it is safe to log.
+#[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct HandshakeMessage {
+    /// Unix timestamp in milliseconds at which the message was created.
+    /// It is set when decoding, [`HandshakeMessage::encode_for_request`]
+    /// always writes the current time.
+    pub timestamp: u64,
+    pub agent_name: TinyString,
+    pub version: Version,
+    pub peer_name: TinyString,
+    pub declared_address: Option<SocketAddr>,
+    pub features: Vec<PeerFeature>,
+}
+
+impl fmt::Debug for HandshakeMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeMessage")
+            .field("timestamp", &self.timestamp)
+            .field("agent_name", &Redacted(&self.agent_name))
+            .field("version", &self.version)
+            .field("peer_name", &Redacted(&self.peer_name))
+            .field("declared_address", &self.declared_address)
+            .field("features", &self.features)
+            .finish()
+    }
+}
+
+impl HandshakeMessage {
+    /// Number of bytes of the encoded message, the timestamp included.
+    pub fn encoded_len(&self) -> usize {
+        self.fields(self.timestamp).encoded_len()
+    }
+
+    pub(crate) fn fields(&self, timestamp: u64) -> HandshakeFields<'_> {
+        HandshakeFields {
+            timestamp,
+            agent_name: &self.agent_name,
+            version: &self.version,
+            peer_name: &self.peer_name,
+            declared_address: self.declared_address.as_ref(),
+            features: &self.features,
+        }
+    }
+}
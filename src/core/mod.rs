@@ -0,0 +1,53 @@
+//! This module implements the handshake message format without the
+//! standard library, for the targets without an operating system, ex.
+//! embedded monitoring probes or a WASM module.
+//!
+//! It only needs an allocator, and builds with the default features
+//! disabled (`default-features = false`). The `std` feature, enabled by
+//! default, layers the async transport on top of it: reading handshakes
+//! from streams, connecting to peers and the rest of the crate. The types
+//! defined here are the ones the rest of the crate uses, ex.
+//! [`HandshakeMessage`] is re-exported at the root of the crate.
+//!
+//! Messages are encoded to and decoded from any [`ByteSink`] and
+//! [`ByteSource`], slices and vectors included, or fed in chunks of any
+//! size to a [`HandshakeDecoder`].
+//!
+//! ```
+//! use p2p_handshake::core::{decode, encode, DecodeLimits, HandshakeMessage, TinyString};
+//!
+//! let message = HandshakeMessage {
+//!     agent_name: TinyString::new("ergoref").unwrap(),
+//!     ..Default::default()
+//! };
+//! let data = encode(&message, 1_700_000_000_000).unwrap();
+//! let (decoded, len) = decode(&data, &DecodeLimits::default()).unwrap();
+//! assert_eq!(decoded.agent_name.as_str(), "ergoref");
+//! assert_eq!(len, data.len());
+//! ```
+//!
+
+mod codec;
+mod decoder;
+mod error;
+mod feature;
+mod message;
+pub mod spec;
+
+pub use codec::{decode, decode_from, encode, encode_into, encode_to_slice, ByteSink, ByteSource};
+pub use decoder::{Decoded, HandshakeDecoder};
+pub use error::CodecError;
+pub use feature::{
+    PeerFeature, LOCAL_ADDRESS_FEATURE_ID, MODE_FEATURE_ID, REST_API_URL_FEATURE_ID,
+    SESSION_FEATURE_ID,
+};
+pub use message::{Capability, HandshakeMessage, TinyString, Version};
+pub use spec::DecodeLimits;
+
+#[cfg(all(feature = "std", test))]
+pub(crate) use codec::vlq_len;
+#[cfg(feature = "std")]
+pub(crate) use codec::{
+    read_declared_address, read_peer_fields, read_string, read_vlq, write_declared_address,
+    write_peer_spec, write_vlq, HandshakeFields,
+};
@@ -0,0 +1,160 @@
+//! The documented limits of the handshake message layout.
+//!
+//! The field order and size limits come from the [handshake format](https://docs.ergoplatform.com/dev/p2p/p2p-handshake/#handshake-format)
+//! documentation.
+//!
+
+use core::fmt;
+
+use super::error::CodecError;
+use super::feature::PeerFeature;
+
+/// Maximum size of a handshake message accepted by the reference node.
+pub const MAX_HANDSHAKE_SIZE: usize = 8096;
+
+/// Maximum number of bytes of a length prefixed string (agent and peer name).
+pub const MAX_SHORT_STRING_LEN: usize = u8::MAX as usize;
+
+/// Number of bytes of the version field.
+pub const VERSION_LEN: usize = 3;
+
+/// Maximum number of features, the count is written as a single byte.
+pub const MAX_FEATURES: usize = u8::MAX as usize;
+
+/// Maximum size of a feature body, the length is written as an unsigned short.
+pub const MAX_FEATURE_LEN: usize = u16::MAX as usize;
+
+/// Declared address size byte of an IPv4 address (ip bytes + 4).
+pub const IPV4_ADDRESS_SIZE: u8 = 4 + 4;
+
+/// Declared address size byte of an IPv6 address (ip bytes + 4).
+pub const IPV6_ADDRESS_SIZE: u8 = 16 + 4;
+
+/// Limits enforced while decoding a handshake, the documented limits by
+/// default. Embedders of the decoder may tighten them to their own
+/// constraints, limits above the documented ones have no effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Maximum size of a message, in bytes, at most [`MAX_HANDSHAKE_SIZE`].
+    pub max_message: usize,
+    /// Maximum length of the agent and peer names, in bytes.
+    pub max_string: usize,
+    /// Maximum number of features.
+    pub max_features: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_message: MAX_HANDSHAKE_SIZE,
+            max_string: MAX_SHORT_STRING_LEN,
+            max_features: MAX_FEATURES,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// The documented limits with the given maximum message size, at most
+    /// [`MAX_HANDSHAKE_SIZE`].
+    pub fn with_max_message(max_message: usize) -> Self {
+        Self {
+            max_message: max_message.min(MAX_HANDSHAKE_SIZE),
+            ..Default::default()
+        }
+    }
+
+    /// The maximum size of a message, at most [`MAX_HANDSHAKE_SIZE`].
+    pub(crate) fn message_limit(&self) -> usize {
+        self.max_message.min(MAX_HANDSHAKE_SIZE)
+    }
+
+    /// Checks the length of a length prefixed string field.
+    pub(crate) fn check_string_len(&self, field: Field, len: usize) -> Result<(), CodecError> {
+        let max = self.max_string.min(MAX_SHORT_STRING_LEN);
+        if len > max {
+            return Err(CodecError::StringTooLong { field, len, max });
+        }
+        Ok(())
+    }
+
+    /// Checks the number of features.
+    pub(crate) fn check_features_count(&self, count: usize) -> Result<(), CodecError> {
+        let max = self.max_features.min(MAX_FEATURES);
+        if count > max {
+            return Err(CodecError::FeatureCountExceeded { count, max });
+        }
+        Ok(())
+    }
+}
+
+/// The handshake fields as they appear on the wire.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Field {
+    Timestamp,
+    AgentName,
+    Version,
+    PeerName,
+    DeclaredAddress,
+    Features,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Field::Timestamp => "timestamp",
+            Field::AgentName => "agent name",
+            Field::Version => "version",
+            Field::PeerName => "peer name",
+            Field::DeclaredAddress => "declared address",
+            Field::Features => "features",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The documented order of the handshake fields.
+pub const FIELD_ORDER: [Field; 6] = [
+    Field::Timestamp,
+    Field::AgentName,
+    Field::Version,
+    Field::PeerName,
+    Field::DeclaredAddress,
+    Field::Features,
+];
+
+pub(crate) fn check_fields(
+    agent_name: &str,
+    peer_name: &str,
+    features: &[PeerFeature],
+) -> Result<(), CodecError> {
+    check_string_len(Field::AgentName, agent_name.len())?;
+    check_string_len(Field::PeerName, peer_name.len())?;
+    if features.len() > MAX_FEATURES {
+        return Err(CodecError::TooManyFeatures(features.len()));
+    }
+    for feature in features {
+        check_feature_len(feature.id, feature.payload.len() as u64)?;
+    }
+    Ok(())
+}
+
+/// Checks the length of a length prefixed string field.
+pub(crate) fn check_string_len(field: Field, len: usize) -> Result<(), CodecError> {
+    DecodeLimits::default().check_string_len(field, len)
+}
+
+/// Checks the length of a feature body.
+pub(crate) fn check_feature_len(id: u8, len: u64) -> Result<(), CodecError> {
+    if len > MAX_FEATURE_LEN as u64 {
+        return Err(CodecError::FeatureTooLong { id, len });
+    }
+    Ok(())
+}
+
+/// Checks the size byte of a declared address.
+pub(crate) fn check_address_size(size: u8) -> Result<(), CodecError> {
+    if size != IPV4_ADDRESS_SIZE && size != IPV6_ADDRESS_SIZE {
+        return Err(CodecError::InvalidAddressSize(size));
+    }
+    Ok(())
+}
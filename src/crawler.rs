@@ -0,0 +1,551 @@
+//! This module implements crawling the network: starting from seed nodes,
+//! every reachable node is handshaken and asked for its known peers,
+//! whose declared addresses are crawled in turn.
+//!
+//! The crawl progresses in waves, each wave probing the addresses
+//! discovered by the previous one. A seed controls the order in which the
+//! targets of a wave are dialed and the jitter delaying each dial, and the
+//! discovered addresses are queued in a deterministic order, so a given
+//! seed and network reproduce the same traversal.
+//!
+//! Every result records the depth at which the node was discovered and the
+//! node which referred it, so the addresses advertised by a misbehaving
+//! node can be traced back to it. The depth and the number of addresses
+//! taken from each node can be limited.
+//!
+//! A crawl can be bounded in nodes and in time. The frontier can then be
+//! prioritized for coverage, so that a partial crawl still samples every
+//! part of the network.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::aggregate::subnet;
+use crate::batch::{connect_and_exchange, with_timeout, ProbeResult};
+use crate::config::HandshakeConfig;
+use crate::encoder::{HandshakeMessage, Version};
+use crate::error::ProtocolResult;
+use crate::framing::Network;
+use crate::peers::{request_peers_with_filter, AddressFilter, PeerSpec};
+use crate::rng::SplitMix64;
+
+/// Configuration of a crawl.
+#[derive(Debug, Clone)]
+pub struct CrawlerConfig {
+    /// Configuration of the handshake made with every node, the timeout
+    /// also bounds the peers request following the handshake.
+    pub handshake: HandshakeConfig,
+    /// The crawled network.
+    pub network: Network,
+    /// Maximum number of nodes probed at the same time.
+    pub concurrency: usize,
+    /// Maximum number of nodes probed by the crawl.
+    pub max_nodes: Option<usize>,
+    /// Maximum discovery depth of the probed nodes, the seeds being at
+    /// depth 0.
+    pub max_depth: Option<usize>,
+    /// Maximum number of addresses taken from the peers of each node.
+    pub max_fanout: Option<usize>,
+    /// Filter of the addresses declared by the peers, the default one
+    /// keeps the crawl off private and reserved networks.
+    pub address_filter: AddressFilter,
+    /// Maximum duration of the crawl, the nodes still being probed when it
+    /// elapses are dropped from the results.
+    pub max_duration: Option<Duration>,
+    /// Order in which the nodes of a wave are dialed.
+    pub priority: FrontierPriority,
+    /// Seed of the scheduling decisions.
+    pub seed: u64,
+    /// Maximum random delay before dialing each node, spreading the load
+    /// of a wave.
+    pub jitter: Option<Duration>,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> Self {
+        Self {
+            handshake: HandshakeConfig::default(),
+            network: Network::Mainnet,
+            concurrency: 64,
+            max_nodes: None,
+            max_depth: None,
+            max_fanout: None,
+            address_filter: AddressFilter::default(),
+            max_duration: None,
+            priority: FrontierPriority::default(),
+            seed: 0,
+            jitter: None,
+        }
+    }
+}
+
+/// Order in which the frontier is dialed, it matters when the crawl is
+/// bounded in nodes or in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrontierPriority {
+    /// A random order drawn from the seed.
+    #[default]
+    Random,
+    /// Nodes in the subnets (/24 in IPv4, /48 in IPv6) with the fewest
+    /// probed nodes first, then nodes advertised with higher versions.
+    Coverage,
+}
+
+/// The outcome of crawling a single node.
+#[derive(Debug)]
+pub struct CrawlResult {
+    pub address: SocketAddr,
+    pub reply: ProtocolResult<HandshakeMessage>,
+    /// The peers known by the node, empty when it didn't answer the
+    /// peers request.
+    pub peers: Vec<PeerSpec>,
+    /// Number of referrals between a seed and the node, 0 for the seeds.
+    pub depth: usize,
+    /// The node whose peers included this node, `None` for the seeds.
+    pub referrer: Option<SocketAddr>,
+    /// Number of addresses first discovered in the peers of the node.
+    pub discovered: usize,
+    /// Time between sending the request and reading the reply.
+    pub latency: Option<Duration>,
+}
+
+/// Returns the referral chain of `address`, from its seed to the node
+/// itself, or an empty chain when the node isn't part of the results.
+pub fn referral_chain(results: &[CrawlResult], address: SocketAddr) -> Vec<SocketAddr> {
+    let by_address: HashMap<SocketAddr, &CrawlResult> = results
+        .iter()
+        .map(|result| (result.address, result))
+        .collect();
+    let mut chain = Vec::new();
+    let mut next = Some(address);
+    while let Some(address) = next {
+        let Some(result) = by_address.get(&address) else {
+            break;
+        };
+        chain.push(address);
+        next = result.referrer;
+    }
+    chain.reverse();
+    chain
+}
+
+/// An address waiting to be crawled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FrontierEntry {
+    address: SocketAddr,
+    /// The version of the node, when advertised by its referrer.
+    version: Option<Version>,
+    depth: usize,
+    referrer: Option<SocketAddr>,
+}
+
+/// Crawls the network from a set of seed addresses.
+#[derive(Debug)]
+pub struct Crawler {
+    config: CrawlerConfig,
+    frontier: Vec<FrontierEntry>,
+    seen: HashSet<SocketAddr>,
+    /// Number of probed nodes by subnet.
+    probed_subnets: HashMap<IpAddr, usize>,
+    rng: SplitMix64,
+}
+
+impl Crawler {
+    pub fn new(config: CrawlerConfig) -> Self {
+        let rng = SplitMix64::new(config.seed);
+        Self {
+            config,
+            frontier: Vec::new(),
+            seen: HashSet::new(),
+            probed_subnets: HashMap::new(),
+            rng,
+        }
+    }
+
+    /// Adds addresses to crawl, addresses already known are ignored.
+    pub fn seed<I: IntoIterator<Item = SocketAddr>>(&mut self, addresses: I) {
+        for address in addresses {
+            self.enqueue(FrontierEntry {
+                address,
+                version: None,
+                depth: 0,
+                referrer: None,
+            });
+        }
+    }
+
+    /// Queues the entry unless its address is already known, returning
+    /// whether it was queued.
+    fn enqueue(&mut self, entry: FrontierEntry) -> bool {
+        if !self.seen.insert(entry.address) {
+            return false;
+        }
+        self.frontier.push(entry);
+        true
+    }
+
+    /// Queues the peers declared by the node of `result`, within the depth
+    /// and fan-out limits.
+    fn enqueue_peers(&mut self, result: &mut CrawlResult) {
+        let depth = result.depth + 1;
+        if self
+            .config
+            .max_depth
+            .is_some_and(|max_depth| depth > max_depth)
+        {
+            return;
+        }
+        let max_fanout = self.config.max_fanout.unwrap_or(usize::MAX);
+        let peers = result
+            .peers
+            .iter()
+            .filter_map(|peer| Some((peer.declared_address?, peer.version.clone())))
+            .take(max_fanout);
+        for (address, version) in peers {
+            let entry = FrontierEntry {
+                address,
+                version: Some(version),
+                depth,
+                referrer: Some(result.address),
+            };
+            if self.enqueue(entry) {
+                result.discovered += 1;
+            }
+        }
+    }
+
+    /// Crawls until no new address is discovered or the node or time limit
+    /// is reached, returning the results in the order the nodes were dialed.
+    pub async fn run(mut self) -> Vec<CrawlResult> {
+        let deadline = self
+            .config
+            .max_duration
+            .map(|max_duration| Instant::now() + max_duration);
+        let mut results = Vec::new();
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return results;
+            }
+            let budget = self.config.max_nodes.map_or(usize::MAX, |max_nodes| {
+                max_nodes.saturating_sub(results.len())
+            });
+            let wave = self.next_wave(budget);
+            if wave.is_empty() {
+                return results;
+            }
+
+            for mut result in self.crawl_wave(wave, deadline).await {
+                self.enqueue_peers(&mut result);
+                results.push(result);
+            }
+        }
+    }
+
+    /// Takes the next wave out of the frontier, in seeded and prioritized
+    /// order, along with the jitter delaying each dial.
+    fn next_wave(&mut self, budget: usize) -> Vec<(FrontierEntry, Duration)> {
+        let mut wave = std::mem::take(&mut self.frontier);
+        self.rng.shuffle(&mut wave);
+        if self.config.priority == FrontierPriority::Coverage {
+            wave = self.prioritize(wave);
+        }
+        if wave.len() > budget {
+            wave.truncate(budget);
+        }
+        wave.into_iter()
+            .map(|entry| {
+                *self
+                    .probed_subnets
+                    .entry(subnet(entry.address.ip()))
+                    .or_default() += 1;
+                let delay = match self.config.jitter {
+                    Some(jitter) => jitter.mul_f64(self.rng.next_f64()),
+                    None => Duration::ZERO,
+                };
+                (entry, delay)
+            })
+            .collect()
+    }
+
+    /// Orders the wave round robin across subnets, starting with the least
+    /// probed ones, and by decreasing version within each subnet.
+    fn prioritize(&self, mut wave: Vec<FrontierEntry>) -> Vec<FrontierEntry> {
+        // Stable sorts keep the seeded order between equal entries.
+        wave.sort_by(|first, second| second.version.cmp(&first.version));
+        let mut ranks = HashMap::new();
+        let mut ranked: Vec<(usize, FrontierEntry)> = wave
+            .into_iter()
+            .map(|entry| {
+                let subnet = subnet(entry.address.ip());
+                let rank = ranks.entry(subnet).or_insert_with(|| {
+                    self.probed_subnets
+                        .get(&subnet)
+                        .copied()
+                        .unwrap_or_default()
+                });
+                *rank += 1;
+                (*rank, entry)
+            })
+            .collect();
+        ranked.sort_by_key(|(rank, _)| *rank);
+        ranked.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    async fn crawl_wave(
+        &self,
+        wave: Vec<(FrontierEntry, Duration)>,
+        deadline: Option<Instant>,
+    ) -> Vec<CrawlResult> {
+        let semaphore = Arc::new(Semaphore::new(self.config.concurrency.max(1)));
+        let mut tasks = JoinSet::new();
+        for (index, (entry, delay)) in wave.into_iter().enumerate() {
+            let semaphore = semaphore.clone();
+            let config = self.config.clone();
+            tasks.spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _permit = semaphore.acquire_owned().await;
+                (index, crawl_node(entry, &config).await)
+            });
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        loop {
+            let joined = match deadline {
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline, tasks.join_next()).await {
+                        Ok(joined) => joined,
+                        Err(_) => {
+                            tasks.abort_all();
+                            break;
+                        }
+                    }
+                }
+                None => tasks.join_next().await,
+            };
+            match joined {
+                Some(Ok(result)) => results.push(result),
+                Some(Err(_)) => {}
+                None => break,
+            }
+        }
+        // Completion order depends on the network, the dial order doesn't.
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+async fn crawl_node(entry: FrontierEntry, config: &CrawlerConfig) -> CrawlResult {
+    let mut timings = ProbeResult::new(());
+    let (reply, peers) =
+        match connect_and_exchange(entry.address, &config.handshake, &mut timings).await {
+            Ok((mut stream, reply)) => {
+                let peers = with_timeout(
+                    config.handshake.timeout,
+                    request_peers_with_filter(
+                        &mut stream,
+                        config.network.magic(),
+                        &config.address_filter,
+                    ),
+                )
+                .await;
+                (Ok(reply), peers.unwrap_or_default())
+            }
+            Err(err) => (Err(err), Vec::new()),
+        };
+    CrawlResult {
+        address: entry.address,
+        reply,
+        peers,
+        depth: entry.depth,
+        referrer: entry.referrer,
+        discovered: 0,
+        latency: timings.handshake_time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::peers::{encode_peers, GET_PEERS_CODE};
+    use crate::testing::MockErgoNode;
+
+    async fn mock_node(name: &str, peers: &[SocketAddr]) -> ProtocolResult<MockErgoNode> {
+        let peers: Vec<PeerSpec> = peers
+            .iter()
+            .map(|address| PeerSpec {
+                declared_address: Some(*address),
+                ..Default::default()
+            })
+            .collect();
+        MockErgoNode::builder()
+            .respond_handshake(HandshakeBuilder::new().agent_name(name).build()?)
+            .then_expect_frame(GET_PEERS_CODE)
+            .then_send_frame(encode_peers(&peers)?)
+            .then_close()
+            .spawn()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_crawl() -> ProtocolResult<()> {
+        let leaf = mock_node("leaf", &[]).await?;
+        let middle = mock_node("middle", &[leaf.address()]).await?;
+        // The seed node lists the middle node twice and an unreachable node.
+        let unreachable: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let seed = mock_node("seed", &[middle.address(), middle.address(), unreachable]).await?;
+
+        let config = CrawlerConfig {
+            address_filter: AddressFilter::allow_all(),
+            handshake: HandshakeConfig::new(HandshakeBuilder::new().agent_name("crawler"))
+                .timeout(Duration::from_secs(5)),
+            jitter: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let mut crawler = Crawler::new(config);
+        crawler.seed([seed.address(), seed.address()]);
+        let results = crawler.run().await;
+
+        let names: Vec<String> = results
+            .iter()
+            .map(|result| match &result.reply {
+                Ok(reply) => reply.agent_name.to_string(),
+                Err(_) => result.address.to_string(),
+            })
+            .collect();
+        assert_eq!(names.len(), 4);
+        assert_eq!(names[0], "seed");
+        assert!(names[1..3].contains(&"middle".to_string()));
+        assert!(names[1..3].contains(&unreachable.to_string()));
+        assert_eq!(names[3], "leaf");
+        assert_eq!(results[0].peers.len(), 3);
+        assert_eq!(results[0].discovered, 2);
+        assert_eq!(results[3].depth, 2);
+        assert_eq!(
+            referral_chain(&results, leaf.address()),
+            [seed.address(), middle.address(), leaf.address()]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_depth_and_fanout_limits() -> ProtocolResult<()> {
+        let leaf = mock_node("leaf", &[]).await?;
+        let middle = mock_node("middle", &[leaf.address()]).await?;
+        let other = mock_node("other", &[]).await?;
+        let seed = mock_node("seed", &[middle.address(), other.address()]).await?;
+
+        let mut crawler = Crawler::new(CrawlerConfig {
+            address_filter: AddressFilter::allow_all(),
+            max_depth: Some(1),
+            max_fanout: Some(1),
+            ..Default::default()
+        });
+        crawler.seed([seed.address()]);
+        let results = crawler.run().await;
+        let addresses: Vec<SocketAddr> = results.iter().map(|result| result.address).collect();
+        assert_eq!(addresses, [seed.address(), middle.address()]);
+        assert_eq!(results[1].referrer, Some(seed.address()));
+        assert_eq!(results[1].discovered, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_schedule() {
+        let addresses: Vec<SocketAddr> = (1..=20)
+            .map(|port| SocketAddr::from(([10, 0, 0, 1], port)))
+            .collect();
+        let schedule = |seed| {
+            let mut crawler = Crawler::new(CrawlerConfig {
+                seed,
+                jitter: Some(Duration::from_secs(1)),
+                ..Default::default()
+            });
+            crawler.seed(addresses.clone());
+            crawler.next_wave(15)
+        };
+
+        let wave = schedule(7);
+        assert_eq!(wave.len(), 15);
+        assert_eq!(wave, schedule(7));
+        assert_ne!(wave, schedule(8));
+        assert!(wave
+            .iter()
+            .all(|(_, delay)| *delay < Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_node_limit() -> ProtocolResult<()> {
+        let leaf = mock_node("leaf", &[]).await?;
+        let seed = mock_node("seed", &[leaf.address()]).await?;
+        let mut crawler = Crawler::new(CrawlerConfig {
+            address_filter: AddressFilter::allow_all(),
+            max_nodes: Some(1),
+            ..Default::default()
+        });
+        crawler.seed([seed.address()]);
+        let results = crawler.run().await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].address, seed.address());
+        Ok(())
+    }
+
+    #[test]
+    fn test_coverage_priority() {
+        let mut crawler = Crawler::new(CrawlerConfig {
+            priority: FrontierPriority::Coverage,
+            ..Default::default()
+        });
+        crawler.seed((1..=4).map(|host| SocketAddr::from(([10, 0, 0, host], 9030))));
+        for (host, version) in [(1, [4, 0, 0]), (2, [5, 0, 21])] {
+            crawler.enqueue(FrontierEntry {
+                address: SocketAddr::from(([10, 0, 1, host], 9030)),
+                version: Some(Version(version)),
+                depth: 1,
+                referrer: None,
+            });
+        }
+        crawler.seed([SocketAddr::from(([10, 0, 2, 1], 9030))]);
+
+        let wave: Vec<SocketAddr> = crawler
+            .next_wave(3)
+            .into_iter()
+            .map(|(entry, _)| entry.address)
+            .collect();
+        let subnets: HashSet<IpAddr> = wave.iter().map(|address| subnet(address.ip())).collect();
+        assert_eq!(subnets.len(), 3);
+        assert!(wave.contains(&SocketAddr::from(([10, 0, 1, 2], 9030))));
+
+        // The subnets probed by the previous wave come last.
+        crawler.seed([
+            SocketAddr::from(([10, 0, 2, 2], 9030)),
+            SocketAddr::from(([10, 0, 3, 1], 9030)),
+        ]);
+        let wave = crawler.next_wave(1);
+        assert_eq!(wave[0].0.address, SocketAddr::from(([10, 0, 3, 1], 9030)));
+    }
+
+    #[tokio::test]
+    async fn test_time_limit() -> ProtocolResult<()> {
+        // Accepts connections without ever replying.
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let mut crawler = Crawler::new(CrawlerConfig {
+            max_duration: Some(Duration::from_millis(200)),
+            ..Default::default()
+        });
+        crawler.seed([silent.local_addr()?]);
+
+        let started = Instant::now();
+        let results = crawler.run().await;
+        assert!(results.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+//! This module implements dialing a target resolving to several
+//! addresses, following the Happy Eyeballs algorithm (RFC 8305).
+//!
+//! The addresses are interleaved by family, starting with the family of
+//! the first resolved address, then a connection attempt is started every
+//! [`CONNECTION_ATTEMPT_DELAY`], or as soon as the previous one fails,
+//! without waiting for the slower ones to complete. The first connection
+//! established wins and the other attempts are dropped, so that a node
+//! with a broken IPv6 address is reached over IPv4 after the delay rather
+//! than after the timeout of the operating system.
+//!
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::route::BoxFuture;
+
+/// Delay between the start of two connection attempts, as recommended by
+/// RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Runs before every connection attempt, ex. to knock the ports of the
+/// address or to open it on the API of a firewall. The attempt fails with
+/// the error of the hook, without connecting.
+///
+/// It is implemented by the functions taking the address and returning a
+/// future, see [`HandshakeConfig::pre_connect`](crate::HandshakeConfig::pre_connect).
+pub trait PreConnect: Send + Sync {
+    fn before_connect(&self, address: SocketAddr) -> BoxFuture<'static, io::Result<()>>;
+}
+
+impl<F, Fut> PreConnect for F
+where
+    F: Fn(SocketAddr) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    fn before_connect(&self, address: SocketAddr) -> BoxFuture<'static, io::Result<()>> {
+        Box::pin(self(address))
+    }
+}
+
+/// A shared [`PreConnect`] hook, cloning it only bumps a reference count.
+#[derive(Clone)]
+pub struct PreConnectHook(Arc<dyn PreConnect>);
+
+impl PreConnectHook {
+    pub fn new<H: PreConnect + 'static>(hook: H) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for PreConnectHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreConnectHook").finish_non_exhaustive()
+    }
+}
+
+/// Orders the addresses by alternating their family, starting with the
+/// family of the first address. The order within a family is kept.
+pub fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addresses.first() else {
+        return addresses;
+    };
+    let preferred = first.is_ipv6();
+    let (mut primary, mut secondary): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == preferred);
+    let mut ordered = Vec::with_capacity(primary.len() + secondary.len());
+    let (mut primary, mut secondary) = (primary.drain(..), secondary.drain(..));
+    loop {
+        match (primary.next(), secondary.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Connects to the first of the addresses accepting the connection,
+/// starting an attempt every `attempt_delay` in the order of the
+/// addresses. Returns the error of the last attempt when all fail.
+///
+/// With a `local_address`, the sockets are bound to it before connecting
+/// and only the addresses of its family are dialed.
+pub async fn connect_any(
+    addresses: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    local_address: Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    connect_any_with(addresses, attempt_delay, local_address, None).await
+}
+
+/// Same as [`connect_any`], running the hook before every attempt.
+pub(crate) async fn connect_any_with(
+    mut addresses: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    local_address: Option<SocketAddr>,
+    pre_connect: Option<PreConnectHook>,
+) -> io::Result<TcpStream> {
+    if let Some(local) = local_address {
+        let resolved = addresses.len();
+        addresses.retain(|address| address.is_ipv6() == local.is_ipv6());
+        if addresses.is_empty() && resolved > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no address of the target has the family of the local address {local}"),
+            ));
+        }
+    }
+    let connect = move |address| {
+        let pre_connect = pre_connect.clone();
+        async move {
+            if let Some(PreConnectHook(hook)) = pre_connect {
+                hook.before_connect(address).await?;
+            }
+            crate::connect_address(address, local_address).await
+        }
+    };
+    race(addresses, attempt_delay, connect).await
+}
+
+/// Connects to the address from a socket bound to the local address.
+pub(crate) async fn connect_from(local: SocketAddr, address: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(local)?;
+    socket.connect(address).await
+}
+
+/// Runs the staggered attempts of [`connect_any`] with the given
+/// connection function.
+async fn race<F, Fut, T>(
+    addresses: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    connect: F,
+) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut candidates = addresses.into_iter();
+    // Dropping the set aborts the attempts still running.
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(address) = candidates.next() {
+            attempts.spawn(connect(address));
+        } else if attempts.is_empty() {
+            break;
+        }
+        // Waits for the next attempt to complete, or for the delay to
+        // start another one when candidates are left.
+        let delay = tokio::time::sleep(attempt_delay);
+        tokio::pin!(delay);
+        loop {
+            let joined = tokio::select! {
+                joined = attempts.join_next(), if !attempts.is_empty() => joined,
+                _ = &mut delay, if candidates.len() > 0 => break,
+            };
+            match joined {
+                Some(Ok(Ok(stream))) => return Ok(stream),
+                Some(Ok(Err(err))) => last_error = Some(err),
+                Some(Err(err)) => last_error = Some(io::Error::other(err)),
+                None => {}
+            }
+            // A failed attempt starts the next one right away.
+            if candidates.len() > 0 || attempts.is_empty() {
+                break;
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addresses = vec![
+            address("[::1]:1"),
+            address("[::1]:2"),
+            address("[::1]:3"),
+            address("127.0.0.1:4"),
+            address("127.0.0.1:5"),
+        ];
+        let ports: Vec<u16> = interleave(addresses).iter().map(SocketAddr::port).collect();
+        assert_eq!(ports, [1, 4, 2, 5, 3]);
+
+        let addresses = vec![address("127.0.0.1:1"), address("[::1]:2")];
+        let ports: Vec<u16> = interleave(addresses).iter().map(SocketAddr::port).collect();
+        assert_eq!(ports, [1, 2]);
+        assert!(interleave(vec![]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_any() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+        // The refused address is skipped right away.
+        let stream = connect_any(vec![closed, node_address], Duration::from_secs(60), None).await?;
+        assert_eq!(stream.peer_addr()?, node_address);
+
+        let err = connect_any(vec![closed], Duration::from_millis(50), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = connect_any(vec![], Duration::from_millis(50), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_address() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+
+        // Any address of 127.0.0.0/8 is a loopback address on Linux.
+        let local = address("127.0.0.2:0");
+        let delay = Duration::from_millis(50);
+        let _stream = connect_any(vec![node_address], delay, Some(local)).await?;
+        let (_, remote) = listener.accept().await?;
+        assert_eq!(remote.ip(), local.ip());
+
+        let err = connect_any(vec![node_address], delay, Some(address("[::1]:0")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hanging_address() -> io::Result<()> {
+        // The first address never answers, as a broken AAAA record.
+        let hanging = address("[2001:db8::1]:9030");
+        let connect = |address: SocketAddr| async move {
+            if address == hanging {
+                std::future::pending::<()>().await;
+            }
+            Ok(address)
+        };
+        let started = Instant::now();
+        let addresses = interleave(vec![hanging, address("127.0.0.1:9030")]);
+        let reached = race(addresses, Duration::from_millis(50), connect).await?;
+        assert_eq!(reached, address("127.0.0.1:9030"));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(5));
+        Ok(())
+    }
+}
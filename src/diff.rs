@@ -0,0 +1,141 @@
+//! This module implements field-level comparison of handshake messages.
+//!
+//! It is mainly useful to detect when a peer's identity or features
+//! change between two handshakes.
+//!
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::encoder::{HandshakeMessage, TinyString, Version};
+use crate::feature::PeerFeature;
+
+/// A difference between two handshake messages, from `old` to `new`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FieldDiff {
+    AgentName {
+        old: TinyString,
+        new: TinyString,
+    },
+    Version {
+        old: Version,
+        new: Version,
+    },
+    PeerName {
+        old: TinyString,
+        new: TinyString,
+    },
+    DeclaredAddress {
+        old: Option<SocketAddr>,
+        new: Option<SocketAddr>,
+    },
+    FeatureAdded(PeerFeature),
+    FeatureRemoved(PeerFeature),
+    FeatureChanged {
+        old: PeerFeature,
+        new: PeerFeature,
+    },
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldDiff::AgentName { old, new } => write!(f, "agent name: {old} -> {new}"),
+            FieldDiff::Version { old, new } => write!(f, "version: {old} -> {new}"),
+            FieldDiff::PeerName { old, new } => write!(f, "peer name: {old} -> {new}"),
+            FieldDiff::DeclaredAddress { old, new } => {
+                write!(f, "declared address: {old:?} -> {new:?}")
+            }
+            FieldDiff::FeatureAdded(feature) => write!(f, "feature added: {}", feature.id),
+            FieldDiff::FeatureRemoved(feature) => write!(f, "feature removed: {}", feature.id),
+            FieldDiff::FeatureChanged { old, .. } => write!(f, "feature changed: {}", old.id),
+        }
+    }
+}
+
+impl HandshakeMessage {
+    /// Returns the differences between this message and `other`,
+    /// considering `self` as the old value. Features are matched by id.
+    pub fn diff(&self, other: &HandshakeMessage) -> Vec<FieldDiff> {
+        let mut diffs = vec![];
+        if self.agent_name != other.agent_name {
+            diffs.push(FieldDiff::AgentName {
+                old: self.agent_name.clone(),
+                new: other.agent_name.clone(),
+            });
+        }
+        if self.version != other.version {
+            diffs.push(FieldDiff::Version {
+                old: self.version.clone(),
+                new: other.version.clone(),
+            });
+        }
+        if self.peer_name != other.peer_name {
+            diffs.push(FieldDiff::PeerName {
+                old: self.peer_name.clone(),
+                new: other.peer_name.clone(),
+            });
+        }
+        if self.declared_address != other.declared_address {
+            diffs.push(FieldDiff::DeclaredAddress {
+                old: self.declared_address,
+                new: other.declared_address,
+            });
+        }
+
+        for old in &self.features {
+            match other.features.iter().find(|new| new.id == old.id) {
+                Some(new) if new != old => diffs.push(FieldDiff::FeatureChanged {
+                    old: old.clone(),
+                    new: new.clone(),
+                }),
+                Some(_) => {}
+                None => diffs.push(FieldDiff::FeatureRemoved(old.clone())),
+            }
+        }
+        for new in &other.features {
+            if !self.features.iter().any(|old| old.id == new.id) {
+                diffs.push(FieldDiff::FeatureAdded(new.clone()));
+            }
+        }
+        diffs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let old = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version([5, 0, 20]),
+            features: vec![PeerFeature::new(2, vec![1]), PeerFeature::new(3, vec![1])],
+            ..Default::default()
+        };
+        assert!(old.diff(&old).is_empty());
+
+        let new = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version([5, 0, 21]),
+            features: vec![PeerFeature::new(3, vec![2]), PeerFeature::new(16, vec![])],
+            ..Default::default()
+        };
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                FieldDiff::Version {
+                    old: Version([5, 0, 20]),
+                    new: Version([5, 0, 21]),
+                },
+                FieldDiff::FeatureRemoved(PeerFeature::new(2, vec![1])),
+                FieldDiff::FeatureChanged {
+                    old: PeerFeature::new(3, vec![1]),
+                    new: PeerFeature::new(3, vec![2]),
+                },
+                FieldDiff::FeatureAdded(PeerFeature::new(16, vec![])),
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! This module implements resolving the targets with
+//! [hickory](https://docs.rs/hickory-resolver), a resolver written in
+//! Rust, with the `hickory-dns` feature.
+//!
+//! The default resolution goes through the `getaddrinfo` of the C
+//! library, which statically linked binaries, ex. built for musl to run
+//! on routers, can't always rely on. [`DnsResolver`] instead queries the
+//! name servers itself.
+//!
+
+use std::io;
+use std::net::SocketAddr;
+
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::error::{ProtocolError, ProtocolResult};
+
+/// Resolves the host name of the targets.
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsResolver {
+    /// A resolver querying the name servers of the system, read from
+    /// `/etc/resolv.conf` on unix.
+    pub fn from_system_conf() -> ProtocolResult<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(io::Error::other)?;
+        Ok(Self { resolver })
+    }
+
+    /// A resolver querying the public name servers of Cloudflare, for
+    /// hosts without a resolver configuration.
+    pub fn cloudflare() -> Self {
+        let resolver =
+            TokioAsyncResolver::tokio(ResolverConfig::cloudflare(), ResolverOpts::default());
+        Self { resolver }
+    }
+
+    /// The system resolver if configured, else [`DnsResolver::cloudflare`].
+    pub fn system_or_cloudflare() -> Self {
+        Self::from_system_conf().unwrap_or_else(|_| Self::cloudflare())
+    }
+
+    /// Resolves a `host:port` target, IP addresses are returned without
+    /// querying the name servers.
+    pub async fn lookup(&self, target: &str) -> ProtocolResult<Vec<SocketAddr>> {
+        if let Ok(address) = target.parse::<SocketAddr>() {
+            return Ok(vec![address]);
+        }
+        let (host, port) = split_host_port(target)?;
+        let ips = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
+
+fn split_host_port(target: &str) -> ProtocolResult<(&str, u16)> {
+    let invalid = || ProtocolError::InvalidTarget(target.to_string());
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lookup() -> ProtocolResult<()> {
+        let resolver = DnsResolver::cloudflare();
+        let addresses = resolver.lookup("127.0.0.1:9030").await?;
+        assert_eq!(addresses, ["127.0.0.1:9030".parse().unwrap()]);
+        let addresses = resolver.lookup("[::1]:9030").await?;
+        assert_eq!(addresses, ["[::1]:9030".parse().unwrap()]);
+
+        assert!(matches!(
+            resolver.lookup("node").await,
+            Err(ProtocolError::InvalidTarget(_))
+        ));
+        assert!(matches!(
+            resolver.lookup(":9030").await,
+            Err(ProtocolError::InvalidTarget(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! This module implements the durations written with their unit, ex.
+//! `250ms`, `30s`, `2m` or `1h30m`, shared by the configuration files and
+//! the command line options so that no option leaves its unit implied.
+//!
+//! ```
+//! use std::time::Duration;
+//! use p2p_handshake::HumanDuration;
+//!
+//! let timeout: HumanDuration = "1m30s".parse()?;
+//! assert_eq!(Duration::from(timeout), Duration::from_secs(90));
+//! assert_eq!(timeout.to_string(), "1m30s");
+//! # Ok::<(), String>(())
+//! ```
+//!
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The units of a duration, from the largest, with their length in
+/// milliseconds.
+const UNITS: [(&str, u64); 5] = [
+    ("d", 24 * 60 * 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("m", 60 * 1000),
+    ("s", 1000),
+    ("ms", 1),
+];
+
+/// A duration with millisecond precision, parsed from and displayed as
+/// numbers followed by their unit: `d`, `h`, `m`, `s` or `ms`.
+///
+/// A number without unit is rejected rather than guessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct HumanDuration(Duration);
+
+impl HumanDuration {
+    pub const fn from_millis(millis: u64) -> Self {
+        Self(Duration::from_millis(millis))
+    }
+
+    pub const fn from_secs(secs: u64) -> Self {
+        Self(Duration::from_secs(secs))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl From<Duration> for HumanDuration {
+    fn from(duration: Duration) -> Self {
+        Self(duration)
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(duration: HumanDuration) -> Self {
+        duration.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid duration `{value}`, expected ex. `250ms`, `30s` or `2m`");
+        let mut rest = value.trim();
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+        let mut millis: u64 = 0;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            rest = &rest[digits..];
+            let unit_len = rest
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let (_, unit) = UNITS
+                .iter()
+                .find(|(unit, _)| *unit == rest[..unit_len].trim())
+                .ok_or_else(invalid)?;
+            millis = amount
+                .checked_mul(*unit)
+                .and_then(|amount| millis.checked_add(amount))
+                .ok_or_else(invalid)?;
+            rest = rest[unit_len..].trim_start();
+        }
+        Ok(Self::from_millis(millis))
+    }
+}
+
+/// Displays the duration in its largest units, ex. `90s` as `1m30s`.
+/// Precision below the millisecond is dropped.
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut millis = self.0.as_millis();
+        if millis == 0 {
+            return f.write_str("0s");
+        }
+        for (unit, length) in UNITS {
+            let amount = millis / u128::from(length);
+            if amount > 0 {
+                write!(f, "{amount}{unit}")?;
+                millis %= u128::from(length);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for HumanDuration {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Durations are (de)serialized with their unit, ex. `"30s"`.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for HumanDuration {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        HumanDuration::from_str(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Duration {
+        value.parse::<HumanDuration>().unwrap().into()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("250ms"), Duration::from_millis(250));
+        assert_eq!(parse("30s"), Duration::from_secs(30));
+        assert_eq!(parse("2m"), Duration::from_secs(120));
+        assert_eq!(parse("1h 30m"), Duration::from_secs(90 * 60));
+        assert_eq!(parse("7d"), Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!(parse("0s"), Duration::ZERO);
+
+        for invalid in [
+            "",
+            "30",
+            "s",
+            "30 sec",
+            "-1s",
+            "1.5s",
+            "99999999999999999999d",
+        ] {
+            assert!(invalid.parse::<HumanDuration>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        for value in ["250ms", "30s", "2m", "1h30m", "1d1ms", "0s"] {
+            assert_eq!(value.parse::<HumanDuration>().unwrap().to_string(), value);
+        }
+        let duration = HumanDuration::from(Duration::from_micros(1500));
+        assert_eq!(duration.to_string(), "1ms");
+    }
+}
@@ -6,143 +6,463 @@
 //! Please note that this implementation is kept as minimal as possible
 //! only including necessary parameters for performing a node handshake.
 //!
+//! The message types and their encoding live in the [`core`](crate::core)
+//! module, which doesn't need the standard library. This module adds the
+//! readers, writers and streams on top of it.
+//!
 
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
-use std::ops::Deref;
+use std::net::SocketAddr;
+#[cfg(feature = "serde")]
 use std::str::FromStr;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
+use crate::clock::{Clock, SystemClock};
+use crate::core::{ByteSink, ByteSource, CodecError};
+use crate::error::DecodeError;
 use crate::error::ProtocolError;
 use crate::error::ProtocolResult;
+use crate::feature::PeerFeature;
+use crate::peers::PeerSpec;
+use crate::spec::{self, DecodeLimits};
 
-use byteorder::ReadBytesExt;
+use tokio::io::AsyncRead;
 
-#[derive(Debug, PartialEq, Eq, Default, Clone)]
-pub struct Version(pub [u8; 3]);
+pub(crate) use crate::core::HandshakeFields;
+pub use crate::core::{Capability, HandshakeMessage, TinyString, Version};
 
-impl ToString for Version {
-    fn to_string(&self) -> String {
-        format!("{}.{}.{}", self.0[0], self.0[1], self.0[2])
+#[cfg(feature = "serde")]
+impl serde::Serialize for Version {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
     }
 }
 
-impl FromStr for Version {
-    type Err = String;
+/// Versions are (de)serialized in their dotted form, ex. `"5.0.21"`.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Version {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Version::from_str(&value).map_err(serde::de::Error::custom)
+    }
+}
 
-    fn from_str(value: &str) -> Result<Self, Self::Err> {
-        let parts = value.split(".");
-        let mut index = 0;
-        let mut raw_version = [0u8; 3];
-        for s in parts {
-            if index >= 3 {
-                return Err("Malformed version specs.".to_string());
-            }
-            let version_part = s
-                .parse::<u8>()
-                .map_err(|_| format!("Error parsing version component: `{}`.", s))?;
-            raw_version[index] = version_part;
-            index += 1;
+/// Arbitrary strings are truncated to the 255 bytes a tiny string holds.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for TinyString {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let mut value = String::arbitrary(u)?;
+        let mut len = value.len().min(spec::MAX_SHORT_STRING_LEN);
+        while !value.is_char_boundary(len) {
+            len -= 1;
         }
+        value.truncate(len);
+        Self::new(value).map_err(|_| arbitrary::Error::IncorrectFormat)
+    }
+}
 
-        if index != 3 {
-            return Err("Malformed version specs.".to_string());
-        }
+#[cfg(feature = "serde")]
+impl serde::Serialize for TinyString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self)
+    }
+}
 
-        Ok(Version(raw_version))
+/// Deserialization enforces the same length limit as [`TryFrom<&str>`].
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TinyString {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        TinyString::try_from(value.as_str()).map_err(serde::de::Error::custom)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Default)]
-pub struct TinyString(pub String);
+impl HandshakeMessage {
+    pub fn encode_for_request(&self) -> ProtocolResult<Vec<u8>> {
+        self.encode_with_timestamp(get_current_unix_timestamp()?)
+    }
+
+    /// Same as [`HandshakeMessage::encode_for_request`] but writes the
+    /// message to `writer`, ex. a reused buffer or a `BytesMut` through
+    /// `BufMut::writer`, instead of allocating a new one.
+    pub fn encode_into<W: Write>(&self, writer: &mut W) -> ProtocolResult<()> {
+        self.write_with_timestamp(writer, get_current_unix_timestamp()?)
+    }
+
+    /// Same as [`HandshakeMessage::encode_for_request`] but writes the
+    /// message at the start of `buf`, without allocating, and returns the
+    /// number of bytes written, see [`HandshakeMessage::encoded_len`].
+    pub fn encode_to_slice(&self, buf: &mut [u8]) -> ProtocolResult<usize> {
+        let timestamp = get_current_unix_timestamp()?;
+        Ok(crate::core::encode_to_slice(self, timestamp, buf)?)
+    }
 
-impl TryFrom<&str> for TinyString {
-    type Error = String;
+    /// Encodes the message with the given timestamp instead of the current
+    /// time, the same message always encoding to the same bytes, ex. to
+    /// compare them with test vectors.
+    pub fn encode_with_timestamp(&self, timestamp: u64) -> ProtocolResult<Vec<u8>> {
+        Ok(crate::core::encode(self, timestamp)?)
+    }
+
+    pub(crate) fn write_with_timestamp<W: Write>(
+        &self,
+        writer: &mut W,
+        timestamp: u64,
+    ) -> ProtocolResult<()> {
+        crate::core::encode_into(self, timestamp, &mut IoSink(writer))
+    }
+
+    /// Decodes a message in the [`Lenient`](DecodeMode::Lenient) mode.
+    pub fn decode_from_response(data: Vec<u8>) -> ProtocolResult<Self> {
+        Self::decode_from_reader(&mut Cursor::new(data))
+    }
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if value.len() > 255 {
-            return Err("TinyString cannot hold more than 255 bytes.".to_string());
+    /// Same as [`HandshakeMessage::decode_from_response`] but enforces the
+    /// given limits instead of the documented ones.
+    pub fn decode_with_limits(data: &[u8], limits: &DecodeLimits) -> ProtocolResult<Self> {
+        let (message, _) = crate::core::decode(data, limits)?;
+        Ok(message)
+    }
+
+    /// Same as [`HandshakeMessage::decode_from_response`] with the given
+    /// mode, [`DecodeMode::Strict`] rejects any departure from the layout
+    /// of the reference node, walking the message with [`spec::layout`].
+    pub fn decode_with_mode(data: &[u8], mode: DecodeMode) -> ProtocolResult<Self> {
+        let mut remaining = data;
+        let message = Self::decode_from_reader(&mut remaining)?;
+        if mode == DecodeMode::Strict {
+            if !remaining.is_empty() {
+                return Err(ProtocolError::TrailingBytes(remaining.len()));
+            }
+            spec::layout(data)?;
+            for feature in &message.features {
+                feature.check_payload()?;
+            }
         }
-        Ok(Self(value.to_string()))
+        Ok(message)
+    }
+
+    /// Decodes the message at the start of untrusted bytes, ex. traffic
+    /// captured off the internet, the bytes following it are ignored.
+    ///
+    /// It never panics, whatever the input. The lengths read from the
+    /// input are checked before anything is allocated and the buffers only
+    /// grow with the bytes actually present, so a few bytes claiming a
+    /// large field allocate at most a few kilobytes. The `decode_handshake`
+    /// fuzz target checks both guarantees.
+    pub fn try_decode_any(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut remaining = data;
+        crate::core::decode_from(&mut remaining, &DecodeLimits::default()).map_err(|source| {
+            DecodeError {
+                offset: data.len() - remaining.len(),
+                source: source.into(),
+            }
+        })
+    }
+
+    /// Decodes a message from the reader, consuming exactly the bytes of the message.
+    pub(crate) fn decode_from_reader<R: Read>(reader: &mut R) -> ProtocolResult<Self> {
+        Self::decode_from_reader_with(reader, &DecodeLimits::default())
+    }
+
+    /// Same as [`HandshakeMessage::decode_from_reader`] with the given limits.
+    pub(crate) fn decode_from_reader_with<R: Read>(
+        reader: &mut R,
+        limits: &DecodeLimits,
+    ) -> ProtocolResult<Self> {
+        crate::core::decode_from(&mut IoSource(reader), limits)
     }
 }
 
-impl ToString for TinyString {
-    fn to_string(&self) -> String {
-        self.0.clone()
+impl HandshakeMessage {
+    /// Checks that the message timestamp is within `max_skew` of the
+    /// local clock, rejecting peers whose clocks are out of sync.
+    pub fn validate(&self, max_skew: Duration) -> ProtocolResult<()> {
+        self.validate_with_clock(&SystemClock, max_skew)
+    }
+
+    /// Same as [`HandshakeMessage::validate`] but compares the timestamp
+    /// with the given clock.
+    pub fn validate_with_clock(&self, clock: &dyn Clock, max_skew: Duration) -> ProtocolResult<()> {
+        let now = clock.now_millis()?;
+        let skew = Duration::from_millis(now.abs_diff(self.timestamp));
+        if skew > max_skew {
+            return Err(ProtocolError::ClockSkew(skew));
+        }
+        Ok(())
     }
 }
 
-impl Deref for TinyString {
-    type Target = String;
+/// Writes the peer spec fields shared by the handshake and the peers messages.
+/// The caller is responsible for checking the fields against the spec limits.
+pub(crate) fn write_peer_spec<W: Write>(
+    writer: &mut W,
+    agent_name: &str,
+    version: &Version,
+    peer_name: &str,
+    declared_address: Option<&SocketAddr>,
+    features: &[PeerFeature],
+) -> ProtocolResult<()> {
+    crate::core::write_peer_spec(
+        &mut IoSink(writer),
+        agent_name,
+        version,
+        peer_name,
+        declared_address,
+        features,
+    )
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+/// Reads the peer spec fields shared by the handshake and the peers messages.
+pub(crate) fn read_peer_spec<R: Read>(reader: &mut R) -> ProtocolResult<PeerSpec> {
+    let fields = crate::core::read_peer_fields(&mut IoSource(reader), &DecodeLimits::default())?;
+    Ok(PeerSpec {
+        agent_name: fields.agent_name,
+        version: fields.version,
+        peer_name: fields.peer_name,
+        declared_address: fields.declared_address,
+        features: fields.features,
+    })
+}
+
+/// How strictly a message is decoded, see
+/// [`HandshakeMessage::decode_with_mode`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// Fails on bytes following the message and on known features whose
+    /// payload doesn't follow their layout, ex. to check the conformance
+    /// of a node implementation.
+    Strict,
+    /// Ignores the bytes following the message and keeps the feature
+    /// payloads as is, as the reference node does.
+    #[default]
+    Lenient,
+}
+
+/// A decoded handshake along with the exact bytes the peer sent, ex. to
+/// compute a digest of the message.
+#[derive(Debug, Clone)]
+pub struct HandshakeResponse {
+    pub message: HandshakeMessage,
+    pub raw: Vec<u8>,
+}
+
+/// Reads a complete handshake message from the stream.
+///
+/// The stream is consumed field by field, so no byte past the end of the
+/// handshake message is read, leaving subsequent messages on the stream
+/// untouched. An error is returned as soon as the message grows beyond
+/// `max_size` bytes.
+pub async fn read_handshake<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> ProtocolResult<HandshakeMessage> {
+    Ok(read_handshake_raw(reader, max_size).await?.message)
+}
+
+/// Same as [`read_handshake`] but enforces the given limits, ex. shorter
+/// agent and peer names.
+pub async fn read_handshake_with_limits<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> ProtocolResult<HandshakeMessage> {
+    Ok(read_handshake_raw_with_limits(reader, limits)
+        .await?
+        .message)
+}
+
+/// Same as [`read_handshake`] but also returns the bytes of the message.
+pub async fn read_handshake_raw<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> ProtocolResult<HandshakeResponse> {
+    read_handshake_raw_with_limits(reader, &DecodeLimits::with_max_message(max_size)).await
+}
+
+/// Same as [`read_handshake_raw`] but enforces the given limits.
+pub async fn read_handshake_raw_with_limits<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> ProtocolResult<HandshakeResponse> {
+    let limit = limits.message_limit();
+    let mut data = Vec::new();
+    loop {
+        // The decoder tells how many bytes the field it stopped at lacks,
+        // reading no more never reads past the end of the message.
+        let (expected, got) = match crate::core::decode(&data, limits) {
+            Ok((message, _)) => return Ok(HandshakeResponse { message, raw: data }),
+            Err(CodecError::Truncated { expected, got }) => (expected, got),
+            Err(err) => return Err(err.into()),
+        };
+        let start = data.len();
+        let needed = start - got + expected;
+        if needed > limit {
+            return Err(ProtocolError::MessageTooLarge(limit));
+        }
+        data.resize(needed, 0);
+        let read = tokio::io::AsyncReadExt::read(reader, &mut data[start..]).await?;
+        data.truncate(start + read);
+        if read == 0 {
+            // Nothing was received at all, the peer gave up on us.
+            if data.is_empty() {
+                return Err(ProtocolError::HandshakeTimedOutByPeer);
+            }
+            return Err(ProtocolError::TruncatedMessage { expected, got });
+        }
     }
 }
 
-#[derive(Debug, Default)]
-pub struct HandshakeMessage {
-    pub agent_name: TinyString,
-    pub version: Version,
-    pub peer_name: TinyString,
+/// Returns the current unix timestamp in milliseconds, failing instead of
+/// panicking when the system clock is set before the unix epoch or isn't
+/// available at all.
+pub(crate) fn get_current_unix_timestamp() -> ProtocolResult<u64> {
+    // Reading the time panics on this target.
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        return Err(ProtocolError::ClockUnavailable);
+    }
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| ProtocolError::InvalidSystemTime)?;
+    Ok(elapsed.as_millis() as u64)
 }
 
-impl HandshakeMessage {
-    pub fn encode_for_request(&self) -> ProtocolResult<Vec<u8>> {
-        let mut buf = std::io::Cursor::new(vec![]);
+/// Maximum number of bytes allocated ahead of reading a field.
+const MAX_PREALLOCATION: usize = 4096;
+
+/// Reads the message fields from a reader, reporting its failures as
+/// [`ProtocolError::Io`].
+struct IoSource<'a, R: ?Sized>(&'a mut R);
+
+impl<R: Read + ?Sized> ByteSource for IoSource<'_, R> {
+    type Error = ProtocolError;
 
-        // The timestamp is encoded in Little Endian Base 128 also referred
-        // VLQ (variable length quantity)
-        leb128::write::unsigned(&mut buf, get_current_unix_timestamp())?;
-        buf.write_all(&vec![self.agent_name.len() as u8])?;
-        buf.write_all(self.agent_name.as_bytes())?;
-        buf.write_all(&self.version.0)?;
-        buf.write_all(&vec![self.peer_name.len() as u8])?;
-        buf.write_all(self.peer_name.as_bytes())?;
-        // We put `0`` to ignore peer_address parameter
-        buf.write_all(&vec![0])?;
-        Ok(buf.into_inner())
+    fn read_bytes(&mut self, len: usize) -> ProtocolResult<Vec<u8>> {
+        // The length comes from the peer, the buffer only grows with the
+        // bytes actually received.
+        let mut data = Vec::with_capacity(len.min(MAX_PREALLOCATION));
+        (&mut *self.0).take(len as u64).read_to_end(&mut data)?;
+        if data.len() < len {
+            return Err(ProtocolError::TruncatedMessage {
+                expected: len,
+                got: data.len(),
+            });
+        }
+        Ok(data)
     }
+}
 
-    pub fn decode_from_response(data: Vec<u8>) -> ProtocolResult<Self> {
-        let mut cursor = Cursor::new(data);
-        let _timestamp = leb128::read::unsigned(&mut cursor).map_err(ProtocolError::LEB128Error)?;
-        let agent_name = read_string(&mut cursor)?;
-        let mut raw_version = [0u8; 3];
-        cursor.read_exact(&mut raw_version)?;
-        let peer_name = read_string(&mut cursor)?;
-
-        Ok(HandshakeMessage {
-            agent_name,
-            version: Version(raw_version),
-            peer_name,
-        })
+/// Writes the message fields to a writer.
+struct IoSink<'a, W: ?Sized>(&'a mut W);
+
+impl<W: Write + ?Sized> ByteSink for IoSink<'_, W> {
+    type Error = ProtocolError;
+
+    fn write_bytes(&mut self, data: &[u8]) -> ProtocolResult<()> {
+        Ok(self.0.write_all(data)?)
     }
 }
 
-fn get_current_unix_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("expected a valid unix epoch timestamp")
-        .as_millis() as u64
+/// Writes the optional declared address: a presence flag, then the
+/// size of the ip bytes plus 4, the ip bytes and the VLQ encoded port.
+pub(crate) fn write_declared_address<W: Write>(
+    writer: &mut W,
+    address: Option<&SocketAddr>,
+) -> ProtocolResult<()> {
+    crate::core::write_declared_address(&mut IoSink(writer), address)
+}
+
+pub(crate) fn read_declared_address<R: Read>(reader: &mut R) -> ProtocolResult<Option<SocketAddr>> {
+    crate::core::read_declared_address(&mut IoSource(reader))
+}
+
+/// Reads exactly `len` bytes, reporting a truncated message when the
+/// reader ends early.
+pub(crate) fn read_bytes<R: Read>(reader: &mut R, len: usize) -> ProtocolResult<Vec<u8>> {
+    IoSource(reader).read_bytes(len)
+}
+
+/// Whether decoding failed only because more bytes are needed.
+pub(crate) fn is_truncated(err: &ProtocolError) -> bool {
+    matches!(err, ProtocolError::TruncatedMessage { .. })
 }
 
-fn read_string<R: Read>(reader: &mut R) -> ProtocolResult<TinyString> {
-    let len: u8 = reader.read_u8()?;
-    let mut buf = vec![0; len as usize];
-    reader.read_exact(&mut buf)?;
-    String::from_utf8(buf)
-        .map_err(ProtocolError::Utf8Error)
-        .map(|s| TinyString::try_from(s.as_str()))?
-        .map_err(ProtocolError::Unknown)
+pub(crate) fn read_byte<R: Read>(reader: &mut R) -> ProtocolResult<u8> {
+    IoSource(reader).read_byte()
 }
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use super::*;
+    use crate::clock::FixedClock;
+    use crate::core::vlq_len;
+    use crate::rng::SplitMix64;
+
+    #[test]
+    fn test_decode_mode() -> ProtocolResult<()> {
+        let message = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            features: vec![
+                PeerFeature::new(crate::feature::MODE_FEATURE_ID, vec![0, 1, 1, 4, 1]),
+                PeerFeature::new(200, vec![0xff]),
+            ],
+            ..Default::default()
+        };
+        let mut encoded = message.encode_with_timestamp(42)?;
+        let strict = HandshakeMessage::decode_with_mode(&encoded, DecodeMode::Strict)?;
+        assert_eq!(strict.features, message.features);
+
+        encoded.extend_from_slice(&[1, 2]);
+        assert!(HandshakeMessage::decode_with_mode(&encoded, DecodeMode::Lenient).is_ok());
+        assert!(matches!(
+            HandshakeMessage::decode_with_mode(&encoded, DecodeMode::Strict),
+            Err(ProtocolError::TrailingBytes(2))
+        ));
+
+        let malformed = HandshakeMessage {
+            features: vec![PeerFeature::new(
+                crate::feature::SESSION_FEATURE_ID,
+                vec![2, 0, 2, 3, 1, 9],
+            )],
+            ..Default::default()
+        };
+        let encoded = malformed.encode_with_timestamp(42)?;
+        assert!(HandshakeMessage::decode_with_mode(&encoded, DecodeMode::Lenient).is_ok());
+        assert!(matches!(
+            HandshakeMessage::decode_with_mode(&encoded, DecodeMode::Strict),
+            Err(ProtocolError::SpecViolation(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_redacted_debug() {
+        let message = HandshakeMessage {
+            agent_name: TinyString::new("ergo\x1b[2Jref\u{202e}").unwrap(),
+            peer_name: TinyString::new("a".repeat(200)).unwrap(),
+            features: vec![PeerFeature::new(16, vec![7; 300])],
+            ..Default::default()
+        };
+        let debug = format!("{message:?}");
+        assert!(debug.contains(r#"agent_name: "ergo\u{1b}[2Jref\u{202e}""#));
+        assert!(debug.contains(&format!(
+            r#"peer_name: "{}"... (200 bytes)"#,
+            "a".repeat(64)
+        )));
+        assert!(debug
+            .contains("payload: [7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7]... (300 bytes)"));
+        assert!(!debug.contains('\x1b'));
+
+        assert_eq!(
+            format!("{:?}", TinyString::new("node\n").unwrap()),
+            r#"TinyString("node\n")"#
+        );
+    }
 
     #[test]
     fn test_version_parsing() {
@@ -161,6 +481,18 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_version_ordering() {
+        assert!(Version([5, 0, 0]) > Version([4, 0, 100]));
+        assert!(Version([4, 1, 0]) > Version([4, 0, 100]));
+        assert!(Version([4, 0, 15]) < Version([4, 0, 16]));
+
+        assert!(Version([4, 0, 16]).supports(Capability::SyncV2));
+        assert!(!Version([4, 0, 15]).supports(Capability::SyncV2));
+        assert!(Version([5, 0, 21]).supports(Capability::Nipopow));
+        assert!(!Version([5, 0, 12]).supports(Capability::Nipopow));
+    }
+
     #[test]
     fn test_tiny_string() {
         assert_eq!(TinyString::try_from("value").unwrap().to_string(), "value");
@@ -170,23 +502,355 @@ mod tests {
             TinyString::try_from(large_text.as_str()).unwrap_err(),
             "TinyString cannot hold more than 255 bytes."
         );
+        assert!(TinyString::try_from(large_text.clone()).is_err());
+        assert!(TinyString::new("x".repeat(255)).is_ok());
+        assert_eq!(
+            TinyString::new(large_text).unwrap_err(),
+            "TinyString cannot hold more than 255 bytes."
+        );
+        assert_eq!(
+            TinyString::try_from("value".to_string())
+                .unwrap()
+                .into_inner(),
+            "value"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let message = HandshakeMessage {
+            timestamp: 1,
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version([5, 0, 21]),
+            declared_address: Some("1.2.3.4:9030".parse().unwrap()),
+            features: vec![PeerFeature::new(16, vec![0, 1])],
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["agent_name"], "ergoref");
+        assert_eq!(json["version"], "5.0.21");
+        assert_eq!(json["declared_address"], "1.2.3.4:9030");
+
+        let decoded: HandshakeMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.diff(&message), vec![]);
+        assert_eq!(decoded.timestamp, 1);
+
+        let spec: PeerSpec = serde_json::from_str(
+            r#"{"agent_name": "ergoref", "version": "5.0.21", "peer_name": "node",
+                "declared_address": null, "features": []}"#,
+        )
+        .unwrap();
+        assert_eq!(spec.version, Version([5, 0, 21]));
+
+        assert!(serde_json::from_str::<Version>(r#""5.0""#).is_err());
+        let long_name = format!(r#""{}""#, "x".repeat(256));
+        assert!(serde_json::from_str::<TinyString>(&long_name).is_err());
+    }
+
+    #[test]
+    fn test_decoding_errors() {
+        // Agent name announced as 3 bytes long, only 2 follow.
+        let error = HandshakeMessage::decode_from_response(vec![1, 3, b'a', b'b']).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::TruncatedMessage {
+                expected: 3,
+                got: 2
+            }
+        ));
+
+        let error =
+            HandshakeMessage::decode_from_response(vec![1, 0, 1, 2, 3, 0, 1, 9]).unwrap_err();
+        assert!(matches!(error, ProtocolError::InvalidAddressSize(9)));
     }
 
     #[test]
     fn test_encoding_decoding() -> ProtocolResult<()> {
         let handshake = HandshakeMessage {
-            agent_name: TinyString("paul".to_string()),
+            agent_name: TinyString::new("paul").unwrap(),
             version: Version::from_str("3.2.1").expect("should extract version"),
-            peer_name: TinyString("paul-node".to_string()),
+            peer_name: TinyString::new("paul-node").unwrap(),
+            declared_address: Some("[2001:db8::1]:9030".parse().unwrap()),
+            features: vec![PeerFeature::new(2, vec![1, 2, 3])],
+            ..Default::default()
         };
 
         let encoded_data = handshake.encode_for_request()?;
         let message = HandshakeMessage::decode_from_response(encoded_data)?;
 
-        assert_eq!(message.agent_name, TinyString("paul".to_string()));
+        assert_eq!(message.agent_name, TinyString::new("paul").unwrap());
         assert_eq!(message.version.to_string(), "3.2.1".to_string());
-        assert_eq!(message.peer_name, TinyString("paul-node".to_string()));
+        assert_eq!(message.peer_name, TinyString::new("paul-node").unwrap());
+        assert_eq!(message.declared_address, handshake.declared_address);
+        assert_eq!(message.features, handshake.features);
+        assert!(message.timestamp > 0);
+
+        let mut buf = vec![0xff];
+        handshake.encode_into(&mut buf)?;
+        let message = HandshakeMessage::decode_from_response(buf[1..].to_vec())?;
+        assert_eq!(message.agent_name, handshake.agent_name);
 
         Ok(())
     }
+
+    #[test]
+    fn test_validate_clock_skew() {
+        let message = HandshakeMessage {
+            timestamp: 10_000,
+            ..Default::default()
+        };
+        let max_skew = Duration::from_secs(5);
+        assert!(message
+            .validate_with_clock(&FixedClock(14_000), max_skew)
+            .is_ok());
+        assert!(message
+            .validate_with_clock(&FixedClock(6_000), max_skew)
+            .is_ok());
+        assert!(matches!(
+            message.validate_with_clock(&FixedClock(16_000), max_skew),
+            Err(ProtocolError::ClockSkew(skew)) if skew == Duration::from_secs(6)
+        ));
+        assert!(message
+            .validate_with_clock(&FixedClock(0), max_skew)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_handshake() -> ProtocolResult<()> {
+        let handshake = HandshakeMessage {
+            agent_name: TinyString::new("paul").unwrap(),
+            declared_address: Some("1.2.3.4:9030".parse().unwrap()),
+            features: vec![PeerFeature::new(16, vec![0; 300])],
+            ..Default::default()
+        };
+        let mut encoded_data = handshake.encode_for_request()?;
+        let message_len = encoded_data.len();
+        encoded_data.extend_from_slice(b"next message");
+
+        // Deliver the data in small chunks to exercise incremental reads.
+        let (mut client, mut server) = tokio::io::duplex(7);
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            client.write_all(&encoded_data).await.unwrap();
+        });
+
+        let message = read_handshake(&mut server, spec::MAX_HANDSHAKE_SIZE).await?;
+        assert_eq!(message.agent_name, handshake.agent_name);
+        assert_eq!(message.features, handshake.features);
+
+        // Bytes following the handshake are left on the stream.
+        let mut rest = vec![0; 12];
+        tokio::io::AsyncReadExt::read_exact(&mut server, &mut rest).await?;
+        assert_eq!(rest, b"next message");
+
+        let encoded_data = handshake.encode_for_request()?;
+        let error = read_handshake(&mut encoded_data.as_slice(), message_len - 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ProtocolError::MessageTooLarge(_)));
+
+        let error = read_handshake(&mut &encoded_data[..message_len / 2], message_len)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ProtocolError::TruncatedMessage { .. }));
+
+        let error = read_handshake(&mut [].as_slice(), message_len)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ProtocolError::HandshakeTimedOutByPeer));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoded_len() -> ProtocolResult<()> {
+        for (value, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16_383, 2),
+            (16_384, 3),
+            (u64::MAX, 10),
+        ] {
+            assert_eq!(vlq_len(value), len);
+            let mut data = vec![];
+            leb128::write::unsigned(&mut data, value)?;
+            assert_eq!(data.len(), len);
+        }
+
+        let messages = [
+            HandshakeMessage::default(),
+            HandshakeMessage {
+                timestamp: 1_700_000_000_000,
+                agent_name: TinyString::new("ergoref").unwrap(),
+                peer_name: TinyString::new("x".repeat(255)).unwrap(),
+                declared_address: Some("[2001:db8::1]:9030".parse().unwrap()),
+                features: vec![
+                    PeerFeature::new(16, vec![0; 300]),
+                    PeerFeature::new(3, vec![]),
+                ],
+                ..Default::default()
+            },
+            HandshakeMessage {
+                declared_address: Some("1.2.3.4:80".parse().unwrap()),
+                ..Default::default()
+            },
+        ];
+        for message in messages {
+            let data = message.encode_with_timestamp(message.timestamp)?;
+            assert_eq!(message.encoded_len(), data.len());
+        }
+
+        // The builder writes its own fields, as the message it builds.
+        let builder = crate::HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .declared_address("1.2.3.4:9030".parse().unwrap())
+            .feature(PeerFeature::new(16, vec![1, 2, 3]))
+            .timestamp(42);
+        let message = builder.build()?;
+        assert_eq!(builder.encode()?, message.encode_with_timestamp(42)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decode_limits() -> ProtocolResult<()> {
+        let data = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            peer_name: TinyString::new("a-rather-long-peer-name").unwrap(),
+            ..Default::default()
+        }
+        .encode_for_request()?;
+        let limits = DecodeLimits {
+            max_string: 8,
+            ..Default::default()
+        };
+        let error = read_handshake_with_limits(&mut data.as_slice(), &limits)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::StringTooLong {
+                field: spec::Field::PeerName,
+                len: 23,
+                max: 8
+            }
+        ));
+        let error = HandshakeMessage::decode_with_limits(&data, &limits).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::StringTooLong { len: 23, .. }
+        ));
+
+        let limits = DecodeLimits::with_max_message(data.len() - 1);
+        let error = HandshakeMessage::decode_with_limits(&data, &limits).unwrap_err();
+        assert!(matches!(error, ProtocolError::MessageTooLarge(_)));
+
+        let limits = DecodeLimits::with_max_message(data.len());
+        let message = HandshakeMessage::decode_with_limits(&data, &limits)?;
+        assert_eq!(message.peer_name.as_str(), "a-rather-long-peer-name");
+
+        let data = HandshakeMessage {
+            features: vec![PeerFeature::new(16, vec![0, 1, 0, 1]); 3],
+            ..Default::default()
+        }
+        .encode_for_request()?;
+        let limits = DecodeLimits {
+            max_features: 2,
+            ..Default::default()
+        };
+        let error = HandshakeMessage::decode_with_limits(&data, &limits).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::FeatureCountExceeded { count: 3, max: 2 }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decoding_random_bytes() {
+        let valid = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            declared_address: Some("1.2.3.4:9030".parse().unwrap()),
+            features: vec![PeerFeature::new(16, vec![0, 1, 0, 1])],
+            ..Default::default()
+        }
+        .encode_with_timestamp(42)
+        .unwrap();
+        let mut rng = SplitMix64::new(0);
+        for _ in 0..10_000 {
+            // Random bytes, and valid messages with random mutations.
+            let mut data = valid.clone();
+            for _ in 0..rng.below(4) {
+                let index = rng.below(data.len() as u64) as usize;
+                data[index] = rng.next_u64() as u8;
+            }
+            if rng.below(2) == 0 {
+                data = (0..rng.below(64)).map(|_| rng.next_u64() as u8).collect();
+            }
+            let _ = HandshakeMessage::decode_from_response(data.clone());
+            let _ = crate::borrowed::HandshakeMessageRef::decode(&data);
+            if let Err(err) = HandshakeMessage::try_decode_any(&data) {
+                assert!(err.offset <= data.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_decode_any() -> ProtocolResult<()> {
+        let message = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            features: vec![PeerFeature::new(16, vec![0, 1, 0, 1])],
+            ..Default::default()
+        };
+        let mut encoded = message.encode_with_timestamp(42)?;
+        for len in 0..encoded.len() {
+            let err = HandshakeMessage::try_decode_any(&encoded[..len]).unwrap_err();
+            assert!(err.offset <= len);
+            assert!(matches!(err.source, ProtocolError::TruncatedMessage { .. }));
+        }
+        encoded.push(0xff);
+        let decoded = HandshakeMessage::try_decode_any(&encoded)?;
+        assert_eq!(decoded.agent_name.as_str(), "ergoref");
+
+        // A feature claiming 65535 bytes of payload with only one present.
+        let mut claimed = message.encode_with_timestamp(42)?;
+        claimed.truncate(claimed.len() - 5);
+        claimed.extend_from_slice(&[0xff, 0xff, 0x03, 0x00]);
+        let err = HandshakeMessage::try_decode_any(&claimed).unwrap_err();
+        assert_eq!(err.offset, claimed.len());
+        assert!(matches!(
+            err.source,
+            ProtocolError::TruncatedMessage {
+                expected: 65535,
+                got: 1
+            }
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary_round_trip() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let mut rng = SplitMix64::new(0);
+        let mut round_trips = 0;
+        for _ in 0..1_000 {
+            let bytes: Vec<u8> = (0..512).map(|_| rng.next_u64() as u8).collect();
+            let Ok(message) = HandshakeMessage::arbitrary(&mut Unstructured::new(&bytes)) else {
+                continue;
+            };
+            // Messages breaking the spec limits fail to encode.
+            let Ok(encoded) = message.encode_with_timestamp(message.timestamp) else {
+                continue;
+            };
+            let decoded = HandshakeMessage::decode_from_response(encoded.clone()).unwrap();
+            assert_eq!(
+                decoded.encode_with_timestamp(decoded.timestamp).unwrap(),
+                encoded
+            );
+            round_trips += 1;
+        }
+        assert!(round_trips > 100);
+    }
 }
@@ -2,16 +2,235 @@ use std::{io, string::FromUtf8Error};
 
 use thiserror::Error;
 
+use crate::core::CodecError;
+use crate::policy::PolicyViolation;
+use crate::spec::Field;
+
 pub type ProtocolResult<T> = Result<T, ProtocolError>;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ProtocolError {
-    #[error("An io error occurred")]
+    #[error("An io error occurred: {0}")]
     Io(#[from] io::Error),
     #[error("A string conversion error occurred")]
     Utf8Error(#[from] FromUtf8Error),
     #[error("A variable length integer conversion error occurred")]
     LEB128Error(#[from] leb128::read::Error),
-    #[error("unknown error")]
-    Unknown(String),
+    #[error("The message does not follow the protocol spec: {0}")]
+    SpecViolation(String),
+    #[error("The {field} is {len} bytes long, at most {max} bytes are allowed")]
+    StringTooLong {
+        field: Field,
+        len: usize,
+        max: usize,
+    },
+    /// The message ended early, `expected` bytes were needed to read the
+    /// next part of the message but only `got` were available.
+    #[error("The message is truncated, expected {expected} bytes, got {got}")]
+    TruncatedMessage { expected: usize, got: usize },
+    #[error("Invalid version: {0}")]
+    InvalidVersion(String),
+    #[error("Invalid declared address size {0}")]
+    InvalidAddressSize(u8),
+    #[error("Invalid declared address port {0}")]
+    InvalidPort(u64),
+    #[error("Feature {id} body is {len} bytes long, at most 65535 bytes are allowed")]
+    FeatureTooLong { id: u8, len: u64 },
+    #[error("The message holds {0} features, at most 255 are allowed")]
+    TooManyFeatures(usize),
+    #[error("The message holds {0} peers, at most 64 are allowed")]
+    TooManyPeers(u64),
+    /// Bytes follow the message where none were expected, in strict
+    /// decoding, see [`DecodeMode`](crate::DecodeMode).
+    #[error("The message is followed by {0} unexpected bytes")]
+    TrailingBytes(usize),
+    #[error("Unexpected message code {0}")]
+    UnexpectedMessage(u8),
+    /// The peer closed the connection without sending its handshake, the
+    /// reference node does so when the request doesn't reach it in time.
+    #[error("The peer closed the connection before replying")]
+    HandshakeTimedOutByPeer,
+    #[error("The message exceeds the maximum size of {0} bytes")]
+    MessageTooLarge(usize),
+    /// The message holds more features than the
+    /// [`DecodeLimits`](crate::DecodeLimits) allow.
+    #[error("The message holds {count} features, at most {max} are allowed by the limits")]
+    FeatureCountExceeded { count: usize, max: usize },
+    /// The buffer the message is encoded to is too small, see
+    /// [`HandshakeBuilder::encode_to_slice`](crate::HandshakeBuilder::encode_to_slice).
+    #[error("The message needs {needed} bytes, the buffer only holds {got}")]
+    BufferTooSmall { needed: usize, got: usize },
+    /// An initiator opened a connection while holding the maximum number
+    /// of connections allowed from its IP address, see
+    /// [`HandshakeListener`](crate::listener::HandshakeListener).
+    #[error("{ip} already holds the maximum of {limit} connections")]
+    TooManyConnections { ip: std::net::IpAddr, limit: usize },
+    /// An initiator didn't complete its handshake within the deadline of
+    /// the listener.
+    #[error("The peer did not complete its handshake within {0:?}")]
+    HandshakeDeadline(std::time::Duration),
+    #[error("Unexpected network magic bytes {0:?}")]
+    InvalidMagic([u8; 4]),
+    /// The peer sent the magic bytes of another known network.
+    #[error("The peer is on the {received} network instead of {expected}")]
+    WrongNetwork {
+        expected: crate::framing::Network,
+        received: crate::framing::Network,
+    },
+    #[error("The message checksum does not match its body")]
+    InvalidChecksum,
+    #[error("A storage error occurred: {0}")]
+    Storage(String),
+    #[error("The peer clock is off by {0:?}")]
+    ClockSkew(std::time::Duration),
+    #[error("The operation timed out")]
+    TimedOut,
+    #[error("The system clock is set before the unix epoch")]
+    InvalidSystemTime,
+    /// The target has no system clock, ex. `wasm32-unknown-unknown`, the
+    /// timestamp of the request must be given to the builder, see
+    /// [`HandshakeBuilder::clock`](crate::HandshakeBuilder::clock).
+    #[error("The system clock is not available on this target")]
+    ClockUnavailable,
+    #[error("Invalid target `{0}`, expected host:port")]
+    InvalidTarget(String),
+    /// The host ends with `.onion` but isn't a valid v3 onion service.
+    #[error("Invalid onion address `{0}`, expected a v3 onion service")]
+    InvalidOnionAddress(String),
+    #[error("The proxy failed to connect to the target: {0}")]
+    Proxy(String),
+    #[error("The peer was rejected by the policy: {0}")]
+    PolicyViolation(PolicyViolation),
+    #[error("The policy plugin failed: {0}")]
+    Plugin(String),
+    /// The connection to the peer was closed after the handshake.
+    #[error("The peer is disconnected")]
+    Disconnected,
+    /// The handshake was aborted through the cancellation token of its
+    /// configuration.
+    #[error("The handshake was cancelled")]
+    Cancelled,
+    /// The `on_accept` callback panicked, with the panic message.
+    #[error("The on_accept callback panicked: {0}")]
+    CallbackPanicked(String),
+    /// The peer replied with the session id of our handshake, the
+    /// connection looped back to ourselves.
+    #[error("The peer is ourselves, the connection looped back")]
+    SelfConnection,
+    /// The TLS session could not be set up, ex. the server name of the
+    /// certificate cannot be derived from the target.
+    #[error("TLS failure: {0}")]
+    Tls(String),
+}
+
+/// A message failed to decode from untrusted bytes, see
+/// [`HandshakeMessage::try_decode_any`](crate::HandshakeMessage::try_decode_any).
+#[derive(Error, Debug)]
+#[error("Cannot decode the message at byte {offset}: {source}")]
+pub struct DecodeError {
+    /// Number of bytes read when the decoding failed.
+    pub offset: usize,
+    #[source]
+    pub source: ProtocolError,
+}
+
+impl From<DecodeError> for ProtocolError {
+    fn from(err: DecodeError) -> Self {
+        err.source
+    }
+}
+
+impl From<CodecError> for ProtocolError {
+    fn from(err: CodecError) -> Self {
+        match err {
+            CodecError::Truncated { expected, got } => {
+                ProtocolError::TruncatedMessage { expected, got }
+            }
+            CodecError::VlqOverflow => ProtocolError::LEB128Error(leb128::read::Error::Overflow),
+            CodecError::StringTooLong { field, len, max } => {
+                ProtocolError::StringTooLong { field, len, max }
+            }
+            CodecError::InvalidUtf8(err) => ProtocolError::Utf8Error(err),
+            CodecError::InvalidAddressSize(size) => ProtocolError::InvalidAddressSize(size),
+            CodecError::InvalidPort(port) => ProtocolError::InvalidPort(port),
+            CodecError::FeatureTooLong { id, len } => ProtocolError::FeatureTooLong { id, len },
+            CodecError::TooManyFeatures(count) => ProtocolError::TooManyFeatures(count),
+            CodecError::MessageTooLarge(max) => ProtocolError::MessageTooLarge(max),
+            CodecError::FeatureCountExceeded { count, max } => {
+                ProtocolError::FeatureCountExceeded { count, max }
+            }
+            CodecError::BufferTooSmall { needed, got } => {
+                ProtocolError::BufferTooSmall { needed, got }
+            }
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Whether the failure may not happen again on a new attempt, ex. a
+    /// refused connection or a timeout, as opposed to a peer breaking the
+    /// protocol.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::Io(_)
+                | ProtocolError::TimedOut
+                | ProtocolError::HandshakeTimedOutByPeer
+                | ProtocolError::TruncatedMessage { .. }
+                | ProtocolError::Disconnected
+        )
+    }
+
+    /// A short and stable name of the failure, ex. `timed_out`, suitable
+    /// as a metric label. IO errors are named after their kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolError::Io(err) => match err.kind() {
+                io::ErrorKind::ConnectionRefused => "connection_refused",
+                io::ErrorKind::ConnectionReset => "connection_reset",
+                io::ErrorKind::ConnectionAborted => "connection_aborted",
+                io::ErrorKind::UnexpectedEof => "unexpected_eof",
+                io::ErrorKind::TimedOut => "timed_out",
+                _ => "io",
+            },
+            ProtocolError::Utf8Error(_) => "invalid_utf8",
+            ProtocolError::LEB128Error(_) => "invalid_vlq",
+            ProtocolError::SpecViolation(_)
+            | ProtocolError::StringTooLong { .. }
+            | ProtocolError::InvalidVersion(_)
+            | ProtocolError::InvalidAddressSize(_)
+            | ProtocolError::InvalidPort(_)
+            | ProtocolError::FeatureTooLong { .. }
+            | ProtocolError::TooManyFeatures(_)
+            | ProtocolError::TooManyPeers(_) => "spec_violation",
+            ProtocolError::TruncatedMessage { .. } => "truncated_message",
+            ProtocolError::TrailingBytes(_) => "trailing_bytes",
+            ProtocolError::UnexpectedMessage(_) => "unexpected_message",
+            ProtocolError::HandshakeTimedOutByPeer => "closed_by_peer",
+            ProtocolError::MessageTooLarge(_) => "message_too_large",
+            ProtocolError::FeatureCountExceeded { .. } => "feature_count_exceeded",
+            ProtocolError::BufferTooSmall { .. } => "buffer_too_small",
+            ProtocolError::TooManyConnections { .. } => "too_many_connections",
+            ProtocolError::HandshakeDeadline(_) => "handshake_deadline",
+            ProtocolError::InvalidMagic(_) => "invalid_magic",
+            ProtocolError::WrongNetwork { .. } => "wrong_network",
+            ProtocolError::InvalidChecksum => "invalid_checksum",
+            ProtocolError::Storage(_) => "storage",
+            ProtocolError::ClockSkew(_) => "clock_skew",
+            ProtocolError::TimedOut => "timed_out",
+            ProtocolError::InvalidSystemTime => "invalid_system_time",
+            ProtocolError::ClockUnavailable => "clock_unavailable",
+            ProtocolError::InvalidTarget(_) => "invalid_target",
+            ProtocolError::InvalidOnionAddress(_) => "invalid_onion_address",
+            ProtocolError::Proxy(_) => "proxy",
+            ProtocolError::PolicyViolation(_) => "policy_violation",
+            ProtocolError::Plugin(_) => "plugin",
+            ProtocolError::Disconnected => "disconnected",
+            ProtocolError::Cancelled => "cancelled",
+            ProtocolError::CallbackPanicked(_) => "callback_panicked",
+            ProtocolError::SelfConnection => "self_connection",
+            ProtocolError::Tls(_) => "tls",
+        }
+    }
 }
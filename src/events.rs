@@ -0,0 +1,198 @@
+//! This module implements a structured log of the probe lifecycle: every
+//! connection, handshake and alert becomes one JSON line, giving an
+//! auditable record of long runs.
+//!
+//! Each event carries the unix timestamp in milliseconds at which it
+//! happened and a correlation id shared by the events of the same probe.
+//!
+
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::batch::ProbeResult;
+use crate::error::ProtocolError;
+
+/// An event of the probe of a target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    /// Unix timestamp in milliseconds.
+    pub timestamp: u64,
+    pub correlation_id: String,
+    pub target: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The connection to the target was established.
+    Connected {
+        address: SocketAddr,
+        duration_ms: u128,
+    },
+    /// The target replied with a valid handshake.
+    HandshakeCompleted {
+        agent_name: String,
+        version: String,
+        peer_name: String,
+        duration_ms: u128,
+        attempts: u32,
+    },
+    /// The probe failed after all its attempts.
+    HandshakeFailed { error: String, attempts: u32 },
+    /// Something deserving attention, ex. a policy violation or a change
+    /// of the target identity.
+    Alert { message: String },
+}
+
+impl Event {
+    pub fn new(timestamp: u64, correlation_id: &str, target: &str, kind: EventKind) -> Self {
+        Self {
+            timestamp,
+            correlation_id: correlation_id.to_string(),
+            target: target.to_string(),
+            kind,
+        }
+    }
+
+    /// The events of a completed probe, in the order they happened. Policy
+    /// violations also raise an alert.
+    pub fn from_probe<A: Display>(result: &ProbeResult<A>, correlation_id: &str) -> Vec<Self> {
+        let target = result.target.to_string();
+        let event = |timestamp, kind| Event::new(timestamp, correlation_id, &target, kind);
+        let mut events = Vec::new();
+        let mut timestamp = result.started_at;
+        if let (Some(address), Some(connect_time)) = (result.address, result.connect_time) {
+            timestamp += connect_time.as_millis() as u64;
+            let kind = EventKind::Connected {
+                address,
+                duration_ms: connect_time.as_millis(),
+            };
+            events.push(event(timestamp, kind));
+        }
+        timestamp += result.handshake_time.unwrap_or_default().as_millis() as u64;
+        match &result.outcome {
+            Ok(reply) => events.push(event(
+                timestamp,
+                EventKind::HandshakeCompleted {
+                    agent_name: reply.agent_name.to_string(),
+                    version: reply.version.to_string(),
+                    peer_name: reply.peer_name.to_string(),
+                    duration_ms: result.handshake_time.unwrap_or_default().as_millis(),
+                    attempts: result.attempts,
+                },
+            )),
+            Err(err) => {
+                events.push(event(
+                    timestamp,
+                    EventKind::HandshakeFailed {
+                        error: err.to_string(),
+                        attempts: result.attempts,
+                    },
+                ));
+                if let ProtocolError::PolicyViolation(violation) = err {
+                    let message = format!("policy violation: {violation}");
+                    events.push(event(timestamp, EventKind::Alert { message }));
+                }
+            }
+        }
+        events
+    }
+}
+
+/// Writes events as JSON lines.
+#[derive(Debug)]
+pub struct EventLog<W: Write> {
+    writer: W,
+}
+
+impl EventLog<BufWriter<File>> {
+    /// Appends the events to the file at `path`, creating it if needed.
+    pub fn append_to<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> EventLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::encoder::{HandshakeMessage, TinyString};
+    use crate::policy::PolicyViolation;
+
+    #[test]
+    fn test_probe_events() -> io::Result<()> {
+        let mut result = ProbeResult::new("node:9030");
+        result.started_at = 1_000;
+        result.attempts = 1;
+        result.address = Some("1.2.3.4:9030".parse().unwrap());
+        result.connect_time = Some(Duration::from_millis(20));
+        result.handshake_time = Some(Duration::from_millis(30));
+        result.outcome = Ok(HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            ..Default::default()
+        });
+
+        let events = Event::from_probe(&result, "run-0");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].timestamp, 1_020);
+        assert_eq!(events[1].timestamp, 1_050);
+
+        let mut log = EventLog::new(Vec::new());
+        for event in &events {
+            log.write(event)?;
+        }
+        let written = String::from_utf8(log.into_inner()).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["event"], "connected");
+        assert_eq!(lines[0]["address"], "1.2.3.4:9030");
+        assert_eq!(lines[1]["event"], "handshake_completed");
+        assert_eq!(lines[1]["agent_name"], "ergoref");
+        assert_eq!(lines[1]["correlation_id"], "run-0");
+        assert_eq!(lines[1]["target"], "node:9030");
+
+        result.outcome = Err(ProtocolError::PolicyViolation(
+            PolicyViolation::AgentNotAllowed("ergoref".to_string()),
+        ));
+        let events = Event::from_probe(&result, "run-1");
+        let kinds: Vec<&EventKind> = events.iter().map(|event| &event.kind).collect();
+        assert!(matches!(kinds[1], EventKind::HandshakeFailed { .. }));
+        assert_eq!(
+            kinds[2],
+            &EventKind::Alert {
+                message: "policy violation: agent `ergoref` is not allowed".to_string()
+            }
+        );
+        Ok(())
+    }
+}
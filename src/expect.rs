@@ -0,0 +1,225 @@
+//! This module implements checking the reply of a node against what its
+//! operator expects it to advertise, ex. in CI after a deployment.
+//!
+//! Unlike a [`HandshakePolicy`](crate::HandshakePolicy), which rejects a
+//! peer at the first broken rule, every expectation is checked and the
+//! unmet ones are reported with the expected and the actual values.
+//!
+
+use std::fmt;
+
+use crate::encoder::{HandshakeMessage, Version};
+
+/// What the reply of a node is expected to hold, the fields left to their
+/// default aren't checked.
+///
+/// ```
+/// use p2p_handshake::{Expected, HandshakeMessage, TinyString, Version};
+///
+/// let expected = Expected {
+///     agent_matches: Some("ergo*".to_string()),
+///     min_version: Some(Version([5, 0, 21])),
+///     required_features: vec![16],
+///     ..Default::default()
+/// };
+/// let reply = HandshakeMessage {
+///     agent_name: TinyString::new("ergoref").unwrap(),
+///     version: Version([5, 0, 20]),
+///     ..Default::default()
+/// };
+/// assert_eq!(expected.check(reply).unmet.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expected {
+    /// The agent name, where `*` matches any run of characters, ex.
+    /// `ergo-mainnet-*`.
+    pub agent_matches: Option<String>,
+    /// The lowest accepted version.
+    pub min_version: Option<Version>,
+    /// The exact peer name.
+    pub peer_name: Option<String>,
+    /// The identifiers of the features the reply must hold.
+    pub required_features: Vec<u8>,
+}
+
+/// An expectation the reply doesn't meet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unmet {
+    AgentName { pattern: String, got: String },
+    Version { minimum: Version, got: Version },
+    PeerName { expected: String, got: String },
+    MissingFeature(u8),
+}
+
+impl fmt::Display for Unmet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unmet::AgentName { pattern, got } => {
+                write!(f, "agent name: expected `{pattern}`, got `{got}`")
+            }
+            Unmet::Version { minimum, got } => {
+                write!(f, "version: expected at least {minimum}, got {got}")
+            }
+            Unmet::PeerName { expected, got } => {
+                write!(f, "peer name: expected `{expected}`, got `{got}`")
+            }
+            Unmet::MissingFeature(id) => write!(f, "feature {id}: missing"),
+        }
+    }
+}
+
+/// The reply of a node and the expectations it doesn't meet.
+#[derive(Debug, Clone)]
+pub struct ExpectationReport {
+    /// The decoded reply.
+    pub message: HandshakeMessage,
+    /// Empty when every expectation is met.
+    pub unmet: Vec<Unmet>,
+}
+
+impl ExpectationReport {
+    pub fn is_met(&self) -> bool {
+        self.unmet.is_empty()
+    }
+}
+
+impl fmt::Display for ExpectationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = &self.message;
+        writeln!(f, "reply: {} {}", message.agent_name, message.version)?;
+        if self.unmet.is_empty() {
+            writeln!(f, "ok: every expectation is met")?;
+        }
+        for unmet in &self.unmet {
+            writeln!(f, "unmet: {unmet}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Expected {
+    /// Checks the reply against every expectation.
+    pub fn check(&self, message: HandshakeMessage) -> ExpectationReport {
+        let mut unmet = vec![];
+        if let Some(pattern) = &self.agent_matches {
+            if !wildcard_match(pattern, message.agent_name.as_str()) {
+                unmet.push(Unmet::AgentName {
+                    pattern: pattern.clone(),
+                    got: message.agent_name.to_string(),
+                });
+            }
+        }
+        if let Some(minimum) = &self.min_version {
+            if message.version < *minimum {
+                unmet.push(Unmet::Version {
+                    minimum: minimum.clone(),
+                    got: message.version.clone(),
+                });
+            }
+        }
+        if let Some(expected) = &self.peer_name {
+            if expected != message.peer_name.as_str() {
+                unmet.push(Unmet::PeerName {
+                    expected: expected.clone(),
+                    got: message.peer_name.to_string(),
+                });
+            }
+        }
+        for id in &self.required_features {
+            if !message.features.iter().any(|feature| feature.id == *id) {
+                unmet.push(Unmet::MissingFeature(*id));
+            }
+        }
+        ExpectationReport { message, unmet }
+    }
+}
+
+/// Whether the text matches the pattern, where `*` matches any run of
+/// characters, including none.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard, the pattern must match the whole text.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::TinyString;
+    use crate::feature::{PeerFeature, MODE_FEATURE_ID};
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("ergoref", "ergoref"));
+        assert!(!wildcard_match("ergoref", "ergoref2"));
+        assert!(wildcard_match("ergo*", "ergoref"));
+        assert!(wildcard_match("*-mainnet-*", "ergo-mainnet-5.0.21"));
+        assert!(wildcard_match("ergo*5.0.*", "ergo-mainnet-5.0.21"));
+        assert!(!wildcard_match("ergo*5.1.*", "ergo-mainnet-5.0.21"));
+        assert!(!wildcard_match("ab*ba", "aba"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn test_check() {
+        let reply = HandshakeMessage {
+            agent_name: TinyString::new("ergo-mainnet-5.0.20").unwrap(),
+            version: Version([5, 0, 20]),
+            peer_name: TinyString::new("node-1").unwrap(),
+            features: vec![PeerFeature::new(MODE_FEATURE_ID, vec![0, 1, 0, 1])],
+            ..Default::default()
+        };
+        assert!(Expected::default().check(reply.clone()).is_met());
+
+        let expected = Expected {
+            agent_matches: Some("ergo-mainnet-*".to_string()),
+            min_version: Some(Version([5, 0, 20])),
+            peer_name: Some("node-1".to_string()),
+            required_features: vec![MODE_FEATURE_ID],
+        };
+        assert!(expected.check(reply.clone()).is_met());
+
+        let expected = Expected {
+            agent_matches: Some("ergo-testnet-*".to_string()),
+            min_version: Some(Version([5, 0, 21])),
+            peer_name: Some("node-2".to_string()),
+            required_features: vec![MODE_FEATURE_ID, 42],
+        };
+        let report = expected.check(reply);
+        assert_eq!(
+            report.unmet,
+            vec![
+                Unmet::AgentName {
+                    pattern: "ergo-testnet-*".to_string(),
+                    got: "ergo-mainnet-5.0.20".to_string(),
+                },
+                Unmet::Version {
+                    minimum: Version([5, 0, 21]),
+                    got: Version([5, 0, 20]),
+                },
+                Unmet::PeerName {
+                    expected: "node-2".to_string(),
+                    got: "node-1".to_string(),
+                },
+                Unmet::MissingFeature(42),
+            ]
+        );
+        assert!(report
+            .to_string()
+            .contains("unmet: version: expected at least 5.0.21, got 5.0.20"));
+    }
+}
@@ -0,0 +1,514 @@
+//! This module implements the peer features that can be attached
+//! to a handshake message.
+//!
+//! Each feature is written on the wire as its identifier, followed by
+//! the VLQ encoded length of its body and the body itself.
+//!
+//! The decoder doesn't interpret the bodies, a feature with an id we
+//! don't know of, ex. introduced by a newer node release, is skipped with
+//! its length and kept as is, so that it encodes back to the same bytes.
+//! [`Feature::parse`] interprets the bodies of the known features.
+//!
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::encoder::HandshakeMessage;
+use crate::encoder::{read_byte, read_bytes};
+use crate::error::{ProtocolError, ProtocolResult};
+
+use crate::core::{read_vlq, write_vlq};
+
+pub use crate::core::{
+    PeerFeature, LOCAL_ADDRESS_FEATURE_ID, MODE_FEATURE_ID, REST_API_URL_FEATURE_ID,
+    SESSION_FEATURE_ID,
+};
+
+impl PeerFeature {
+    /// Checks that the payload of a known feature follows the layout of the
+    /// reference node and holds nothing more, unknown features are opaque
+    /// and always pass.
+    pub fn check_payload(&self) -> ProtocolResult<()> {
+        let mut payload = self.payload.as_slice();
+        match self.id {
+            LOCAL_ADDRESS_FEATURE_ID => {
+                // An IPv4 address followed by the port.
+                read_bytes(&mut payload, 4)?;
+                let port = read_vlq(&mut payload)?;
+                if port > u16::MAX as u64 {
+                    return Err(ProtocolError::InvalidPort(port));
+                }
+            }
+            SESSION_FEATURE_ID => {
+                read_bytes(&mut payload, 4)?;
+                read_vlq(&mut payload)?;
+            }
+            REST_API_URL_FEATURE_ID => {
+                let len = read_byte(&mut payload)?;
+                String::from_utf8(read_bytes(&mut payload, len as usize)?)?;
+            }
+            MODE_FEATURE_ID => {
+                // The state type, the verifying flag, the optional number
+                // of NiPoPoW bootstrap proofs and the blocks to keep.
+                read_bytes(&mut payload, 2)?;
+                match read_byte(&mut payload)? {
+                    0 => {}
+                    1 => {
+                        read_vlq(&mut payload)?;
+                    }
+                    flag => {
+                        return Err(ProtocolError::SpecViolation(format!(
+                            "feature {} holds an invalid option flag {flag}",
+                            self.id
+                        )))
+                    }
+                }
+                read_vlq(&mut payload)?;
+            }
+            _ => return Ok(()),
+        }
+        if !payload.is_empty() {
+            return Err(ProtocolError::SpecViolation(format!(
+                "feature {} is followed by {} unexpected bytes",
+                self.id,
+                payload.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The session feature: the network magic followed by a random session
+/// id, written as a ZigZag and VLQ encoded long. A node receiving its own
+/// session id back dialed itself, ex. through NAT loopback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionFeature {
+    pub magic: [u8; 4],
+    pub session_id: i64,
+}
+
+impl SessionFeature {
+    pub fn new(magic: [u8; 4], session_id: i64) -> Self {
+        Self { magic, session_id }
+    }
+
+    /// A session on the network with a random session id.
+    pub fn random(magic: [u8; 4]) -> Self {
+        Self::new(magic, crate::rng::random_u64() as i64)
+    }
+
+    /// Parses the session feature of the message, if any and well formed.
+    pub fn find(message: &HandshakeMessage) -> Option<Self> {
+        message
+            .features
+            .iter()
+            .find(|feature| feature.id == SESSION_FEATURE_ID)
+            .and_then(|feature| Self::parse(feature).ok())
+    }
+
+    /// Parses the payload of a session feature.
+    pub fn parse(feature: &PeerFeature) -> ProtocolResult<Self> {
+        if feature.id != SESSION_FEATURE_ID {
+            return Err(ProtocolError::SpecViolation(format!(
+                "feature {} is not a session feature",
+                feature.id
+            )));
+        }
+        let (magic, mut rest) =
+            feature
+                .payload
+                .split_at_checked(4)
+                .ok_or(ProtocolError::TruncatedMessage {
+                    expected: 4,
+                    got: feature.payload.len(),
+                })?;
+        let encoded = read_vlq(&mut rest)?;
+        // ZigZag decoding.
+        let session_id = (encoded >> 1) as i64 ^ -((encoded & 1) as i64);
+        let mut network_magic = [0; 4];
+        network_magic.copy_from_slice(magic);
+        Ok(Self::new(network_magic, session_id))
+    }
+}
+
+impl From<SessionFeature> for PeerFeature {
+    fn from(session: SessionFeature) -> Self {
+        let mut payload = session.magic.to_vec();
+        // ZigZag encoding maps small negative ids to small numbers.
+        let encoded = ((session.session_id << 1) ^ (session.session_id >> 63)) as u64;
+        // Writing to a vector cannot fail.
+        let _ = write_vlq(&mut payload, encoded);
+        PeerFeature::new(SESSION_FEATURE_ID, payload)
+    }
+}
+
+/// How a node holds the state, the first byte of the mode feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateType {
+    /// The node holds the whole UTXO set.
+    Utxo,
+    /// The node only holds the digest of the UTXO set, it is stateless.
+    Digest,
+}
+
+/// The mode feature: how the node holds the state and the blocks, ex. to
+/// tell the archive nodes from the pruned ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeFeature {
+    pub state_type: StateType,
+    /// Whether the node validates the transactions.
+    pub verifying_transactions: bool,
+    /// The number of NiPoPoW proofs the node bootstrapped from, if it did.
+    pub nipopow_bootstrapped: Option<i32>,
+    /// The number of last blocks kept, -1 when the node keeps them all.
+    pub blocks_to_keep: i32,
+}
+
+impl ModeFeature {
+    /// The mode of a node holding the UTXO set and every block.
+    pub fn archive() -> Self {
+        Self {
+            state_type: StateType::Utxo,
+            verifying_transactions: true,
+            nipopow_bootstrapped: None,
+            blocks_to_keep: -1,
+        }
+    }
+
+    /// Whether the node only keeps the last blocks.
+    pub fn is_pruned(&self) -> bool {
+        self.blocks_to_keep >= 0
+    }
+
+    /// Parses the mode feature of the message, if any and well formed.
+    pub fn find(message: &HandshakeMessage) -> Option<Self> {
+        message
+            .features
+            .iter()
+            .find(|feature| feature.id == MODE_FEATURE_ID)
+            .and_then(|feature| Self::parse(feature).ok())
+    }
+
+    /// Parses the payload of a mode feature: the state type, the verifying
+    /// flag, the optional number of NiPoPoW proofs and the blocks to keep,
+    /// the numbers being written as ZigZag and VLQ encoded ints.
+    pub fn parse(feature: &PeerFeature) -> ProtocolResult<Self> {
+        check_id(feature, MODE_FEATURE_ID, "mode")?;
+        let mut payload = feature.payload.as_slice();
+        let state_type = match read_byte(&mut payload)? {
+            0 => StateType::Utxo,
+            1 => StateType::Digest,
+            code => return Err(invalid_byte(feature, "state type", code)),
+        };
+        let verifying_transactions = match read_byte(&mut payload)? {
+            0 => false,
+            1 => true,
+            flag => return Err(invalid_byte(feature, "verifying flag", flag)),
+        };
+        let nipopow_bootstrapped = match read_byte(&mut payload)? {
+            0 => None,
+            1 => Some(read_int(&mut payload)?),
+            flag => return Err(invalid_byte(feature, "option flag", flag)),
+        };
+        let blocks_to_keep = read_int(&mut payload)?;
+        check_consumed(feature, payload)?;
+        Ok(Self {
+            state_type,
+            verifying_transactions,
+            nipopow_bootstrapped,
+            blocks_to_keep,
+        })
+    }
+}
+
+impl From<ModeFeature> for PeerFeature {
+    fn from(mode: ModeFeature) -> Self {
+        let state_type = match mode.state_type {
+            StateType::Utxo => 0,
+            StateType::Digest => 1,
+        };
+        let mut payload = vec![state_type, u8::from(mode.verifying_transactions)];
+        match mode.nipopow_bootstrapped {
+            Some(proofs) => {
+                payload.push(1);
+                write_int(&mut payload, proofs);
+            }
+            None => payload.push(0),
+        }
+        write_int(&mut payload, mode.blocks_to_keep);
+        PeerFeature::new(MODE_FEATURE_ID, payload)
+    }
+}
+
+/// A feature with its body interpreted after its id.
+///
+/// ```
+/// use p2p_handshake::feature::{Feature, ModeFeature, PeerFeature, MODE_FEATURE_ID};
+///
+/// let feature = PeerFeature::new(MODE_FEATURE_ID, vec![0, 1, 0, 1]);
+/// let parsed = Feature::parse(&feature).unwrap();
+/// assert_eq!(parsed, Feature::Mode(ModeFeature::archive()));
+/// assert_eq!(parsed.encode().unwrap(), feature);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Feature {
+    Mode(ModeFeature),
+    /// The address of the node on its local network.
+    LocalAddress(SocketAddrV4),
+    Session(SessionFeature),
+    /// The url of the REST API of the node.
+    RestApiUrl(String),
+    /// A feature we don't know of, kept as is.
+    Unknown(PeerFeature),
+}
+
+impl Feature {
+    /// Interprets the body of a known feature, the bodies not following
+    /// the layout of the reference node fail.
+    pub fn parse(feature: &PeerFeature) -> ProtocolResult<Self> {
+        let mut payload = feature.payload.as_slice();
+        let parsed = match feature.id {
+            MODE_FEATURE_ID => return ModeFeature::parse(feature).map(Feature::Mode),
+            SESSION_FEATURE_ID => {
+                feature.check_payload()?;
+                return SessionFeature::parse(feature).map(Feature::Session);
+            }
+            LOCAL_ADDRESS_FEATURE_ID => {
+                let octets = read_bytes(&mut payload, 4)?;
+                let port = read_vlq(&mut payload)?;
+                let port = u16::try_from(port).map_err(|_| ProtocolError::InvalidPort(port))?;
+                let ip = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+                Feature::LocalAddress(SocketAddrV4::new(ip, port))
+            }
+            REST_API_URL_FEATURE_ID => {
+                let len = read_byte(&mut payload)?;
+                Feature::RestApiUrl(String::from_utf8(read_bytes(&mut payload, len as usize)?)?)
+            }
+            _ => return Ok(Feature::Unknown(feature.clone())),
+        };
+        check_consumed(feature, payload)?;
+        Ok(parsed)
+    }
+
+    /// Parses every feature of the message.
+    pub fn parse_all(message: &HandshakeMessage) -> ProtocolResult<Vec<Self>> {
+        message.features.iter().map(Self::parse).collect()
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Feature::Mode(_) => MODE_FEATURE_ID,
+            Feature::LocalAddress(_) => LOCAL_ADDRESS_FEATURE_ID,
+            Feature::Session(_) => SESSION_FEATURE_ID,
+            Feature::RestApiUrl(_) => REST_API_URL_FEATURE_ID,
+            Feature::Unknown(feature) => feature.id,
+        }
+    }
+
+    /// Writes the body of the feature back, ex. to advertise it with
+    /// [`HandshakeBuilder::feature`](crate::HandshakeBuilder::feature).
+    /// Urls longer than 255 bytes fail.
+    pub fn encode(&self) -> ProtocolResult<PeerFeature> {
+        Ok(match self {
+            Feature::Mode(mode) => (*mode).into(),
+            Feature::Session(session) => (*session).into(),
+            Feature::LocalAddress(address) => {
+                let mut payload = address.ip().octets().to_vec();
+                let _ = write_vlq(&mut payload, u64::from(address.port()));
+                PeerFeature::new(LOCAL_ADDRESS_FEATURE_ID, payload)
+            }
+            Feature::RestApiUrl(url) => {
+                let len = u8::try_from(url.len()).map_err(|_| {
+                    ProtocolError::SpecViolation(format!(
+                        "the REST API url is {} bytes long, at most 255 bytes are allowed",
+                        url.len()
+                    ))
+                })?;
+                let mut payload = vec![len];
+                payload.extend_from_slice(url.as_bytes());
+                PeerFeature::new(REST_API_URL_FEATURE_ID, payload)
+            }
+            Feature::Unknown(feature) => feature.clone(),
+        })
+    }
+}
+
+fn check_id(feature: &PeerFeature, id: u8, name: &str) -> ProtocolResult<()> {
+    if feature.id != id {
+        return Err(ProtocolError::SpecViolation(format!(
+            "feature {} is not a {name} feature",
+            feature.id
+        )));
+    }
+    Ok(())
+}
+
+fn check_consumed(feature: &PeerFeature, payload: &[u8]) -> ProtocolResult<()> {
+    if !payload.is_empty() {
+        return Err(ProtocolError::SpecViolation(format!(
+            "feature {} is followed by {} unexpected bytes",
+            feature.id,
+            payload.len()
+        )));
+    }
+    Ok(())
+}
+
+fn invalid_byte(feature: &PeerFeature, name: &str, value: u8) -> ProtocolError {
+    ProtocolError::SpecViolation(format!(
+        "feature {} holds an invalid {name} {value}",
+        feature.id
+    ))
+}
+
+/// Reads a ZigZag and VLQ encoded int.
+fn read_int(payload: &mut &[u8]) -> ProtocolResult<i32> {
+    let encoded = read_vlq(payload)?;
+    let encoded = u32::try_from(encoded)
+        .map_err(|_| ProtocolError::SpecViolation(format!("the int {encoded} exceeds 32 bits")))?;
+    Ok((encoded >> 1) as i32 ^ -((encoded & 1) as i32))
+}
+
+fn write_int(payload: &mut Vec<u8>, value: i32) {
+    let encoded = ((value << 1) ^ (value >> 31)) as u32;
+    // Writing to a vector cannot fail.
+    let _ = write_vlq(payload, u64::from(encoded));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::borrowed::HandshakeMessageRef;
+    use crate::encoder::TinyString;
+
+    #[test]
+    fn test_unknown_features() -> ProtocolResult<()> {
+        let message = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            features: vec![
+                PeerFeature::from(SessionFeature::new([2, 0, 2, 3], 42)),
+                PeerFeature::new(200, vec![0xde, 0xad, 0xbe, 0xef]),
+                PeerFeature::new(201, vec![7; 300]),
+                PeerFeature::new(MODE_FEATURE_ID, vec![0, 1, 0, 1]),
+            ],
+            ..Default::default()
+        };
+        let encoded = message.encode_with_timestamp(42)?;
+
+        let decoded = HandshakeMessage::decode_from_response(encoded.clone())?;
+        assert_eq!(decoded.features, message.features);
+        let known: Vec<bool> = decoded.features.iter().map(PeerFeature::is_known).collect();
+        assert_eq!(known, [true, false, false, true]);
+        assert_eq!(decoded.encode_with_timestamp(decoded.timestamp)?, encoded);
+
+        let borrowed = HandshakeMessageRef::decode(&encoded)?;
+        assert_eq!(
+            HandshakeMessage::try_from(&borrowed)?.features,
+            message.features
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_feature() -> ProtocolResult<()> {
+        for session_id in [0, 8192, -8192, i64::MIN, i64::MAX] {
+            let session = SessionFeature::new([2, 0, 2, 3], session_id);
+            let feature = PeerFeature::from(session);
+            assert_eq!(SessionFeature::parse(&feature)?, session);
+        }
+        let feature = PeerFeature::from(SessionFeature::new([2, 0, 2, 3], -1));
+        assert_eq!(feature.payload, [2, 0, 2, 3, 1]);
+
+        let truncated = PeerFeature::new(SESSION_FEATURE_ID, vec![2, 0]);
+        assert!(SessionFeature::parse(&truncated).is_err());
+        assert!(Feature::parse(&truncated).is_err());
+        assert_ne!(
+            SessionFeature::random([2, 0, 2, 3]),
+            SessionFeature::random([2, 0, 2, 3])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_features() -> ProtocolResult<()> {
+        // The features built by hand from the serializers of the reference
+        // node, not captured from a node: an archive node, a pruned
+        // stateless node bootstrapped from 2 NiPoPoW proofs, its local
+        // address and its REST API url.
+        let features = [
+            (
+                PeerFeature::new(MODE_FEATURE_ID, vec![0, 1, 0, 1]),
+                Feature::Mode(ModeFeature::archive()),
+            ),
+            (
+                PeerFeature::new(MODE_FEATURE_ID, vec![1, 0, 1, 4, 0xa0, 0x1f]),
+                Feature::Mode(ModeFeature {
+                    state_type: StateType::Digest,
+                    verifying_transactions: false,
+                    nipopow_bootstrapped: Some(2),
+                    blocks_to_keep: 2000,
+                }),
+            ),
+            (
+                PeerFeature::new(LOCAL_ADDRESS_FEATURE_ID, vec![192, 168, 1, 10, 0xc6, 0x46]),
+                Feature::LocalAddress("192.168.1.10:9030".parse().unwrap()),
+            ),
+            (
+                PeerFeature::new(
+                    REST_API_URL_FEATURE_ID,
+                    [&[14][..], b"http://n1:9053"].concat(),
+                ),
+                Feature::RestApiUrl("http://n1:9053".to_string()),
+            ),
+            (
+                PeerFeature::from(SessionFeature::new([1, 0, 2, 4], -42)),
+                Feature::Session(SessionFeature::new([1, 0, 2, 4], -42)),
+            ),
+            (
+                PeerFeature::new(200, vec![0xde, 0xad]),
+                Feature::Unknown(PeerFeature::new(200, vec![0xde, 0xad])),
+            ),
+        ];
+        for (feature, parsed) in features {
+            assert_eq!(Feature::parse(&feature)?, parsed);
+            assert_eq!(parsed.id(), feature.id);
+            assert_eq!(parsed.encode()?, feature);
+        }
+        assert!(ModeFeature::parse(&PeerFeature::new(
+            MODE_FEATURE_ID,
+            vec![0, 1, 0, 0xa0, 0x1f]
+        ))?
+        .is_pruned());
+
+        for payload in [
+            vec![2, 1, 0, 1],
+            vec![0, 1, 2, 1],
+            vec![0, 1, 0, 1, 0],
+            vec![0, 1],
+        ] {
+            assert!(Feature::parse(&PeerFeature::new(MODE_FEATURE_ID, payload)).is_err());
+        }
+        assert!(Feature::RestApiUrl("x".repeat(256)).encode().is_err());
+
+        let message = HandshakeMessage {
+            features: vec![ModeFeature::archive().into()],
+            ..Default::default()
+        };
+        assert_eq!(ModeFeature::find(&message), Some(ModeFeature::archive()));
+        assert_eq!(Feature::parse_all(&message)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_captured_features() -> ProtocolResult<()> {
+        // The features of the replies captured from reference nodes, see
+        // `data/captured_vectors.txt`.
+        for vector in crate::conformance::TestVector::captured() {
+            let message = vector.decode()?;
+            for feature in &message.features {
+                let parsed = Feature::parse(feature)?;
+                assert_eq!(parsed.encode()?, *feature, "{}", vector.name);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+//! This module implements the framing of the Ergo network messages
+//! exchanged after a successful handshake.
+//!
+//! Every message is written as the network magic bytes, the message code,
+//! the body length as a 4 bytes big endian integer and, for non-empty
+//! bodies, the first 4 bytes of the Blake2b256 digest of the body
+//! followed by the body itself.
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{ProtocolError, ProtocolResult};
+
+/// Magic bytes of the Ergo mainnet network.
+pub const MAINNET_MAGIC: [u8; 4] = [1, 0, 2, 4];
+
+/// Magic bytes of the Ergo testnet network.
+pub const TESTNET_MAGIC: [u8; 4] = [2, 0, 2, 3];
+
+/// Magic bytes of the Ergo devnet network.
+pub const DEVNET_MAGIC: [u8; 4] = [2, 0, 0, 1];
+
+/// An Ergo network, identified by the magic bytes starting its messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Devnet,
+    /// A private network using its own magic bytes.
+    Custom([u8; 4]),
+}
+
+impl Network {
+    /// The magic bytes of the network.
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => MAINNET_MAGIC,
+            Network::Testnet => TESTNET_MAGIC,
+            Network::Devnet => DEVNET_MAGIC,
+            Network::Custom(magic) => magic,
+        }
+    }
+
+    /// The network using the magic bytes, [`Network::Custom`] for unknown
+    /// ones.
+    pub fn from_magic(magic: [u8; 4]) -> Self {
+        match magic {
+            MAINNET_MAGIC => Network::Mainnet,
+            TESTNET_MAGIC => Network::Testnet,
+            DEVNET_MAGIC => Network::Devnet,
+            magic => Network::Custom(magic),
+        }
+    }
+}
+
+impl From<Network> for [u8; 4] {
+    fn from(network: Network) -> Self {
+        network.magic()
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Devnet => write!(f, "devnet"),
+            Network::Custom([a, b, c, d]) => write!(f, "{a},{b},{c},{d}"),
+        }
+    }
+}
+
+/// Parses a network name, or the comma separated magic bytes of a custom
+/// network, ex. `1,2,3,4`.
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mainnet" => return Ok(Network::Mainnet),
+            "testnet" => return Ok(Network::Testnet),
+            "devnet" => return Ok(Network::Devnet),
+            _ => {}
+        }
+        let bytes = value
+            .split(',')
+            .map(|byte| byte.trim().parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>();
+        match bytes.ok().and_then(|bytes| <[u8; 4]>::try_from(bytes).ok()) {
+            Some(magic) => Ok(Network::from_magic(magic)),
+            None => Err(format!(
+                "unknown network `{value}`, expected mainnet, testnet, devnet or 4 comma separated bytes"
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Network {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Networks are (de)serialized as their name or the comma separated magic
+/// bytes of a custom network.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Network {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Number of bytes of the magic, code and length fields.
+pub const HEADER_LEN: usize = 4 + 1 + 4;
+
+/// Number of bytes of the body checksum.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Maximum body size accepted by default, matching the reference node
+/// maximum packet size.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// A network message exchanged after the handshake.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NetworkMessage {
+    pub code: u8,
+    pub body: Vec<u8>,
+}
+
+impl NetworkMessage {
+    pub fn new(code: u8, body: Vec<u8>) -> Self {
+        Self { code, body }
+    }
+
+    /// Frames the message for the network identified by `magic`.
+    pub fn encode(&self, magic: [u8; 4]) -> ProtocolResult<Vec<u8>> {
+        let len = u32::try_from(self.body.len())
+            .map_err(|_| ProtocolError::MessageTooLarge(u32::MAX as usize))?;
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + CHECKSUM_LEN + self.body.len());
+        buf.extend_from_slice(&magic);
+        buf.push(self.code);
+        buf.extend_from_slice(&len.to_be_bytes());
+        if !self.body.is_empty() {
+            buf.extend_from_slice(&checksum(&self.body));
+            buf.extend_from_slice(&self.body);
+        }
+        Ok(buf)
+    }
+
+    /// Decodes a framed message, returning it along with the number
+    /// of bytes it occupied in `data`.
+    pub fn decode(data: &[u8], magic: [u8; 4]) -> ProtocolResult<(Self, usize)> {
+        let header = data
+            .get(..HEADER_LEN)
+            .ok_or_else(|| truncated(HEADER_LEN, data))?;
+        let (code, len) = parse_header(header, magic, usize::MAX)?;
+        if len == 0 {
+            return Ok((NetworkMessage::new(code, vec![]), HEADER_LEN));
+        }
+
+        // The length is only bounded by 32 bits, as much as the address
+        // space of wasm32.
+        let too_large = ProtocolError::MessageTooLarge(usize::MAX - HEADER_LEN - CHECKSUM_LEN);
+        let end = (HEADER_LEN + CHECKSUM_LEN)
+            .checked_add(len)
+            .ok_or(too_large)?;
+        let frame = data.get(..end).ok_or_else(|| truncated(end, data))?;
+        let (expected_checksum, body) = frame[HEADER_LEN..].split_at(CHECKSUM_LEN);
+        verify_checksum(body, expected_checksum)?;
+        Ok((NetworkMessage::new(code, body.to_vec()), end))
+    }
+}
+
+/// Reads a framed message from the stream, rejecting bodies larger than `max_body_size`.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    magic: [u8; 4],
+    max_body_size: usize,
+) -> ProtocolResult<NetworkMessage> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    read_body(reader, &header, magic, max_body_size).await
+}
+
+/// Reads the body of the message whose header was read.
+pub(crate) async fn read_body<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    header: &[u8; HEADER_LEN],
+    magic: [u8; 4],
+    max_body_size: usize,
+) -> ProtocolResult<NetworkMessage> {
+    let (code, len) = parse_header(header, magic, max_body_size)?;
+    if len == 0 {
+        return Ok(NetworkMessage::new(code, vec![]));
+    }
+
+    let mut expected_checksum = [0u8; CHECKSUM_LEN];
+    reader.read_exact(&mut expected_checksum).await?;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    verify_checksum(&body, &expected_checksum)?;
+    Ok(NetworkMessage::new(code, body))
+}
+
+/// Frames and writes a message to the stream.
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    magic: [u8; 4],
+    message: &NetworkMessage,
+) -> ProtocolResult<()> {
+    writer.write_all(&message.encode(magic)?).await?;
+    Ok(())
+}
+
+pub(crate) fn parse_header(
+    header: &[u8],
+    magic: [u8; 4],
+    max_body_size: usize,
+) -> ProtocolResult<(u8, usize)> {
+    let received_magic = [header[0], header[1], header[2], header[3]];
+    if received_magic != magic {
+        // A known magic means the peer is on another network, anything
+        // else that the stream isn't made of Ergo messages.
+        return Err(match Network::from_magic(received_magic) {
+            Network::Custom(_) => ProtocolError::InvalidMagic(received_magic),
+            received => ProtocolError::WrongNetwork {
+                expected: Network::from_magic(magic),
+                received,
+            },
+        });
+    }
+    let code = header[4];
+    let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if len > max_body_size {
+        return Err(ProtocolError::MessageTooLarge(max_body_size));
+    }
+    Ok((code, len))
+}
+
+fn checksum(body: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Blake2b::<U32>::digest(body);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+fn verify_checksum(body: &[u8], expected: &[u8]) -> ProtocolResult<()> {
+    if checksum(body) != expected {
+        return Err(ProtocolError::InvalidChecksum);
+    }
+    Ok(())
+}
+
+fn truncated(expected: usize, data: &[u8]) -> ProtocolError {
+    ProtocolError::TruncatedMessage {
+        expected,
+        got: data.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_decoding() -> ProtocolResult<()> {
+        let message = NetworkMessage::new(1, vec![1, 2, 3]);
+        let mut data = message.encode(MAINNET_MAGIC)?;
+        assert_eq!(&data[..HEADER_LEN], &[1, 0, 2, 4, 1, 0, 0, 0, 3]);
+        assert_eq!(data.len(), HEADER_LEN + CHECKSUM_LEN + 3);
+
+        data.extend_from_slice(&NetworkMessage::new(2, vec![]).encode(MAINNET_MAGIC)?);
+        let (decoded, read) = NetworkMessage::decode(&data, MAINNET_MAGIC)?;
+        assert_eq!(decoded, message);
+
+        let (decoded, _) = NetworkMessage::decode(&data[read..], MAINNET_MAGIC)?;
+        assert_eq!(decoded, NetworkMessage::new(2, vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_network() {
+        assert_eq!("testnet".parse(), Ok(Network::Testnet));
+        assert_eq!("2,0,2,3".parse(), Ok(Network::Testnet));
+        assert_eq!("9,9,9,9".parse(), Ok(Network::Custom([9, 9, 9, 9])));
+        assert!("9,9,9".parse::<Network>().is_err());
+        assert!("moon".parse::<Network>().is_err());
+        assert_eq!(Network::Custom([9, 9, 9, 9]).to_string(), "9,9,9,9");
+
+        let data = NetworkMessage::new(1, vec![]).encode([9, 9, 9, 9]).unwrap();
+        assert!(matches!(
+            NetworkMessage::decode(&data, MAINNET_MAGIC),
+            Err(ProtocolError::InvalidMagic([9, 9, 9, 9]))
+        ));
+    }
+
+    #[test]
+    fn test_decoding_errors() -> ProtocolResult<()> {
+        let mut data = NetworkMessage::new(1, vec![1, 2, 3]).encode(MAINNET_MAGIC)?;
+        assert!(matches!(
+            NetworkMessage::decode(&data, TESTNET_MAGIC),
+            Err(ProtocolError::WrongNetwork {
+                expected: Network::Testnet,
+                received: Network::Mainnet,
+            })
+        ));
+        assert!(matches!(
+            NetworkMessage::decode(&data[..data.len() - 1], MAINNET_MAGIC),
+            Err(ProtocolError::TruncatedMessage {
+                expected: 16,
+                got: 15
+            })
+        ));
+
+        *data.last_mut().unwrap() = 42;
+        assert!(matches!(
+            NetworkMessage::decode(&data, MAINNET_MAGIC),
+            Err(ProtocolError::InvalidChecksum)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_write_message() -> ProtocolResult<()> {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let message = NetworkMessage::new(65, vec![7; 100]);
+        let sent = message.clone();
+        tokio::spawn(async move { write_message(&mut client, TESTNET_MAGIC, &sent).await });
+
+        let received = read_message(&mut server, TESTNET_MAGIC, DEFAULT_MAX_BODY_SIZE).await?;
+        assert_eq!(received, message);
+
+        let data = message.encode(TESTNET_MAGIC)?;
+        let error = read_message(&mut data.as_slice(), TESTNET_MAGIC, 99)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ProtocolError::MessageTooLarge(99)));
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! This module implements the history of the probes kept between runs,
+//! so that one-shot probes track a fleet of nodes: what changed since the
+//! last run and which targets were probed recently enough to be skipped.
+//!
+//! The last outcome of each target is saved to a JSON file, keyed by the
+//! target as given, along with its last successful reply.
+//!
+//! ```no_run
+//! # fn run(results: Vec<p2p_handshake::ProbeResult<String>>) -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::history::ProbeHistory;
+//!
+//! let mut history = ProbeHistory::load("history.json")?;
+//! for result in &results {
+//!     for change in history.record(&result.target, result.outcome.as_ref(), result.started_at) {
+//!         println!("{}: {change}", result.target);
+//!     }
+//! }
+//! history.save("history.json")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::diff::FieldDiff;
+use crate::encoder::HandshakeMessage;
+use crate::error::{ProtocolError, ProtocolResult};
+
+/// Version of the history schema written by this release.
+pub const HISTORY_SCHEMA_VERSION: u32 = 1;
+
+/// The last outcome of a target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetRecord {
+    /// Unix timestamp in milliseconds of the last probe.
+    pub probed_at: u64,
+    /// Whether the last probe completed the handshake.
+    pub online: bool,
+    /// The error of the last probe, if it failed.
+    pub error: Option<String>,
+    /// Unix timestamp in milliseconds of the last completed handshake.
+    pub seen_at: Option<u64>,
+    /// The reply of the last completed handshake.
+    pub reply: Option<HandshakeMessage>,
+}
+
+/// What changed for a target since its previous probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetChange {
+    /// The target was never probed before.
+    New,
+    /// A field of the reply differs from the last completed handshake.
+    Changed(FieldDiff),
+    /// The target completed the previous handshake but not this one.
+    WentOffline(String),
+    /// The target failed the previous handshake but completed this one.
+    BackOnline,
+}
+
+impl fmt::Display for TargetChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetChange::New => f.write_str("new target"),
+            TargetChange::Changed(diff) => write!(f, "{diff}"),
+            TargetChange::WentOffline(error) => write!(f, "went offline: {error}"),
+            TargetChange::BackOnline => f.write_str("back online"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HistoryDocument {
+    version: u32,
+    targets: BTreeMap<String, TargetRecord>,
+}
+
+/// The last outcome of every target probed in the previous runs.
+#[derive(Debug, Default)]
+pub struct ProbeHistory {
+    targets: BTreeMap<String, TargetRecord>,
+}
+
+impl ProbeHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the history saved at `path`, an empty history when the file
+    /// doesn't exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> ProtocolResult<Self> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let document: HistoryDocument = serde_json::from_slice(&content).map_err(json_error)?;
+        if document.version > HISTORY_SCHEMA_VERSION {
+            return Err(ProtocolError::Storage(format!(
+                "unsupported history schema version {}",
+                document.version
+            )));
+        }
+        Ok(Self {
+            targets: document.targets,
+        })
+    }
+
+    /// Writes the history to `path`, through a temporary file renamed
+    /// over it so that an interrupted run doesn't lose the history.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> ProtocolResult<()> {
+        let path = path.as_ref();
+        let document = HistoryDocument {
+            version: HISTORY_SCHEMA_VERSION,
+            targets: self.targets.clone(),
+        };
+        let content = serde_json::to_vec_pretty(&document).map_err(json_error)?;
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, content)?;
+        Ok(fs::rename(&temporary, path)?)
+    }
+
+    pub fn get(&self, target: &str) -> Option<&TargetRecord> {
+        self.targets.get(target)
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Whether the target was probed less than `max_age` before `now`, a
+    /// unix timestamp in milliseconds, whatever the outcome.
+    pub fn is_fresh(&self, target: &str, max_age: Duration, now: u64) -> bool {
+        self.targets.get(target).is_some_and(|record| {
+            u128::from(now.saturating_sub(record.probed_at)) < max_age.as_millis()
+        })
+    }
+
+    /// Records the outcome of the probe of the target started at
+    /// `probed_at`, a unix timestamp in milliseconds, and returns what
+    /// changed since the previous probe.
+    pub fn record(
+        &mut self,
+        target: &str,
+        outcome: Result<&HandshakeMessage, &ProtocolError>,
+        probed_at: u64,
+    ) -> Vec<TargetChange> {
+        let previous = self.targets.remove(target);
+        let mut changes = vec![];
+        match (&previous, outcome) {
+            (None, _) => changes.push(TargetChange::New),
+            (Some(previous), Ok(reply)) => {
+                if !previous.online {
+                    changes.push(TargetChange::BackOnline);
+                }
+                if let Some(last) = &previous.reply {
+                    changes.extend(last.diff(reply).into_iter().map(TargetChange::Changed));
+                }
+            }
+            (Some(previous), Err(err)) if previous.online => {
+                changes.push(TargetChange::WentOffline(err.to_string()));
+            }
+            (Some(_), Err(_)) => {}
+        }
+
+        let (seen_at, reply) = match (outcome, previous) {
+            (Ok(reply), _) => (Some(probed_at), Some(reply.clone())),
+            (Err(_), Some(previous)) => (previous.seen_at, previous.reply),
+            (Err(_), None) => (None, None),
+        };
+        self.targets.insert(
+            target.to_string(),
+            TargetRecord {
+                probed_at,
+                online: outcome.is_ok(),
+                error: outcome.err().map(ToString::to_string),
+                seen_at,
+                reply,
+            },
+        );
+        changes
+    }
+}
+
+fn json_error(err: serde_json::Error) -> ProtocolError {
+    ProtocolError::Storage(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{TinyString, Version};
+
+    fn reply(version: [u8; 3]) -> HandshakeMessage {
+        HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version(version),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut history = ProbeHistory::new();
+        let target = "1.2.3.4:9030";
+        assert_eq!(
+            history.record(target, Ok(&reply([5, 0, 20])), 1_000),
+            [TargetChange::New]
+        );
+        assert!(history
+            .record(target, Ok(&reply([5, 0, 20])), 2_000)
+            .is_empty());
+        assert_eq!(
+            history.record(target, Err(&ProtocolError::TimedOut), 3_000),
+            [TargetChange::WentOffline(
+                ProtocolError::TimedOut.to_string()
+            )]
+        );
+        assert!(history
+            .record(target, Err(&ProtocolError::TimedOut), 4_000)
+            .is_empty());
+        assert_eq!(history.get(target).unwrap().seen_at, Some(2_000));
+        assert_eq!(
+            history.record(target, Ok(&reply([5, 0, 21])), 5_000),
+            [
+                TargetChange::BackOnline,
+                TargetChange::Changed(FieldDiff::Version {
+                    old: Version([5, 0, 20]),
+                    new: Version([5, 0, 21]),
+                }),
+            ]
+        );
+
+        assert!(history.is_fresh(target, Duration::from_secs(1), 5_999));
+        assert!(!history.is_fresh(target, Duration::from_secs(1), 6_000));
+        assert!(!history.is_fresh("5.6.7.8:9030", Duration::from_secs(1), 5_000));
+    }
+
+    #[test]
+    fn test_save_load() -> ProtocolResult<()> {
+        let path =
+            std::env::temp_dir().join(format!("p2p-handshake-history-{}.json", std::process::id()));
+        assert!(ProbeHistory::load(&path)?.is_empty());
+
+        let mut history = ProbeHistory::new();
+        history.record("1.2.3.4:9030", Ok(&reply([5, 0, 21])), 1_000);
+        history.record("5.6.7.8:9030", Err(&ProtocolError::TimedOut), 2_000);
+        history.save(&path)?;
+
+        let loaded = ProbeHistory::load(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(loaded.len(), 2);
+        let record = loaded.get("1.2.3.4:9030").unwrap();
+        assert!(record.online);
+        assert_eq!(record.reply.as_ref().unwrap().version, Version([5, 0, 21]));
+        let record = loaded.get("5.6.7.8:9030").unwrap();
+        assert_eq!(record.error, Some(ProtocolError::TimedOut.to_string()));
+        assert_eq!(record.seen_at, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+//! This module implements keeping a handshaken connection alive.
+//!
+//! The Ergo network protocol has no ping message, the reference node
+//! drops the connections that stay silent for too long. Nodes instead
+//! keep their connections busy with periodic `GetPeers` requests,
+//! answered with a `Peers` message. The keep-alive ping is thus a
+//! `GetPeers` message and its pong a `Peers` message, which the reader of
+//! the connection is free to ignore.
+//!
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::AsyncWrite;
+
+use crate::error::ProtocolResult;
+use crate::framing::{write_message, NetworkMessage};
+use crate::peers::{get_peers_message, PEERS_CODE};
+
+/// Returns the message keeping the connection alive.
+pub fn ping_message() -> NetworkMessage {
+    get_peers_message()
+}
+
+/// Whether the message is the reply of the remote node to a ping.
+pub fn is_pong(message: &NetworkMessage) -> bool {
+    message.code == PEERS_CODE
+}
+
+/// Writes a ping to the stream every interval, starting one interval
+/// from now, until writing fails. A zero interval is rejected with an
+/// [`io::ErrorKind::InvalidInput`] error. The stream is typically the
+/// write half of the connection handed to `on_accept`:
+///
+/// ```no_run
+/// # async fn run(stream: tokio::net::TcpStream) {
+/// use std::time::Duration;
+/// use p2p_handshake::framing::Network;
+/// use p2p_handshake::keepalive::keep_alive;
+///
+/// let (reader, writer) = stream.into_split();
+/// tokio::spawn(keep_alive(writer, Network::Mainnet, Duration::from_secs(60)));
+/// // Keep reading the messages of the node from `reader`.
+/// # }
+/// ```
+pub async fn keep_alive<W, N>(mut writer: W, network: N, interval: Duration) -> ProtocolResult<()>
+where
+    W: AsyncWrite + Unpin,
+    N: Into<[u8; 4]>,
+{
+    if interval.is_zero() {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "the interval must not be zero");
+        return Err(err.into());
+    }
+    let magic = network.into();
+    let start = tokio::time::Instant::now() + interval;
+    let mut ticks = tokio::time::interval_at(start, interval);
+    loop {
+        ticks.tick().await;
+        write_message(&mut writer, magic, &ping_message()).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProtocolError;
+    use crate::framing::{read_message, Network, DEFAULT_MAX_BODY_SIZE};
+    use crate::peers::GET_PEERS_CODE;
+
+    #[tokio::test]
+    async fn test_keep_alive() -> ProtocolResult<()> {
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let task = tokio::spawn(keep_alive(
+            writer,
+            Network::Testnet,
+            Duration::from_millis(10),
+        ));
+        for _ in 0..2 {
+            let message =
+                read_message(&mut reader, Network::Testnet.magic(), DEFAULT_MAX_BODY_SIZE).await?;
+            assert_eq!(message.code, GET_PEERS_CODE);
+            assert!(!is_pong(&message));
+        }
+
+        // The task stops once the connection is closed.
+        drop(reader);
+        let outcome = task.await.expect("keep alive should not panic");
+        assert!(matches!(outcome, Err(ProtocolError::Io(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_zero_interval() {
+        let (writer, _reader) = tokio::io::duplex(1024);
+        let outcome = keep_alive(writer, Network::Testnet, Duration::ZERO).await;
+        let Err(ProtocolError::Io(err)) = outcome else {
+            panic!("a zero interval should be rejected, got {outcome:?}");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
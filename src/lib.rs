@@ -3,11 +3,16 @@
 //!
 //! The handshake process is describes  [here](https://docs.ergoplatform.com/dev/p2p/p2p-handshake).
 //!
-//! This library itself doesn't use any timeout to operate, but as it is
-//! advise in communicating with any third party service, All call should be wrapped
-//! in a timeout. By not using an internal timeout feature, we keep the API and dependencies
-//! minimal and most importantly let users choose what library and strategy they are most
-//! comfortable with.
+//! The plain `handshake` functions don't use any timeout, but as it is
+//! advised in communicating with any third party service, their calls should
+//! be wrapped in one, ex. with `tokio::time::timeout`.
+//!
+//! A `HandshakeConfig` bounds the handshakes instead: `handshake_with_config`
+//! and the scans of the `batch` module enforce its overall `timeout`, its
+//! `connect_timeout` and `read_timeout`, retry the transient failures and,
+//! with the `cancellation` feature, abort once its token is cancelled. As
+//! the responder, `listener::HandshakeListener` disconnects the initiators
+//! not completing their handshake within its `handshake_timeout`.
 //!
 //! ```ignore
 //! use p2p_handshake::{handshake, Version};
@@ -18,13 +23,186 @@
 //! }).await;
 //! ```
 //!
+//! The library never panics on its own, errors are always reported through
+//! [`ProtocolError`].
+//!
+//! Without the default `std` feature, the crate is `no_std` and only holds
+//! the message encoder and decoder of the [`core`] module.
+//!
+//! Without the default `net` feature, the handshakes run over the streams
+//! supplied by the caller, see [`handshake_with_stream`], so that the crate
+//! builds for `wasm32-unknown-unknown` with only the `std` feature.
+//!
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod agent;
+#[cfg(feature = "std")]
+pub mod aggregate;
+#[cfg(feature = "net")]
+pub mod batch;
+#[cfg(feature = "sync")]
+pub mod blocking;
+#[cfg(feature = "std")]
+pub mod borrowed;
+#[cfg(feature = "std")]
+mod builder;
+#[cfg(feature = "net")]
+pub mod cache;
+#[cfg(feature = "std")]
+mod callback;
+#[cfg(feature = "std")]
+pub mod channel;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "codec")]
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod compression;
+#[cfg(feature = "net")]
+mod config;
+#[cfg(feature = "std")]
+pub mod conformance;
+pub mod core;
+#[cfg(feature = "crawl")]
+pub mod crawler;
+#[cfg(feature = "net")]
+pub mod dial;
+#[cfg(feature = "std")]
+mod diff;
+#[cfg(feature = "hickory-dns")]
+pub mod dns;
+#[cfg(feature = "std")]
+mod duration;
+#[cfg(feature = "std")]
 mod encoder;
+#[cfg(feature = "std")]
 mod error;
+#[cfg(feature = "json")]
+pub mod events;
+#[cfg(feature = "std")]
+mod expect;
+#[cfg(feature = "std")]
+pub mod feature;
+#[cfg(feature = "std")]
+pub mod framing;
+#[cfg(feature = "json")]
+pub mod history;
+#[cfg(feature = "net")]
+pub mod keepalive;
+#[cfg(feature = "net")]
+pub mod listener;
+#[cfg(feature = "net")]
+pub mod manager;
+#[cfg(feature = "net")]
+pub mod mdns;
+#[cfg(feature = "net")]
+pub mod monitor;
+#[cfg(feature = "std")]
+pub mod negotiation;
+#[cfg(feature = "net")]
+pub mod observer;
+#[cfg(feature = "onion")]
+pub mod onion;
+#[cfg(feature = "net")]
+mod outcome;
+#[cfg(feature = "std")]
+pub mod peers;
+#[cfg(feature = "wasm-policy")]
+pub mod plugin;
+#[cfg(feature = "std")]
+mod policy;
+#[cfg(feature = "net")]
+pub mod ports;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "std")]
+pub mod protocol;
+#[cfg(feature = "net")]
+pub mod ratelimit;
+#[cfg(feature = "std")]
+mod rng;
+#[cfg(feature = "net")]
+pub mod route;
+#[cfg(feature = "net")]
+pub mod sample;
+#[cfg(feature = "net")]
+pub mod seeds;
+#[cfg(feature = "socks")]
+pub mod socks;
+#[cfg(feature = "std")]
+pub mod spec;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "net")]
+pub mod stream;
+#[cfg(feature = "net")]
+mod sync;
+#[cfg(feature = "metrics")]
+pub mod telemetry;
+#[cfg(all(feature = "net", any(test, feature = "test-util")))]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "std")]
+pub mod versions;
+#[cfg(feature = "std")]
+pub mod wire;
+
+#[cfg(feature = "std")]
+pub use agent::NodeInfo;
+#[cfg(feature = "net")]
+pub use batch::{handshake_many, ProbeResult};
+#[cfg(feature = "std")]
+pub use borrowed::HandshakeMessageRef;
+#[cfg(feature = "std")]
+pub use builder::HandshakeBuilder;
+#[cfg(feature = "net")]
+pub use config::{Backoff, HandshakeConfig, ScanStrategy};
+#[cfg(feature = "std")]
+pub use diff::FieldDiff;
+#[cfg(feature = "std")]
+pub use duration::HumanDuration;
+#[cfg(feature = "std")]
+pub use encoder::{
+    read_handshake, read_handshake_raw, read_handshake_raw_with_limits, read_handshake_with_limits,
+    Capability, DecodeMode, HandshakeMessage, HandshakeResponse, TinyString, Version,
+};
+#[cfg(feature = "std")]
+pub use error::{DecodeError, ProtocolError, ProtocolResult};
+#[cfg(feature = "std")]
+pub use expect::{ExpectationReport, Expected, Unmet};
+#[cfg(feature = "std")]
+pub use feature::PeerFeature;
+#[cfg(feature = "net")]
+pub use manager::{PeerHandle, PeerManager};
+#[cfg(feature = "net")]
+pub use outcome::HandshakeOutcome;
+#[cfg(feature = "std")]
+pub use peers::{request_peers, AddressFilter, PeerSpec};
+#[cfg(feature = "std")]
+pub use policy::{HandshakePolicy, PolicyViolation};
+#[cfg(feature = "std")]
+pub use spec::DecodeLimits;
+#[cfg(feature = "net")]
+use std::future::Future;
+#[cfg(feature = "net")]
+use std::io;
+#[cfg(feature = "net")]
+use std::net::SocketAddr;
+#[cfg(feature = "net")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "net")]
+pub use stream::PeerStream;
 
-pub use encoder::{HandshakeMessage, TinyString, Version};
-use error::{ProtocolError, ProtocolResult};
+#[cfg(feature = "std")]
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "net")]
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::BufReader,
     net::{TcpStream, ToSocketAddrs},
 };
 
@@ -36,6 +214,7 @@ use tokio::{
 /// * `version` - The version of this client making the request
 /// * `on_accept` - A callback that gets called when the handshake is successful.
 ///
+#[cfg(feature = "net")]
 pub async fn handshake<A: ToSocketAddrs, F>(
     target_address: A,
     agent_name: &str,
@@ -45,23 +224,637 @@ pub async fn handshake<A: ToSocketAddrs, F>(
 where
     F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
 {
+    let request = HandshakeBuilder::new()
+        .agent_name(agent_name)
+        .version(version);
+    handshake_with_builder(target_address, &request, on_accept).await
+}
+
+/// Same as [`handshake`] but sends the request composed by the given builder,
+/// giving full control over the fields written on the wire.
+///
+/// * `target_address` - The address and port of this target node (ex. 127.0.0.1:9030).
+/// * `request` - The builder used to produce the request.
+/// * `on_accept` - A callback that gets called when the handshake is successful.
+///
+#[cfg(feature = "net")]
+pub async fn handshake_with_builder<A: ToSocketAddrs, F>(
+    target_address: A,
+    request: &HandshakeBuilder,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
+{
+    // Compose the request before dialing so that invalid fields
+    // are reported without touching the network. Encoded once per
+    // connection, a buffer held across the awaits would grow the future
+    // of every probe by the maximum message size.
+    request.check_plaintext()?;
+    let data = request.encode()?;
+
     // Making the connection
-    let mut stream = TcpStream::connect(target_address).await?;
+    let stream = connect(target_address).await?;
+
+    handshake_encoded(stream, request, &data, on_accept).await
+}
+
+/// Same as [`handshake_with_builder`] but `on_accept` returns a future, so
+/// that the work following the handshake can await, ex. exchanging more
+/// messages on the stream. The output of the future is returned.
+///
+/// ```no_run
+/// # async fn run() -> p2p_handshake::ProtocolResult<()> {
+/// use p2p_handshake::framing::{write_message, Network};
+/// use p2p_handshake::peers::get_peers_message;
+/// use p2p_handshake::{handshake_async_cb, HandshakeBuilder};
+///
+/// let request = HandshakeBuilder::new().agent_name("paul");
+/// handshake_async_cb("127.0.0.1:9030", &request, |mut stream, _reply| async move {
+///     write_message(&mut stream, Network::Mainnet.magic(), &get_peers_message()).await
+/// })
+/// .await
+/// # }
+/// ```
+#[cfg(feature = "net")]
+pub async fn handshake_async_cb<A, F, Fut, T>(
+    target_address: A,
+    request: &HandshakeBuilder,
+    on_accept: F,
+) -> ProtocolResult<T>
+where
+    A: ToSocketAddrs,
+    F: FnOnce(TcpStream, HandshakeMessage) -> Fut,
+    Fut: Future<Output = ProtocolResult<T>>,
+{
+    request.check_plaintext()?;
+    let data = request.encode()?;
+    let mut stream = connect(target_address).await?;
+    let response = exchange(&mut stream, &data).await?;
+    request.check_self_connection(&response)?;
+    callback::call_async(|| on_accept(stream, response)).await
+}
+
+/// Same as [`handshake_with_builder`] but runs the protocol on a stream
+/// the caller already connected, ex. taken from a connection pool or
+/// dialed through a custom transport.
+///
+/// * `stream` - The connected stream, handed back to `on_accept`.
+/// * `request` - The builder used to produce the request.
+/// * `on_accept` - A callback that gets called when the handshake is successful.
+///
+#[cfg(feature = "std")]
+pub async fn handshake_with_stream<S, F>(
+    stream: S,
+    request: &HandshakeBuilder,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(S, HandshakeMessage) -> ProtocolResult<()>,
+{
+    let data = request.encode()?;
+    handshake_encoded(stream, request, &data, on_accept).await
+}
+
+#[cfg(feature = "std")]
+async fn handshake_encoded<S, F>(
+    mut stream: S,
+    request: &HandshakeBuilder,
+    data: &[u8],
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(S, HandshakeMessage) -> ProtocolResult<()>,
+{
+    let response = exchange(&mut stream, data).await?;
+    request.check_self_connection(&response)?;
+    callback::call(|| on_accept(stream, response))
+}
+
+/// Same as [`handshake_with_builder`] but applies the timeouts, retries and
+/// policy of the configuration, so callers don't have to wrap the call.
+/// The request may be sent over TLS, see the `tls` module, the stream is
+/// handed over as a [`PeerStream`].
+///
+/// ```no_run
+/// # async fn run() -> p2p_handshake::ProtocolResult<()> {
+/// use std::time::Duration;
+/// use p2p_handshake::{handshake_with_config, Backoff, HandshakeBuilder, HandshakeConfig};
+///
+/// let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"))
+///     .connect_timeout(Duration::from_secs(5))
+///     .read_timeout(Duration::from_secs(30))
+///     .retries(2, Backoff::default());
+/// handshake_with_config("127.0.0.1:9030", &config, |_stream, reply| {
+///     println!("{}", reply.agent_name);
+///     Ok(())
+/// })
+/// .await
+/// # }
+/// ```
+#[cfg(feature = "net")]
+pub async fn handshake_with_config<A: route::Target + Clone, F>(
+    target_address: A,
+    config: &HandshakeConfig,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(PeerStream, HandshakeMessage) -> ProtocolResult<()>,
+{
+    let mut result = ProbeResult::new(());
+    let (stream, response) =
+        batch::connect_and_exchange(target_address, config, &mut result).await?;
+    callback::call(|| on_accept(stream, response))
+}
+
+/// Same as [`handshake_with_builder`] but also hands the exact bytes of the
+/// target reply to `on_accept`, alongside the decoded message.
+#[cfg(feature = "net")]
+pub async fn handshake_with_raw<A: ToSocketAddrs, F>(
+    target_address: A,
+    request: &HandshakeBuilder,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TcpStream, HandshakeResponse) -> ProtocolResult<()>,
+{
+    request.check_plaintext()?;
+    let data = request.encode()?;
+    let mut stream = connect(target_address).await?;
+    let response = exchange_raw(&mut stream, &data).await?;
+    request.check_self_connection(&response.message)?;
+    callback::call(|| on_accept(stream, response))
+}
 
-    // Compose the request and send to the wire.
-    let request = HandshakeMessage {
-        agent_name: agent_name.try_into().map_err(ProtocolError::Unknown)?,
-        version,
-        peer_name: TinyString("evan-testnet".into()),
+/// Same as [`handshake_with_builder`] but returns the reply and the stream
+/// along with the timings and sizes of the handshake, instead of calling a
+/// callback. Name resolution, connection and round trip are measured
+/// separately.
+///
+/// ```no_run
+/// # async fn run() -> p2p_handshake::ProtocolResult<()> {
+/// use p2p_handshake::{handshake_info, HandshakeBuilder};
+///
+/// let request = HandshakeBuilder::new().agent_name("paul");
+/// let outcome = handshake_info("127.0.0.1:9030", &request).await?;
+/// println!("{} replied in {:?}", outcome.message.agent_name, outcome.rtt);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "net")]
+pub async fn handshake_info<A: ToSocketAddrs>(
+    target_address: A,
+    request: &HandshakeBuilder,
+) -> ProtocolResult<HandshakeOutcome> {
+    request.check_plaintext()?;
+    let data = request.encode()?;
+
+    let started = Instant::now();
+    let addresses = dial::interleave(resolve(target_address).await?);
+    let resolve_latency = started.elapsed();
+
+    let started = Instant::now();
+    let mut stream = dial::connect_any(addresses, dial::CONNECTION_ATTEMPT_DELAY, None).await?;
+    let connect_latency = started.elapsed();
+    let resolved_addr = stream.peer_addr()?;
+
+    let started = Instant::now();
+    let response = exchange_raw(&mut stream, &data).await?;
+    let rtt = started.elapsed();
+    request.check_self_connection(&response.message)?;
+
+    Ok(HandshakeOutcome {
+        node_info: response.message.node_info(),
+        negotiation: negotiation::negotiate(request.version_ref(), &response.message.version),
+        message: response.message,
+        stream,
+        resolve_latency,
+        connect_latency,
+        rtt,
+        resolved_addr,
+        bytes_sent: data.len(),
+        bytes_received: response.raw.len(),
+    })
+}
+
+/// Same as [`handshake_with_builder`] but rejects the peer with a
+/// [`ProtocolError::PolicyViolation`] when its reply breaks the policy,
+/// `on_accept` is only called for accepted peers.
+#[cfg(feature = "net")]
+pub async fn handshake_with_policy<A: ToSocketAddrs, F>(
+    target_address: A,
+    request: &HandshakeBuilder,
+    policy: &HandshakePolicy,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
+{
+    handshake_with_builder(target_address, request, |stream, response| {
+        policy.check(&response)?;
+        on_accept(stream, response)
+    })
+    .await
+}
+
+/// Handshakes the target and checks its reply against the expectations,
+/// returning the reply along with every unmet expectation rather than
+/// failing at the first one.
+///
+/// ```no_run
+/// # async fn run() -> p2p_handshake::ProtocolResult<()> {
+/// use p2p_handshake::{handshake_expect, Expected, HandshakeBuilder, Version};
+///
+/// let expected = Expected {
+///     min_version: Some(Version([5, 0, 21])),
+///     required_features: vec![16],
+///     ..Default::default()
+/// };
+/// let request = HandshakeBuilder::new().agent_name("paul");
+/// let report = handshake_expect("127.0.0.1:9030", &request, &expected).await?;
+/// print!("{report}");
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "net")]
+pub async fn handshake_expect<A: ToSocketAddrs>(
+    target_address: A,
+    request: &HandshakeBuilder,
+    expected: &Expected,
+) -> ProtocolResult<ExpectationReport> {
+    let outcome = handshake_info(target_address, request).await?;
+    Ok(expected.check(outcome.message))
+}
+
+/// Same as [`handshake_with_builder`] but runs the handshake of the codec,
+/// ex. of another P2P network, see [`protocol`]. `on_accept` receives the
+/// validated reply and the stream, buffered as it may hold the bytes the
+/// peer sent after its reply. The output of `on_accept` is returned.
+#[cfg(feature = "net")]
+pub async fn handshake_with_codec<A, C, F, T>(
+    target_address: A,
+    codec: &C,
+    on_accept: F,
+) -> ProtocolResult<T>
+where
+    A: ToSocketAddrs,
+    C: protocol::HandshakeCodec + ?Sized,
+    F: FnOnce(BufReader<TcpStream>, C::Response) -> ProtocolResult<T>,
+{
+    let mut stream = BufReader::new(connect(target_address).await?);
+    let response = protocol::exchange(&mut stream, codec).await?;
+    callback::call(|| on_accept(stream, response))
+}
+
+/// Completes the handshake as the responder of an incoming connection,
+/// sending the reply composed by the builder and returning the request
+/// of the initiator.
+///
+/// The reply is sent without waiting for the request, as both peers send
+/// their handshake as soon as the connection is established. Use
+/// [`HandshakePolicy::check`] on the returned request to filter initiators.
+#[cfg(feature = "std")]
+pub async fn respond_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    reply: &HandshakeBuilder,
+) -> ProtocolResult<HandshakeMessage> {
+    let data = reply.encode()?;
+    let request = exchange(stream, &data).await?;
+    reply.check_self_connection(&request)?;
+    Ok(request)
+}
+
+/// Resolves the target and connects to the first address accepting the
+/// connection, trying the addresses with the Happy Eyeballs algorithm of
+/// [`dial`], tracing each step.
+#[cfg(feature = "net")]
+pub(crate) async fn connect<A: ToSocketAddrs>(target_address: A) -> ProtocolResult<TcpStream> {
+    connect_with(target_address, dial::CONNECTION_ATTEMPT_DELAY, None, None).await
+}
+
+/// Same as [`connect`] with the delay between two connection attempts,
+/// binding the sockets to the local address if any and running the hook
+/// before every attempt.
+#[cfg(feature = "net")]
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+pub(crate) async fn connect_with<A: ToSocketAddrs>(
+    target_address: A,
+    attempt_delay: Duration,
+    local_address: Option<SocketAddr>,
+    pre_connect: Option<dial::PreConnectHook>,
+) -> ProtocolResult<TcpStream> {
+    let addresses = dial::interleave(resolve(target_address).await?);
+    Ok(dial::connect_any_with(addresses, attempt_delay, local_address, pre_connect).await?)
+}
+
+#[cfg(feature = "net")]
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+pub(crate) async fn resolve<A: ToSocketAddrs>(target_address: A) -> io::Result<Vec<SocketAddr>> {
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host(target_address).await?.collect();
+    #[cfg(feature = "tracing")]
+    tracing::debug!(?addresses, "resolved target");
+    Ok(addresses)
+}
+
+#[cfg(feature = "net")]
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug"))]
+pub(crate) async fn connect_address(
+    address: SocketAddr,
+    local_address: Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    let result = match local_address {
+        Some(local) => dial::connect_from(local, address).await,
+        None => TcpStream::connect(address).await,
     };
+    #[cfg(feature = "tracing")]
+    match &result {
+        Ok(_) => tracing::debug!("connected"),
+        Err(err) => tracing::debug!(error = %err, "connection failed"),
+    }
+    result
+}
+
+/// Sends the encoded request and reads the target response.
+#[cfg(feature = "std")]
+pub(crate) async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &[u8],
+) -> ProtocolResult<HandshakeMessage> {
+    Ok(exchange_raw(stream, request).await?.message)
+}
 
-    let data = request.encode_for_request()?;
-    stream.write_all(&data).await?;
+/// Same as [`exchange`] but keeps the bytes of the target response.
+#[cfg(feature = "std")]
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+pub(crate) async fn exchange_raw<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &[u8],
+) -> ProtocolResult<HandshakeResponse> {
+    // Send the request to the wire.
+    stream.write_all(request).await?;
+    #[cfg(feature = "tracing")]
+    tracing::debug!(bytes = request.len(), "request sent");
 
     // Read just enough data from the wire to extract the target response.
-    let mut raw_response = vec![0; 255];
-    stream.read(&mut raw_response).await?;
-    let response = HandshakeMessage::decode_from_response(raw_response)?;
+    let result = read_handshake_raw(stream, spec::MAX_HANDSHAKE_SIZE).await;
+    #[cfg(feature = "tracing")]
+    match &result {
+        Ok(response) => tracing::debug!(
+            bytes = response.raw.len(),
+            agent_name = %response.message.agent_name,
+            version = %response.message.version,
+            "reply decoded"
+        ),
+        Err(err) => tracing::debug!(error = %err, "reply not decoded"),
+    }
+    result
+}
+
+#[cfg(all(feature = "net", test))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::framing::Network;
+
+    #[tokio::test]
+    async fn test_respond_handshake() -> ProtocolResult<()> {
+        let (mut initiator, mut responder) = tokio::io::duplex(1024);
+        let reply = HandshakeBuilder::new().agent_name("ergoref");
+        let node = tokio::spawn(async move { respond_handshake(&mut responder, &reply).await });
+
+        let request = HandshakeBuilder::new().agent_name("paul").encode()?;
+        let response = exchange(&mut initiator, &request).await?;
+        assert_eq!(response.agent_name.as_str(), "ergoref");
+
+        let received = node.await.expect("responder should not panic")?;
+        assert_eq!(received.agent_name.as_str(), "paul");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_callback_panicked() -> ProtocolResult<()> {
+        let (initiator, mut responder) = tokio::io::duplex(1024);
+        let reply = HandshakeBuilder::new().agent_name("ergoref");
+        tokio::spawn(async move { respond_handshake(&mut responder, &reply).await });
+
+        let request = HandshakeBuilder::new().agent_name("paul");
+        let outcome = handshake_with_stream(initiator, &request, |_stream, reply| {
+            panic!("cannot handle {}", reply.agent_name)
+        })
+        .await;
+        let Err(ProtocolError::CallbackPanicked(message)) = outcome else {
+            panic!("expected the panic to be caught");
+        };
+        assert_eq!(message, "cannot handle ergoref");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_self_connection() -> ProtocolResult<()> {
+        let request = HandshakeBuilder::new()
+            .agent_name("paul")
+            .session(Network::Mainnet);
+        // The responder is the same node, answering with the same session.
+        let (initiator, mut responder) = tokio::io::duplex(1024);
+        let reply = request.clone().agent_name("ergoref");
+        let responded =
+            tokio::spawn(async move { respond_handshake(&mut responder, &reply).await });
+        let outcome = handshake_with_stream(initiator, &request, |_, _| Ok(())).await;
+        assert!(matches!(outcome, Err(ProtocolError::SelfConnection)));
+        assert!(matches!(
+            responded.await,
+            Ok(Err(ProtocolError::SelfConnection))
+        ));
+
+        let (initiator, mut responder) = tokio::io::duplex(1024);
+        let reply = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .session(Network::Mainnet);
+        tokio::spawn(async move { respond_handshake(&mut responder, &reply).await });
+        handshake_with_stream(initiator, &request, |_, _| Ok(())).await
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_policy() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .version(Version([4, 0, 100]))
+            .build()?;
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+
+        let policy = HandshakePolicy::new().min_version(Version([5, 0, 0]));
+        let mut accepted = false;
+        let result =
+            handshake_with_policy(node.address(), &HandshakeBuilder::new(), &policy, |_, _| {
+                accepted = true;
+                Ok(())
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::PolicyViolation(
+                PolicyViolation::VersionTooLow { .. }
+            ))
+        ));
+        assert!(!accepted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_info() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .version(Version([5, 0, 12]));
+        let reply_len = reply.encode()?.len();
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(reply.build()?)
+            .spawn()
+            .await?;
+
+        let request = HandshakeBuilder::new()
+            .agent_name("paul")
+            .version(Version([5, 0, 21]));
+        let outcome = handshake_info(node.address(), &request).await?;
+        assert_eq!(outcome.message.agent_name.as_str(), "ergoref");
+        assert_eq!(outcome.node_info.client, "ergoref");
+        assert!(outcome.negotiation.proceed());
+        assert!(outcome.negotiation.supports(Capability::UtxoSnapshots));
+        assert!(!outcome.negotiation.supports(Capability::Nipopow));
+        assert_eq!(outcome.resolved_addr, node.address());
+        assert_eq!(outcome.stream.peer_addr()?, node.address());
+        assert_eq!(outcome.bytes_sent, request.encode()?.len());
+        assert_eq!(outcome.bytes_received, reply_len);
+        assert!(outcome.rtt > Duration::ZERO);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_expect() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .version(Version([5, 0, 21]));
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(reply.build()?)
+            .spawn()
+            .await?;
+
+        let expected = Expected {
+            agent_matches: Some("ergo*".to_string()),
+            min_version: Some(Version([5, 0, 21])),
+            required_features: vec![feature::MODE_FEATURE_ID],
+            ..Default::default()
+        };
+        let report = handshake_expect(node.address(), &HandshakeBuilder::new(), &expected).await?;
+        assert_eq!(
+            report.unmet,
+            [Unmet::MissingFeature(feature::MODE_FEATURE_ID)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_raw() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        let expected = reply.encode_with_timestamp(reply.timestamp)?;
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+
+        let mut response = None;
+        handshake_with_raw(node.address(), &HandshakeBuilder::new(), |_, reply| {
+            response = Some(reply);
+            Ok(())
+        })
+        .await?;
+        let response = response.unwrap();
+        assert_eq!(response.raw, expected);
+        assert_eq!(response.message.agent_name.as_str(), "ergoref");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_stream() -> ProtocolResult<()> {
+        let (initiator, mut responder) = tokio::io::duplex(1024);
+        let reply = HandshakeBuilder::new().agent_name("ergoref");
+        let node = tokio::spawn(async move { respond_handshake(&mut responder, &reply).await });
+
+        let request = HandshakeBuilder::new().agent_name("paul");
+        let mut agent_name = None;
+        handshake_with_stream(initiator, &request, |_, reply| {
+            agent_name = Some(reply.agent_name.to_string());
+            Ok(())
+        })
+        .await?;
+        assert_eq!(agent_name.as_deref(), Some("ergoref"));
+        let received = node.await.expect("responder should not panic")?;
+        assert_eq!(received.agent_name.as_str(), "paul");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_async_cb() -> ProtocolResult<()> {
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(HandshakeBuilder::new().agent_name("ergoref").build()?)
+            .then_expect_frame(peers::GET_PEERS_CODE)
+            .spawn()
+            .await?;
+        let request = HandshakeBuilder::new().agent_name("paul");
+        let agent_name =
+            handshake_async_cb(node.address(), &request, |mut stream, reply| async move {
+                let magic = framing::Network::Mainnet.magic();
+                framing::write_message(&mut stream, magic, &peers::get_peers_message()).await?;
+                Ok(reply.agent_name.to_string())
+            })
+            .await?;
+        assert_eq!(agent_name, "ergoref");
+        let transcript = node.finish().await?;
+        assert_eq!(transcript.frames.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect() -> ProtocolResult<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let stream = connect(address).await?;
+        assert_eq!(stream.peer_addr()?, address);
+
+        drop(listener);
+        let Err(ProtocolError::Io(err)) = connect(address).await else {
+            panic!("expected a connection failure");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_config() -> ProtocolResult<()> {
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(HandshakeBuilder::new().agent_name("ergoref").build()?)
+            .spawn()
+            .await?;
+        let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"))
+            .connect_timeout(Duration::from_secs(5))
+            .read_timeout(Duration::from_secs(5));
+        let mut agent_name = None;
+        handshake_with_config(node.address(), &config, |_, reply| {
+            agent_name = Some(reply.agent_name.to_string());
+            Ok(())
+        })
+        .await?;
+        assert_eq!(agent_name.as_deref(), Some("ergoref"));
 
-    on_accept(stream, response)
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let config = config.read_timeout(Duration::from_millis(50));
+        let result = handshake_with_config(silent.local_addr()?, &config, |_, _| Ok(())).await;
+        assert!(matches!(result, Err(ProtocolError::TimedOut)));
+        Ok(())
+    }
 }
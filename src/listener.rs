@@ -0,0 +1,330 @@
+//! This module implements accepting incoming handshakes with the
+//! protections needed to expose a responder publicly.
+//!
+//! A [`HandshakeListener`] bounds the resources an initiator can hold:
+//! the number of connections open at the same time from a single IP
+//! address, the duration of the handshake and the size of the request.
+//! Initiators breaking them are disconnected, with
+//! [`ProtocolError::TooManyConnections`], [`ProtocolError::HandshakeDeadline`]
+//! and [`ProtocolError::MessageTooLarge`] respectively.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use std::time::Duration;
+//! use p2p_handshake::listener::HandshakeListener;
+//! use p2p_handshake::HandshakeBuilder;
+//!
+//! let reply = HandshakeBuilder::new().agent_name("paul");
+//! let listener = HandshakeListener::bind("0.0.0.0:9030", reply)
+//!     .await?
+//!     .handshake_timeout(Duration::from_secs(10))
+//!     .max_connections_per_ip(2);
+//! loop {
+//!     // Rejected initiators are reported without stopping the listener.
+//!     let Ok(incoming) = listener.accept().await else {
+//!         continue;
+//!     };
+//!     tokio::spawn(async move {
+//!         if let Ok(peer) = incoming.handshake().await {
+//!             println!("{}: {}", peer.remote, peer.request.agent_name);
+//!         }
+//!     });
+//! }
+//! # }
+//! ```
+//!
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::time::Instant;
+
+use crate::builder::HandshakeBuilder;
+use crate::encoder::{read_handshake_with_limits, HandshakeMessage};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::spec::{self, DecodeLimits};
+use crate::sync::lock;
+
+/// The reference node drops the initiators not completing their
+/// handshake within 30s.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections open at the same time from a single IP address by default,
+/// enough for the nodes behind a shared address.
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+
+type Connections = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Accepts incoming connections and answers their handshakes, see the
+/// [module](self) documentation.
+#[derive(Debug)]
+pub struct HandshakeListener {
+    listener: TcpListener,
+    reply: HandshakeBuilder,
+    handshake_timeout: Duration,
+    max_connections_per_ip: usize,
+    limits: DecodeLimits,
+    connections: Connections,
+}
+
+/// A connection accepted within the limits, whose handshake is yet to be
+/// completed.
+#[derive(Debug)]
+pub struct IncomingConnection {
+    stream: TcpStream,
+    remote: SocketAddr,
+    reply: HandshakeBuilder,
+    handshake_timeout: Duration,
+    accepted: Instant,
+    limits: DecodeLimits,
+    slot: ConnectionSlot,
+}
+
+/// An initiator which completed its handshake. The connection counts in
+/// the limit of its IP address until this is dropped.
+#[derive(Debug)]
+pub struct AcceptedPeer {
+    pub stream: TcpStream,
+    pub remote: SocketAddr,
+    /// The handshake of the initiator.
+    pub request: HandshakeMessage,
+    _slot: ConnectionSlot,
+}
+
+/// Counts a connection in the limit of its IP address while alive.
+#[derive(Debug)]
+struct ConnectionSlot {
+    ip: IpAddr,
+    connections: Connections,
+}
+
+impl HandshakeListener {
+    /// Listens on the address, answering the handshakes with the reply
+    /// composed by the builder.
+    pub async fn bind<A: ToSocketAddrs>(
+        address: A,
+        reply: HandshakeBuilder,
+    ) -> ProtocolResult<Self> {
+        Ok(Self::from_listener(
+            TcpListener::bind(address).await?,
+            reply,
+        ))
+    }
+
+    pub fn from_listener(listener: TcpListener, reply: HandshakeBuilder) -> Self {
+        Self {
+            listener,
+            reply,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            limits: DecodeLimits::default(),
+            connections: Connections::default(),
+        }
+    }
+
+    /// Maximum duration between accepting a connection and decoding the
+    /// handshake of the initiator.
+    pub fn handshake_timeout(mut self, timeout: impl Into<Duration>) -> Self {
+        self.handshake_timeout = timeout.into();
+        self
+    }
+
+    /// Maximum number of connections open at the same time from a single
+    /// IP address, handshaken or not.
+    pub fn max_connections_per_ip(mut self, limit: usize) -> Self {
+        self.max_connections_per_ip = limit.max(1);
+        self
+    }
+
+    /// Maximum size of the handshake of the initiators, in bytes, at most
+    /// [`MAX_HANDSHAKE_SIZE`](crate::spec::MAX_HANDSHAKE_SIZE).
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.limits.max_message = size.min(spec::MAX_HANDSHAKE_SIZE);
+        self
+    }
+
+    /// Limits enforced while decoding the handshake of the initiators,
+    /// replacing the maximum message size.
+    pub fn decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn local_addr(&self) -> ProtocolResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Number of connections open from the IP address.
+    pub fn connections_from(&self, ip: IpAddr) -> usize {
+        lock(&self.connections)
+            .get(&ip)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Waits for the next connection. A connection from an IP address
+    /// holding the maximum number of connections already is closed at once
+    /// and reported as [`ProtocolError::TooManyConnections`], the listener
+    /// can keep accepting afterwards.
+    pub async fn accept(&self) -> ProtocolResult<IncomingConnection> {
+        let (stream, remote) = self.listener.accept().await?;
+        let slot = {
+            let mut connections = lock(&self.connections);
+            let count = connections.entry(remote.ip()).or_default();
+            if *count >= self.max_connections_per_ip {
+                return Err(ProtocolError::TooManyConnections {
+                    ip: remote.ip(),
+                    limit: self.max_connections_per_ip,
+                });
+            }
+            *count += 1;
+            ConnectionSlot {
+                ip: remote.ip(),
+                connections: self.connections.clone(),
+            }
+        };
+        Ok(IncomingConnection {
+            stream,
+            remote,
+            reply: self.reply.clone(),
+            handshake_timeout: self.handshake_timeout,
+            accepted: Instant::now(),
+            limits: self.limits,
+            slot,
+        })
+    }
+}
+
+impl IncomingConnection {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Sends the reply and reads the handshake of the initiator, within
+    /// the deadline and the size limit of the listener. The deadline runs
+    /// from the connection being accepted. The connection is closed when
+    /// the handshake fails.
+    pub async fn handshake(mut self) -> ProtocolResult<AcceptedPeer> {
+        let data = self.reply.encode()?;
+        let exchanging = async {
+            self.stream.write_all(&data).await?;
+            read_handshake_with_limits(&mut self.stream, &self.limits).await
+        };
+        let deadline = self.accepted + self.handshake_timeout;
+        let request = tokio::time::timeout_at(deadline, exchanging)
+            .await
+            .map_err(|_| ProtocolError::HandshakeDeadline(self.handshake_timeout))??;
+        self.reply.check_self_connection(&request)?;
+        Ok(AcceptedPeer {
+            stream: self.stream,
+            remote: self.remote,
+            request,
+            _slot: self.slot,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = lock(&self.connections);
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::encoder::read_handshake;
+    use crate::spec;
+
+    async fn listener() -> ProtocolResult<HandshakeListener> {
+        let reply = HandshakeBuilder::new().agent_name("responder");
+        HandshakeListener::bind("127.0.0.1:0", reply).await
+    }
+
+    #[tokio::test]
+    async fn test_handshake() -> ProtocolResult<()> {
+        let listener = listener().await?;
+        let address = listener.local_addr()?;
+        let initiator = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await?;
+            let request = HandshakeBuilder::new().agent_name("initiator").encode()?;
+            stream.write_all(&request).await?;
+            let reply = read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE).await?;
+            ProtocolResult::Ok((stream, reply))
+        });
+        let peer = listener.accept().await?.handshake().await?;
+        assert_eq!(peer.request.agent_name.as_str(), "initiator");
+        assert_eq!(listener.connections_from(peer.remote.ip()), 1);
+        let (_stream, reply) = initiator.await.unwrap()?;
+        assert_eq!(reply.agent_name.as_str(), "responder");
+
+        drop(peer);
+        assert_eq!(listener.connections_from(address.ip()), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connections_per_ip() -> ProtocolResult<()> {
+        let listener = listener().await?.max_connections_per_ip(2);
+        let address = listener.local_addr()?;
+        let mut streams = vec![];
+        for _ in 0..3 {
+            streams.push(TcpStream::connect(address).await?);
+        }
+        let first = listener.accept().await?;
+        let _second = listener.accept().await?;
+        let Err(ProtocolError::TooManyConnections { ip, limit: 2 }) = listener.accept().await
+        else {
+            panic!("expected the third connection to be rejected");
+        };
+        assert_eq!(ip, address.ip());
+        // The rejected connection is closed.
+        let mut buffer = [0; 1];
+        assert_eq!(streams[2].read(&mut buffer).await?, 0);
+
+        // A closed connection frees its slot.
+        drop(first);
+        let _stream = TcpStream::connect(address).await?;
+        assert!(listener.accept().await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deadline_and_size() -> ProtocolResult<()> {
+        let listener = listener()
+            .await?
+            .handshake_timeout(Duration::from_millis(50))
+            .max_message_size(32);
+        let address = listener.local_addr()?;
+
+        // The initiator never sends its handshake.
+        let _silent = TcpStream::connect(address).await?;
+        let result = listener.accept().await?.handshake().await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::HandshakeDeadline(timeout)) if timeout == Duration::from_millis(50)
+        ));
+
+        let mut large = TcpStream::connect(address).await?;
+        let request = HandshakeBuilder::new()
+            .agent_name("a".repeat(64))
+            .encode()?;
+        large.write_all(&request).await?;
+        let result = listener.accept().await?.handshake().await;
+        assert!(matches!(result, Err(ProtocolError::MessageTooLarge(32))));
+        assert_eq!(listener.connections_from(address.ip()), 0);
+        Ok(())
+    }
+}
@@ -1,51 +1,1022 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::time::Duration;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
 
-use p2p_handshake::{handshake, Version};
+use p2p_handshake::aggregate::Concentration;
+#[cfg(any(feature = "json", feature = "prometheus"))]
+use p2p_handshake::clock::{Clock, SystemClock};
+use p2p_handshake::compression::FileWriter;
+use p2p_handshake::conformance::TestVector;
+use p2p_handshake::framing::Network;
+use p2p_handshake::listener::DEFAULT_MAX_CONNECTIONS_PER_IP;
+use p2p_handshake::mdns::MdnsResolver;
+#[cfg(feature = "prometheus")]
+use p2p_handshake::prometheus;
+use p2p_handshake::route::TargetRouter;
+use p2p_handshake::sample::{self, SampleSummary};
+use p2p_handshake::seeds::Seeds;
+use p2p_handshake::spec;
+use p2p_handshake::versions::VersionTable;
+use p2p_handshake::wire::{hex_dump, Direction};
+use p2p_handshake::{
+    handshake, Backoff, HandshakeBuilder, HandshakeConfig, HandshakeMessage, HumanDuration,
+    ScanStrategy, Version,
+};
+
+mod cli;
+
+use cli::bench::{BenchOptions, Budget};
+use cli::config::{EffectiveConfig, RunConfig};
+#[cfg(feature = "crawl")]
+use cli::crawl::{CrawlFormat, CrawlOptions};
+use cli::exit::{ExitStatus, TargetsFailed};
+use cli::listen::ListenOptions;
+#[cfg(feature = "tracing")]
+use cli::log::LogFormat;
+use cli::monitor::{MonitorFormat, MonitorOptions};
+use cli::output::{render, Field, OutputFormat, ProbeReport};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
-#[command(about, long_about = None)]
+#[command(about, long_about = None, subcommand_negates_reqs = true)]
 struct App {
-    /// Url of the target node
+    /// Url of the target node, repeat it to probe several nodes. A range
+    /// in CIDR notation, ex. `10.0.0.0/24:9030`, or comma separated ports,
+    /// ex. `host:9030,9053`, expand to a target per address and port
     #[arg(short, long)]
-    target: String,
+    target: Vec<String>,
+
+    /// File listing the target nodes, one per line
+    #[arg(long)]
+    targets_file: Option<PathBuf>,
 
     /// Name of the client node
     #[arg(short, long)]
-    name: String,
+    name: Option<String>,
 
     /// Version of the client node
     #[arg(short, long)]
     version: Option<Version>,
+
+    /// Public address advertised to the targets, ex. for nodes behind NAT
+    #[arg(long)]
+    declared_address: Option<SocketAddr>,
+
+    /// Local address the connections originate from, ex. `192.168.1.2:0`
+    /// on a multi-homed host, only targets of its family are dialed
+    #[arg(long)]
+    bind: Option<SocketAddr>,
+
+    /// SOCKS5 proxy the `.onion` targets are dialed through, ex. the Tor
+    /// proxy at `127.0.0.1:9050`, the default with the `onion` feature
+    #[cfg(feature = "socks")]
+    #[arg(long)]
+    onion_proxy: Option<String>,
+
+    /// Resolve the `.local` targets with multicast DNS
+    #[arg(long)]
+    mdns: bool,
+
+    /// Shell command run before every connection attempt, ex. to knock
+    /// the ports of the target, with the address in the `TARGET_IP` and
+    /// `TARGET_PORT` environment variables. A failure skips the attempt
+    #[arg(long)]
+    pre_connect: Option<String>,
+
+    /// Print the bytes of every handshake on the standard error, as
+    /// hexadecimal with the fields labeled
+    #[arg(long)]
+    dump_hex: bool,
+
+    /// Also probe the peers resolved from the default seeds of the
+    /// network, the known peers of the reference node
+    #[arg(long)]
+    use_seeds: bool,
+
+    /// Maximum number of handshakes running at the same time [default: 64]
+    #[arg(long)]
+    concurrency: Option<usize>,
+
+    /// Timeout of each handshake, ex. `10s` or `500ms` [default: 30s]
+    #[arg(long)]
+    timeout: Option<HumanDuration>,
+
+    /// Start at most this many connections per second over all the
+    /// targets, ex. to stay below the rate limits of the networks probed
+    #[arg(long)]
+    rate: Option<f64>,
+
+    /// Run at most this many handshakes at the same time with a single host
+    #[arg(long)]
+    per_host: Option<usize>,
+
+    /// Run at most this many handshakes at the same time within a single
+    /// /24 subnet, /48 in IPv6
+    #[arg(long)]
+    per_subnet: Option<usize>,
+
+    /// Sweep the targets with bare connections first, then handshake only
+    /// the ones accepting them, ex. for lists of mostly dead addresses
+    #[arg(long)]
+    two_phase: bool,
+
+    /// TOML file holding the options of the run, the command line options
+    /// take precedence
+    #[cfg(feature = "config-file")]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Probe only this many targets picked at random, and estimate the
+    /// statistics of the whole list
+    #[arg(long)]
+    sample: Option<usize>,
+
+    /// Seed of the random sample, the same seed picks the same targets
+    #[arg(long, default_value_t = 0, requires = "sample")]
+    seed: u64,
+
+    /// Print how the reachable targets concentrate in /24 subnets
+    #[arg(long)]
+    concentration: bool,
+
+    /// MaxMind ASN database, also print how the reachable targets
+    /// concentrate in autonomous systems
+    #[cfg(feature = "geoip")]
+    #[arg(long, requires = "concentration")]
+    asn_db: Option<PathBuf>,
+
+    /// Reject the peers advertising another network: mainnet, testnet,
+    /// devnet or the comma separated magic bytes of a custom network
+    #[arg(long)]
+    network: Option<Network>,
+
+    /// Table of the known releases of the reference node, replacing the
+    /// embedded one, peers reporting an unknown or obsolete version are
+    /// warned about
+    #[arg(long)]
+    known_versions: Option<PathBuf>,
+
+    /// WebAssembly module deciding which peers are accepted
+    #[cfg(feature = "wasm-policy")]
+    #[arg(long)]
+    policy_plugin: Option<PathBuf>,
+
+    /// Only report the results matching this expression, ex.
+    /// `version >= "5.0" && agent.contains("ergoref")`
+    #[cfg(feature = "filter")]
+    #[arg(long)]
+    filter: Option<String>,
+
+    /// Format of the results
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
+    /// Write the results to this file instead of the standard output,
+    /// `.gz` and `.zst` files are compressed
+    #[arg(long)]
+    output_file: Option<PathBuf>,
+
+    /// Also write Prometheus metrics of the probes to this file, for the
+    /// textfile collector of node_exporter
+    #[cfg(feature = "prometheus")]
+    #[arg(long)]
+    prom_textfile: Option<PathBuf>,
+
+    /// Append the connection, handshake and alert events of every probe to
+    /// this file, one JSON object per line
+    #[cfg(feature = "json")]
+    #[arg(long)]
+    event_log: Option<PathBuf>,
+
+    /// Expose the handshake metrics on this address, at the Prometheus
+    /// `/metrics` endpoint, the probe then keeps serving them until
+    /// interrupted
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Format of the logs of the handshake phases written to the standard
+    /// error
+    #[cfg(feature = "tracing")]
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+
+    /// Most verbose level of the logs, ex. `debug` for the phases of every
+    /// handshake
+    #[cfg(feature = "tracing")]
+    #[arg(long, default_value = "warn")]
+    log_level: tracing_subscriber::filter::LevelFilter,
+
+    /// JSON file keeping the last outcome of every target between runs,
+    /// the changes since the previous run are printed on the standard
+    /// error, ex. a version upgrade or a node gone offline
+    #[cfg(feature = "json")]
+    #[arg(long)]
+    history: Option<PathBuf>,
+
+    /// Skip the targets the history shows were probed less than this
+    /// duration ago, ex. `1h`
+    #[cfg(feature = "json")]
+    #[arg(long, requires = "history")]
+    skip_fresh: Option<HumanDuration>,
+
+    /// Comma separated fields to report, in order, ex. `target,version,latency_ms`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    fields: Vec<Field>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Handshake two nodes and print the differences between their replies
+    Compare {
+        /// Url of the first target node
+        first: String,
+
+        /// Url of the second target node
+        second: String,
+
+        /// Name of the client node
+        #[arg(short, long)]
+        name: String,
+
+        /// Version of the client node
+        #[arg(short, long)]
+        version: Option<Version>,
+    },
+
+    /// Check the replies of a reference node against the encoding of
+    /// this crate
+    Conformance {
+        #[command(subcommand)]
+        command: ConformanceCommand,
+    },
+
+    /// Accept incoming connections and answer their handshakes, printing
+    /// the peer spec of every initiator
+    Listen {
+        /// Address to listen on
+        #[arg(short, long, default_value = "0.0.0.0:9030")]
+        bind: SocketAddr,
+
+        /// Name of this node
+        #[arg(short, long)]
+        name: String,
+
+        /// Version of this node
+        #[arg(short, long)]
+        version: Option<Version>,
+
+        /// Public address advertised to the initiators
+        #[arg(long)]
+        declared_address: Option<SocketAddr>,
+
+        /// Disconnect the initiators not completing their handshake within
+        /// this duration
+        #[arg(long, default_value = "30s")]
+        handshake_timeout: HumanDuration,
+
+        /// Refuse the connections from an IP address holding this many
+        /// connections already
+        #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS_PER_IP)]
+        max_connections_per_ip: usize,
+
+        /// Disconnect the initiators whose handshake exceeds this size, in
+        /// bytes, at most the 8096 accepted by the reference node
+        #[arg(long, default_value_t = spec::MAX_HANDSHAKE_SIZE)]
+        max_message_size: usize,
+    },
+
+    /// Probe the targets every interval, tracking their uptime, latency
+    /// percentiles and version changes
+    Monitor {
+        /// Time between the start of two rounds, ex. `30s` or `5m`
+        #[arg(long, default_value = "60s")]
+        interval: HumanDuration,
+
+        /// Stop after this many rounds instead of running forever
+        #[arg(long)]
+        rounds: Option<u64>,
+
+        /// Format of the rounds
+        #[arg(short, long, value_enum, default_value_t = MonitorFormat::Table)]
+        output: MonitorFormat,
+    },
+
+    /// Crawl the network from the targets, following the peers they
+    /// announce, and print the discovered topology
+    #[cfg(feature = "crawl")]
+    Crawl {
+        /// Stop following the announcements this many hops away from the
+        /// targets
+        #[arg(long)]
+        max_depth: Option<usize>,
+
+        /// Stop after probing this many nodes
+        #[arg(long)]
+        max_nodes: Option<usize>,
+
+        /// Stop after this duration, ex. `10m`, dropping the nodes still
+        /// being probed
+        #[arg(long)]
+        max_duration: Option<HumanDuration>,
+
+        /// Format of the topology [default: json, or dot without the
+        /// `json` feature]
+        #[arg(long, value_enum)]
+        format: Option<CrawlFormat>,
+    },
+
+    /// Handshake an in-process responder over loopback and check both
+    /// peers received the exact messages, without touching real peers
+    SelfTest,
+
+    /// Perform loopback handshakes against an in-process responder and
+    /// report their throughput and latency, failing below the budget
+    Bench {
+        /// Number of handshakes to perform
+        #[arg(long, default_value_t = 1000)]
+        count: usize,
+
+        /// Number of handshakes running at the same time
+        #[arg(long, default_value_t = 1)]
+        concurrency: usize,
+
+        /// Fail below this many handshakes per second
+        #[arg(long)]
+        min_throughput: Option<f64>,
+
+        /// Fail when the 99th percentile latency exceeds this duration,
+        /// ex. `20ms`
+        #[arg(long)]
+        max_p99: Option<HumanDuration>,
+    },
+
+    /// Union several exported crawl results into a single file
+    #[cfg(feature = "json")]
+    Merge {
+        /// Paths of the exported files to merge
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<std::path::PathBuf>,
+
+        /// Path of the merged file
+        #[arg(short, long)]
+        output: std::path::PathBuf,
+    },
+
+    /// Write the peers of an exported crawl as the `knownPeers` setting of
+    /// the reference node, to seed a node deployment
+    #[cfg(feature = "json")]
+    KnownPeers {
+        /// Path of the exported crawl results
+        input: std::path::PathBuf,
+
+        /// Path of the node configuration fragment
+        #[arg(short, long)]
+        output: std::path::PathBuf,
+
+        /// Keep the peers with a private address, ex. for a local network
+        #[arg(long)]
+        allow_private: bool,
+
+        /// Write at most this many peers, the most recently seen ones
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+
+    /// Check a configuration file
+    #[cfg(feature = "config-file")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Maintain a peer store database
+    #[cfg(feature = "sqlite")]
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[cfg(feature = "config-file")]
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Parse the file, apply the defaults, cross-check the options and
+    /// print the effective configuration
+    Validate {
+        /// Path of the TOML configuration file
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConformanceCommand {
+    /// Handshake a trusted reference node, re-encode its reply and compare
+    /// it byte for byte, reporting what this crate cannot represent
+    Live {
+        /// Url of the reference node
+        #[arg(long)]
+        target: String,
+
+        /// Name of the client node
+        #[arg(short, long, default_value = "ergo-handshake")]
+        name: String,
+
+        /// Version of the client node
+        #[arg(short, long)]
+        version: Option<Version>,
+
+        /// Append the reply to this vectors file, with its source
+        #[arg(long)]
+        capture: Option<PathBuf>,
+
+        /// Network of the reference node, recorded in the source of the
+        /// captured reply
+        #[arg(long)]
+        network: Option<Network>,
+    },
+
+    /// Decode and re-encode the handshake test vectors, checking that
+    /// every vector round trips byte for byte
+    Vectors {
+        /// File of vectors replacing the embedded and captured ones, a
+        /// name followed by the message as hexadecimal on every line
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Drop stale peers so the database doesn't grow unboundedly
+    Prune {
+        /// Path of the SQLite peer store
+        path: std::path::PathBuf,
+
+        /// Drop peers not seen for this duration, ex. `30d`
+        #[arg(long)]
+        max_age: Option<HumanDuration>,
+
+        /// Keep at most this many peers, dropping the least recently seen
+        #[arg(long)]
+        max_entries: Option<usize>,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     let app = App::parse();
-    let version = match app.version {
-        Some(version) => version,
-        None => Version([3, 3, 6]), // default version
+    #[cfg(feature = "json")]
+    let json_errors = app.output != OutputFormat::Table;
+    let Err(err) = run(app).await else {
+        return ExitCode::SUCCESS;
+    };
+    let status = ExitStatus::of_error(&err);
+    #[cfg(feature = "json")]
+    if json_errors {
+        eprintln!("{}", cli::exit::error_json(&err, status));
+        return status.into();
+    }
+    eprintln!("Error: {err:?}");
+    status.into()
+}
+
+async fn run(mut app: App) -> Result<()> {
+    #[cfg(feature = "tracing")]
+    cli::log::init(app.log_format, app.log_level)?;
+    #[cfg(feature = "metrics")]
+    if let Some(address) = app.metrics_listen {
+        install_metrics_exporter(address)?;
+    }
+    match app.command.take() {
+        Some(Command::Compare {
+            first,
+            second,
+            name,
+            version,
+        }) => return compare(&first, &second, &name, version).await,
+        Some(Command::Conformance {
+            command:
+                ConformanceCommand::Live {
+                    target,
+                    name,
+                    version,
+                    capture,
+                    network,
+                },
+        }) => {
+            let capture = capture.map(|path| (path, network));
+            return conformance_live(&target, &name, version, capture).await;
+        }
+        Some(Command::Conformance {
+            command: ConformanceCommand::Vectors { file },
+        }) => return conformance_vectors(file.as_deref()),
+        #[cfg(feature = "crawl")]
+        Some(Command::Crawl {
+            max_depth,
+            max_nodes,
+            max_duration,
+            format,
+        }) => {
+            let (run, targets, config) = prepare(&app).await?;
+            let options = CrawlOptions {
+                network: run.network.unwrap_or_default(),
+                max_depth,
+                max_nodes,
+                max_duration: max_duration.map(Duration::from),
+                #[cfg(feature = "json")]
+                history: app.history.clone(),
+            };
+            let topology = cli::crawl::crawl(targets, &config, run.concurrency, options).await?;
+            let rendered = topology.render(format.unwrap_or_default())?;
+            match &app.output_file {
+                Some(path) => {
+                    let mut writer = FileWriter::create(path)?;
+                    writer.write_all(rendered.as_bytes())?;
+                    writer.finish()?;
+                }
+                None => print!("{rendered}"),
+            }
+            return Ok(());
+        }
+        Some(Command::SelfTest) => {
+            for check in cli::selftest::self_test().await? {
+                println!("ok: {check}");
+            }
+            println!("Self-test passed.");
+            return Ok(());
+        }
+        Some(Command::Bench {
+            count,
+            concurrency,
+            min_throughput,
+            max_p99,
+        }) => {
+            let options = BenchOptions {
+                count,
+                concurrency,
+                budget: Budget {
+                    min_throughput,
+                    max_p99: max_p99.map(Duration::from),
+                },
+            };
+            let report = cli::bench::bench(&options).await?;
+            println!("{report}");
+            return report.check(&options.budget);
+        }
+        #[cfg(feature = "json")]
+        Some(Command::Merge { inputs, output }) => {
+            let count = p2p_handshake::store::merge_exports(inputs, output)?;
+            println!("Merged {} peers.", count);
+            return Ok(());
+        }
+        #[cfg(feature = "json")]
+        Some(Command::KnownPeers {
+            input,
+            output,
+            allow_private,
+            limit,
+        }) => {
+            let mut store = p2p_handshake::store::PeerStore::new();
+            store.import(input)?;
+            let filter = p2p_handshake::AddressFilter {
+                allow_private,
+                ..Default::default()
+            };
+            let count = store.export_known_peers(output, &filter, limit)?;
+            println!("Wrote {} known peers.", count);
+            return Ok(());
+        }
+        #[cfg(feature = "sqlite")]
+        Some(Command::Db { command }) => return db(command),
+        #[cfg(feature = "config-file")]
+        Some(Command::Config {
+            command: ConfigCommand::Validate { path },
+        }) => {
+            let (config, warnings) = RunConfig::load(&path)?.resolve()?;
+            for warning in warnings {
+                eprintln!("warning: {warning}");
+            }
+            print!("{}", toml::to_string(&config)?);
+            return Ok(());
+        }
+        Some(Command::Listen {
+            bind,
+            name,
+            version,
+            declared_address,
+            handshake_timeout,
+            max_connections_per_ip,
+            max_message_size,
+        }) => {
+            let version = version.unwrap_or(Version([3, 3, 6]));
+            let mut reply = HandshakeBuilder::new().agent_name(name).version(version);
+            if let Some(address) = declared_address {
+                reply = reply.declared_address(address);
+            }
+            let options = ListenOptions {
+                handshake_timeout: handshake_timeout.into(),
+                max_connections_per_ip,
+                max_message_size,
+            };
+            return cli::listen::listen(bind, reply, options).await;
+        }
+        Some(Command::Monitor {
+            interval,
+            rounds,
+            output,
+        }) => {
+            let (run, targets, config) = prepare(&app).await?;
+            let options = MonitorOptions {
+                interval: Duration::from(interval).max(Duration::from_secs(1)),
+                rounds,
+                format: output,
+                #[cfg(feature = "json")]
+                history: app.history.clone(),
+            };
+            return cli::monitor::monitor(targets, &config, run.concurrency, options).await;
+        }
+        None => {}
+    }
+
+    let (run, mut targets, config) = prepare(&app).await?;
+    #[cfg(feature = "json")]
+    let mut history = app
+        .history
+        .as_deref()
+        .map(cli::history::HistoryFile::open)
+        .transpose()?;
+    #[cfg(feature = "json")]
+    if let (Some(history), Some(max_age)) = (&history, app.skip_fresh) {
+        let skipped = history.skip_fresh(&mut targets, max_age.into(), SystemClock.now_millis()?);
+        if skipped > 0 {
+            eprintln!("Skipped {skipped} targets probed within {max_age}.");
+        }
+    }
+    let population = targets.len();
+    if let Some(size) = app.sample {
+        targets = sample::sample(targets, size, app.seed);
+    }
+    #[cfg(feature = "filter")]
+    let filter = app
+        .filter
+        .as_deref()
+        .map(cli::filter::Filter::new)
+        .transpose()?;
+    let versions = match &app.known_versions {
+        Some(path) => VersionTable::load(path)?,
+        None => VersionTable::embedded(),
+    };
+    let results = cli::targets::probe_targets(targets, &config, run.concurrency).await;
+    for result in &results {
+        if let Ok(reply) = &result.outcome {
+            if let Some(warning) = versions.check(&reply.version) {
+                eprintln!("warning: {} reports an {warning}", result.target);
+            }
+        }
+    }
+    #[cfg(feature = "json")]
+    if let Some(history) = &mut history {
+        history.record(results.iter().map(|result| {
+            let outcome = result.outcome.as_ref();
+            (result.target.clone(), outcome, result.started_at)
+        }))?;
+    }
+    let reports: Vec<ProbeReport> = results.iter().map(ProbeReport::from).collect();
+    #[cfg(feature = "filter")]
+    let reports = match &filter {
+        Some(filter) => {
+            let mut matching = Vec::with_capacity(reports.len());
+            for report in reports {
+                if filter.matches(&report)? {
+                    matching.push(report);
+                }
+            }
+            matching
+        }
+        None => reports,
+    };
+    let rendered = render(&reports, app.output, &app.fields)?;
+    match &app.output_file {
+        Some(path) => {
+            let mut writer = FileWriter::create(path)?;
+            writer.write_all(rendered.as_bytes())?;
+            writer.finish()?;
+        }
+        None => print!("{}", rendered),
+    }
+    #[cfg(feature = "json")]
+    if let Some(path) = &app.event_log {
+        use p2p_handshake::events::{Event, EventLog};
+
+        // Probes of different runs appended to the same log stay distinct.
+        let run = SystemClock.now_millis()?;
+        let mut log = EventLog::append_to(path)?;
+        for (index, result) in results.iter().enumerate() {
+            for event in Event::from_probe(result, &format!("{run:x}-{index}")) {
+                log.write(&event)?;
+            }
+        }
+        log.flush()?;
+    }
+    #[cfg(feature = "prometheus")]
+    if let Some(path) = &app.prom_textfile {
+        let timestamp = SystemClock.now_millis()?;
+        prometheus::write_textfile(path, &prometheus::render_textfile(&results, timestamp))?;
+    }
+    if app.sample.is_some() {
+        // Keep the standard output parsable in the JSON formats.
+        eprint!("{}", SampleSummary::new(population, &results));
+    }
+    if app.concentration {
+        let reachable = results
+            .iter()
+            .filter(|result| result.outcome.is_ok())
+            .filter_map(|result| result.address.map(|address| address.ip()));
+        eprint!("By subnet: {}", Concentration::by_subnet(reachable.clone()));
+        #[cfg(feature = "geoip")]
+        if let Some(path) = &app.asn_db {
+            let database = p2p_handshake::aggregate::AsnDatabase::open(path)?;
+            eprint!("By ASN: {}", Concentration::by_asn(reachable, &database));
+        }
+    }
+    #[cfg(feature = "metrics")]
+    if app.metrics_listen.is_some() {
+        tokio::signal::ctrl_c().await?;
+    }
+
+    TargetsFailed::check(&results)?;
+    Ok(())
+}
+
+/// Latency buckets of the handshake histograms, in seconds, up to the
+/// handshake timeout of the reference node.
+#[cfg(feature = "metrics")]
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Serves the metrics recorded by the library on `address`.
+#[cfg(feature = "metrics")]
+fn install_metrics_exporter(address: SocketAddr) -> Result<()> {
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(address)
+        .set_buckets(&LATENCY_BUCKETS)?
+        .install()?;
+    p2p_handshake::telemetry::describe();
+    Ok(())
+}
+
+/// Resolves the options of the run, from the command line and the
+/// configuration file, into the targets and the handshake configuration.
+async fn prepare(app: &App) -> Result<(EffectiveConfig, Vec<String>, HandshakeConfig)> {
+    let args = RunConfig {
+        name: app.name.clone(),
+        version: app.version.clone(),
+        declared_address: app.declared_address,
+        bind: app.bind,
+        concurrency: app.concurrency,
+        timeout: app.timeout,
+        rate: app.rate,
+        per_host: app.per_host,
+        per_subnet: app.per_subnet,
+        network: app.network,
+        targets: app.target.clone(),
+        targets_file: app.targets_file.clone(),
+        use_seeds: app.use_seeds,
+        ..Default::default()
+    };
+    #[cfg(feature = "config-file")]
+    let args = match &app.config {
+        Some(path) => RunConfig::load(path)?.overridden_by(args),
+        None => args,
+    };
+    let (run, warnings) = args.resolve()?;
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+
+    let mut targets = run.targets.clone();
+    if let Some(path) = &run.targets_file {
+        targets.extend(cli::targets::read_targets_file(path)?);
+    }
+    targets = cli::targets::expand_targets(targets)?;
+    if run.use_seeds {
+        let seeds = Seeds::for_network(run.network.unwrap_or_default())
+            .resolve()
+            .await;
+        for (seed, err) in &seeds.failures {
+            eprintln!("warning: cannot resolve the seed {seed}: {err}");
+        }
+        targets.extend(seeds.addresses.iter().map(SocketAddr::to_string));
+        if targets.is_empty() {
+            bail!("no peer could be resolved from the seeds");
+        }
+    }
+
+    let mut request = HandshakeBuilder::new()
+        .agent_name(run.name.as_str())
+        .version(run.version.clone());
+    if let Some(address) = run.declared_address {
+        request = request.declared_address(address);
+    }
+    if let Some(network) = run.network {
+        request = request.session(network);
+    }
+    let mut config = HandshakeConfig::new(request)
+        .timeout(run.timeout)
+        .retries(run.retries, Backoff::default());
+    if let Some(address) = run.bind {
+        config = config.local_address(address);
+    }
+    if let Some(limiter) = run.rate_limiter() {
+        config = config.rate_limiter(limiter);
+    }
+    if app.dump_hex {
+        config = config.on_wire(|address, direction, data: &[u8]| {
+            let (arrow, preposition) = match direction {
+                Direction::Sent => (">>>", "to"),
+                Direction::Received => ("<<<", "from"),
+            };
+            eprint!(
+                "{arrow} {direction} {} bytes {preposition} {address}\n{}",
+                data.len(),
+                hex_dump(data)
+            );
+        });
+    }
+    let router = TargetRouter::new();
+    #[cfg(feature = "onion")]
+    let router = {
+        use p2p_handshake::onion::{OnionConnector, DEFAULT_TOR_PROXY};
+        let proxy = app.onion_proxy.as_deref().unwrap_or(DEFAULT_TOR_PROXY);
+        router.route(".onion", OnionConnector::new(proxy))
+    };
+    #[cfg(all(feature = "socks", not(feature = "onion")))]
+    let router = match &app.onion_proxy {
+        Some(proxy) => router.route(".onion", p2p_handshake::socks::SocksProxy::new(proxy)),
+        None => router,
     };
+    let router = if app.mdns {
+        router.route(".local", MdnsResolver::new())
+    } else {
+        router
+    };
+    config = config.router(router);
+    if let Some(command) = &app.pre_connect {
+        config = config.pre_connect(cli::hook::CommandHook::new(command));
+    }
+    if app.two_phase {
+        config = config.strategy(ScanStrategy::TwoPhase);
+    }
+    let policy = run.handshake_policy();
+    #[cfg(feature = "wasm-policy")]
+    let policy = match &app.policy_plugin {
+        Some(path) => {
+            let plugin = p2p_handshake::plugin::WasmPolicy::from_file(path)?;
+            Some(policy.unwrap_or_default().plugin(plugin))
+        }
+        None => policy,
+    };
+    if let Some(policy) = policy {
+        config = config.policy(policy);
+    }
+    Ok((run, targets, config))
+}
+
+/// Handshakes both targets and prints the differences between their replies.
+async fn compare(first: &str, second: &str, name: &str, version: Option<Version>) -> Result<()> {
+    let version = version.unwrap_or(Version([3, 3, 6]));
+    let first_reply = probe(first, name, version.clone()).await?;
+    let second_reply = probe(second, name, version).await?;
+    let diffs = first_reply.diff(&second_reply);
+    if diffs.is_empty() {
+        println!("No differences.");
+    }
+    for diff in diffs {
+        println!("{}", diff);
+    }
+    Ok(())
+}
+
+/// Checks the reply of the reference node, failing when it doesn't round
+/// trip or holds invalid known features. Unknown features are only reported.
+/// The reply is appended to the vectors file to capture it to, if any.
+async fn conformance_live(
+    target: &str,
+    name: &str,
+    version: Option<Version>,
+    capture: Option<(PathBuf, Option<Network>)>,
+) -> Result<()> {
+    let request = HandshakeBuilder::new()
+        .agent_name(name)
+        .version(version.unwrap_or(Version([3, 3, 6])));
+    let task = p2p_handshake::conformance::check_live(target, &request);
+    let report = tokio::time::timeout(HANDSHAKE_TIMEOUT, task).await??;
+    print!("{report}");
+    if let Some((path, network)) = capture {
+        let source = match network {
+            Some(network) => format!("{target}, {network}"),
+            None => target.to_string(),
+        };
+        let vector = TestVector::from_capture(&report, &source);
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("cannot open the vectors {}", path.display()))?;
+        std::io::Write::write_all(&mut file, vector.to_lines().as_bytes())?;
+        println!("captured: {} in {}", vector.name, path.display());
+    }
+    if !report.is_conformant() {
+        bail!("the reply of {target} doesn't conform");
+    }
+    Ok(())
+}
+
+/// Checks every test vector, failing when one of them doesn't conform.
+fn conformance_vectors(file: Option<&std::path::Path>) -> Result<()> {
+    let vectors = match file {
+        Some(path) => TestVector::load(path)
+            .with_context(|| format!("cannot read the vectors {}", path.display()))?,
+        None => [TestVector::embedded(), TestVector::captured()].concat(),
+    };
+    let mut failed = 0;
+    for vector in &vectors {
+        println!("vector: {}", vector.name);
+        match vector.check() {
+            Ok(report) => {
+                print!("{report}");
+                if !report.is_conformant() {
+                    failed += 1;
+                }
+            }
+            Err(err) => {
+                println!("error: {err}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} vectors don't conform", vectors.len());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn db(command: DbCommand) -> Result<()> {
+    use p2p_handshake::store::{PeerStore, RetentionPolicy, SqliteBackend};
+
+    match command {
+        DbCommand::Prune {
+            path,
+            max_age,
+            max_entries,
+        } => {
+            let policy = RetentionPolicy {
+                max_age: max_age.map(Duration::from),
+                max_entries,
+            };
+            let mut store = PeerStore::with_backend(SqliteBackend::open(path)?)?;
+            let removed = store.prune(&policy)?;
+            if removed > 0 {
+                store.backend().vacuum()?;
+            }
+            println!("Pruned {} peers, {} left.", removed, store.len());
+        }
+    }
+    Ok(())
+}
+
+// Because target node could be anything, we take care of timing-out after
+// a certain period.
+// The Ergo reference node implementation will timeout after 30s. We expect any good behaving
+// to follow this guideline. Anything taking longer than that period should
+// be avoided.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Performs a handshake with the target and returns its reply.
+async fn probe(target: &str, name: &str, version: Version) -> Result<HandshakeMessage> {
+    let mut reply = None;
 
     // We could pool the future right away, but we want to wrap
     // in a timeout future.
-    let task = handshake(&app.target, &app.name, version, |_stream, reply| {
-        println!("Handshake Reply: {:?}", reply);
-
+    let task = handshake(target, name, version, |_stream, message| {
         // On can keep using the stream for further work ...
-
+        reply = Some(message);
         Ok(())
     });
 
-    // Because target node could be anything, we take care of timing-out after
-    // a certain period.
-    // The Ergo reference node implementation will timeout after 30s. We expect any good behaving
-    // to follow this guideline. Anything taking longer than that period should
-    // be avoided.
-    tokio::time::timeout(Duration::from_secs(30), task).await??;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, task).await??;
 
-    Ok(())
+    Ok(reply.expect("reply should be set on successful handshake"))
 }
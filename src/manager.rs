@@ -0,0 +1,396 @@
+//! This module implements keeping long-lived connections to a set of
+//! peers.
+//!
+//! [`PeerManager`] dials every added peer, completes the handshake and
+//! keeps the connection in its pool. When a connection drops, the peer is
+//! dialed and handshaken again after a delay growing with the consecutive
+//! failures, see [`Backoff`].
+//!
+//! The framed messages received from the connected peers are published
+//! to the receivers returned by [`PeerManager::subscribe`], messages are
+//! sent through the [`PeerHandle`] of a peer or to all of them with
+//! [`PeerManager::broadcast`]. With [`PeerManager::keep_alive`], idle
+//! connections are kept open with the pings of the [`keepalive`](crate::keepalive)
+//! module.
+//!
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::ReadHalf;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::batch::{connect_and_exchange, ProbeResult};
+use crate::config::{Backoff, HandshakeConfig};
+use crate::encoder::HandshakeMessage;
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::framing::{read_message, write_message, Network, NetworkMessage, DEFAULT_MAX_BODY_SIZE};
+use crate::keepalive::ping_message;
+use crate::stream::PeerStream;
+use crate::sync::lock;
+
+/// Number of messages queued for a peer before [`PeerHandle::send`] waits.
+const OUTGOING_CAPACITY: usize = 64;
+
+/// Number of received messages kept for slow subscribers, older messages
+/// are dropped.
+const INCOMING_CAPACITY: usize = 1024;
+
+/// A message received from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerMessage {
+    pub target: String,
+    pub message: NetworkMessage,
+}
+
+/// A handshaken connection of the pool.
+///
+/// The handle stays usable after the connection drops, sending through it
+/// then fails with [`ProtocolError::Disconnected`] and a new handle is
+/// returned by [`PeerManager::get_peer`] once the peer is reconnected.
+#[derive(Debug, Clone)]
+pub struct PeerHandle {
+    pub target: String,
+    /// The address the connection was established with.
+    pub address: Option<SocketAddr>,
+    /// The handshake the peer replied with on this connection.
+    pub handshake: HandshakeMessage,
+    /// Number of the connection to this peer, starting at 1 and increased
+    /// on every reconnection.
+    pub connection: u64,
+    outgoing: mpsc::Sender<NetworkMessage>,
+}
+
+impl PeerHandle {
+    /// Queues the message to be sent to the peer.
+    pub async fn send(&self, message: NetworkMessage) -> ProtocolResult<()> {
+        self.outgoing
+            .send(message)
+            .await
+            .map_err(|_| ProtocolError::Disconnected)
+    }
+
+    /// Whether the connection of this handle is still open.
+    pub fn is_connected(&self) -> bool {
+        !self.outgoing.is_closed()
+    }
+}
+
+type Pool = Arc<Mutex<HashMap<String, PeerHandle>>>;
+
+/// Maintains handshaken connections to a set of peers, see the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct PeerManager {
+    config: HandshakeConfig,
+    network: Network,
+    reconnect_backoff: Backoff,
+    keep_alive: Option<Duration>,
+    pool: Pool,
+    incoming: broadcast::Sender<PeerMessage>,
+    tasks: HashMap<String, JoinHandle<()>>,
+}
+
+impl PeerManager {
+    /// Creates a manager handshaking peers with the configuration, its
+    /// timeouts, retries and policy apply to every (re)connection.
+    pub fn new(config: HandshakeConfig) -> Self {
+        let (incoming, _) = broadcast::channel(INCOMING_CAPACITY);
+        Self {
+            config,
+            network: Network::default(),
+            reconnect_backoff: Backoff::default(),
+            keep_alive: None,
+            pool: Pool::default(),
+            incoming,
+            tasks: HashMap::new(),
+        }
+    }
+
+    /// The network of the messages exchanged after the handshake.
+    pub fn network<N: Into<[u8; 4]>>(mut self, network: N) -> Self {
+        self.network = Network::from_magic(network.into());
+        self
+    }
+
+    /// Delay between the reconnections of a peer, growing with the
+    /// consecutive failures and reset by a successful handshake.
+    pub fn reconnect_backoff(mut self, backoff: Backoff) -> Self {
+        self.reconnect_backoff = backoff;
+        self
+    }
+
+    /// Sends a ping to the peers nothing was sent to for the interval, so
+    /// that they don't drop the idle connections. A zero interval
+    /// disables the pings.
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval).filter(|interval| !interval.is_zero());
+        self
+    }
+
+    /// Starts maintaining a connection to the target, adding a peer
+    /// twice has no effect.
+    pub fn add_peer<T: Into<String>>(&mut self, target: T) {
+        let target = target.into();
+        if self.tasks.contains_key(&target) {
+            return;
+        }
+        let task = supervise(
+            target.clone(),
+            self.config.clone(),
+            Connection {
+                magic: self.network.magic(),
+                keep_alive: self.keep_alive,
+            },
+            self.reconnect_backoff,
+            self.pool.clone(),
+            self.incoming.clone(),
+        );
+        self.tasks.insert(target, tokio::spawn(task));
+    }
+
+    /// Stops maintaining the connection to the target and closes it.
+    pub fn remove_peer(&mut self, target: &str) -> bool {
+        lock(&self.pool).remove(target);
+        match self.tasks.remove(target) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The connection to the target, if it is currently handshaken.
+    pub fn get_peer(&self, target: &str) -> Option<PeerHandle> {
+        lock(&self.pool)
+            .get(target)
+            .filter(|peer| peer.is_connected())
+            .cloned()
+    }
+
+    /// The peers currently connected.
+    pub fn connected_peers(&self) -> Vec<PeerHandle> {
+        lock(&self.pool)
+            .values()
+            .filter(|peer| peer.is_connected())
+            .cloned()
+            .collect()
+    }
+
+    /// Sends the message to every connected peer and returns the number
+    /// of peers it was queued for.
+    pub async fn broadcast(&self, message: &NetworkMessage) -> usize {
+        let mut sent = 0;
+        for peer in self.connected_peers() {
+            if peer.send(message.clone()).await.is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    /// Receives the messages sent by the connected peers from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerMessage> {
+        self.incoming.subscribe()
+    }
+}
+
+impl Drop for PeerManager {
+    fn drop(&mut self) {
+        for task in self.tasks.values() {
+            task.abort();
+        }
+    }
+}
+
+/// The settings of the connections after the handshake.
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    magic: [u8; 4],
+    keep_alive: Option<Duration>,
+}
+
+/// Connects and handshakes the target, serves the connection until it
+/// drops, then starts over after the backoff delay. Stops once the
+/// cancellation token of the configuration is cancelled.
+async fn supervise(
+    target: String,
+    config: HandshakeConfig,
+    settings: Connection,
+    backoff: Backoff,
+    pool: Pool,
+    incoming: broadcast::Sender<PeerMessage>,
+) {
+    let mut connection = 0;
+    let mut failures = 0;
+    loop {
+        let mut result = ProbeResult::new(());
+        let outcome = connect_and_exchange(target.as_str(), &config, &mut result).await;
+        if let Err(ProtocolError::Cancelled) = outcome {
+            return;
+        }
+        if let Ok((stream, handshake)) = outcome {
+            failures = 0;
+            connection += 1;
+            let (outgoing, queued) = mpsc::channel(OUTGOING_CAPACITY);
+            let peer = PeerHandle {
+                target: target.clone(),
+                address: result.address,
+                handshake,
+                connection,
+                outgoing,
+            };
+            lock(&pool).insert(target.clone(), peer);
+            serve(stream, queued, &target, &config, settings, &incoming).await;
+            lock(&pool).remove(&target);
+        }
+        tokio::select! {
+            _ = config.cancelled() => return,
+            _ = tokio::time::sleep(backoff.delay(failures)) => {}
+        }
+        failures = failures.saturating_add(1);
+    }
+}
+
+/// Writes the queued messages to the peer and publishes the messages it
+/// sends, until either direction fails or the handshakes are cancelled.
+async fn serve(
+    stream: PeerStream,
+    mut queued: mpsc::Receiver<NetworkMessage>,
+    target: &str,
+    config: &HandshakeConfig,
+    settings: Connection,
+    incoming: &broadcast::Sender<PeerMessage>,
+) {
+    let magic = settings.magic;
+    let idle = settings.keep_alive.unwrap_or_default();
+    let (reader, mut writer) = tokio::io::split(stream);
+    // Reading a frame isn't cancel safe, it runs in its own task rather
+    // than racing the writes.
+    let mut reading = tokio::spawn(read_messages(
+        reader,
+        target.to_string(),
+        magic,
+        incoming.clone(),
+    ));
+    // Pings are only sent when nothing else was for the interval.
+    let ping = tokio::time::sleep(idle);
+    tokio::pin!(ping);
+    loop {
+        let message = tokio::select! {
+            _ = &mut reading => break,
+            _ = config.cancelled() => break,
+            _ = &mut ping, if settings.keep_alive.is_some() => ping_message(),
+            message = queued.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+        if write_message(&mut writer, magic, &message).await.is_err() {
+            break;
+        }
+        ping.as_mut().reset(tokio::time::Instant::now() + idle);
+    }
+    reading.abort();
+}
+
+async fn read_messages(
+    mut reader: ReadHalf<PeerStream>,
+    target: String,
+    magic: [u8; 4],
+    incoming: broadcast::Sender<PeerMessage>,
+) {
+    while let Ok(message) = read_message(&mut reader, magic, DEFAULT_MAX_BODY_SIZE).await {
+        // Sending only fails when nobody subscribed.
+        let _ = incoming.send(PeerMessage {
+            target: target.clone(),
+            message,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::testing::MockErgoNode;
+
+    #[tokio::test]
+    async fn test_reconnect() -> ProtocolResult<()> {
+        let server = MockErgoNode::builder()
+            .respond_handshake(HandshakeBuilder::new().agent_name("ergoref").build()?)
+            .then_expect_frame(7)
+            .then_send_frame(NetworkMessage::new(8, vec![1, 2, 3]))
+            .then_close()
+            .serve()
+            .await?;
+        let target = server.address().to_string();
+
+        let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"))
+            .timeout(Duration::from_secs(5));
+        let mut manager = PeerManager::new(config).reconnect_backoff(Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(10),
+        });
+        let mut incoming = manager.subscribe();
+        manager.add_peer(target.as_str());
+
+        let peer = wait_for_peer(&manager, &target, 1).await;
+        assert_eq!(peer.handshake.agent_name.as_str(), "ergoref");
+        assert_eq!(peer.address, Some(server.address()));
+
+        // The node replies, then closes the connection.
+        assert_eq!(manager.broadcast(&NetworkMessage::new(7, vec![])).await, 1);
+        let received = incoming.recv().await.unwrap();
+        assert_eq!(received.target, target);
+        assert_eq!(received.message, NetworkMessage::new(8, vec![1, 2, 3]));
+
+        let peer = wait_for_peer(&manager, &target, 2).await;
+        assert!(peer.is_connected());
+        assert!(manager.remove_peer(&target));
+        assert!(manager.get_peer(&target).is_none());
+        // The aborted task closes the connection shortly after.
+        while peer.is_connected() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let Err(ProtocolError::Disconnected) = peer.send(NetworkMessage::new(7, vec![])).await
+        else {
+            panic!("expected a closed connection");
+        };
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() -> ProtocolResult<()> {
+        let node = MockErgoNode::builder()
+            .respond_handshake(HandshakeBuilder::new().agent_name("ergoref").build()?)
+            .then_expect_frame(crate::peers::GET_PEERS_CODE)
+            .spawn()
+            .await?;
+        let target = node.address().to_string();
+        let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"))
+            .timeout(Duration::from_secs(5));
+        let mut manager = PeerManager::new(config).keep_alive(Duration::from_millis(10));
+        manager.add_peer(target.as_str());
+
+        // The node only completes its script once it received a ping.
+        let transcript = node.finish().await?;
+        assert_eq!(transcript.frames.len(), 1);
+        Ok(())
+    }
+
+    async fn wait_for_peer(manager: &PeerManager, target: &str, connection: u64) -> PeerHandle {
+        loop {
+            match manager.get_peer(target) {
+                Some(peer) if peer.connection == connection => return peer,
+                _ => tokio::time::sleep(Duration::from_millis(5)).await,
+            }
+        }
+    }
+}
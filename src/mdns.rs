@@ -0,0 +1,231 @@
+//! This module implements resolving `.local` host names with a one-shot
+//! multicast DNS query ([RFC 6762](https://www.rfc-editor.org/rfc/rfc6762)),
+//! to reach the nodes of a local network without a DNS server.
+//!
+//! The query is sent from an ephemeral port, so the responders answer it
+//! with a unicast message, as they would answer a regular DNS client.
+//!
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::dial;
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::route::{BoxFuture, TargetHandler};
+
+/// The mDNS IPv4 multicast group and port.
+pub const MDNS_GROUP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+const A_RECORD: u16 = 1;
+const AAAA_RECORD: u16 = 28;
+const IN_CLASS: u16 = 1;
+const HEADER_LEN: usize = 12;
+
+/// Resolves host names with mDNS, and dials the resolved addresses when
+/// routed the `.local` targets by a [`TargetRouter`](crate::route::TargetRouter).
+#[derive(Debug, Clone)]
+pub struct MdnsResolver {
+    group: SocketAddr,
+    timeout: Duration,
+}
+
+impl Default for MdnsResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MdnsResolver {
+    /// Queries the mDNS group, waiting a second for the answers.
+    pub fn new() -> Self {
+        Self::with_group(MDNS_GROUP)
+    }
+
+    /// Sends the queries to the given address instead of the mDNS group.
+    pub fn with_group(group: SocketAddr) -> Self {
+        Self {
+            group,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Maximum duration to wait for an answer.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Resolves the host name to the addresses of the first answer.
+    pub async fn lookup(&self, host: &str) -> ProtocolResult<Vec<IpAddr>> {
+        let query = encode_query(host)?;
+        let local = match self.group {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.send_to(&query, self.group).await?;
+
+        let answer = async {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, _) = socket.recv_from(&mut buf).await?;
+                // Malformed or unrelated answers are ignored.
+                match decode_answers(&buf[..len]) {
+                    Some(addresses) if !addresses.is_empty() => return Ok(addresses),
+                    _ => continue,
+                }
+            }
+        };
+        match tokio::time::timeout(self.timeout, answer).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no mDNS answer for {host}"),
+            )
+            .into()),
+        }
+    }
+}
+
+impl TargetHandler for MdnsResolver {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, ProtocolResult<TcpStream>> {
+        Box::pin(async move {
+            let addresses = self
+                .lookup(host)
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+            let addresses = dial::interleave(addresses);
+            Ok(dial::connect_any(addresses, dial::CONNECTION_ATTEMPT_DELAY, None).await?)
+        })
+    }
+}
+
+/// Encodes a query of the A and AAAA records of the host.
+fn encode_query(host: &str) -> ProtocolResult<Vec<u8>> {
+    let invalid_target = || ProtocolError::InvalidTarget(host.to_string());
+    let mut query = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+    let mut name = Vec::with_capacity(host.len() + 2);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid_target());
+        }
+        name.push(label.len() as u8);
+        name.extend_from_slice(label.as_bytes());
+    }
+    name.push(0);
+    if name.len() > 255 {
+        return Err(invalid_target());
+    }
+    for record in [A_RECORD, AAAA_RECORD] {
+        query.extend_from_slice(&name);
+        query.extend_from_slice(&record.to_be_bytes());
+        query.extend_from_slice(&IN_CLASS.to_be_bytes());
+    }
+    Ok(query)
+}
+
+/// Decodes the addresses of the A and AAAA answers of a response, `None`
+/// when it is malformed.
+fn decode_answers(message: &[u8]) -> Option<Vec<IpAddr>> {
+    let header = message.get(..HEADER_LEN)?;
+    // Only responses are expected on the socket.
+    if header[2] & 0x80 == 0 {
+        return None;
+    }
+    let questions = BigEndian::read_u16(&header[4..6]);
+    let answers = BigEndian::read_u16(&header[6..8]);
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+    let mut addresses = vec![];
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let record = message.get(offset..offset + 10)?;
+        let record_type = BigEndian::read_u16(&record[..2]);
+        let len = BigEndian::read_u16(&record[8..10]) as usize;
+        offset += 10;
+        let data = message.get(offset..offset + len)?;
+        offset += len;
+        match (record_type, data.len()) {
+            (A_RECORD, 4) => addresses.push(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (AAAA_RECORD, 16) => addresses.push(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)),
+            _ => {}
+        }
+    }
+    Some(addresses)
+}
+
+/// Returns the offset following the name starting at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            // A compression pointer ends the name.
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Answers the first query with the address, as an mDNS responder.
+    async fn respond(socket: UdpSocket, address: Ipv4Addr) -> ProtocolResult<Vec<u8>> {
+        let mut buf = [0u8; 512];
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let query = buf[..len].to_vec();
+        let mut response = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        // The name of the answer points to the one of the query.
+        response.extend_from_slice(&[0xc0, 0x0c]);
+        response.extend_from_slice(&[0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4]);
+        response.extend_from_slice(&address.octets());
+        socket.send_to(&response, from).await?;
+        Ok(query)
+    }
+
+    #[test]
+    fn test_encode_query() -> ProtocolResult<()> {
+        let query = encode_query("node.local.")?;
+        assert_eq!(&query[4..6], [0, 2]);
+        assert_eq!(&query[12..24], b"\x04node\x05local\x00");
+        assert_eq!(&query[24..28], [0, 1, 0, 1]);
+        assert!(encode_query("node..local").is_err());
+        assert!(decode_answers(&query).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mdns_resolver() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node_address = listener.local_addr()?;
+        let responder = UdpSocket::bind("127.0.0.1:0").await?;
+        let resolver = MdnsResolver::with_group(responder.local_addr()?);
+        let responded = tokio::spawn(respond(responder, Ipv4Addr::LOCALHOST));
+
+        let stream = resolver.connect("node.local", node_address.port()).await?;
+        assert_eq!(stream.peer_addr()?, node_address);
+        let query = responded.await.unwrap()?;
+        assert_eq!(&query[12..24], b"\x04node\x05local\x00");
+
+        let silent = UdpSocket::bind("127.0.0.1:0").await?;
+        let resolver =
+            MdnsResolver::with_group(silent.local_addr()?).timeout(Duration::from_millis(50));
+        let Err(ProtocolError::Io(err)) = resolver.lookup("node.local").await else {
+            panic!("expected the lookup to time out");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        Ok(())
+    }
+}
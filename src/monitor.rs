@@ -0,0 +1,354 @@
+//! This module implements the building blocks of monitoring a set
+//! of nodes with repeated handshakes.
+//!
+//! [`ChangeDetector`] remembers the last reply of each target and reports
+//! a [`ChangeEvent`] when the agent name, version or feature set of a
+//! target changes between two probes.
+//!
+//! [`TargetStats`] tracks the uptime and latency percentiles of a target
+//! over the probes.
+//!
+//! [`ErrorThrottle`] collapses the identical errors a target keeps
+//! returning into periodic [`ErrorSummary`] lines, so that an unreachable
+//! node doesn't flood the logs.
+//!
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::diff::FieldDiff;
+use crate::encoder::HandshakeMessage;
+use crate::error::ProtocolError;
+
+/// A change in the identity or features of a monitored target.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChangeEvent {
+    pub target: String,
+    pub diffs: Vec<FieldDiff>,
+}
+
+impl ChangeEvent {
+    /// Whether the target now reports a lower version than before,
+    /// which usually deserves more attention than an upgrade.
+    pub fn is_downgrade(&self) -> bool {
+        self.diffs.iter().any(|diff| match diff {
+            FieldDiff::Version { old, new } => new < old,
+            _ => false,
+        })
+    }
+}
+
+/// Tracks the last reply of each target to detect changes between probes.
+#[derive(Debug, Default)]
+pub struct ChangeDetector {
+    last_replies: HashMap<String, HandshakeMessage>,
+}
+
+impl ChangeDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the reply of a target and returns a change event if its
+    /// agent name, version or features differ from the previous reply.
+    /// The first reply of a target never raises an event.
+    pub fn observe(&mut self, target: &str, reply: HandshakeMessage) -> Option<ChangeEvent> {
+        let previous = self.last_replies.insert(target.to_string(), reply);
+        let previous = previous?;
+        let current = &self.last_replies[target];
+
+        let diffs: Vec<FieldDiff> = previous
+            .diff(current)
+            .into_iter()
+            .filter(|diff| {
+                !matches!(
+                    diff,
+                    FieldDiff::PeerName { .. } | FieldDiff::DeclaredAddress { .. }
+                )
+            })
+            .collect();
+        if diffs.is_empty() {
+            return None;
+        }
+
+        Some(ChangeEvent {
+            target: target.to_string(),
+            diffs,
+        })
+    }
+
+    /// Returns the last reply recorded for the target.
+    pub fn last_reply(&self, target: &str) -> Option<&HandshakeMessage> {
+        self.last_replies.get(target)
+    }
+}
+
+/// Number of latency samples kept per target, older samples are dropped.
+const LATENCY_WINDOW: usize = 1024;
+
+/// The uptime and latency of a monitored target.
+#[derive(Debug, Default, Clone)]
+pub struct TargetStats {
+    /// Number of probes made.
+    pub probes: u64,
+    /// Number of probes completing the handshake.
+    pub successes: u64,
+    latencies: VecDeque<Duration>,
+}
+
+impl TargetStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the outcome of a probe, given the latency of a successful
+    /// handshake or `None` for a failure.
+    pub fn record(&mut self, latency: Option<Duration>) {
+        self.probes += 1;
+        if let Some(latency) = latency {
+            self.successes += 1;
+            if self.latencies.len() == LATENCY_WINDOW {
+                self.latencies.pop_front();
+            }
+            self.latencies.push_back(latency);
+        }
+    }
+
+    /// Share of the probes completing the handshake, between 0 and 1.
+    pub fn uptime(&self) -> f64 {
+        match self.probes {
+            0 => 0.0,
+            probes => self.successes as f64 / probes as f64,
+        }
+    }
+
+    /// The latency under which the given percentile, between 0 and 100,
+    /// of the recent successful probes completed, using the nearest rank.
+    pub fn latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut latencies: Vec<Duration> = self.latencies.iter().copied().collect();
+        latencies.sort();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * latencies.len() as f64).ceil();
+        let index = (rank as usize).saturating_sub(1);
+        latencies.get(index).copied()
+    }
+}
+
+/// The occurrences of an error collapsed over a period of time.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ErrorSummary {
+    pub target: String,
+    /// A short description of the error, ex. `connection refused`.
+    pub kind: String,
+    /// Number of occurrences, the first one included.
+    pub count: usize,
+    pub period: Duration,
+}
+
+impl fmt::Display for ErrorSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} x{} in last {}s",
+            self.target,
+            self.kind,
+            self.count,
+            self.period.as_secs()
+        )
+    }
+}
+
+#[derive(Debug)]
+struct ErrorWindow {
+    started: Instant,
+    count: usize,
+}
+
+/// Deduplicates the errors reported for each target.
+///
+/// The first occurrence of an error (same target, same kind) should be
+/// logged right away, [`ErrorThrottle::record`] then swallows the repeats
+/// until the window elapses and [`ErrorThrottle::flush`] reports them as
+/// a single summary.
+#[derive(Debug)]
+pub struct ErrorThrottle {
+    window: Duration,
+    windows: HashMap<(String, String), ErrorWindow>,
+    elapsed: Vec<ErrorSummary>,
+}
+
+impl ErrorThrottle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            windows: HashMap::new(),
+            elapsed: Vec::new(),
+        }
+    }
+
+    /// Records an error returned by the target, returns whether it is the
+    /// first occurrence of the window and should be logged.
+    pub fn record(&mut self, target: &str, error: &ProtocolError) -> bool {
+        self.record_at(target, error, Instant::now())
+    }
+
+    pub(crate) fn record_at(&mut self, target: &str, error: &ProtocolError, now: Instant) -> bool {
+        let key = (target.to_string(), error_kind(error));
+        if let Some(window) = self.windows.get_mut(&key) {
+            if now.duration_since(window.started) < self.window {
+                window.count += 1;
+                return false;
+            }
+        }
+
+        let previous = self.windows.insert(
+            key.clone(),
+            ErrorWindow {
+                started: now,
+                count: 1,
+            },
+        );
+        if let Some(previous) = previous {
+            self.elapsed.extend(summarize(key, previous, now));
+        }
+        true
+    }
+
+    /// Returns the summaries of the windows that elapsed with repeated
+    /// errors. Call it periodically to log them.
+    pub fn flush(&mut self) -> Vec<ErrorSummary> {
+        self.flush_at(Instant::now())
+    }
+
+    pub(crate) fn flush_at(&mut self, now: Instant) -> Vec<ErrorSummary> {
+        let expired: Vec<(String, String)> = self
+            .windows
+            .iter()
+            .filter(|(_, window)| now.duration_since(window.started) >= self.window)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(window) = self.windows.remove(&key) {
+                self.elapsed.extend(summarize(key, window, now));
+            }
+        }
+        std::mem::take(&mut self.elapsed)
+    }
+}
+
+/// Only repeated errors are summarized, the first occurrence has already
+/// been logged.
+fn summarize(key: (String, String), window: ErrorWindow, now: Instant) -> Option<ErrorSummary> {
+    if window.count < 2 {
+        return None;
+    }
+    Some(ErrorSummary {
+        target: key.0,
+        kind: key.1,
+        count: window.count,
+        period: now.duration_since(window.started),
+    })
+}
+
+/// IO errors are told apart by their kind only, their messages often
+/// carry details (ex. the OS error code) irrelevant for deduplication.
+fn error_kind(error: &ProtocolError) -> String {
+    match error {
+        ProtocolError::Io(err) => err.kind().to_string(),
+        _ => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::encoder::{TinyString, Version};
+
+    #[test]
+    fn test_change_detection() {
+        let mut detector = ChangeDetector::new();
+        let reply = HandshakeMessage {
+            version: Version([5, 0, 21]),
+            ..Default::default()
+        };
+        assert_eq!(detector.observe("node", reply.clone()), None);
+        assert_eq!(detector.observe("node", reply.clone()), None);
+
+        // Peer name changes are not considered identity changes.
+        let renamed = HandshakeMessage {
+            peer_name: TinyString::new("renamed").unwrap(),
+            ..reply.clone()
+        };
+        assert_eq!(detector.observe("node", renamed), None);
+
+        let downgraded = HandshakeMessage {
+            version: Version([4, 0, 100]),
+            ..reply.clone()
+        };
+        let event = detector.observe("node", downgraded).unwrap();
+        assert_eq!(event.target, "node");
+        assert!(event.is_downgrade());
+
+        let event = detector.observe("node", reply).unwrap();
+        assert!(!event.is_downgrade());
+    }
+
+    #[test]
+    fn test_target_stats() {
+        let mut stats = TargetStats::new();
+        assert_eq!(stats.uptime(), 0.0);
+        assert_eq!(stats.latency_percentile(50.0), None);
+
+        for millis in (1..=100).rev() {
+            stats.record(Some(Duration::from_millis(millis)));
+        }
+        stats.record(None);
+        assert_eq!(stats.probes, 101);
+        assert!((stats.uptime() - 100.0 / 101.0).abs() < 1e-9);
+        assert_eq!(
+            stats.latency_percentile(50.0),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            stats.latency_percentile(99.0),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(
+            stats.latency_percentile(0.0),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(
+            stats.latency_percentile(100.0),
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn test_error_throttle() {
+        let mut throttle = ErrorThrottle::new(Duration::from_secs(60));
+        let refused = || ProtocolError::Io(io::ErrorKind::ConnectionRefused.into());
+        let start = Instant::now();
+
+        assert!(throttle.record_at("node", &refused(), start));
+        for seconds in 1..124 {
+            let now = start + Duration::from_secs(seconds % 60);
+            assert!(!throttle.record_at("node", &refused(), now));
+        }
+        // Other targets and other errors are tracked separately.
+        assert!(throttle.record_at("other", &refused(), start));
+        assert!(throttle.record_at("node", &ProtocolError::TimedOut, start));
+
+        assert_eq!(throttle.flush_at(start + Duration::from_secs(30)), vec![]);
+        let summaries = throttle.flush_at(start + Duration::from_secs(60));
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(
+            summaries[0].to_string(),
+            "node: connection refused x124 in last 60s"
+        );
+
+        // A new window starts with a logged occurrence.
+        assert!(throttle.record_at("node", &refused(), start + Duration::from_secs(61)));
+    }
+}
@@ -0,0 +1,107 @@
+//! This module implements deciding how to go on with a peer from the
+//! versions exchanged in the handshake, following the compatibility
+//! rules of the reference node.
+//!
+//! Past the EIP-37 hard fork, the reference node disconnects the peers
+//! running a release predating it, their chain diverged. The other
+//! capabilities, ex. serving UTXO snapshots, are only used when both
+//! nodes support them.
+//!
+
+use std::sync::OnceLock;
+
+use crate::encoder::{Capability, Version};
+use crate::versions::{VersionTable, VersionWarning};
+
+/// The capabilities a node supporting them requires from its peers.
+const REQUIRED: [Capability; 1] = [Capability::Eip37];
+
+/// How to go on with a peer.
+///
+/// ```
+/// use p2p_handshake::negotiation::negotiate;
+/// use p2p_handshake::{Capability, Version};
+///
+/// let negotiation = negotiate(&Version([5, 0, 21]), &Version([5, 0, 12]));
+/// assert!(negotiation.proceed());
+/// assert!(negotiation.supports(Capability::UtxoSnapshots));
+/// assert!(!negotiation.supports(Capability::Nipopow));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiation {
+    /// A capability we require that the peer lacks, the connection
+    /// shouldn't go on.
+    pub missing: Option<Capability>,
+    /// The capabilities supported by both nodes.
+    pub capabilities: Vec<Capability>,
+    /// Why the version of the peer deserves attention, ex. an obsolete
+    /// release.
+    pub warning: Option<VersionWarning>,
+}
+
+impl Negotiation {
+    /// Whether the connection with the peer can go on.
+    pub fn proceed(&self) -> bool {
+        self.missing.is_none()
+    }
+
+    /// Whether both nodes support the capability.
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// Negotiates with a peer, the releases being checked against the table
+/// embedded in the library.
+pub fn negotiate(ours: &Version, theirs: &Version) -> Negotiation {
+    static EMBEDDED: OnceLock<VersionTable> = OnceLock::new();
+    negotiate_with(ours, theirs, EMBEDDED.get_or_init(VersionTable::embedded))
+}
+
+/// Negotiates with a peer, the releases being checked against the table.
+pub fn negotiate_with(ours: &Version, theirs: &Version, table: &VersionTable) -> Negotiation {
+    let missing = REQUIRED
+        .into_iter()
+        .find(|capability| ours.supports(*capability) && !theirs.supports(*capability));
+    let capabilities = Capability::ALL
+        .into_iter()
+        .filter(|capability| ours.supports(*capability) && theirs.supports(*capability))
+        .collect();
+    Negotiation {
+        missing,
+        capabilities,
+        warning: table.check(theirs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let negotiation = negotiate(&Version([5, 0, 21]), &Version([5, 0, 21]));
+        assert!(negotiation.proceed());
+        assert_eq!(negotiation.capabilities, Capability::ALL);
+        assert_eq!(negotiation.warning, None);
+
+        // The capabilities are limited by the older node.
+        let negotiation = negotiate(&Version([5, 0, 12]), &Version([5, 0, 21]));
+        assert!(negotiation.supports(Capability::UtxoSnapshots));
+        assert!(!negotiation.supports(Capability::Nipopow));
+
+        let negotiation = negotiate(&Version([5, 0, 21]), &Version([4, 0, 100]));
+        assert!(negotiation.proceed());
+        assert_eq!(
+            negotiation.warning,
+            Some(VersionWarning::Obsolete(Version([4, 0, 100])))
+        );
+
+        let negotiation = negotiate(&Version([5, 0, 21]), &Version([4, 0, 99]));
+        assert_eq!(negotiation.missing, Some(Capability::Eip37));
+        assert_eq!(negotiation.capabilities, [Capability::SyncV2]);
+
+        // A node predating the fork doesn't require it.
+        assert!(negotiate(&Version([4, 0, 99]), &Version([4, 0, 20])).proceed());
+    }
+}
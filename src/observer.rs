@@ -0,0 +1,181 @@
+//! This module implements observing the lifecycle of the handshakes run
+//! with a [`HandshakeConfig`](crate::HandshakeConfig), ex. to feed the
+//! steps of every attempt into the telemetry or the audit log of a
+//! service.
+//!
+//! The observer is called synchronously from the task running the
+//! handshake, it should return quickly and hand the events to another
+//! task for any slow processing. Every attempt of a retried handshake is
+//! observed, the replies shared from an outcome cache are not.
+//!
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::encoder::{read_handshake, HandshakeMessage};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::spec;
+
+/// The step of a handshake attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Resolving the name of the target.
+    Resolve,
+    /// Connecting to the resolved addresses.
+    Connect,
+    /// Sending the request.
+    Send,
+    /// Reading and decoding the reply.
+    Receive,
+    /// Checking the decoded reply, ex. against the policy.
+    Validate,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Resolve => "resolve",
+            Phase::Connect => "connect",
+            Phase::Send => "send",
+            Phase::Receive => "receive",
+            Phase::Validate => "validate",
+        })
+    }
+}
+
+/// Receives the events of every handshake attempt, all the methods do
+/// nothing by default.
+///
+/// The targets routed by suffix are resolved by their handler, see
+/// [`route`](crate::route), `on_resolved` isn't called for them.
+pub trait HandshakeObserver: Send + Sync {
+    /// The target resolved to the addresses, in the order they are dialed.
+    fn on_resolved(&self, _addresses: &[SocketAddr]) {}
+
+    /// The connection to the address was established.
+    fn on_connected(&self, _address: SocketAddr) {}
+
+    /// The request was written to the connection.
+    fn on_request_sent(&self, _address: SocketAddr, _data: &[u8]) {}
+
+    /// The reply was decoded, it is validated afterwards.
+    fn on_response_received(&self, _address: SocketAddr, _message: &HandshakeMessage) {}
+
+    /// The attempt failed during the phase, `address` is the connected
+    /// address, if any.
+    fn on_error(&self, _error: &ProtocolError, _phase: Phase, _address: Option<SocketAddr>) {}
+}
+
+/// An observer kept by the caller too, ex. to read what it collected.
+impl<O: HandshakeObserver + ?Sized> HandshakeObserver for Arc<O> {
+    fn on_resolved(&self, addresses: &[SocketAddr]) {
+        (**self).on_resolved(addresses)
+    }
+
+    fn on_connected(&self, address: SocketAddr) {
+        (**self).on_connected(address)
+    }
+
+    fn on_request_sent(&self, address: SocketAddr, data: &[u8]) {
+        (**self).on_request_sent(address, data)
+    }
+
+    fn on_response_received(&self, address: SocketAddr, message: &HandshakeMessage) {
+        (**self).on_response_received(address, message)
+    }
+
+    fn on_error(&self, error: &ProtocolError, phase: Phase, address: Option<SocketAddr>) {
+        (**self).on_error(error, phase, address)
+    }
+}
+
+/// A shared [`HandshakeObserver`], cloning it only bumps a reference count.
+#[derive(Clone)]
+pub struct ObserverHook(Arc<dyn HandshakeObserver>);
+
+impl ObserverHook {
+    pub fn new<O: HandshakeObserver + 'static>(observer: O) -> Self {
+        Self(Arc::new(observer))
+    }
+}
+
+impl fmt::Debug for ObserverHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObserverHook").finish_non_exhaustive()
+    }
+}
+
+/// Follows the phase of a single attempt, reporting its events to the
+/// observer, if any.
+pub(crate) struct Observation<'a> {
+    observer: Option<&'a ObserverHook>,
+    phase: Phase,
+    address: Option<SocketAddr>,
+}
+
+impl<'a> Observation<'a> {
+    pub(crate) fn new(observer: Option<&'a ObserverHook>) -> Self {
+        Self {
+            observer,
+            phase: Phase::Resolve,
+            address: None,
+        }
+    }
+
+    pub(crate) fn resolved(&mut self, addresses: &[SocketAddr]) {
+        self.phase = Phase::Connect;
+        if let Some(ObserverHook(observer)) = self.observer {
+            observer.on_resolved(addresses);
+        }
+    }
+
+    pub(crate) fn connected(&mut self, address: Option<SocketAddr>) {
+        self.phase = Phase::Send;
+        self.address = address;
+        if let (Some(ObserverHook(observer)), Some(address)) = (self.observer, address) {
+            observer.on_connected(address);
+        }
+    }
+
+    fn request_sent(&mut self, data: &[u8]) {
+        self.phase = Phase::Receive;
+        if let (Some(ObserverHook(observer)), Some(address)) = (self.observer, self.address) {
+            observer.on_request_sent(address, data);
+        }
+    }
+
+    fn response_received(&mut self, message: &HandshakeMessage) {
+        self.phase = Phase::Validate;
+        if let (Some(ObserverHook(observer)), Some(address)) = (self.observer, self.address) {
+            observer.on_response_received(address, message);
+        }
+    }
+
+    pub(crate) fn failed(&self, error: &ProtocolError) {
+        if let Some(ObserverHook(observer)) = self.observer {
+            observer.on_error(error, self.phase, self.address);
+        }
+    }
+}
+
+/// Same as [`exchange`](crate::exchange) reporting the request sent and
+/// the reply received to the observation.
+pub(crate) async fn exchange_observed<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &[u8],
+    observation: &mut Observation<'_>,
+) -> ProtocolResult<HandshakeMessage> {
+    if observation.observer.is_none() {
+        let message = crate::exchange(stream, request).await?;
+        observation.phase = Phase::Validate;
+        return Ok(message);
+    }
+    stream.write_all(request).await?;
+    observation.request_sent(request);
+    let message = read_handshake(stream, spec::MAX_HANDSHAKE_SIZE).await?;
+    observation.response_received(&message);
+    Ok(message)
+}
@@ -0,0 +1,329 @@
+//! This module implements dialing the nodes running as Tor v3 onion
+//! services through the SOCKS port of a Tor client, ex. `tor` or `arti
+//! proxy` listening at `127.0.0.1:9050`.
+//!
+//! The onion addresses are checked before dialing: the 56 characters of
+//! the service name encode its public key, a checksum and the version, so
+//! a mistyped address fails right away instead of after the proxy gave up
+//! building a circuit. They are never resolved with the system resolver,
+//! the name is handed over to Tor as is.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::onion::OnionConnector;
+//! use p2p_handshake::route::TargetRouter;
+//! use p2p_handshake::{handshake_many, HandshakeBuilder, HandshakeConfig};
+//!
+//! let router = TargetRouter::new().route(".onion", OnionConnector::tor());
+//! let config = HandshakeConfig::new(HandshakeBuilder::new()).router(router);
+//! let target = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:9030";
+//! let results = handshake_many(vec![target], &config, 1).await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use sha3::{Digest, Sha3_256};
+use tokio::net::TcpStream;
+
+use crate::builder::HandshakeBuilder;
+use crate::encoder::HandshakeMessage;
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::route::{BoxFuture, TargetHandler};
+use crate::socks;
+
+/// The SOCKS port of a Tor client on the local host.
+pub const DEFAULT_TOR_PROXY: &str = "127.0.0.1:9050";
+
+const ONION_SUFFIX: &str = ".onion";
+/// Length of the base32 service name of a v3 onion address.
+const SERVICE_NAME_LEN: usize = 56;
+const ONION_VERSION: u8 = 3;
+const CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Whether the host name is in the `.onion` domain, ignoring the case and
+/// a trailing dot.
+pub fn is_onion(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    // Sliced with `get`, the offset may fall within a multi-byte character.
+    host.len() > ONION_SUFFIX.len()
+        && host
+            .get(host.len() - ONION_SUFFIX.len()..)
+            .is_some_and(|suffix| suffix.eq_ignore_ascii_case(ONION_SUFFIX))
+}
+
+/// The address of a node running as a v3 onion service.
+///
+/// ```
+/// use p2p_handshake::onion::OnionAddress;
+///
+/// let address: OnionAddress = "2GZYXA5IHM7NSGGFXNU52RCK2VV4RVMDLKIU3ZZUI5DU4XYCLEN53WID.onion:9030"
+///     .parse()
+///     .unwrap();
+/// assert_eq!(address.port(), 9030);
+/// assert_eq!(
+///     address.to_string(),
+///     "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:9030"
+/// );
+/// assert!("mistyped.onion:9030".parse::<OnionAddress>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OnionAddress {
+    /// The host, lowercase, ex. `2gzy...53wid.onion`.
+    host: String,
+    public_key: [u8; 32],
+    port: u16,
+}
+
+impl OnionAddress {
+    /// Checks the host, ex. `2gzy...53wid.onion`, of the service listening
+    /// on the port.
+    pub fn new(host: &str, port: u16) -> ProtocolResult<Self> {
+        let invalid = || ProtocolError::InvalidOnionAddress(host.to_string());
+        let host = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+        let name = host.strip_suffix(ONION_SUFFIX).ok_or_else(invalid)?;
+        // Subdomains of a service, ex. `www.<name>.onion`, reach the service.
+        let name = name.rsplit('.').next().unwrap_or(name);
+        if name.len() != SERVICE_NAME_LEN {
+            return Err(invalid());
+        }
+        let decoded = decode_base32(name).ok_or_else(invalid)?;
+
+        // The name encodes the public key, the checksum and the version.
+        let (public_key, rest) = decoded.split_at(32);
+        let (checksum, version) = rest.split_at(2);
+        if version != [ONION_VERSION] || checksum != onion_checksum(public_key) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host,
+            public_key: public_key.try_into().map_err(|_| invalid())?,
+            port,
+        })
+    }
+
+    /// The host, lowercase, as handed over to the proxy.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The ed25519 public key of the service.
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl FromStr for OnionAddress {
+    type Err = ProtocolError;
+
+    /// Parses `host:port`, ex. `2gzy...53wid.onion:9030`.
+    fn from_str(target: &str) -> ProtocolResult<Self> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .ok_or_else(|| ProtocolError::InvalidTarget(target.to_string()))?;
+        let port = port
+            .parse()
+            .map_err(|_| ProtocolError::InvalidTarget(target.to_string()))?;
+        Self::new(host, port)
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Connects the onion services routed to it through the SOCKS port of a
+/// Tor client, see [`TargetRouter`](crate::route::TargetRouter). The
+/// hosts that aren't valid v3 onion addresses are refused without
+/// reaching the proxy.
+#[derive(Debug, Clone)]
+pub struct OnionConnector {
+    proxy: String,
+}
+
+impl OnionConnector {
+    /// * `proxy` - The address and port of the SOCKS port of the Tor client.
+    pub fn new(proxy: &str) -> Self {
+        Self {
+            proxy: proxy.to_string(),
+        }
+    }
+
+    /// Dials through the Tor client at [`DEFAULT_TOR_PROXY`].
+    pub fn tor() -> Self {
+        Self::new(DEFAULT_TOR_PROXY)
+    }
+
+    /// Opens a connection to the service.
+    pub async fn connect_onion(&self, address: &OnionAddress) -> ProtocolResult<TcpStream> {
+        socks::connect_via_proxy(self.proxy.as_str(), &address.to_string()).await
+    }
+
+    /// Same as [`handshake`](crate::handshake) but reaches the service,
+    /// ex. `2gzy...53wid.onion:9030`, through the Tor client and sends the
+    /// request composed by the builder.
+    pub async fn handshake<F>(
+        &self,
+        target: &str,
+        request: &HandshakeBuilder,
+        on_accept: F,
+    ) -> ProtocolResult<()>
+    where
+        F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
+    {
+        let address: OnionAddress = target.parse()?;
+        socks::handshake_via_proxy_with_builder(
+            self.proxy.as_str(),
+            &address.to_string(),
+            request,
+            on_accept,
+        )
+        .await
+    }
+}
+
+impl Default for OnionConnector {
+    fn default() -> Self {
+        Self::tor()
+    }
+}
+
+impl TargetHandler for OnionConnector {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, ProtocolResult<TcpStream>> {
+        Box::pin(async move { self.connect_onion(&OnionAddress::new(host, port)?).await })
+    }
+}
+
+/// The first two bytes of `SHA3-256(".onion checksum" | key | version)`.
+fn onion_checksum(public_key: &[u8]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(CHECKSUM_PREFIX);
+    hasher.update(public_key);
+    hasher.update([ONION_VERSION]);
+    let digest = hasher.finalize();
+    [digest[0], digest[1]]
+}
+
+/// Decodes the lowercase base32 of RFC 4648, without padding.
+fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in encoded.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|c| *c == byte)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::route::TargetRouter;
+    use crate::{handshake_many, testing, HandshakeConfig};
+
+    const TORPROJECT: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+    const DUCKDUCKGO: &str = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    #[test]
+    fn test_parse_onion_address() {
+        let address: OnionAddress = format!("{TORPROJECT}:9030").parse().unwrap();
+        assert_eq!(address.host(), TORPROJECT);
+        assert_eq!(address.port(), 9030);
+        let upper = OnionAddress::new(&format!("{}.", DUCKDUCKGO.to_uppercase()), 80).unwrap();
+        assert_eq!(upper.host(), DUCKDUCKGO);
+        assert!(OnionAddress::new(&format!("www.{DUCKDUCKGO}"), 80).is_ok());
+
+        // A mistyped character breaks the checksum.
+        let mistyped = TORPROJECT.replacen('2', "3", 1);
+        assert!(matches!(
+            OnionAddress::new(&mistyped, 9030),
+            Err(ProtocolError::InvalidOnionAddress(_))
+        ));
+        // The 16 characters of the retired v2 services.
+        assert!(OnionAddress::new("expyuzz4wqqyqhjn.onion", 9030).is_err());
+        assert!(OnionAddress::new("node.example.org", 9030).is_err());
+        assert!(matches!(
+            TORPROJECT.parse::<OnionAddress>(),
+            Err(ProtocolError::InvalidTarget(_))
+        ));
+
+        assert!(is_onion("abcdef.ONION."));
+        assert!(!is_onion(".onion"));
+        assert!(!is_onion("onion.example.org"));
+        assert!(!is_onion("€é€"));
+        assert!(is_onion("nœud.onion"));
+        assert!(OnionAddress::new("€é€.onion", 9030).is_err());
+    }
+
+    /// A minimal SOCKS5 proxy dialing `node` whatever the requested host,
+    /// returning the host.
+    async fn run_proxy(
+        listener: TcpListener,
+        node: std::net::SocketAddr,
+    ) -> ProtocolResult<String> {
+        let (mut client, _) = listener.accept().await?;
+        let mut greeting = [0u8; 3];
+        client.read_exact(&mut greeting).await?;
+        client.write_all(&[5, 0]).await?;
+        let mut header = [0u8; 5];
+        client.read_exact(&mut header).await?;
+        let mut host = vec![0u8; header[4] as usize];
+        client.read_exact(&mut host).await?;
+        client.read_u16().await?;
+
+        let mut stream = TcpStream::connect(node).await?;
+        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+        tokio::spawn(async move { tokio::io::copy_bidirectional(&mut client, &mut stream).await });
+        Ok(String::from_utf8(host)?)
+    }
+
+    #[tokio::test]
+    async fn test_onion_connector() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let connector = OnionConnector::new(&proxy.local_addr()?.to_string());
+        let requested = tokio::spawn(run_proxy(proxy, node.address()));
+
+        let router = TargetRouter::new().route(ONION_SUFFIX, connector);
+        let config = HandshakeConfig::new(HandshakeBuilder::new()).router(router);
+        let targets = vec![
+            format!("{}:9030", TORPROJECT.to_uppercase()),
+            "mistyped.onion:9030".to_string(),
+        ];
+        let results = handshake_many(targets, &config, 1).await;
+        assert_eq!(
+            results[0].outcome.as_ref().unwrap().agent_name.as_str(),
+            "ergoref"
+        );
+        assert!(matches!(
+            results[1].outcome,
+            Err(ProtocolError::InvalidOnionAddress(_))
+        ));
+        // The proxy resolves the normalized name.
+        assert_eq!(requested.await.unwrap()?, TORPROJECT);
+        Ok(())
+    }
+}
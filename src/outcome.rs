@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use crate::agent::NodeInfo;
+use crate::encoder::HandshakeMessage;
+use crate::negotiation::Negotiation;
+
+/// The result of a successful handshake, as returned by
+/// [`handshake_info`](crate::handshake_info), with the measurements of
+/// each of its phases.
+#[derive(Debug)]
+pub struct HandshakeOutcome {
+    /// The reply of the target.
+    pub message: HandshakeMessage,
+    /// The client and release parsed from the agent name of the reply.
+    pub node_info: NodeInfo,
+    /// How to go on with the target given the version of the request and
+    /// of the reply.
+    pub negotiation: Negotiation,
+    /// The connection, ready for the messages following the handshake.
+    pub stream: TcpStream,
+    /// Time spent resolving the target to its addresses.
+    pub resolve_latency: Duration,
+    /// Time spent connecting to the resolved addresses, excluding the
+    /// name resolution.
+    pub connect_latency: Duration,
+    /// Time between sending the request and reading the complete reply.
+    pub rtt: Duration,
+    /// The address the connection was established with.
+    pub resolved_addr: SocketAddr,
+    /// Size of the request on the wire.
+    pub bytes_sent: usize,
+    /// Size of the reply on the wire.
+    pub bytes_received: usize,
+}
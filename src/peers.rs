@@ -0,0 +1,301 @@
+//! This module implements the peer discovery messages of the Ergo
+//! network protocol.
+//!
+//! After a successful handshake, a node can be asked for the peers it knows
+//! about by sending a `GetPeers` message, it replies with a `Peers` message
+//! holding the spec of each known peer.
+//!
+//! Misconfigured nodes advertise private or reserved addresses, the
+//! [`AddressFilter`] applied to the `Peers` replies drops them so they
+//! aren't dialed.
+//!
+
+use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::encoder::{read_peer_spec, write_peer_spec, HandshakeMessage, TinyString, Version};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::feature::PeerFeature;
+use crate::framing::{read_message, write_message, NetworkMessage, DEFAULT_MAX_BODY_SIZE};
+use crate::spec;
+
+/// Code of the `GetPeers` message.
+pub const GET_PEERS_CODE: u8 = 1;
+
+/// Code of the `Peers` message.
+pub const PEERS_CODE: u8 = 2;
+
+/// Maximum number of peers in a `Peers` message, as configured
+/// on the reference node.
+pub const MAX_PEERS: usize = 64;
+
+/// The description of a peer as advertised on the network.
+#[derive(Debug, PartialEq, Eq, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerSpec {
+    pub agent_name: TinyString,
+    pub version: Version,
+    pub peer_name: TinyString,
+    pub declared_address: Option<SocketAddr>,
+    pub features: Vec<PeerFeature>,
+}
+
+impl From<HandshakeMessage> for PeerSpec {
+    fn from(message: HandshakeMessage) -> Self {
+        Self {
+            agent_name: message.agent_name,
+            version: message.version,
+            peer_name: message.peer_name,
+            declared_address: message.declared_address,
+            features: message.features,
+        }
+    }
+}
+
+/// Decides which declared addresses are worth dialing.
+///
+/// The default filter rejects private addresses (RFC 1918, unique and link
+/// local IPv6 addresses), reserved ones (loopback, unspecified, multicast,
+/// broadcast, documentation, shared and future use ranges) and port 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AddressFilter {
+    /// Accepts the addresses of private networks.
+    pub allow_private: bool,
+    /// Accepts reserved addresses and port 0.
+    pub allow_reserved: bool,
+}
+
+impl AddressFilter {
+    /// A filter accepting every address, ex. to crawl a local test network.
+    pub fn allow_all() -> Self {
+        Self {
+            allow_private: true,
+            allow_reserved: true,
+        }
+    }
+
+    /// Whether the address is accepted by the filter.
+    pub fn accepts(&self, address: &SocketAddr) -> bool {
+        let ip = match address.ip() {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => IpAddr::V6(ip),
+            },
+            ip => ip,
+        };
+        let (private, reserved) = match ip {
+            IpAddr::V4(ip) => (is_private_v4(ip), is_reserved_v4(ip)),
+            IpAddr::V6(ip) => (is_private_v6(ip), is_reserved_v6(ip)),
+        };
+        (self.allow_private || !private)
+            && (self.allow_reserved || !(reserved || address.port() == 0))
+    }
+
+    /// Clears the declared address of the peers rejected by the filter.
+    pub fn apply(&self, peers: &mut [PeerSpec]) {
+        for peer in peers {
+            if peer
+                .declared_address
+                .is_some_and(|address| !self.accepts(&address))
+            {
+                peer.declared_address = None;
+            }
+        }
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_link_local()
+}
+
+fn is_reserved_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        // This network, shared address space, benchmarking and future use.
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    ip.is_unique_local() || ip.is_unicast_link_local()
+}
+
+fn is_reserved_v6(ip: Ipv6Addr) -> bool {
+    // Documentation range 2001:db8::/32.
+    let documentation = ip.segments()[..2] == [0x2001, 0xdb8];
+    ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || documentation
+}
+
+/// Returns the `GetPeers` message.
+pub fn get_peers_message() -> NetworkMessage {
+    NetworkMessage::new(GET_PEERS_CODE, vec![])
+}
+
+/// Encodes the given peers as a `Peers` message.
+pub fn encode_peers(peers: &[PeerSpec]) -> ProtocolResult<NetworkMessage> {
+    if peers.len() > MAX_PEERS {
+        return Err(ProtocolError::TooManyPeers(peers.len() as u64));
+    }
+
+    let mut body = vec![];
+    leb128::write::unsigned(&mut body, peers.len() as u64)?;
+    for peer in peers {
+        spec::check_peer_spec(peer)?;
+        write_peer_spec(
+            &mut body,
+            &peer.agent_name,
+            &peer.version,
+            &peer.peer_name,
+            peer.declared_address.as_ref(),
+            &peer.features,
+        )?;
+    }
+    Ok(NetworkMessage::new(PEERS_CODE, body))
+}
+
+/// Decodes the peers held by a `Peers` message.
+pub fn decode_peers(message: &NetworkMessage) -> ProtocolResult<Vec<PeerSpec>> {
+    if message.code != PEERS_CODE {
+        return Err(ProtocolError::UnexpectedMessage(message.code));
+    }
+
+    let mut cursor = Cursor::new(message.body.as_slice());
+    let count = leb128::read::unsigned(&mut cursor)?;
+    if count > MAX_PEERS as u64 {
+        return Err(ProtocolError::TooManyPeers(count));
+    }
+    (0..count).map(|_| read_peer_spec(&mut cursor)).collect()
+}
+
+/// Asks the node at the other end of an already handshaken stream for its
+/// known peers. Messages received before the `Peers` reply are skipped.
+///
+/// The declared addresses rejected by the default [`AddressFilter`] are
+/// cleared, see [`request_peers_with_filter`] to use another filter.
+///
+/// As for the plain handshake functions, this doesn't use any timeout, the
+/// call should be wrapped in one.
+pub async fn request_peers<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    magic: [u8; 4],
+) -> ProtocolResult<Vec<PeerSpec>> {
+    request_peers_with_filter(stream, magic, &AddressFilter::default()).await
+}
+
+/// Same as [`request_peers`] but clears the declared addresses rejected by
+/// the given filter.
+pub async fn request_peers_with_filter<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    magic: [u8; 4],
+    filter: &AddressFilter,
+) -> ProtocolResult<Vec<PeerSpec>> {
+    write_message(stream, magic, &get_peers_message()).await?;
+    loop {
+        let message = read_message(stream, magic, DEFAULT_MAX_BODY_SIZE).await?;
+        if message.code == PEERS_CODE {
+            let mut peers = decode_peers(&message)?;
+            filter.apply(&mut peers);
+            return Ok(peers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::MAINNET_MAGIC;
+
+    fn sample_peers() -> Vec<PeerSpec> {
+        vec![
+            PeerSpec {
+                agent_name: TinyString::new("ergoref").unwrap(),
+                version: Version([5, 0, 21]),
+                peer_name: TinyString::new("node-a").unwrap(),
+                declared_address: Some("1.2.3.4:9030".parse().unwrap()),
+                features: vec![PeerFeature::new(16, vec![0, 1, 0, 1])],
+            },
+            PeerSpec {
+                agent_name: TinyString::new("ergoref").unwrap(),
+                version: Version([4, 0, 100]),
+                peer_name: TinyString::new("node-b").unwrap(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_peers_encoding_decoding() -> ProtocolResult<()> {
+        let peers = sample_peers();
+        let message = encode_peers(&peers)?;
+        assert_eq!(message.code, PEERS_CODE);
+        assert_eq!(decode_peers(&message)?, peers);
+
+        assert!(decode_peers(&get_peers_message()).is_err());
+        let too_many = NetworkMessage::new(PEERS_CODE, vec![65]);
+        assert!(decode_peers(&too_many).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_peers() -> ProtocolResult<()> {
+        let (mut client, mut node) = tokio::io::duplex(1024);
+        let peers = sample_peers();
+        let reply = encode_peers(&peers)?;
+        tokio::spawn(async move {
+            let request = read_message(&mut node, MAINNET_MAGIC, DEFAULT_MAX_BODY_SIZE).await?;
+            assert_eq!(request.code, GET_PEERS_CODE);
+            // An unrelated message sent before the reply.
+            write_message(&mut node, MAINNET_MAGIC, &NetworkMessage::new(65, vec![1])).await?;
+            write_message(&mut node, MAINNET_MAGIC, &reply).await
+        });
+
+        assert_eq!(request_peers(&mut client, MAINNET_MAGIC).await?, peers);
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_filter() {
+        let filter = AddressFilter::default();
+        let accepts = |address: &str| filter.accepts(&address.parse().unwrap());
+        assert!(accepts("1.2.3.4:9030"));
+        assert!(accepts("[2a01:4f8::1]:9030"));
+        assert!(!accepts("1.2.3.4:0"));
+        for private in ["10.0.0.1:9030", "192.168.1.1:9030", "[fd00::1]:9030"] {
+            assert!(!accepts(private), "{private}");
+        }
+        for reserved in [
+            "127.0.0.1:9030",
+            "0.0.0.0:9030",
+            "224.0.0.1:9030",
+            "100.64.0.1:9030",
+            "203.0.113.1:9030",
+            "[::1]:9030",
+            "[::ffff:192.168.1.1]:9030",
+            "[2001:db8::1]:9030",
+        ] {
+            assert!(!accepts(reserved), "{reserved}");
+        }
+
+        let filter = AddressFilter {
+            allow_private: true,
+            ..Default::default()
+        };
+        assert!(filter.accepts(&"10.0.0.1:9030".parse().unwrap()));
+        assert!(!filter.accepts(&"127.0.0.1:9030".parse().unwrap()));
+        assert!(AddressFilter::allow_all().accepts(&"127.0.0.1:0".parse().unwrap()));
+
+        let mut peers = sample_peers();
+        peers[1].declared_address = Some("192.168.1.1:9030".parse().unwrap());
+        AddressFilter::default().apply(&mut peers);
+        assert!(peers[0].declared_address.is_some());
+        assert_eq!(peers[1].declared_address, None);
+    }
+}
@@ -0,0 +1,253 @@
+//! This module implements policies written as WebAssembly modules, so the
+//! filtering of peers can be customized without recompiling the tool.
+//!
+//! A plugin module must export:
+//!
+//! * `memory` - its linear memory.
+//! * `alloc(len: i32) -> i32` - returns the offset of `len` bytes the host
+//!   can write the handshake to.
+//! * `check(ptr: i32, len: i32) -> i32` - receives the decoded handshake,
+//!   serialized as JSON, and returns `0` to accept the peer. Any other
+//!   value rejects it and is reported in
+//!   [`PolicyViolation::RejectedByPlugin`].
+//!
+//! Plugins have no access to the host, and every check runs in a fresh
+//! instance with a bounded amount of fuel and memory, so a faulty plugin
+//! can't keep state between peers, loop forever nor exhaust the memory
+//! of the host.
+//!
+
+use std::fmt;
+use std::path::Path;
+
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::encoder::HandshakeMessage;
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::policy::PolicyViolation;
+
+/// Fuel given to every check, roughly the number of executed instructions.
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+
+/// Linear memory a plugin may use, in bytes.
+pub const DEFAULT_MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// A policy implemented by a WebAssembly module.
+pub struct WasmPolicy {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    max_memory: usize,
+}
+
+impl fmt::Debug for WasmPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmPolicy")
+            .field("fuel", &self.fuel)
+            .field("max_memory", &self.max_memory)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WasmPolicy {
+    /// Compiles a plugin from its binary module, checking it exports the
+    /// expected functions.
+    pub fn new(wasm: &[u8]) -> ProtocolResult<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(plugin_error)?;
+        // Checked without instantiating the module, its memory isn't
+        // bounded yet.
+        if let Some(import) = module.imports().next() {
+            return Err(ProtocolError::Plugin(format!(
+                "the module imports `{}::{}`, plugins have no access to the host",
+                import.module(),
+                import.name()
+            )));
+        }
+        for name in ["alloc", "check"] {
+            let mut exports = module.exports();
+            if !exports.any(|export| export.name() == name && export.ty().func().is_some()) {
+                return Err(missing_export(name));
+            }
+        }
+        Ok(Self {
+            engine,
+            module,
+            fuel: DEFAULT_FUEL,
+            max_memory: DEFAULT_MAX_MEMORY,
+        })
+    }
+
+    /// Compiles the plugin stored at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> ProtocolResult<Self> {
+        Self::new(&std::fs::read(path)?)
+    }
+
+    /// Sets the fuel given to every check, checks running out of fuel
+    /// fail with [`ProtocolError::Plugin`].
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    /// Sets the linear memory the plugin may use, in bytes. A module
+    /// declaring more fails with [`ProtocolError::Plugin`], growing the
+    /// memory beyond it fails within the plugin.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+
+    /// Runs the plugin on the handshake of a peer.
+    pub fn check(&self, message: &HandshakeMessage) -> ProtocolResult<()> {
+        let input = serde_json::to_vec(message)
+            .map_err(|err| ProtocolError::Plugin(format!("cannot serialize handshake: {err}")))?;
+        let len = i32::try_from(input.len())
+            .map_err(|_| ProtocolError::Plugin("handshake too large".to_string()))?;
+
+        let (mut store, instance) = self.instantiate()?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| missing_export("memory"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(plugin_error)?;
+        let check = instance
+            .get_typed_func::<(i32, i32), i32>(&store, "check")
+            .map_err(plugin_error)?;
+
+        let ptr = alloc.call(&mut store, len).map_err(plugin_error)?;
+        let offset = usize::try_from(ptr)
+            .map_err(|_| ProtocolError::Plugin(format!("invalid offset {ptr}")))?;
+        memory
+            .write(&mut store, offset, &input)
+            .map_err(plugin_error)?;
+        match check.call(&mut store, (ptr, len)).map_err(plugin_error)? {
+            0 => Ok(()),
+            code => Err(ProtocolError::PolicyViolation(
+                PolicyViolation::RejectedByPlugin(code),
+            )),
+        }
+    }
+
+    fn instantiate(&self) -> ProtocolResult<(Store<StoreLimits>, wasmi::Instance)> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.add_fuel(self.fuel).map_err(plugin_error)?;
+        let instance = Linker::<StoreLimits>::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(plugin_error)?;
+        Ok((store, instance))
+    }
+}
+
+fn plugin_error<E: fmt::Display>(err: E) -> ProtocolError {
+    ProtocolError::Plugin(err.to_string())
+}
+
+fn missing_export(name: &str) -> ProtocolError {
+    ProtocolError::Plugin(format!("the module doesn't export `{name}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::TinyString;
+
+    /// Rejects agents whose name contains `evil` with code 3, the input is
+    /// written at offset 1024.
+    const EVIL_FILTER: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "check") (param $ptr i32) (param $len i32) (result i32)
+            (local $end i32)
+            (local.set $end (i32.sub (i32.add (local.get $ptr) (local.get $len)) (i32.const 3)))
+            (block $done
+              (loop $scan
+                (br_if $done (i32.ge_s (local.get $ptr) (local.get $end)))
+                (if (i32.eq (i32.load (local.get $ptr)) (i32.const 0x6c697665))
+                  (then (return (i32.const 3))))
+                (local.set $ptr (i32.add (local.get $ptr) (i32.const 1)))
+                (br $scan)))
+            i32.const 0))
+    "#;
+
+    const ENDLESS: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) i32.const 0)
+          (func (export "check") (param i32 i32) (result i32)
+            (loop $forever (br $forever))
+            i32.const 0))
+    "#;
+
+    /// Returns `1` when growing its memory by a MiB fails.
+    const GREEDY: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) i32.const 0)
+          (func (export "check") (param i32 i32) (result i32)
+            (i32.eq (memory.grow (i32.const 16)) (i32.const -1))))
+    "#;
+
+    fn reply(agent_name: &str) -> HandshakeMessage {
+        HandshakeMessage {
+            agent_name: TinyString::new(agent_name).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_wasm_policy() -> ProtocolResult<()> {
+        let policy = WasmPolicy::new(&wat::parse_str(EVIL_FILTER).unwrap())?;
+        policy.check(&reply("ergoref"))?;
+        assert!(matches!(
+            policy.check(&reply("evil-node")),
+            Err(ProtocolError::PolicyViolation(
+                PolicyViolation::RejectedByPlugin(3)
+            ))
+        ));
+
+        let policy = WasmPolicy::new(&wat::parse_str(ENDLESS).unwrap())?.fuel(10_000);
+        assert!(matches!(
+            policy.check(&reply("ergoref")),
+            Err(ProtocolError::Plugin(_))
+        ));
+
+        // The memory is bounded, when declared and when grown.
+        let greedy = wat::parse_str(GREEDY).unwrap();
+        WasmPolicy::new(&greedy)?.check(&reply("ergoref"))?;
+        let policy = WasmPolicy::new(&greedy)?.max_memory(1024 * 1024);
+        assert!(matches!(
+            policy.check(&reply("ergoref")),
+            Err(ProtocolError::PolicyViolation(
+                PolicyViolation::RejectedByPlugin(1)
+            ))
+        ));
+        assert!(matches!(
+            WasmPolicy::new(&greedy)?
+                .max_memory(1024)
+                .check(&reply("ergoref")),
+            Err(ProtocolError::Plugin(_))
+        ));
+
+        let no_exports = wat::parse_str("(module)").unwrap();
+        assert!(matches!(
+            WasmPolicy::new(&no_exports),
+            Err(ProtocolError::Plugin(_))
+        ));
+        let host_access = wat::parse_str(r#"(module (import "env" "log" (func)))"#).unwrap();
+        assert!(matches!(
+            WasmPolicy::new(&host_access),
+            Err(ProtocolError::Plugin(_))
+        ));
+        Ok(())
+    }
+}
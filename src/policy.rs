@@ -0,0 +1,217 @@
+//! This module implements the acceptance rules applied to the handshake
+//! of a peer, so that consumers don't have to repeat them.
+//!
+
+use std::fmt;
+
+use crate::encoder::{HandshakeMessage, Version};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::feature::SessionFeature;
+
+/// The rule a peer handshake broke.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PolicyViolation {
+    AgentNotAllowed(String),
+    VersionTooLow {
+        version: Version,
+        minimum: Version,
+    },
+    /// The network magic advertised in the session feature doesn't match,
+    /// `got` is `None` when the peer sent no session feature.
+    NetworkMismatch {
+        expected: [u8; 4],
+        got: Option<[u8; 4]>,
+    },
+    /// A policy plugin rejected the peer with the given code.
+    RejectedByPlugin(i32),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::AgentNotAllowed(agent_name) => {
+                write!(f, "agent `{agent_name}` is not allowed")
+            }
+            PolicyViolation::VersionTooLow { version, minimum } => {
+                write!(f, "version {version} is below {minimum}")
+            }
+            PolicyViolation::NetworkMismatch { expected, got } => match got {
+                Some(got) => write!(f, "network magic {got:?} instead of {expected:?}"),
+                None => write!(f, "no network magic, expected {expected:?}"),
+            },
+            PolicyViolation::RejectedByPlugin(code) => {
+                write!(f, "rejected by the policy plugin with code {code}")
+            }
+        }
+    }
+}
+
+/// The rules a peer handshake must follow to be accepted, nothing is
+/// checked by default.
+///
+/// ```
+/// use p2p_handshake::{HandshakeMessage, HandshakePolicy, TinyString, Version};
+///
+/// let policy = HandshakePolicy::new()
+///     .allow_agent("ergoref")
+///     .min_version(Version([5, 0, 0]));
+/// let reply = HandshakeMessage {
+///     agent_name: TinyString::new("ergoref").unwrap(),
+///     version: Version([4, 0, 100]),
+///     ..Default::default()
+/// };
+/// assert!(policy.check(&reply).is_err());
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct HandshakePolicy {
+    allowed_agents: Vec<String>,
+    min_version: Option<Version>,
+    network: Option<[u8; 4]>,
+    #[cfg(feature = "wasm-policy")]
+    plugin: Option<std::sync::Arc<crate::plugin::WasmPolicy>>,
+}
+
+impl HandshakePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an agent name to the allowlist, any agent is accepted while
+    /// the allowlist is empty.
+    pub fn allow_agent(mut self, agent_name: impl Into<String>) -> Self {
+        self.allowed_agents.push(agent_name.into());
+        self
+    }
+
+    /// Rejects peers running a lower version.
+    pub fn min_version(mut self, version: Version) -> Self {
+        self.min_version = Some(version);
+        self
+    }
+
+    /// Rejects peers whose session feature doesn't advertise the magic of
+    /// this network, given as a [`Network`](crate::framing::Network) or as
+    /// magic bytes.
+    pub fn network<N: Into<[u8; 4]>>(mut self, network: N) -> Self {
+        self.network = Some(network.into());
+        self
+    }
+
+    /// Also runs the given plugin on the peers following the other rules.
+    #[cfg(feature = "wasm-policy")]
+    pub fn plugin(mut self, plugin: crate::plugin::WasmPolicy) -> Self {
+        self.plugin = Some(std::sync::Arc::new(plugin));
+        self
+    }
+
+    /// Checks the handshake of a peer, returning a
+    /// [`ProtocolError::PolicyViolation`] for the first broken rule.
+    pub fn check(&self, message: &HandshakeMessage) -> ProtocolResult<()> {
+        if let Some(violation) = self.violation(message) {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(reason = %violation, "peer rejected");
+            return Err(ProtocolError::PolicyViolation(violation));
+        }
+        #[cfg(feature = "wasm-policy")]
+        if let Some(plugin) = &self.plugin {
+            let result = plugin.check(message);
+            #[cfg(feature = "tracing")]
+            if let Err(err) = &result {
+                tracing::debug!(reason = %err, "peer rejected by the plugin");
+            }
+            return result;
+        }
+        Ok(())
+    }
+
+    fn violation(&self, message: &HandshakeMessage) -> Option<PolicyViolation> {
+        if !self.allowed_agents.is_empty()
+            && !self
+                .allowed_agents
+                .iter()
+                .any(|agent_name| agent_name == message.agent_name.as_str())
+        {
+            return Some(PolicyViolation::AgentNotAllowed(
+                message.agent_name.to_string(),
+            ));
+        }
+
+        if let Some(minimum) = &self.min_version {
+            if message.version < *minimum {
+                return Some(PolicyViolation::VersionTooLow {
+                    version: message.version.clone(),
+                    minimum: minimum.clone(),
+                });
+            }
+        }
+
+        if let Some(expected) = self.network {
+            let got = SessionFeature::find(message).map(|session| session.magic);
+            if got != Some(expected) {
+                return Some(PolicyViolation::NetworkMismatch { expected, got });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::TinyString;
+    use crate::framing::{MAINNET_MAGIC, TESTNET_MAGIC};
+
+    fn reply(agent_name: &str, version: [u8; 3], magic: Option<[u8; 4]>) -> HandshakeMessage {
+        HandshakeMessage {
+            agent_name: TinyString::new(agent_name).unwrap(),
+            version: Version(version),
+            features: magic
+                .map(|magic| vec![SessionFeature::new(magic, 7).into()])
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    fn violation(policy: &HandshakePolicy, message: &HandshakeMessage) -> Option<PolicyViolation> {
+        match policy.check(message) {
+            Err(ProtocolError::PolicyViolation(violation)) => Some(violation),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = HandshakePolicy::new()
+            .allow_agent("ergoref")
+            .allow_agent("ergo-sync")
+            .min_version(Version([5, 0, 0]))
+            .network(MAINNET_MAGIC);
+
+        assert!(policy
+            .check(&reply("ergo-sync", [5, 0, 21], Some(MAINNET_MAGIC)))
+            .is_ok());
+        assert_eq!(
+            violation(&policy, &reply("evil", [5, 0, 21], Some(MAINNET_MAGIC))),
+            Some(PolicyViolation::AgentNotAllowed("evil".to_string()))
+        );
+        assert_eq!(
+            violation(&policy, &reply("ergoref", [4, 0, 100], Some(MAINNET_MAGIC))),
+            Some(PolicyViolation::VersionTooLow {
+                version: Version([4, 0, 100]),
+                minimum: Version([5, 0, 0])
+            })
+        );
+        assert_eq!(
+            violation(&policy, &reply("ergoref", [5, 0, 21], Some(TESTNET_MAGIC))),
+            Some(PolicyViolation::NetworkMismatch {
+                expected: MAINNET_MAGIC,
+                got: Some(TESTNET_MAGIC)
+            })
+        );
+        assert!(violation(&policy, &reply("ergoref", [5, 0, 21], None)).is_some());
+
+        assert!(HandshakePolicy::new()
+            .check(&reply("anything", [0, 0, 1], None))
+            .is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+//! This module implements probing a host on several ports, for the node
+//! operators listening on nonstandard ones.
+//!
+//! A target entry lists the ports in brackets, ex. `node.example.org:[9030,9020,9053]`
+//! or `[2001:db8::1]:[9030,9020]`. The ports are tried in order until one
+//! of them completes the handshake.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::ports::{handshake_many_ports, PortList};
+//! use p2p_handshake::{HandshakeBuilder, HandshakeConfig};
+//!
+//! let entry: PortList = "node.example.org:[9030,9020]".parse()?;
+//! let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"));
+//! for result in handshake_many_ports(vec![entry], &config, 1).await {
+//!     println!("{}: {:?}", result.target, result.outcome.map(|reply| reply.version));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::batch::{probe, run_concurrently, ProbeResult};
+use crate::config::HandshakeConfig;
+use crate::error::ProtocolError;
+
+/// A host and the ports to try in order, written `host:[9030,9020]`, or
+/// `host:9030` for a single port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortList {
+    /// The host as written, IPv6 addresses in brackets.
+    pub host: String,
+    pub ports: Vec<u16>,
+}
+
+impl PortList {
+    /// Whether the entry lists several ports, as opposed to a regular
+    /// `host:port` target.
+    pub fn is_multiple(&self) -> bool {
+        self.ports.len() > 1
+    }
+
+    /// The `host:port` target of one of the ports.
+    pub fn target(&self, port: u16) -> String {
+        format!("{}:{port}", self.host)
+    }
+}
+
+impl FromStr for PortList {
+    type Err = ProtocolError;
+
+    fn from_str(entry: &str) -> Result<Self, Self::Err> {
+        let invalid_target = || ProtocolError::InvalidTarget(entry.to_string());
+        let (host, ports) = entry.rsplit_once(':').ok_or_else(invalid_target)?;
+        let ports = match ports.strip_prefix('[') {
+            Some(ports) => ports.strip_suffix(']').ok_or_else(invalid_target)?,
+            None => ports,
+        };
+        let ports = ports
+            .split(',')
+            .map(|port| port.trim().parse::<u16>().map_err(|_| invalid_target()))
+            .collect::<Result<Vec<u16>, _>>()?;
+        if host.is_empty() {
+            return Err(invalid_target());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            ports,
+        })
+    }
+}
+
+impl fmt::Display for PortList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ports.as_slice() {
+            [port] => write!(f, "{}:{port}", self.host),
+            ports => {
+                let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+                write!(f, "{}:[{}]", self.host, ports.join(","))
+            }
+        }
+    }
+}
+
+/// Tries the ports of the entry in order, as configured, until one of
+/// them completes the handshake.
+///
+/// The target of the result is the `host:port` the handshake completed
+/// on. When every port fails, it is the entry as written and the outcome
+/// is the failure of the last port. The attempts of all the ports are
+/// counted.
+pub async fn handshake_ports(entry: &PortList, config: &HandshakeConfig) -> ProbeResult<String> {
+    let mut attempts = 0;
+    let mut last = ProbeResult::new(entry.to_string());
+    for &port in &entry.ports {
+        // Brackets only delimit IPv6 addresses in targets.
+        let host = entry.host.trim_start_matches('[').trim_end_matches(']');
+        let result = probe((host.to_string(), port), config).await;
+        attempts += result.attempts;
+        let done =
+            result.outcome.is_ok() || matches!(result.outcome, Err(ProtocolError::Cancelled));
+        last = result.with_target(entry.target(port));
+        last.attempts = attempts;
+        if done {
+            return last;
+        }
+    }
+    last.target = entry.to_string();
+    last
+}
+
+/// Same as [`handshake_many`](crate::handshake_many) for entries listing
+/// several ports, each of them is probed with [`handshake_ports`].
+pub async fn handshake_many_ports<I>(
+    entries: I,
+    config: &HandshakeConfig,
+    concurrency_limit: usize,
+) -> Vec<ProbeResult<String>>
+where
+    I: IntoIterator<Item = PortList>,
+{
+    let entries: Vec<PortList> = entries.into_iter().collect();
+    run_concurrently(entries, concurrency_limit, |entry| {
+        let config = config.clone();
+        async move { handshake_ports(&entry, &config).await }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::error::ProtocolResult;
+    use crate::testing;
+
+    #[test]
+    fn test_parse_port_list() -> ProtocolResult<()> {
+        let entry: PortList = "node.example.org:[9030, 9020,9053]".parse()?;
+        assert_eq!(entry.host, "node.example.org");
+        assert_eq!(entry.ports, [9030, 9020, 9053]);
+        assert_eq!(entry.to_string(), "node.example.org:[9030,9020,9053]");
+
+        let entry: PortList = "[::1]:[9030,9020]".parse()?;
+        assert_eq!(entry.host, "[::1]");
+        assert_eq!(entry.target(9020), "[::1]:9020");
+
+        let entry: PortList = "1.2.3.4:9030".parse()?;
+        assert!(!entry.is_multiple());
+        assert_eq!(entry.to_string(), "1.2.3.4:9030");
+
+        for invalid in ["node", "node:[9030", "node:[]", "node:[9030,x]", ":9030"] {
+            assert!(invalid.parse::<PortList>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_ports() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let config = HandshakeConfig::new(HandshakeBuilder::new());
+
+        let entry = PortList {
+            host: "127.0.0.1".to_string(),
+            ports: vec![closed, node.address().port()],
+        };
+        let result = handshake_ports(&entry, &config).await;
+        assert_eq!(result.target, entry.target(node.address().port()));
+        assert_eq!(result.address, Some(node.address()));
+        assert_eq!(result.attempts, 2);
+        assert!(result.outcome.is_ok());
+
+        let entry = PortList {
+            host: "127.0.0.1".to_string(),
+            ports: vec![closed, closed],
+        };
+        let results = handshake_many_ports(vec![entry.clone()], &config, 1).await;
+        assert_eq!(results[0].target, entry.to_string());
+        assert!(matches!(results[0].outcome, Err(ProtocolError::Io(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! This module implements exporting probe results as Prometheus metrics in
+//! the textfile collector format of node_exporter, for hosts that can't
+//! expose a scraped endpoint.
+//!
+//! node_exporter may read the file at any time, [`write_textfile`] writes
+//! a temporary file next to it and renames it over the previous snapshot.
+//!
+
+use std::fmt::{self, Display, Write as _};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::batch::ProbeResult;
+
+/// Renders the results of a probe round, made at the given unix timestamp
+/// in milliseconds, in the Prometheus text format.
+pub fn render_textfile<A: Display>(results: &[ProbeResult<A>], timestamp_millis: u64) -> String {
+    let mut out = String::new();
+    // Writing to a string never fails.
+    let _ = write_metrics(&mut out, results, timestamp_millis);
+    out
+}
+
+fn write_metrics<A: Display>(
+    out: &mut String,
+    results: &[ProbeResult<A>],
+    timestamp_millis: u64,
+) -> fmt::Result {
+    let reachable = results
+        .iter()
+        .filter(|result| result.outcome.is_ok())
+        .count();
+    header(out, "ergo_probe_targets", "Number of probed targets.")?;
+    writeln!(out, "ergo_probe_targets {}", results.len())?;
+    header(
+        out,
+        "ergo_probe_reachable",
+        "Number of targets completing the handshake.",
+    )?;
+    writeln!(out, "ergo_probe_reachable {reachable}")?;
+    header(
+        out,
+        "ergo_probe_last_run_timestamp_seconds",
+        "Unix time of the last probe round.",
+    )?;
+    writeln!(
+        out,
+        "ergo_probe_last_run_timestamp_seconds {}",
+        timestamp_millis as f64 / 1000.0
+    )?;
+
+    header(
+        out,
+        "ergo_probe_up",
+        "Whether the target completed the handshake.",
+    )?;
+    for result in results {
+        let target = escape(&result.target.to_string());
+        let up = u8::from(result.outcome.is_ok());
+        writeln!(out, "ergo_probe_up{{target=\"{target}\"}} {up}")?;
+    }
+
+    header(
+        out,
+        "ergo_probe_duration_seconds",
+        "Connection and handshake time of the target.",
+    )?;
+    for result in results {
+        if let (Some(connect_time), Some(handshake_time)) =
+            (result.connect_time, result.handshake_time)
+        {
+            let target = escape(&result.target.to_string());
+            let duration = (connect_time + handshake_time).as_secs_f64();
+            writeln!(
+                out,
+                "ergo_probe_duration_seconds{{target=\"{target}\"}} {duration}"
+            )?;
+        }
+    }
+
+    header(
+        out,
+        "ergo_probe_info",
+        "Identity advertised by the target, always 1.",
+    )?;
+    for result in results {
+        if let Ok(reply) = &result.outcome {
+            writeln!(
+                out,
+                "ergo_probe_info{{target=\"{}\",agent=\"{}\",version=\"{}\",peer_name=\"{}\"}} 1",
+                escape(&result.target.to_string()),
+                escape(reply.agent_name.as_str()),
+                reply.version,
+                escape(reply.peer_name.as_str()),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn header(out: &mut String, name: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} gauge")
+}
+
+/// Escapes a label value as required by the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Replaces the file at `path` with `contents` atomically, so that
+/// node_exporter never reads a partial snapshot.
+pub fn write_textfile<P: AsRef<Path>>(path: P, contents: &str) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::encoder::{HandshakeMessage, TinyString, Version};
+    use crate::error::ProtocolError;
+
+    #[test]
+    fn test_render_textfile() -> io::Result<()> {
+        let mut up = ProbeResult::new("1.2.3.4:9030");
+        up.outcome = Ok(HandshakeMessage {
+            agent_name: TinyString::new("ergo\"ref").unwrap(),
+            version: Version([5, 0, 21]),
+            peer_name: TinyString::new("node").unwrap(),
+            ..Default::default()
+        });
+        up.connect_time = Some(Duration::from_millis(20));
+        up.handshake_time = Some(Duration::from_millis(30));
+        let mut down = ProbeResult::new("5.6.7.8:9030");
+        down.outcome = Err(ProtocolError::TimedOut);
+
+        let rendered = render_textfile(&[up, down], 1_700_000_000_500);
+        let samples: Vec<&str> = rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                "ergo_probe_targets 2",
+                "ergo_probe_reachable 1",
+                "ergo_probe_last_run_timestamp_seconds 1700000000.5",
+                "ergo_probe_up{target=\"1.2.3.4:9030\"} 1",
+                "ergo_probe_up{target=\"5.6.7.8:9030\"} 0",
+                "ergo_probe_duration_seconds{target=\"1.2.3.4:9030\"} 0.05",
+                "ergo_probe_info{target=\"1.2.3.4:9030\",agent=\"ergo\\\"ref\",version=\"5.0.21\",peer_name=\"node\"} 1",
+            ]
+        );
+        assert!(rendered.contains("# TYPE ergo_probe_up gauge\n"));
+
+        let path = std::env::temp_dir().join(format!("p2p-prom-{}.prom", std::process::id()));
+        write_textfile(&path, &rendered)?;
+        assert_eq!(fs::read_to_string(&path)?, rendered);
+        fs::remove_file(path)
+    }
+}
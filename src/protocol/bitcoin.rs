@@ -0,0 +1,447 @@
+//! This module implements the Bitcoin handshake: both peers send their
+//! `version` message and acknowledge the one of the other with a `verack`.
+//!
+//! Every message is framed as the network magic bytes, the command padded
+//! with zeros to 12 bytes, the payload length as a 4 bytes little endian
+//! integer, the first 4 bytes of the double SHA-256 digest of the payload
+//! and the payload itself.
+//!
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+
+use super::HandshakeCodec;
+use crate::error::{ProtocolError, ProtocolResult};
+
+/// Magic bytes of the Bitcoin mainnet network.
+pub const MAINNET_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+
+/// Magic bytes of the Bitcoin testnet3 network.
+pub const TESTNET_MAGIC: [u8; 4] = [0x0b, 0x11, 0x09, 0x07];
+
+/// Magic bytes of the Bitcoin signet network.
+pub const SIGNET_MAGIC: [u8; 4] = [0x0a, 0x03, 0xcf, 0x40];
+
+/// Magic bytes of the Bitcoin regtest network.
+pub const REGTEST_MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+/// Protocol version sent by default, the one of Bitcoin Core 0.21.
+pub const PROTOCOL_VERSION: i32 = 70016;
+
+/// Oldest protocol version accepted, the first with the `relay` flag.
+pub const MIN_PROTOCOL_VERSION: i32 = 70001;
+
+/// Number of bytes of the message header.
+const HEADER_LEN: usize = 24;
+
+/// Maximum size of a message payload during the handshake, the `version`
+/// message is about 100 bytes long.
+const MAX_PAYLOAD_LEN: usize = 1024;
+
+/// Maximum number of bytes of the messages preceding the `verack`.
+const MAX_RESPONSE_LEN: usize = 8192;
+
+/// The `version` message of a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub version: i32,
+    pub services: u64,
+    /// Seconds since the unix epoch.
+    pub timestamp: i64,
+    /// The address of the receiver, as seen by the sender.
+    pub receiver: SocketAddr,
+    pub sender: SocketAddr,
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+}
+
+/// The Bitcoin handshake, the reply is the `version` message of the peer,
+/// decoded once the peer acknowledged ours.
+#[derive(Debug, Clone)]
+pub struct BitcoinCodec {
+    magic: [u8; 4],
+    version: i32,
+    services: u64,
+    user_agent: String,
+    start_height: i32,
+    relay: bool,
+    nonce: u64,
+}
+
+impl BitcoinCodec {
+    /// Creates a codec for the network identified by `magic`, with a
+    /// random nonce.
+    pub fn new(magic: [u8; 4]) -> Self {
+        Self {
+            magic,
+            version: PROTOCOL_VERSION,
+            services: 0,
+            user_agent: "/p2p-handshake:0.1.0/".to_string(),
+            start_height: 0,
+            relay: false,
+            nonce: crate::rng::random_u64(),
+        }
+    }
+
+    pub fn version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// The services we advertise, none by default.
+    pub fn services(mut self, services: u64) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn start_height(mut self, height: i32) -> Self {
+        self.start_height = height;
+        self
+    }
+
+    /// Whether the peer should announce its transactions.
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// The nonce of our `version` message, a peer replying with it is
+    /// ourselves.
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    fn version_message(&self) -> ProtocolResult<VersionMessage> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| ProtocolError::InvalidSystemTime)?
+            .as_secs() as i64;
+        let unspecified = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+        Ok(VersionMessage {
+            version: self.version,
+            services: self.services,
+            timestamp,
+            receiver: unspecified,
+            sender: unspecified,
+            nonce: self.nonce,
+            user_agent: self.user_agent.clone(),
+            start_height: self.start_height,
+            relay: self.relay,
+        })
+    }
+}
+
+impl HandshakeCodec for BitcoinCodec {
+    type Response = VersionMessage;
+
+    fn encode_request(&self) -> ProtocolResult<Vec<u8>> {
+        let payload = self.version_message()?.encode();
+        Ok(encode_message(self.magic, "version", &payload))
+    }
+
+    fn decode_response(&self, data: &[u8]) -> ProtocolResult<Option<(VersionMessage, usize)>> {
+        let mut version = None;
+        let mut offset = 0;
+        // The peer may send other messages between `version` and `verack`,
+        // ex. `sendaddrv2` or `wtxidrelay`, they are skipped.
+        while let Some((command, payload, len)) = decode_message(&data[offset..], self.magic)? {
+            offset += len;
+            match (command, version.take()) {
+                ("version", None) => version = Some(VersionMessage::decode(payload)?),
+                ("verack", Some(version)) => return Ok(Some((version, offset))),
+                ("version" | "verack", _) => {
+                    return Err(ProtocolError::SpecViolation(format!(
+                        "unexpected `{command}` message"
+                    )))
+                }
+                (_, pending) => version = pending,
+            }
+        }
+        Ok(None)
+    }
+
+    fn validate(&self, response: &VersionMessage) -> ProtocolResult<()> {
+        if response.nonce == self.nonce {
+            return Err(ProtocolError::SelfConnection);
+        }
+        if response.version < MIN_PROTOCOL_VERSION {
+            return Err(ProtocolError::InvalidVersion(format!(
+                "protocol version {} is older than {MIN_PROTOCOL_VERSION}",
+                response.version
+            )));
+        }
+        Ok(())
+    }
+
+    fn acknowledgement(&self, _response: &VersionMessage) -> ProtocolResult<Option<Vec<u8>>> {
+        Ok(Some(encode_message(self.magic, "verack", &[])))
+    }
+
+    fn max_response_len(&self) -> usize {
+        MAX_RESPONSE_LEN
+    }
+}
+
+impl VersionMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(86 + self.user_agent.len());
+        payload.extend_from_slice(&self.version.to_le_bytes());
+        payload.extend_from_slice(&self.services.to_le_bytes());
+        payload.extend_from_slice(&self.timestamp.to_le_bytes());
+        write_address(&mut payload, self.services, self.receiver);
+        write_address(&mut payload, self.services, self.sender);
+        payload.extend_from_slice(&self.nonce.to_le_bytes());
+        write_compact_size(&mut payload, self.user_agent.len() as u64);
+        payload.extend_from_slice(self.user_agent.as_bytes());
+        payload.extend_from_slice(&self.start_height.to_le_bytes());
+        payload.push(self.relay as u8);
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> ProtocolResult<Self> {
+        let mut reader = PayloadReader(payload);
+        let version = LittleEndian::read_i32(reader.take(4)?);
+        let services = LittleEndian::read_u64(reader.take(8)?);
+        let timestamp = LittleEndian::read_i64(reader.take(8)?);
+        let receiver = reader.address()?;
+        let sender = reader.address()?;
+        let nonce = LittleEndian::read_u64(reader.take(8)?);
+        let len = reader.compact_size()?;
+        let user_agent = String::from_utf8(reader.take(len)?.to_vec())?;
+        let start_height = LittleEndian::read_i32(reader.take(4)?);
+        // Peers older than 70001 don't send the flag.
+        let relay = reader.0.first().is_none_or(|relay| *relay != 0);
+        Ok(Self {
+            version,
+            services,
+            timestamp,
+            receiver,
+            sender,
+            nonce,
+            user_agent,
+            start_height,
+            relay,
+        })
+    }
+}
+
+/// Frames the payload as a message of the command.
+fn encode_message(magic: [u8; 4], command: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    message.extend_from_slice(&magic);
+    let mut name = [0u8; 12];
+    let len = command.len().min(name.len());
+    name[..len].copy_from_slice(&command.as_bytes()[..len]);
+    message.extend_from_slice(&name);
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(&checksum(payload));
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Decodes the message at the start of the data, returning its command,
+/// its payload and its length, or `None` when more bytes are needed.
+fn decode_message(data: &[u8], magic: [u8; 4]) -> ProtocolResult<Option<(&str, &[u8], usize)>> {
+    let Some(header) = data.get(..HEADER_LEN) else {
+        return Ok(None);
+    };
+    let mut received = [0u8; 4];
+    received.copy_from_slice(&header[..4]);
+    if received != magic {
+        return Err(ProtocolError::InvalidMagic(received));
+    }
+    let name = &header[4..16];
+    let command_len = name
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(name.len());
+    let command = std::str::from_utf8(&name[..command_len])
+        .map_err(|_| ProtocolError::SpecViolation("the command is not ascii".to_string()))?;
+    let payload_len = LittleEndian::read_u32(&header[16..20]) as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(ProtocolError::MessageTooLarge(MAX_PAYLOAD_LEN));
+    }
+    let Some(payload) = data.get(HEADER_LEN..HEADER_LEN + payload_len) else {
+        return Ok(None);
+    };
+    if header[20..24] != checksum(payload) {
+        return Err(ProtocolError::InvalidChecksum);
+    }
+    Ok(Some((command, payload, HEADER_LEN + payload_len)))
+}
+
+/// The first 4 bytes of the double SHA-256 digest of the payload.
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(Sha256::digest(payload));
+    let mut checksum = [0u8; 4];
+    checksum.copy_from_slice(&digest[..4]);
+    checksum
+}
+
+/// Writes a network address without its timestamp, as in the `version`
+/// message: the services, the IPv6 or IPv4 mapped address and the big
+/// endian port.
+fn write_address(buf: &mut Vec<u8>, services: u64, address: SocketAddr) {
+    buf.extend_from_slice(&services.to_le_bytes());
+    let ip = match address.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    buf.extend_from_slice(&ip.octets());
+    buf.extend_from_slice(&address.port().to_be_bytes());
+}
+
+fn write_compact_size(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfc => buf.push(value as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xfe);
+            buf.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xff);
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Reads the fields of a payload, failing when it ends early.
+struct PayloadReader<'a>(&'a [u8]);
+
+impl<'a> PayloadReader<'a> {
+    fn take(&mut self, len: usize) -> ProtocolResult<&'a [u8]> {
+        let (taken, rest) =
+            self.0
+                .split_at_checked(len)
+                .ok_or(ProtocolError::TruncatedMessage {
+                    expected: len,
+                    got: self.0.len(),
+                })?;
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn compact_size(&mut self) -> ProtocolResult<usize> {
+        let value = match self.take(1)?[0] {
+            0xfd => LittleEndian::read_u16(self.take(2)?) as u64,
+            0xfe => LittleEndian::read_u32(self.take(4)?) as u64,
+            0xff => LittleEndian::read_u64(self.take(8)?),
+            value => value as u64,
+        };
+        Ok(value as usize)
+    }
+
+    fn address(&mut self) -> ProtocolResult<SocketAddr> {
+        self.take(8)?; // services
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(self.take(16)?);
+        let ip = Ipv6Addr::from(octets);
+        let ip = match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        };
+        let port = BigEndian::read_u16(self.take(2)?);
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::handshake_with_codec;
+
+    /// Answers the handshake as a Bitcoin node would, then reads until
+    /// the initiator closes the connection.
+    async fn respond(mut stream: TcpStream, node: &BitcoinCodec) -> ProtocolResult<()> {
+        stream.write_all(&node.encode_request()?).await?;
+        stream
+            .write_all(&encode_message(MAINNET_MAGIC, "wtxidrelay", &[]))
+            .await?;
+        stream
+            .write_all(&encode_message(MAINNET_MAGIC, "verack", &[]))
+            .await?;
+        stream.read_to_end(&mut Vec::new()).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_message() -> ProtocolResult<()> {
+        let codec = BitcoinCodec::new(MAINNET_MAGIC)
+            .user_agent("/Satoshi:27.0.0/")
+            .start_height(840_000)
+            .relay(true);
+        let mut message = codec.version_message()?;
+        message.receiver = "1.2.3.4:8333".parse().unwrap();
+        assert_eq!(VersionMessage::decode(&message.encode())?, message);
+
+        let request = codec.encode_request()?;
+        assert_eq!(&request[..4], MAINNET_MAGIC);
+        assert_eq!(&request[4..16], b"version\0\0\0\0\0");
+        // The checksum of an empty payload.
+        let verack = encode_message(MAINNET_MAGIC, "verack", &[]);
+        assert_eq!(&verack[20..], [0x5d, 0xf6, 0xe0, 0xe2]);
+
+        // The reply is complete with the `verack` only.
+        let mut data = request.clone();
+        assert!(codec.decode_response(&data)?.is_none());
+        data.extend_from_slice(&verack);
+        data.extend_from_slice(b"next");
+        let (version, len) = codec.decode_response(&data)?.unwrap();
+        assert_eq!(version.user_agent, "/Satoshi:27.0.0/");
+        assert_eq!(len, data.len() - 4);
+
+        let mut corrupted = request;
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            codec.decode_response(&corrupted),
+            Err(ProtocolError::InvalidChecksum)
+        ));
+        assert!(matches!(
+            codec.decode_response(&verack),
+            Err(ProtocolError::SpecViolation(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bitcoin_handshake() -> ProtocolResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let node = BitcoinCodec::new(MAINNET_MAGIC).user_agent("/Satoshi:27.0.0/");
+        tokio::spawn(async move {
+            for node in [node.clone(), node.nonce(42)] {
+                let (stream, _) = listener.accept().await?;
+                respond(stream, &node).await?;
+            }
+            ProtocolResult::Ok(())
+        });
+
+        let codec = BitcoinCodec::new(MAINNET_MAGIC);
+        let reply = handshake_with_codec(address, &codec, |_, reply| Ok(reply)).await?;
+        assert_eq!(reply.user_agent, "/Satoshi:27.0.0/");
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+
+        let codec = BitcoinCodec::new(MAINNET_MAGIC).nonce(42);
+        let result = handshake_with_codec(address, &codec, |_, reply| Ok(reply)).await;
+        assert!(matches!(result, Err(ProtocolError::SelfConnection)));
+        Ok(())
+    }
+}
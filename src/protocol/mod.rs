@@ -0,0 +1,204 @@
+//! This module implements the handshake of any P2P network on top of the
+//! connection scaffolding of the crate, through the [`HandshakeCodec`]
+//! trait.
+//!
+//! A codec tells how to encode our request, how to decode the reply of
+//! the peer from the bytes received so far and how to validate it. The
+//! Ergo handshake is implemented by [`HandshakeBuilder`], and with the
+//! `bitcoin` feature, `bitcoin::BitcoinCodec` implements the Bitcoin
+//! `version` and `verack` exchange.
+//!
+//! Not to be confused with the `tokio_util` codecs of the
+//! `codec` module, which frame the Ergo messages of a `Framed` stream.
+//!
+//! ```no_run
+//! # #[cfg(feature = "net")]
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::{handshake_with_codec, HandshakeBuilder};
+//!
+//! let request = HandshakeBuilder::new().agent_name("paul");
+//! let reply = handshake_with_codec("127.0.0.1:9030", &request, |_stream, reply| Ok(reply)).await?;
+//! println!("{}", reply.agent_name);
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+#[cfg(feature = "bitcoin")]
+pub mod bitcoin;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::builder::HandshakeBuilder;
+use crate::encoder::{is_truncated, HandshakeMessage};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::spec;
+
+/// The messages of the handshake of a P2P network.
+pub trait HandshakeCodec {
+    /// The decoded reply of the peer.
+    type Response;
+
+    /// Encodes the request sent as soon as the connection is established.
+    fn encode_request(&self) -> ProtocolResult<Vec<u8>>;
+
+    /// Decodes the reply at the start of the bytes received so far,
+    /// returning it along with the number of bytes it spans, or `None`
+    /// when more bytes are needed.
+    fn decode_response(&self, data: &[u8]) -> ProtocolResult<Option<(Self::Response, usize)>>;
+
+    /// Checks the decoded reply, the handshake fails with the error.
+    fn validate(&self, _response: &Self::Response) -> ProtocolResult<()> {
+        Ok(())
+    }
+
+    /// The bytes sent once the reply is validated, if any, ex. the
+    /// Bitcoin `verack` message.
+    fn acknowledgement(&self, _response: &Self::Response) -> ProtocolResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Maximum number of bytes of the reply.
+    fn max_response_len(&self) -> usize {
+        spec::MAX_HANDSHAKE_SIZE
+    }
+}
+
+/// The Ergo handshake.
+impl HandshakeCodec for HandshakeBuilder {
+    type Response = HandshakeMessage;
+
+    fn encode_request(&self) -> ProtocolResult<Vec<u8>> {
+        self.encode()
+    }
+
+    fn decode_response(&self, data: &[u8]) -> ProtocolResult<Option<(HandshakeMessage, usize)>> {
+        let mut remaining = data;
+        match HandshakeMessage::decode_from_reader(&mut remaining) {
+            Ok(message) => Ok(Some((message, data.len() - remaining.len()))),
+            Err(err) if is_truncated(&err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn validate(&self, response: &HandshakeMessage) -> ProtocolResult<()> {
+        self.check_self_connection(response)
+    }
+}
+
+/// Runs the handshake of the codec on a connected stream, returning the
+/// validated reply.
+///
+/// The stream is read through the buffer, which keeps the bytes the peer
+/// sent after its reply, so keep using the buffer rather than the stream.
+pub async fn exchange<S, C>(stream: &mut BufReader<S>, codec: &C) -> ProtocolResult<C::Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: HandshakeCodec + ?Sized,
+{
+    let request = codec.encode_request()?;
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let response = read_response(stream, codec).await?;
+    codec.validate(&response)?;
+    if let Some(acknowledgement) = codec.acknowledgement(&response)? {
+        stream.write_all(&acknowledgement).await?;
+        stream.flush().await?;
+    }
+    Ok(response)
+}
+
+/// Reads until the codec decodes a reply, consuming only its bytes.
+async fn read_response<S, C>(stream: &mut BufReader<S>, codec: &C) -> ProtocolResult<C::Response>
+where
+    S: AsyncRead + Unpin,
+    C: HandshakeCodec + ?Sized,
+{
+    let max_len = codec.max_response_len();
+    let mut data = Vec::new();
+    loop {
+        let chunk = stream.fill_buf().await?;
+        if chunk.is_empty() {
+            return Err(if data.is_empty() {
+                ProtocolError::HandshakeTimedOutByPeer
+            } else {
+                ProtocolError::TruncatedMessage {
+                    expected: data.len() + 1,
+                    got: data.len(),
+                }
+            });
+        }
+        let received = data.len();
+        let chunk_len = chunk.len();
+        data.extend_from_slice(chunk);
+        if let Some((response, len)) = codec.decode_response(&data)? {
+            if len > max_len {
+                return Err(ProtocolError::MessageTooLarge(max_len));
+            }
+            stream.consume(len.saturating_sub(received));
+            return Ok(response);
+        }
+        if data.len() >= max_len {
+            return Err(ProtocolError::MessageTooLarge(max_len));
+        }
+        stream.consume(chunk_len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::respond_handshake;
+
+    #[tokio::test]
+    async fn test_ergo_codec() -> ProtocolResult<()> {
+        let (initiator, mut responder) = tokio::io::duplex(64);
+        let reply = HandshakeBuilder::new().agent_name("ergoref");
+        let responded = tokio::spawn(async move {
+            let request = respond_handshake(&mut responder, &reply).await?;
+            // A message following the handshake right away.
+            responder.write_all(b"next").await?;
+            ProtocolResult::Ok(request)
+        });
+
+        let request = HandshakeBuilder::new().agent_name("paul");
+        let mut stream = BufReader::new(initiator);
+        let response = exchange(&mut stream, &request).await?;
+        assert_eq!(response.agent_name.as_str(), "ergoref");
+        assert_eq!(responded.await.unwrap()?.agent_name.as_str(), "paul");
+
+        let mut next = [0; 4];
+        stream.read_exact(&mut next).await?;
+        assert_eq!(&next, b"next");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_response() -> ProtocolResult<()> {
+        let request = HandshakeBuilder::new().agent_name("paul");
+        let (initiator, responder) = tokio::io::duplex(64);
+        drop(responder);
+        let err = read_response(&mut BufReader::new(initiator), &request)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProtocolError::HandshakeTimedOutByPeer));
+
+        let (initiator, mut responder) = tokio::io::duplex(64);
+        responder.write_all(&[0x80]).await?;
+        drop(responder);
+        let err = read_response(&mut BufReader::new(initiator), &request)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::TruncatedMessage {
+                expected: 2,
+                got: 1
+            }
+        ));
+        Ok(())
+    }
+}
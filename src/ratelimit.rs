@@ -0,0 +1,251 @@
+//! This module implements pacing the connections of a scan, so that large
+//! target lists don't trip the rate limits or the intrusion detection of
+//! the networks being probed.
+//!
+//! A [`RateLimiter`] caps the connections started per second over all the
+//! targets, and the handshakes running at the same time with a single
+//! host or a single /24 subnet (/48 in IPv6). Every connection attempt of
+//! the configurations holding a clone of the limiter counts, retries and
+//! the sweep of the [`TwoPhase`](crate::ScanStrategy::TwoPhase) strategy
+//! included. Waiting for the limiter doesn't count in the timeouts.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::ratelimit::RateLimiter;
+//! use p2p_handshake::{handshake_many, HandshakeBuilder, HandshakeConfig};
+//!
+//! let limiter = RateLimiter::new().per_second(50.0).per_host(1).per_subnet(4);
+//! let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"))
+//!     .rate_limiter(limiter);
+//! let results = handshake_many(vec!["1.2.3.4:9030", "1.2.3.5:9030"], &config, 256).await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::aggregate::subnet;
+use crate::route::Target;
+use crate::sync::lock;
+
+/// Paces the connections, see the [module](self) documentation. Cloning
+/// a limiter shares its budget, limiters created apart are independent.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    per_host: Option<usize>,
+    per_subnet: Option<usize>,
+    state: Arc<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The earliest time the next connection may start.
+    next_start: Mutex<Option<Instant>>,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+    subnets: Mutex<HashMap<IpAddr, Arc<Semaphore>>>,
+}
+
+/// Held for the duration of a connection attempt, releasing its slots of
+/// the host and subnet caps once dropped.
+#[derive(Debug)]
+pub(crate) struct RatePermit {
+    host: Option<Slot<String>>,
+    subnet: Option<Slot<IpAddr>>,
+    state: Arc<State>,
+}
+
+#[derive(Debug)]
+struct Slot<K> {
+    key: K,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl RateLimiter {
+    /// A limiter without any limit, set them with the builder methods.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts at most `rate` connections per second, evenly spaced. A rate
+    /// which isn't a positive number lifts the limit.
+    pub fn per_second(mut self, rate: f64) -> Self {
+        self.interval = match rate > 0.0 {
+            true => Duration::try_from_secs_f64(1.0 / rate).ok(),
+            false => None,
+        };
+        self
+    }
+
+    /// Runs at most `limit` handshakes at the same time with a single host,
+    /// the IP address of a target or else its host name.
+    pub fn per_host(mut self, limit: usize) -> Self {
+        self.per_host = Some(limit.max(1));
+        self
+    }
+
+    /// Runs at most `limit` handshakes at the same time within a single
+    /// /24 subnet, /48 in IPv6. Targets given by host name aren't capped by
+    /// subnet, their addresses being unknown until they are resolved.
+    pub fn per_subnet(mut self, limit: usize) -> Self {
+        self.per_subnet = Some(limit.max(1));
+        self
+    }
+
+    /// Whether any limit is set.
+    pub fn is_limited(&self) -> bool {
+        self.interval.is_some() || self.per_host.is_some() || self.per_subnet.is_some()
+    }
+
+    /// Waits until a connection to the target may start. The caps are
+    /// acquired first, so that the connections held back by them don't
+    /// consume the budget per second.
+    pub(crate) fn acquire<A: Target + ?Sized>(
+        &self,
+        target: &A,
+    ) -> impl Future<Output = RatePermit> + Send + '_ {
+        // The keys are read upfront, the target needs not be `Sync`.
+        let ip = target.address().map(|address| address.ip());
+        let host = match (ip, target.host_port()) {
+            (Some(ip), _) => Some(ip.to_string()),
+            (None, Some((host, _))) => Some(host.to_ascii_lowercase()),
+            (None, None) => None,
+        };
+        self.acquire_keys(ip, host)
+    }
+
+    async fn acquire_keys(&self, ip: Option<IpAddr>, host: Option<String>) -> RatePermit {
+        let mut permit = RatePermit {
+            host: None,
+            subnet: None,
+            state: self.state.clone(),
+        };
+        if let (Some(limit), Some(ip)) = (self.per_subnet, ip) {
+            let slot = Slot::acquire(&self.state.subnets, subnet(ip), limit).await;
+            permit.subnet = Some(slot);
+        }
+        if let (Some(limit), Some(host)) = (self.per_host, host) {
+            let slot = Slot::acquire(&self.state.hosts, host, limit).await;
+            permit.host = Some(slot);
+        }
+        if let Some(interval) = self.interval {
+            let start = {
+                let mut next_start = lock(&self.state.next_start);
+                let now = Instant::now();
+                let start = next_start.map_or(now, |next| next.max(now));
+                *next_start = Some(start + interval);
+                start
+            };
+            tokio::time::sleep_until(start).await;
+        }
+        permit
+    }
+}
+
+impl<K: Clone + Eq + std::hash::Hash> Slot<K> {
+    async fn acquire(slots: &Mutex<HashMap<K, Arc<Semaphore>>>, key: K, limit: usize) -> Self {
+        let semaphore = lock(slots)
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+        // The semaphores are never closed, acquiring them can't fail.
+        let permit = semaphore.clone().acquire_owned().await.ok();
+        Self {
+            key,
+            semaphore,
+            permit,
+        }
+    }
+
+    /// Releases the slot, and forgets the semaphore once nobody holds nor
+    /// waits for it, so that the maps don't grow with the targets.
+    fn release(mut self, slots: &Mutex<HashMap<K, Arc<Semaphore>>>) {
+        drop(self.permit.take());
+        let mut slots = lock(slots);
+        // The map and this slot hold the only references left.
+        if Arc::strong_count(&self.semaphore) == 2 {
+            slots.remove(&self.key);
+        }
+    }
+}
+
+impl Drop for RatePermit {
+    fn drop(&mut self) {
+        if let Some(slot) = self.host.take() {
+            slot.release(&self.state.hosts);
+        }
+        if let Some(slot) = self.subnet.take() {
+            slot.release(&self.state.subnets);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_per_second() {
+        let limiter = RateLimiter::new().per_second(50.0);
+        let started = Instant::now();
+        for _ in 0..5 {
+            let _permit = limiter.acquire("1.2.3.4:9030").await;
+        }
+        // The first connection starts at once, the others 20ms apart.
+        assert!(started.elapsed() >= Duration::from_millis(80));
+
+        // Clones share the budget.
+        let cloned = limiter.clone();
+        let started = Instant::now();
+        let _permit = cloned.acquire("5.6.7.8:9030").await;
+        assert!(started.elapsed() >= Duration::from_millis(15));
+
+        assert!(!RateLimiter::new().per_second(0.0).is_limited());
+        assert!(!RateLimiter::new().per_second(f64::NAN).is_limited());
+    }
+
+    #[tokio::test]
+    async fn test_caps() {
+        let waiting = Duration::from_millis(50);
+        let limiter = RateLimiter::new().per_host(1).per_subnet(2);
+        let first = limiter.acquire("1.2.3.4:9030").await;
+        // Another port of the same host waits for the first handshake.
+        let same_host = tokio::time::timeout(waiting, limiter.acquire("1.2.3.4:9031")).await;
+        assert!(same_host.is_err());
+
+        let second: SocketAddr = "1.2.3.5:9030".parse().unwrap();
+        let _second = limiter.acquire(&second).await;
+        // The subnet is full.
+        let same_subnet = tokio::time::timeout(waiting, limiter.acquire("1.2.3.6:9030")).await;
+        assert!(same_subnet.is_err());
+        let _other_subnet = limiter.acquire("1.2.4.6:9030").await;
+
+        drop(first);
+        let _third = limiter.acquire("1.2.3.6:9030").await;
+        // Host names are only capped per host.
+        let _name = limiter.acquire("node.example:9030").await;
+        let same_name = tokio::time::timeout(waiting, limiter.acquire("NODE.example:9031")).await;
+        assert!(same_name.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slots_are_forgotten() {
+        let limiter = RateLimiter::new().per_host(2).per_subnet(2);
+        for port in 0..100 {
+            let _permit = limiter.acquire(&format!("1.2.3.{}:{port}", port % 7)).await;
+        }
+        assert!(lock(&limiter.state.hosts).is_empty());
+        assert!(lock(&limiter.state.subnets).is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+//! A small seeded pseudo random generator, used wherever runs must be
+//! reproducible from a seed.
+//!
+
+/// The SplitMix64 generator, fast and good enough for scheduling.
+#[cfg(any(test, feature = "net"))]
+#[derive(Debug, Clone)]
+pub(crate) struct SplitMix64 {
+    state: u64,
+}
+
+#[cfg(any(test, feature = "net"))]
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in [0, 1).
+    #[cfg(any(test, feature = "test-util", feature = "crawl"))]
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a number in [0, bound), `bound` must not be 0.
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    /// Shuffles the items with the Fisher-Yates algorithm.
+    pub(crate) fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// Returns a number that is hard to guess, ex. a session id. The hasher
+/// of the standard library is keyed from the random source of the system.
+pub(crate) fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Successive calls differ even where the hasher keys are fixed, ex. on
+    // `wasm32-unknown-unknown`, which has neither random source nor clock.
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(CALLS.fetch_add(1, Ordering::Relaxed));
+    if !cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_sequences() {
+        let mut first = SplitMix64::new(42);
+        let mut second = SplitMix64::new(42);
+        let mut items: Vec<u32> = (0..10).collect();
+        let mut same_items = items.clone();
+        first.shuffle(&mut items);
+        second.shuffle(&mut same_items);
+        assert_eq!(items, same_items);
+        assert_ne!(items, (0..10).collect::<Vec<u32>>());
+
+        let value = first.next_f64();
+        assert!((0.0..1.0).contains(&value));
+        assert_ne!(SplitMix64::new(1).next_u64(), SplitMix64::new(2).next_u64());
+    }
+}
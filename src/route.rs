@@ -0,0 +1,249 @@
+//! This module implements routing the targets whose host name ends with
+//! a given suffix to a dedicated transport, ex. `.onion` targets through
+//! the Tor SOCKS proxy or `.local` targets resolved with mDNS.
+//!
+//! The other targets are resolved and dialed as usual.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::mdns::MdnsResolver;
+//! use p2p_handshake::route::TargetRouter;
+//! use p2p_handshake::{handshake_many, HandshakeBuilder, HandshakeConfig};
+//!
+//! let router = TargetRouter::new().route(".local", MdnsResolver::new());
+//! let config = HandshakeConfig::new(HandshakeBuilder::new()).router(router);
+//! let results = handshake_many(vec!["ergo-node.local:9030"], &config, 1).await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::error::ProtocolResult;
+
+/// A boxed future, as returned by the handlers.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A target of a handshake, exposing its host name, if any, so that it
+/// can be routed by its suffix.
+pub trait Target: ToSocketAddrs {
+    /// The host name and port of the target, `None` for IP addresses.
+    fn host_port(&self) -> Option<(&str, u16)> {
+        None
+    }
+
+    /// The address of the target when it is given as one, without
+    /// resolving any name.
+    fn address(&self) -> Option<SocketAddr> {
+        let (host, port) = self.host_port()?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Some(SocketAddr::new(host.parse().ok()?, port))
+    }
+}
+
+impl Target for str {
+    fn host_port(&self) -> Option<(&str, u16)> {
+        let (host, port) = self.rsplit_once(':')?;
+        Some((host, port.parse().ok()?))
+    }
+}
+
+impl Target for String {
+    fn host_port(&self) -> Option<(&str, u16)> {
+        self.as_str().host_port()
+    }
+}
+
+impl Target for (&str, u16) {
+    fn host_port(&self) -> Option<(&str, u16)> {
+        Some(*self)
+    }
+}
+
+impl Target for (String, u16) {
+    fn host_port(&self) -> Option<(&str, u16)> {
+        Some((&self.0, self.1))
+    }
+}
+
+impl Target for SocketAddr {
+    fn address(&self) -> Option<SocketAddr> {
+        Some(*self)
+    }
+}
+
+impl Target for SocketAddrV4 {
+    fn address(&self) -> Option<SocketAddr> {
+        Some((*self).into())
+    }
+}
+
+impl Target for SocketAddrV6 {
+    fn address(&self) -> Option<SocketAddr> {
+        Some((*self).into())
+    }
+}
+
+impl Target for (IpAddr, u16) {
+    fn address(&self) -> Option<SocketAddr> {
+        Some((*self).into())
+    }
+}
+
+impl Target for (Ipv4Addr, u16) {
+    fn address(&self) -> Option<SocketAddr> {
+        Some((*self).into())
+    }
+}
+
+impl Target for (Ipv6Addr, u16) {
+    fn address(&self) -> Option<SocketAddr> {
+        Some((*self).into())
+    }
+}
+
+impl<T: Target + ?Sized> Target for &T {
+    fn host_port(&self) -> Option<(&str, u16)> {
+        (**self).host_port()
+    }
+
+    fn address(&self) -> Option<SocketAddr> {
+        (**self).address()
+    }
+}
+
+/// Connects to the targets routed to it.
+pub trait TargetHandler: Send + Sync {
+    /// Connects to the port of the host, ex. `abcdef.onion` and 9030.
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, ProtocolResult<TcpStream>>;
+}
+
+/// The handlers of the targets by suffix of their host name. Cloning a
+/// router only bumps reference counts.
+#[derive(Clone, Default)]
+pub struct TargetRouter {
+    routes: Vec<(String, Arc<dyn TargetHandler>)>,
+}
+
+impl fmt::Debug for TargetRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let suffixes: Vec<&str> = self
+            .routes
+            .iter()
+            .map(|(suffix, _)| suffix.as_str())
+            .collect();
+        f.debug_struct("TargetRouter")
+            .field("suffixes", &suffixes)
+            .finish()
+    }
+}
+
+impl TargetRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes the hosts ending with the suffix, ex. `.onion`, to the
+    /// handler. Suffixes are compared ignoring the case, the first route
+    /// matching a host wins.
+    pub fn route<H: TargetHandler + 'static>(mut self, suffix: &str, handler: H) -> Self {
+        self.routes
+            .push((suffix.to_ascii_lowercase(), Arc::new(handler)));
+        self
+    }
+
+    /// The handler of the host, if it ends with one of the suffixes.
+    pub fn find(&self, host: &str) -> Option<&dyn TargetHandler> {
+        // Fully qualified names end with a dot.
+        let host = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+        self.routes
+            .iter()
+            .find(|(suffix, _)| host.ends_with(suffix.as_str()))
+            .map(|(_, handler)| handler.as_ref())
+    }
+
+    /// Whether the target is routed to one of the handlers.
+    pub fn routes<A: Target + ?Sized>(&self, target: &A) -> bool {
+        target
+            .host_port()
+            .is_some_and(|(host, _)| self.find(host).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::config::HandshakeConfig;
+    use crate::{handshake_many, testing};
+
+    /// Connects every target to the same address, counting them.
+    struct Redirect {
+        address: SocketAddr,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl TargetHandler for Redirect {
+        fn connect<'a>(
+            &'a self,
+            _host: &'a str,
+            _port: u16,
+        ) -> BoxFuture<'a, ProtocolResult<TcpStream>> {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move { Ok(TcpStream::connect(self.address).await?) })
+        }
+    }
+
+    #[test]
+    fn test_host_port() {
+        assert_eq!("node.onion:9030".host_port(), Some(("node.onion", 9030)));
+        assert_eq!("[::1]:9030".host_port(), Some(("[::1]", 9030)));
+        assert_eq!("node.onion".host_port(), None);
+        assert_eq!(("node.local", 9030).host_port(), Some(("node.local", 9030)));
+        assert_eq!(
+            "127.0.0.1:9030".parse::<SocketAddr>().unwrap().host_port(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_router() -> ProtocolResult<()> {
+        let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+        let node = testing::MockErgoNode::builder()
+            .respond_handshake(reply)
+            .spawn()
+            .await?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let redirect = Redirect {
+            address: node.address(),
+            connections: connections.clone(),
+        };
+        let router = TargetRouter::new().route(".onion", redirect);
+        assert!(router.find("abcdef.ONION.").is_some());
+        assert!(router.find("onion.example.org").is_none());
+        assert!(!router.routes("127.0.0.1:9030"));
+
+        let config = HandshakeConfig::new(HandshakeBuilder::new()).router(router);
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let targets = vec!["abcdef.onion:9030".to_string(), closed.to_string()];
+        let results = handshake_many(targets, &config, 2).await;
+        assert_eq!(
+            results[0].outcome.as_ref().unwrap().agent_name.as_str(),
+            "ergoref"
+        );
+        assert!(results[1].outcome.is_err());
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+}
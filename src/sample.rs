@@ -0,0 +1,200 @@
+//! This module implements probing a random sample of a large target list,
+//! to estimate the health of a network without a full sweep.
+//!
+//! The sample is drawn from a seeded generator, so a given seed and target
+//! list always probe the same targets. The sample results are then
+//! extrapolated to the whole list by [`SampleSummary`].
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::batch::ProbeResult;
+use crate::rng::SplitMix64;
+
+/// Z-score of the 95% confidence level.
+const Z_95: f64 = 1.96;
+
+/// Returns `size` targets picked at random, in the order they were drawn.
+/// All the targets are returned, shuffled, when there are fewer than `size`.
+pub fn sample<A>(mut targets: Vec<A>, size: usize, seed: u64) -> Vec<A> {
+    let mut rng = SplitMix64::new(seed);
+    // A partial Fisher-Yates shuffle only draws the picked targets.
+    let size = size.min(targets.len());
+    for i in 0..size {
+        let j = i + rng.below((targets.len() - i) as u64) as usize;
+        targets.swap(i, j);
+    }
+    targets.truncate(size);
+    targets
+}
+
+/// An estimate of a count over the whole target list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// Fraction of the sample, between 0 and 1.
+    pub ratio: f64,
+    /// Half width of the 95% confidence interval of `ratio`.
+    pub margin: f64,
+    /// Extrapolated count over the whole target list.
+    pub count: u64,
+}
+
+impl Estimate {
+    fn new(hits: usize, sample_size: usize, population: usize) -> Self {
+        if sample_size == 0 {
+            return Self {
+                ratio: 0.0,
+                margin: 0.0,
+                count: 0,
+            };
+        }
+        let n = sample_size as f64;
+        let ratio = hits as f64 / n;
+        // Sampling without replacement, the finite population correction
+        // shrinks the interval down to 0 when the whole list is probed.
+        let correction = if population > 1 {
+            ((population - sample_size) as f64 / (population - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+        let margin = Z_95 * (ratio * (1.0 - ratio) / n).sqrt() * correction;
+        Self {
+            ratio,
+            margin,
+            count: (ratio * population as f64).round() as u64,
+        }
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "~{} ({:.1}% ± {:.1}%)",
+            self.count,
+            self.ratio * 100.0,
+            self.margin * 100.0
+        )
+    }
+}
+
+/// The results of a sample extrapolated to the whole target list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleSummary {
+    /// Number of targets in the whole list.
+    pub population: usize,
+    /// Number of probed targets.
+    pub sample_size: usize,
+    /// Targets completing the handshake.
+    pub reachable: Estimate,
+    /// Reachable targets by advertised version.
+    pub versions: BTreeMap<String, Estimate>,
+}
+
+impl SampleSummary {
+    /// Summarizes the results of probing a sample drawn out of
+    /// `population` targets.
+    pub fn new<A>(population: usize, results: &[ProbeResult<A>]) -> Self {
+        let sample_size = results.len();
+        let population = population.max(sample_size);
+        let mut reachable = 0;
+        let mut versions = BTreeMap::<String, usize>::new();
+        for reply in results
+            .iter()
+            .filter_map(|result| result.outcome.as_ref().ok())
+        {
+            reachable += 1;
+            *versions.entry(reply.version.to_string()).or_default() += 1;
+        }
+        Self {
+            population,
+            sample_size,
+            reachable: Estimate::new(reachable, sample_size, population),
+            versions: versions
+                .into_iter()
+                .map(|(version, hits)| (version, Estimate::new(hits, sample_size, population)))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for SampleSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Probed {} of {} targets.",
+            self.sample_size, self.population
+        )?;
+        writeln!(f, "Reachable: {}", self.reachable)?;
+        for (version, estimate) in &self.versions {
+            writeln!(f, "  {version}: {estimate}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{HandshakeMessage, Version};
+    use crate::error::ProtocolError;
+
+    fn result(version: Option<Version>) -> ProbeResult<()> {
+        ProbeResult {
+            target: (),
+            address: None,
+            outcome: version
+                .map(|version| HandshakeMessage {
+                    version,
+                    ..Default::default()
+                })
+                .ok_or(ProtocolError::TimedOut),
+            connect_time: None,
+            handshake_time: None,
+            attempts: 1,
+            started_at: 0,
+            cached: false,
+        }
+    }
+
+    #[test]
+    fn test_sample() {
+        let targets: Vec<u32> = (0..1000).collect();
+        let picked = sample(targets.clone(), 10, 42);
+        assert_eq!(picked.len(), 10);
+        assert_eq!(picked, sample(targets.clone(), 10, 42));
+        assert_ne!(picked, sample(targets.clone(), 10, 43));
+        let mut unique = picked.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), 10);
+
+        assert_eq!(sample(vec![1, 2, 3], 10, 42).len(), 3);
+    }
+
+    #[test]
+    fn test_summary() {
+        let results = vec![
+            result(Some(Version([5, 0, 21]))),
+            result(Some(Version([5, 0, 21]))),
+            result(Some(Version([4, 0, 100]))),
+            result(None),
+        ];
+        let summary = SampleSummary::new(1000, &results);
+        assert_eq!(summary.sample_size, 4);
+        assert_eq!(summary.reachable.count, 750);
+        assert!(summary.reachable.margin > 0.0);
+        assert_eq!(summary.versions["5.0.21"].count, 500);
+        assert_eq!(summary.versions["4.0.100"].count, 250);
+
+        // Probing every target leaves no uncertainty.
+        let summary = SampleSummary::new(4, &results);
+        assert_eq!(summary.reachable.count, 3);
+        assert_eq!(summary.reachable.margin, 0.0);
+        assert_eq!(
+            summary.to_string(),
+            "Probed 4 of 4 targets.\nReachable: ~3 (75.0% ± 0.0%)\n  4.0.100: ~1 (25.0% ± 0.0%)\n  5.0.21: ~2 (50.0% ± 0.0%)\n"
+        );
+    }
+}
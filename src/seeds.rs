@@ -0,0 +1,178 @@
+//! This module implements bootstrapping from seeds: well known host
+//! names, or addresses, resolved into the candidate peers to start from.
+//!
+//! A seed resolving to several addresses, ex. a DNS seed answering with
+//! many records, contributes all of them. The candidates are deduplicated
+//! and shuffled, so that the runs bootstrapping from the same seeds don't
+//! all hit the same peers first.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::framing::Network;
+//! use p2p_handshake::seeds::Seeds;
+//!
+//! let seeds = Seeds::for_network(Network::Mainnet).resolve().await;
+//! for (seed, err) in &seeds.failures {
+//!     eprintln!("cannot resolve {seed}: {err}");
+//! }
+//! println!("{} candidate peers", seeds.addresses.len());
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::error::ProtocolError;
+use crate::framing::Network;
+use crate::rng::{random_u64, SplitMix64};
+
+/// The known peers of the mainnet configuration of the reference node.
+pub const MAINNET_SEEDS: [&str; 12] = [
+    "213.239.193.208:9030",
+    "159.65.11.55:9030",
+    "165.227.26.175:9030",
+    "159.89.116.15:9030",
+    "136.244.110.145:9030",
+    "94.130.108.35:9030",
+    "51.75.147.1:9020",
+    "221.165.214.185:9030",
+    "217.182.197.196:9030",
+    "173.212.220.9:9030",
+    "176.9.65.58:9130",
+    "213.152.106.56:9030",
+];
+
+/// The known peers of the testnet configuration of the reference node.
+pub const TESTNET_SEEDS: [&str; 1] = ["213.239.193.208:9020"];
+
+/// The seeds to resolve, as `host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Seeds {
+    seeds: Vec<String>,
+    shuffle_seed: Option<u64>,
+}
+
+/// The candidate peers resolved from the seeds.
+#[derive(Debug, Default)]
+pub struct ResolvedSeeds {
+    /// The distinct addresses, shuffled.
+    pub addresses: Vec<SocketAddr>,
+    /// The seeds which couldn't be resolved, with the reason.
+    pub failures: Vec<(String, ProtocolError)>,
+}
+
+impl Seeds {
+    pub fn new<I, S>(seeds: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            seeds: seeds.into_iter().map(Into::into).collect(),
+            shuffle_seed: None,
+        }
+    }
+
+    /// The default seeds of the network, none for the devnet and the
+    /// custom networks.
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::new(MAINNET_SEEDS),
+            Network::Testnet => Self::new(TESTNET_SEEDS),
+            Network::Devnet | Network::Custom(_) => Self::new(Vec::<String>::new()),
+        }
+    }
+
+    /// Shuffles the candidates from the given seed rather than a random
+    /// one, so that runs can be reproduced.
+    pub fn shuffle_seed(mut self, seed: u64) -> Self {
+        self.shuffle_seed = Some(seed);
+        self
+    }
+
+    pub fn seeds(&self) -> &[String] {
+        &self.seeds
+    }
+
+    /// Resolves every seed, with hickory when the `hickory-dns` feature is
+    /// enabled. A seed failing to resolve is reported in the failures
+    /// without failing the others.
+    pub async fn resolve(&self) -> ResolvedSeeds {
+        #[cfg(feature = "hickory-dns")]
+        let resolver = crate::dns::DnsResolver::system_or_cloudflare();
+        let mut resolved = ResolvedSeeds::default();
+        let mut seen = HashSet::new();
+        for seed in &self.seeds {
+            #[cfg(feature = "hickory-dns")]
+            let lookup = resolver.lookup(seed).await;
+            #[cfg(not(feature = "hickory-dns"))]
+            let lookup = lookup_host(seed).await;
+            match lookup {
+                Ok(addresses) if addresses.is_empty() => {
+                    let err = io::Error::new(io::ErrorKind::NotFound, "no address found");
+                    resolved.failures.push((seed.clone(), err.into()));
+                }
+                Ok(addresses) => resolved.addresses.extend(
+                    addresses
+                        .into_iter()
+                        .filter(|address| seen.insert(*address)),
+                ),
+                Err(err) => resolved.failures.push((seed.clone(), err)),
+            }
+        }
+        let mut rng = SplitMix64::new(self.shuffle_seed.unwrap_or_else(random_u64));
+        rng.shuffle(&mut resolved.addresses);
+        resolved
+    }
+}
+
+#[cfg(not(feature = "hickory-dns"))]
+async fn lookup_host(seed: &str) -> crate::error::ProtocolResult<Vec<SocketAddr>> {
+    Ok(tokio::net::lookup_host(seed).await?.collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let seeds = Seeds::new([
+            "127.0.0.1:9030",
+            "127.0.0.2:9030",
+            "127.0.0.1:9030",
+            "[::1]:9030",
+            "not a seed",
+        ])
+        .shuffle_seed(7);
+        let resolved = seeds.resolve().await;
+        assert_eq!(resolved.addresses.len(), 3);
+        assert_eq!(resolved.failures.len(), 1);
+        assert_eq!(resolved.failures[0].0, "not a seed");
+
+        // The same shuffle seed draws the same order.
+        assert_eq!(seeds.resolve().await.addresses, resolved.addresses);
+        let mut sorted = resolved.addresses.clone();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            [
+                "127.0.0.1:9030".parse().unwrap(),
+                "127.0.0.2:9030".parse().unwrap(),
+                "[::1]:9030".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default_seeds() {
+        assert!(!Seeds::for_network(Network::Mainnet).seeds().is_empty());
+        assert!(Seeds::for_network(Network::Devnet).seeds().is_empty());
+        for seed in MAINNET_SEEDS.iter().chain(&TESTNET_SEEDS) {
+            assert!(seed.parse::<SocketAddr>().is_ok(), "{seed}");
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! This module implements dialing the target node through a SOCKS5 proxy
+//! (ex. Tor), as described in [RFC 1928](https://www.rfc-editor.org/rfc/rfc1928).
+//!
+//! Only the `CONNECT` command without authentication is supported. Host
+//! names are resolved by the proxy, so `.onion` targets can be reached
+//! through Tor.
+//!
+
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::builder::HandshakeBuilder;
+use crate::encoder::{HandshakeMessage, Version};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::route::{BoxFuture, TargetHandler};
+use crate::{connect, exchange};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT_COMMAND: u8 = 1;
+const IPV4_ADDRESS_TYPE: u8 = 1;
+const DOMAIN_ADDRESS_TYPE: u8 = 3;
+const IPV6_ADDRESS_TYPE: u8 = 4;
+
+/// Same as [`handshake`](crate::handshake) but reaches the target through
+/// the SOCKS5 proxy listening at `proxy_address`.
+///
+/// * `proxy_address` - The address and port of the proxy (ex. 127.0.0.1:9050).
+/// * `target` - The host and port of the target node, resolved by the proxy.
+///
+pub async fn handshake_via_proxy<A: ToSocketAddrs, F>(
+    proxy_address: A,
+    target: &str,
+    agent_name: &str,
+    version: Version,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
+{
+    let request = HandshakeBuilder::new()
+        .agent_name(agent_name)
+        .version(version);
+    handshake_via_proxy_with_builder(proxy_address, target, &request, on_accept).await
+}
+
+/// Same as [`handshake_via_proxy`] but sends the request composed by the
+/// given builder.
+pub async fn handshake_via_proxy_with_builder<A: ToSocketAddrs, F>(
+    proxy_address: A,
+    target: &str,
+    request: &HandshakeBuilder,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TcpStream, HandshakeMessage) -> ProtocolResult<()>,
+{
+    request.check_plaintext()?;
+    let data = request.encode()?;
+    let mut stream = connect_via_proxy(proxy_address, target).await?;
+    let response = exchange(&mut stream, &data).await?;
+    request.check_self_connection(&response)?;
+    crate::callback::call(|| on_accept(stream, response))
+}
+
+/// Opens a connection to `target` (ex. `node.example.org:9030`) through the
+/// SOCKS5 proxy listening at `proxy_address`.
+pub async fn connect_via_proxy<A: ToSocketAddrs>(
+    proxy_address: A,
+    target: &str,
+) -> ProtocolResult<TcpStream> {
+    let connect_request = connect_request(target)?;
+    let mut stream = connect(proxy_address).await?;
+
+    stream
+        .write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])
+        .await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [SOCKS_VERSION, NO_AUTHENTICATION] {
+        return Err(ProtocolError::Proxy(
+            "no acceptable authentication method".to_string(),
+        ));
+    }
+
+    stream.write_all(&connect_request).await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(ProtocolError::Proxy(format!(
+            "unexpected protocol version {}",
+            reply[0]
+        )));
+    }
+    if reply[1] != 0 {
+        return Err(ProtocolError::Proxy(reply_message(reply[1]).to_string()));
+    }
+
+    // Skip the address the proxy bound for the connection.
+    let address_len = match reply[3] {
+        IPV4_ADDRESS_TYPE => 4,
+        IPV6_ADDRESS_TYPE => 16,
+        DOMAIN_ADDRESS_TYPE => stream.read_u8().await? as usize,
+        address_type => {
+            return Err(ProtocolError::Proxy(format!(
+                "unexpected address type {address_type}"
+            )))
+        }
+    };
+    let mut bound_address = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound_address).await?;
+    Ok(stream)
+}
+
+/// Connects the targets routed to it through the SOCKS5 proxy, ex. the
+/// `.onion` targets through Tor, see [`TargetRouter`](crate::route::TargetRouter).
+#[derive(Debug, Clone)]
+pub struct SocksProxy {
+    address: String,
+}
+
+impl SocksProxy {
+    /// * `address` - The address and port of the proxy (ex. 127.0.0.1:9050).
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+        }
+    }
+}
+
+impl TargetHandler for SocksProxy {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, ProtocolResult<TcpStream>> {
+        Box::pin(async move {
+            let target = format!("{host}:{port}");
+            connect_via_proxy(self.address.as_str(), &target).await
+        })
+    }
+}
+
+/// Encodes the `CONNECT` request, IP addresses are sent as is while host
+/// names are left for the proxy to resolve.
+fn connect_request(target: &str) -> ProtocolResult<Vec<u8>> {
+    let mut request = vec![SOCKS_VERSION, CONNECT_COMMAND, 0];
+    let port = match target.parse::<SocketAddr>() {
+        Ok(address) => {
+            match address.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(IPV4_ADDRESS_TYPE);
+                    request.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    request.push(IPV6_ADDRESS_TYPE);
+                    request.extend_from_slice(&ip.octets());
+                }
+            }
+            address.port()
+        }
+        Err(_) => {
+            let invalid_target = || ProtocolError::InvalidTarget(target.to_string());
+            let (host, port) = target.rsplit_once(':').ok_or_else(invalid_target)?;
+            let port: u16 = port.parse().map_err(|_| invalid_target())?;
+            if host.is_empty() || host.len() > u8::MAX as usize {
+                return Err(invalid_target());
+            }
+            request.extend_from_slice(&[DOMAIN_ADDRESS_TYPE, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown failure",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::encoder::read_handshake;
+    use crate::spec;
+
+    /// A minimal SOCKS5 proxy resolving and dialing the requested target.
+    async fn run_proxy(listener: TcpListener) -> ProtocolResult<()> {
+        let (mut client, _) = listener.accept().await?;
+        let mut greeting = [0u8; 3];
+        client.read_exact(&mut greeting).await?;
+        client.write_all(&[5, 0]).await?;
+
+        let mut header = [0u8; 5];
+        client.read_exact(&mut header).await?;
+        assert_eq!(header[3], DOMAIN_ADDRESS_TYPE);
+        let mut host = vec![0u8; header[4] as usize];
+        client.read_exact(&mut host).await?;
+        let port = client.read_u16().await?;
+
+        let target = format!("{}:{}", String::from_utf8(host)?, port);
+        let mut node = TcpStream::connect(target).await?;
+        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut node).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_via_proxy() -> ProtocolResult<()> {
+        let node = TcpListener::bind("127.0.0.1:0").await?;
+        let node_port = node.local_addr()?.port();
+        tokio::spawn(async move {
+            let (mut stream, _) = node.accept().await?;
+            read_handshake(&mut stream, spec::MAX_HANDSHAKE_SIZE).await?;
+            let reply = HandshakeBuilder::new().agent_name("ergoref").encode()?;
+            stream.write_all(&reply).await?;
+            ProtocolResult::Ok(())
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_address = proxy.local_addr()?;
+        tokio::spawn(run_proxy(proxy));
+
+        let mut reply = None;
+        let target = format!("localhost:{node_port}");
+        handshake_via_proxy(
+            proxy_address,
+            &target,
+            "paul",
+            Version([5, 0, 0]),
+            |_stream, message| {
+                reply = Some(message);
+                Ok(())
+            },
+        )
+        .await?;
+        assert_eq!(reply.unwrap().agent_name.as_str(), "ergoref");
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_request() -> ProtocolResult<()> {
+        assert_eq!(
+            connect_request("1.2.3.4:9030")?,
+            vec![5, 1, 0, 1, 1, 2, 3, 4, 0x23, 0x46]
+        );
+        assert_eq!(
+            connect_request("ab.onion:80")?,
+            vec![5, 1, 0, 3, 8, b'a', b'b', b'.', b'o', b'n', b'i', b'o', b'n', 0, 80]
+        );
+        assert!(matches!(
+            connect_request("no-port"),
+            Err(ProtocolError::InvalidTarget(_))
+        ));
+        Ok(())
+    }
+}
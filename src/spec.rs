@@ -0,0 +1,136 @@
+//! This module encodes the documented handshake message layout as
+//! executable checks.
+//!
+//! The field order and size limits come from the [handshake format](https://docs.ergoplatform.com/dev/p2p/p2p-handshake/#handshake-format)
+//! documentation. Any encoded message can be walked with [`layout`] which
+//! verifies each field against those limits and reports the byte range it
+//! occupies, so deviations between the encoder and the published spec are
+//! caught mechanically.
+//!
+
+use std::ops::Range;
+
+use crate::core::{read_declared_address, read_string, read_vlq, ByteSource, PeerFeature};
+use crate::encoder::HandshakeMessage;
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::peers::PeerSpec;
+
+pub(crate) use crate::core::spec::{
+    check_address_size, check_feature_len, check_fields, check_string_len,
+};
+pub use crate::core::spec::{
+    DecodeLimits, Field, FIELD_ORDER, IPV4_ADDRESS_SIZE, IPV6_ADDRESS_SIZE, MAX_FEATURES,
+    MAX_FEATURE_LEN, MAX_HANDSHAKE_SIZE, MAX_SHORT_STRING_LEN, VERSION_LEN,
+};
+
+/// Checks that a message can be encoded within the documented limits,
+/// its size included.
+pub fn check_message(message: &HandshakeMessage) -> ProtocolResult<()> {
+    check_fields(&message.agent_name, &message.peer_name, &message.features)?;
+    if message.encoded_len() > MAX_HANDSHAKE_SIZE {
+        return Err(ProtocolError::MessageTooLarge(MAX_HANDSHAKE_SIZE));
+    }
+    Ok(())
+}
+
+/// Checks that a peer spec can be encoded within the documented limits.
+pub fn check_peer_spec(spec: &PeerSpec) -> ProtocolResult<()> {
+    Ok(check_fields(
+        &spec.agent_name,
+        &spec.peer_name,
+        &spec.features,
+    )?)
+}
+
+/// Walks an encoded handshake message, checking every field against the
+/// documented limits, and returns the byte range of each field in order.
+/// The fields are read by the decoder of the [`core`](crate::core) module.
+pub fn layout(data: &[u8]) -> ProtocolResult<Vec<(Field, Range<usize>)>> {
+    check(
+        data.len() <= MAX_HANDSHAKE_SIZE,
+        "message exceeds the maximum handshake size",
+    )?;
+
+    let limits = DecodeLimits::default();
+    let mut rest = data;
+    let mut fields = Vec::with_capacity(FIELD_ORDER.len());
+    for field in FIELD_ORDER {
+        let start = data.len() - rest.len();
+        match field {
+            Field::Timestamp => {
+                read_vlq(&mut rest)?;
+            }
+            Field::AgentName | Field::PeerName => {
+                read_string(&mut rest, field, &limits)?;
+            }
+            Field::Version => {
+                rest.read_bytes(VERSION_LEN)?;
+            }
+            Field::DeclaredAddress => {
+                read_declared_address(&mut rest)?;
+            }
+            Field::Features => {
+                let count = rest.read_byte()?;
+                for _ in 0..count {
+                    PeerFeature::decode(&mut rest)?;
+                }
+            }
+        }
+        fields.push((field, start..data.len() - rest.len()));
+    }
+
+    check(rest.is_empty(), "unexpected trailing bytes")?;
+    Ok(fields)
+}
+
+fn check(condition: bool, reason: &str) -> ProtocolResult<()> {
+    if condition {
+        return Ok(());
+    }
+    Err(ProtocolError::SpecViolation(reason.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_reports_fields_in_order() -> ProtocolResult<()> {
+        let data = [1, 1, b'a', 1, 2, 3, 0, 0, 0];
+        let fields = layout(&data)?;
+        assert_eq!(
+            fields,
+            vec![
+                (Field::Timestamp, 0..1),
+                (Field::AgentName, 1..3),
+                (Field::Version, 3..6),
+                (Field::PeerName, 6..7),
+                (Field::DeclaredAddress, 7..8),
+                (Field::Features, 8..9),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_rejects_invalid_messages() {
+        // Truncated version.
+        assert!(matches!(
+            layout(&[1, 0, 1, 2]),
+            Err(ProtocolError::TruncatedMessage {
+                expected: 3,
+                got: 2
+            })
+        ));
+        // Trailing bytes.
+        assert!(matches!(
+            layout(&[1, 0, 1, 2, 3, 0, 0, 0, 42]),
+            Err(ProtocolError::SpecViolation(_))
+        ));
+        // Bad declared address size.
+        assert!(matches!(
+            layout(&[1, 0, 1, 2, 3, 0, 1, 5, 1, 1, 1, 1, 1, 0]),
+            Err(ProtocolError::InvalidAddressSize(5))
+        ));
+    }
+}
@@ -0,0 +1,228 @@
+//! This module defines the persistence interface of the peer store and
+//! the binary format the provided backends use to persist records.
+//!
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+
+use byteorder::ReadBytesExt;
+
+use crate::encoder::{
+    read_declared_address, read_peer_spec, write_declared_address, write_peer_spec,
+    HandshakeMessage,
+};
+use crate::error::{ProtocolError, ProtocolResult};
+
+use super::{IdentityHint, PeerId, PeerRecord};
+
+/// Persistence of the peer records, implement it to plug a custom storage
+/// (ex. a remote database) into the [`PeerStore`](super::PeerStore).
+///
+/// The store keeps its indexes in memory and writes every change
+/// through the backend.
+pub trait PeerStoreBackend {
+    /// Loads all the persisted records.
+    fn load(&self) -> ProtocolResult<Vec<PeerRecord>>;
+
+    /// Inserts or replaces a record.
+    fn save(&mut self, record: &PeerRecord) -> ProtocolResult<()>;
+
+    /// Removes a record, removing an unknown record is not an error.
+    fn remove(&mut self, id: PeerId) -> ProtocolResult<()>;
+}
+
+/// A backend keeping the records in memory, nothing survives the process.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryBackend {
+    records: HashMap<PeerId, PeerRecord>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PeerStoreBackend for MemoryBackend {
+    fn load(&self) -> ProtocolResult<Vec<PeerRecord>> {
+        Ok(self.records.values().cloned().collect())
+    }
+
+    fn save(&mut self, record: &PeerRecord) -> ProtocolResult<()> {
+        self.records.insert(record.id, record.clone());
+        Ok(())
+    }
+
+    fn remove(&mut self, id: PeerId) -> ProtocolResult<()> {
+        self.records.remove(&id);
+        Ok(())
+    }
+}
+
+const DECLARED_ADDRESS_HINT: u8 = 0;
+const REST_API_URL_HINT: u8 = 1;
+const SESSION_HINT: u8 = 2;
+
+/// Encodes a record in the binary format used by the persistent backends.
+#[cfg_attr(not(any(feature = "sled", feature = "sqlite")), allow(dead_code))]
+pub(crate) fn encode_record(record: &PeerRecord) -> ProtocolResult<Vec<u8>> {
+    let mut buf = vec![];
+    leb128::write::unsigned(&mut buf, record.id)?;
+    leb128::write::unsigned(&mut buf, record.first_seen)?;
+    leb128::write::unsigned(&mut buf, record.last_seen)?;
+
+    leb128::write::unsigned(&mut buf, record.addresses.len() as u64)?;
+    for address in &record.addresses {
+        write_declared_address(&mut buf, Some(address))?;
+    }
+
+    leb128::write::unsigned(&mut buf, record.hints.len() as u64)?;
+    for hint in &record.hints {
+        match hint {
+            IdentityHint::DeclaredAddress(address) => {
+                buf.write_all(&[DECLARED_ADDRESS_HINT])?;
+                write_declared_address(&mut buf, Some(address))?;
+            }
+            IdentityHint::RestApiUrl(url) => {
+                buf.write_all(&[REST_API_URL_HINT])?;
+                write_bytes(&mut buf, url.as_bytes())?;
+            }
+            IdentityHint::Session {
+                agent_name,
+                session,
+            } => {
+                buf.write_all(&[SESSION_HINT])?;
+                write_bytes(&mut buf, agent_name.as_bytes())?;
+                write_bytes(&mut buf, session)?;
+            }
+        }
+    }
+
+    let reply = &record.last_reply;
+    leb128::write::unsigned(&mut buf, reply.timestamp)?;
+    write_peer_spec(
+        &mut buf,
+        &reply.agent_name,
+        &reply.version,
+        &reply.peer_name,
+        reply.declared_address.as_ref(),
+        &reply.features,
+    )?;
+    Ok(buf)
+}
+
+/// Decodes a record encoded with [`encode_record`].
+#[cfg_attr(not(any(feature = "sled", feature = "sqlite")), allow(dead_code))]
+pub(crate) fn decode_record(data: &[u8]) -> ProtocolResult<PeerRecord> {
+    let mut reader = Cursor::new(data);
+    let id = leb128::read::unsigned(&mut reader)?;
+    let first_seen = leb128::read::unsigned(&mut reader)?;
+    let last_seen = leb128::read::unsigned(&mut reader)?;
+
+    let count = leb128::read::unsigned(&mut reader)?;
+    let mut addresses = vec![];
+    for _ in 0..count {
+        addresses.push(read_declared_address(&mut reader)?.ok_or_else(corrupted)?);
+    }
+
+    let count = leb128::read::unsigned(&mut reader)?;
+    let mut hints = vec![];
+    for _ in 0..count {
+        let hint = match reader.read_u8()? {
+            DECLARED_ADDRESS_HINT => IdentityHint::DeclaredAddress(
+                read_declared_address(&mut reader)?.ok_or_else(corrupted)?,
+            ),
+            REST_API_URL_HINT => {
+                IdentityHint::RestApiUrl(String::from_utf8(read_bytes(&mut reader)?)?)
+            }
+            SESSION_HINT => IdentityHint::Session {
+                agent_name: String::from_utf8(read_bytes(&mut reader)?)?,
+                session: read_bytes(&mut reader)?,
+            },
+            _ => return Err(corrupted()),
+        };
+        hints.push(hint);
+    }
+
+    let timestamp = leb128::read::unsigned(&mut reader)?;
+    let spec = read_peer_spec(&mut reader)?;
+    Ok(PeerRecord {
+        id,
+        addresses,
+        hints,
+        first_seen,
+        last_seen,
+        last_reply: HandshakeMessage {
+            timestamp,
+            agent_name: spec.agent_name,
+            version: spec.version,
+            peer_name: spec.peer_name,
+            declared_address: spec.declared_address,
+            features: spec.features,
+        },
+    })
+}
+
+fn write_bytes<W: Write>(writer: &mut W, data: &[u8]) -> ProtocolResult<()> {
+    leb128::write::unsigned(writer, data.len() as u64)?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> ProtocolResult<Vec<u8>> {
+    let len = leb128::read::unsigned(reader)?;
+    let mut data = vec![];
+    reader.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(corrupted());
+    }
+    Ok(data)
+}
+
+fn corrupted() -> ProtocolError {
+    ProtocolError::Storage("corrupted peer record".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{TinyString, Version};
+    use crate::feature::PeerFeature;
+
+    #[test]
+    fn test_record_encoding_decoding() -> ProtocolResult<()> {
+        let record = PeerRecord {
+            id: 42,
+            addresses: vec![
+                "1.2.3.4:9030".parse().unwrap(),
+                "[::1]:9020".parse().unwrap(),
+            ],
+            hints: vec![
+                IdentityHint::DeclaredAddress("5.6.7.8:9030".parse().unwrap()),
+                IdentityHint::RestApiUrl("http://5.6.7.8:9053".to_string()),
+                IdentityHint::Session {
+                    agent_name: "ergoref".to_string(),
+                    session: vec![1, 2, 3],
+                },
+            ],
+            first_seen: 1,
+            last_seen: 1_718_000_000_000,
+            last_reply: HandshakeMessage {
+                agent_name: TinyString::new("ergoref").unwrap(),
+                version: Version([5, 0, 21]),
+                features: vec![PeerFeature::new(16, vec![0, 1])],
+                ..Default::default()
+            },
+        };
+
+        let decoded = decode_record(&encode_record(&record)?)?;
+        assert_eq!(decoded.id, record.id);
+        assert_eq!(decoded.addresses, record.addresses);
+        assert_eq!(decoded.hints, record.hints);
+        assert_eq!(decoded.last_seen, record.last_seen);
+        assert_eq!(decoded.last_reply.diff(&record.last_reply), vec![]);
+
+        assert!(decode_record(&[42, 1, 1, 1, 9]).is_err());
+        Ok(())
+    }
+}
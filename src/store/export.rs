@@ -0,0 +1,331 @@
+//! This module implements the portable JSON format of the peer store,
+//! used to share crawl databases between machines.
+//!
+//! The document carries a schema version so that files written by older
+//! releases keep being readable. Store identifiers are local and are not
+//! exported, importing merges the peers by identity instead.
+//!
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::compression::{self, FileWriter};
+use crate::encoder::{HandshakeMessage, TinyString, Version};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::feature::PeerFeature;
+
+use super::{IdentityHint, PeerRecord, PeerStore, PeerStoreBackend};
+
+/// Version of the export schema written by this release.
+pub const EXPORT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportDocument {
+    version: u32,
+    peers: Vec<ExportedPeer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedPeer {
+    addresses: Vec<SocketAddr>,
+    hints: Vec<ExportedHint>,
+    first_seen: u64,
+    last_seen: u64,
+    last_reply: ExportedReply,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExportedHint {
+    DeclaredAddress { address: SocketAddr },
+    RestApiUrl { url: String },
+    Session { agent_name: String, session: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedReply {
+    timestamp: u64,
+    agent_name: String,
+    version: String,
+    peer_name: String,
+    declared_address: Option<SocketAddr>,
+    features: Vec<ExportedFeature>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedFeature {
+    id: u8,
+    payload: String,
+}
+
+impl<B: PeerStoreBackend> PeerStore<B> {
+    /// Writes all the peers of the store to a JSON file at `path`,
+    /// compressed according to its extension, see [`crate::compression`].
+    pub fn export<P: AsRef<Path>>(&self, path: P) -> ProtocolResult<()> {
+        let mut peers: Vec<&PeerRecord> = self.peers().collect();
+        peers.sort_by_key(|record| record.id);
+        let document = ExportDocument {
+            version: EXPORT_SCHEMA_VERSION,
+            peers: peers.into_iter().map(export_record).collect(),
+        };
+
+        let mut writer = FileWriter::create(path)?;
+        serde_json::to_writer_pretty(&mut writer, &document).map_err(json_error)?;
+        Ok(writer.finish()?)
+    }
+
+    /// Reads a JSON file written by [`PeerStore::export`] and merges its
+    /// peers into the store, returning the number of peers read.
+    pub fn import<P: AsRef<Path>>(&mut self, path: P) -> ProtocolResult<usize> {
+        let reader = compression::open(path)?;
+        let document: ExportDocument = serde_json::from_reader(reader).map_err(json_error)?;
+        if document.version > EXPORT_SCHEMA_VERSION {
+            return Err(ProtocolError::Storage(format!(
+                "unsupported export schema version {}",
+                document.version
+            )));
+        }
+
+        let count = document.peers.len();
+        for peer in document.peers {
+            self.merge(import_record(peer)?)?;
+        }
+        Ok(count)
+    }
+}
+
+/// Unions the peers of several exported files, as produced by separate
+/// crawls, into a single file at `output`.
+///
+/// Duplicates are reconciled by identity, keeping the freshest metadata of
+/// each peer. Returns the number of peers written.
+pub fn merge_exports<I, P, O>(inputs: I, output: O) -> ProtocolResult<usize>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+    O: AsRef<Path>,
+{
+    let mut store = PeerStore::new();
+    for input in inputs {
+        store.import(input)?;
+    }
+    store.export(output)?;
+    Ok(store.len())
+}
+
+fn export_record(record: &PeerRecord) -> ExportedPeer {
+    let reply = &record.last_reply;
+    ExportedPeer {
+        addresses: record.addresses.clone(),
+        hints: record
+            .hints
+            .iter()
+            .map(|hint| match hint {
+                IdentityHint::DeclaredAddress(address) => {
+                    ExportedHint::DeclaredAddress { address: *address }
+                }
+                IdentityHint::RestApiUrl(url) => ExportedHint::RestApiUrl { url: url.clone() },
+                IdentityHint::Session {
+                    agent_name,
+                    session,
+                } => ExportedHint::Session {
+                    agent_name: agent_name.clone(),
+                    session: to_hex(session),
+                },
+            })
+            .collect(),
+        first_seen: record.first_seen,
+        last_seen: record.last_seen,
+        last_reply: ExportedReply {
+            timestamp: reply.timestamp,
+            agent_name: reply.agent_name.to_string(),
+            version: reply.version.to_string(),
+            peer_name: reply.peer_name.to_string(),
+            declared_address: reply.declared_address,
+            features: reply
+                .features
+                .iter()
+                .map(|feature| ExportedFeature {
+                    id: feature.id,
+                    payload: to_hex(&feature.payload),
+                })
+                .collect(),
+        },
+    }
+}
+
+fn import_record(peer: ExportedPeer) -> ProtocolResult<PeerRecord> {
+    let reply = peer.last_reply;
+    let hints = peer
+        .hints
+        .into_iter()
+        .map(|hint| {
+            Ok(match hint {
+                ExportedHint::DeclaredAddress { address } => IdentityHint::DeclaredAddress(address),
+                ExportedHint::RestApiUrl { url } => IdentityHint::RestApiUrl(url),
+                ExportedHint::Session {
+                    agent_name,
+                    session,
+                } => IdentityHint::Session {
+                    agent_name,
+                    session: from_hex(&session)?,
+                },
+            })
+        })
+        .collect::<ProtocolResult<_>>()?;
+    let features = reply
+        .features
+        .into_iter()
+        .map(|feature| Ok(PeerFeature::new(feature.id, from_hex(&feature.payload)?)))
+        .collect::<ProtocolResult<_>>()?;
+
+    Ok(PeerRecord {
+        id: 0,
+        addresses: peer.addresses,
+        hints,
+        first_seen: peer.first_seen,
+        last_seen: peer.last_seen,
+        last_reply: HandshakeMessage {
+            timestamp: reply.timestamp,
+            agent_name: tiny_string(&reply.agent_name)?,
+            version: Version::from_str(&reply.version).map_err(ProtocolError::InvalidVersion)?,
+            peer_name: tiny_string(&reply.peer_name)?,
+            declared_address: reply.declared_address,
+            features,
+        },
+    })
+}
+
+fn tiny_string(value: &str) -> ProtocolResult<TinyString> {
+    TinyString::try_from(value).map_err(ProtocolError::Storage)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(value: &str) -> ProtocolResult<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return Err(ProtocolError::Storage(format!("invalid hex `{value}`")));
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(value.get(index..index + 2).unwrap_or_default(), 16)
+                .map_err(|_| ProtocolError::Storage(format!("invalid hex `{value}`")))
+        })
+        .collect()
+}
+
+fn json_error(err: serde_json::Error) -> ProtocolError {
+    ProtocolError::Storage(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feature::SESSION_FEATURE_ID;
+
+    #[test]
+    fn test_export_import() -> ProtocolResult<()> {
+        let dir = std::env::temp_dir().join(format!("p2p-handshake-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("peers.json");
+
+        let reply = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version([5, 0, 21]),
+            features: vec![PeerFeature::new(SESSION_FEATURE_ID, vec![1, 0, 2, 4, 0xff])],
+            ..Default::default()
+        };
+        let mut store = PeerStore::new();
+        store.record("1.2.3.4:9030".parse().unwrap(), reply.clone(), 10)?;
+        store.record(
+            "5.6.7.8:9030".parse().unwrap(),
+            HandshakeMessage::default(),
+            20,
+        )?;
+        store.export(&path)?;
+
+        // The same peer seen from another machine at another address.
+        let mut other = PeerStore::new();
+        other.record("9.9.9.9:9030".parse().unwrap(), reply, 30)?;
+        assert_eq!(other.import(&path)?, 2);
+        assert_eq!(other.len(), 2);
+
+        let merged = other
+            .find_by_address(&"1.2.3.4:9030".parse().unwrap())
+            .unwrap();
+        assert_eq!(merged.addresses.len(), 2);
+        assert_eq!(merged.first_seen, 10);
+        assert_eq!(merged.last_seen, 30);
+        assert_eq!(
+            merged.last_reply.features[0].payload,
+            vec![1, 0, 2, 4, 0xff]
+        );
+
+        std::fs::write(&path, r#"{"version": 99, "peers": []}"#)?;
+        assert!(matches!(
+            other.import(&path),
+            Err(ProtocolError::Storage(_))
+        ));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_exports() -> ProtocolResult<()> {
+        let dir = std::env::temp_dir().join(format!("p2p-handshake-merge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let (first, second, merged) = (
+            dir.join("a.json"),
+            dir.join("b.json"),
+            dir.join("merged.json"),
+        );
+
+        let reply = |version| HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            version: Version(version),
+            declared_address: Some("1.2.3.4:9030".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut store = PeerStore::new();
+        store.record("1.2.3.4:9030".parse().unwrap(), reply([5, 0, 20]), 10)?;
+        store.export(&first)?;
+
+        let mut store = PeerStore::new();
+        store.record("10.0.0.1:9030".parse().unwrap(), reply([5, 0, 21]), 20)?;
+        store.record(
+            "5.6.7.8:9030".parse().unwrap(),
+            HandshakeMessage::default(),
+            5,
+        )?;
+        store.export(&second)?;
+
+        assert_eq!(merge_exports([&first, &second], &merged)?, 2);
+        let mut store = PeerStore::new();
+        store.import(&merged)?;
+        let peer = store
+            .find_by_address(&"10.0.0.1:9030".parse().unwrap())
+            .unwrap();
+        assert_eq!(peer.addresses.len(), 2);
+        assert_eq!(peer.first_seen, 10);
+        assert_eq!(peer.last_reply.version, Version([5, 0, 21]));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hex() -> ProtocolResult<()> {
+        assert_eq!(to_hex(&[0, 1, 0xab]), "0001ab");
+        assert_eq!(from_hex("0001ab")?, vec![0, 1, 0xab]);
+        assert!(from_hex("0").is_err());
+        assert!(from_hex("zz").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! This module implements exporting the peers of the store as the
+//! `knownPeers` setting of the reference node, so that the results of a
+//! crawl can seed a node deployment.
+//!
+//! The output is a HOCON fragment to include in, or merge with, the
+//! configuration file of the node:
+//!
+//! ```text
+//! scorex {
+//!   network {
+//!     knownPeers = [
+//!       "213.239.193.208:9030",
+//!       "[2001:db8::1]:9030"
+//!     ]
+//!   }
+//! }
+//! ```
+//!
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::compression::FileWriter;
+use crate::error::ProtocolResult;
+use crate::peers::AddressFilter;
+
+use super::{PeerRecord, PeerStore, PeerStoreBackend};
+
+impl<B: PeerStoreBackend> PeerStore<B> {
+    /// The addresses other nodes can dial the peers at, most recently seen
+    /// first: the address a peer declares, else the last one it was reached
+    /// at. Addresses rejected by the filter are left out, ex. private ones.
+    pub fn known_peers(&self, filter: &AddressFilter) -> Vec<SocketAddr> {
+        let mut peers: Vec<&PeerRecord> = self.peers().collect();
+        peers.sort_by_key(|record| (std::cmp::Reverse(record.last_seen), record.id));
+        let mut addresses: Vec<SocketAddr> = vec![];
+        for record in peers {
+            let address = record
+                .last_reply
+                .declared_address
+                .or_else(|| record.addresses.last().copied());
+            if let Some(address) = address.filter(|address| filter.accepts(address)) {
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+        addresses
+    }
+
+    /// Writes the [`known_peers`](PeerStore::known_peers) of the store as
+    /// the configuration of the reference node to a file at `path`, keeping
+    /// at most `limit` of them. Returns the number of peers written.
+    pub fn export_known_peers<P: AsRef<Path>>(
+        &self,
+        path: P,
+        filter: &AddressFilter,
+        limit: Option<usize>,
+    ) -> ProtocolResult<usize> {
+        let mut addresses = self.known_peers(filter);
+        addresses.truncate(limit.unwrap_or(usize::MAX));
+        let mut writer = FileWriter::create(path)?;
+        write_known_peers(&mut writer, &addresses)?;
+        writer.finish()?;
+        Ok(addresses.len())
+    }
+}
+
+/// Writes the addresses as the `knownPeers` setting of the reference node.
+pub fn write_known_peers<W: Write>(writer: &mut W, addresses: &[SocketAddr]) -> ProtocolResult<()> {
+    writeln!(writer, "scorex {{")?;
+    writeln!(writer, "  network {{")?;
+    writeln!(writer, "    knownPeers = [")?;
+    for (index, address) in addresses.iter().enumerate() {
+        let separator = if index + 1 < addresses.len() { "," } else { "" };
+        writeln!(writer, "      \"{address}\"{separator}")?;
+    }
+    writeln!(writer, "    ]")?;
+    writeln!(writer, "  }}")?;
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::HandshakeMessage;
+
+    fn reply(declared_address: Option<&str>) -> HandshakeMessage {
+        HandshakeMessage {
+            declared_address: declared_address.map(|address| address.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_known_peers() -> ProtocolResult<()> {
+        let mut store = PeerStore::new();
+        store.record("1.2.3.4:9030".parse().unwrap(), reply(None), 10)?;
+        store.record(
+            "5.6.7.8:41000".parse().unwrap(),
+            reply(Some("5.6.7.8:9030")),
+            30,
+        )?;
+        store.record("192.168.1.2:9030".parse().unwrap(), reply(None), 20)?;
+
+        let addresses = store.known_peers(&AddressFilter::default());
+        assert_eq!(
+            addresses,
+            [
+                "5.6.7.8:9030".parse().unwrap(),
+                "1.2.3.4:9030".parse().unwrap()
+            ]
+        );
+        assert_eq!(store.known_peers(&AddressFilter::allow_all()).len(), 3);
+
+        let mut config = vec![];
+        write_known_peers(&mut config, &addresses)?;
+        assert_eq!(
+            String::from_utf8(config).unwrap(),
+            "scorex {\n  network {\n    knownPeers = [\n      \"5.6.7.8:9030\",\n      \"1.2.3.4:9030\"\n    ]\n  }\n}\n"
+        );
+        Ok(())
+    }
+}
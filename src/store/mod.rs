@@ -0,0 +1,366 @@
+//! This module implements a store of the peers seen through handshakes.
+//!
+//! Peers are tracked by a stable identity rather than by the address they
+//! were reached at: the declared address, the advertised REST API url and
+//! the agent name combined with the session feature are used as identity
+//! hints, so a node that moves to another IP keeps its history.
+//!
+//! Records are persisted through a [`PeerStoreBackend`], the in-memory
+//! backend is always available while the `sled` and `sqlite` features
+//! provide persistent ones. With the `json` feature, the store can be
+//! exported to and imported from a portable JSON file. The peers can also
+//! be exported as the known peers of the reference node.
+//!
+
+mod backend;
+#[cfg(feature = "json")]
+mod export;
+mod known_peers;
+mod retention;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use crate::encoder::HandshakeMessage;
+use crate::error::ProtocolResult;
+use crate::feature::{REST_API_URL_FEATURE_ID, SESSION_FEATURE_ID};
+
+pub use backend::{MemoryBackend, PeerStoreBackend};
+#[cfg(feature = "json")]
+pub use export::{merge_exports, EXPORT_SCHEMA_VERSION};
+pub use known_peers::write_known_peers;
+pub use retention::RetentionPolicy;
+#[cfg(feature = "sled")]
+pub use sled::SledBackend;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteBackend;
+
+/// Identifier of a peer in the store.
+pub type PeerId = u64;
+
+/// A stable piece of information identifying a peer across address changes.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum IdentityHint {
+    DeclaredAddress(SocketAddr),
+    RestApiUrl(String),
+    Session {
+        agent_name: String,
+        session: Vec<u8>,
+    },
+}
+
+impl IdentityHint {
+    /// Extracts the identity hints advertised in a handshake reply.
+    pub fn from_reply(reply: &HandshakeMessage) -> Vec<IdentityHint> {
+        let mut hints = vec![];
+        if let Some(address) = reply.declared_address {
+            hints.push(IdentityHint::DeclaredAddress(address));
+        }
+        for feature in &reply.features {
+            match feature.id {
+                REST_API_URL_FEATURE_ID => {
+                    // The url is written as a length prefixed string.
+                    let url = feature
+                        .payload
+                        .split_first()
+                        .and_then(|(_, url)| String::from_utf8(url.to_vec()).ok());
+                    if let Some(url) = url {
+                        hints.push(IdentityHint::RestApiUrl(url));
+                    }
+                }
+                SESSION_FEATURE_ID => hints.push(IdentityHint::Session {
+                    agent_name: reply.agent_name.to_string(),
+                    session: feature.payload.clone(),
+                }),
+                _ => {}
+            }
+        }
+        hints
+    }
+}
+
+/// What the store knows about a peer.
+#[derive(Debug, Clone)]
+pub struct PeerRecord {
+    pub id: PeerId,
+    pub addresses: Vec<SocketAddr>,
+    pub hints: Vec<IdentityHint>,
+    /// Unix timestamp in milliseconds of the first handshake.
+    pub first_seen: u64,
+    /// Unix timestamp in milliseconds of the last handshake.
+    pub last_seen: u64,
+    pub last_reply: HandshakeMessage,
+}
+
+/// A store of peers keyed by their identity.
+#[derive(Debug, Default)]
+pub struct PeerStore<B = MemoryBackend> {
+    backend: B,
+    records: HashMap<PeerId, PeerRecord>,
+    hints: HashMap<IdentityHint, PeerId>,
+    addresses: HashMap<SocketAddr, PeerId>,
+    next_id: PeerId,
+}
+
+impl PeerStore<MemoryBackend> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<B: PeerStoreBackend> PeerStore<B> {
+    /// Creates a store on top of the given backend, loading the records
+    /// it already holds.
+    pub fn with_backend(backend: B) -> ProtocolResult<Self> {
+        let mut store = Self {
+            backend,
+            records: HashMap::new(),
+            hints: HashMap::new(),
+            addresses: HashMap::new(),
+            next_id: 0,
+        };
+        for record in store.backend.load()? {
+            store.index(&record);
+            store.next_id = store.next_id.max(record.id + 1);
+            store.records.insert(record.id, record);
+        }
+        Ok(store)
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Records a handshake reply received from `address` at `seen_at`
+    /// (unix milliseconds) and returns the identifier of the peer.
+    ///
+    /// The peer is first looked up by the identity hints of the reply and
+    /// then by address. When none matches, a new peer is created.
+    pub fn record(
+        &mut self,
+        address: SocketAddr,
+        reply: HandshakeMessage,
+        seen_at: u64,
+    ) -> ProtocolResult<PeerId> {
+        self.merge(PeerRecord {
+            id: 0,
+            addresses: vec![address],
+            hints: IdentityHint::from_reply(&reply),
+            first_seen: seen_at,
+            last_seen: seen_at,
+            last_reply: reply,
+        })
+    }
+
+    /// Merges a record, typically coming from another store, and returns
+    /// the identifier of the peer in this store. The `id` of the given
+    /// record is ignored.
+    ///
+    /// The peer is looked up the same way as in [`PeerStore::record`], the
+    /// addresses and hints are combined and the freshest reply is kept.
+    pub fn merge(&mut self, record: PeerRecord) -> ProtocolResult<PeerId> {
+        let known_id = record
+            .hints
+            .iter()
+            .find_map(|hint| self.hints.get(hint))
+            .or_else(|| {
+                record
+                    .addresses
+                    .iter()
+                    .find_map(|address| self.addresses.get(address))
+            })
+            .copied();
+
+        let existing = match known_id.and_then(|id| self.records.get_mut(&id)) {
+            Some(existing) => existing,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.records.entry(id).or_insert(PeerRecord {
+                    id,
+                    ..record.clone()
+                })
+            }
+        };
+
+        existing.first_seen = existing.first_seen.min(record.first_seen);
+        if record.last_seen >= existing.last_seen {
+            existing.last_seen = record.last_seen;
+            existing.last_reply = record.last_reply;
+        }
+        for address in record.addresses {
+            if !existing.addresses.contains(&address) {
+                existing.addresses.push(address);
+            }
+        }
+        for hint in record.hints {
+            if !existing.hints.contains(&hint) {
+                existing.hints.push(hint);
+            }
+        }
+        self.backend.save(existing)?;
+
+        let merged = existing.clone();
+        self.index(&merged);
+        Ok(merged.id)
+    }
+
+    fn index(&mut self, record: &PeerRecord) {
+        for address in &record.addresses {
+            self.addresses.insert(*address, record.id);
+        }
+        for hint in &record.hints {
+            self.hints.insert(hint.clone(), record.id);
+        }
+    }
+
+    pub fn get(&self, id: PeerId) -> Option<&PeerRecord> {
+        self.records.get(&id)
+    }
+
+    /// Returns the peer last reached at the given address.
+    pub fn find_by_address(&self, address: &SocketAddr) -> Option<&PeerRecord> {
+        self.addresses
+            .get(address)
+            .and_then(|id| self.records.get(id))
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerRecord> {
+        self.records.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Removes a peer from the store and its backend.
+    pub fn remove(&mut self, id: PeerId) -> ProtocolResult<Option<PeerRecord>> {
+        let Some(record) = self.records.remove(&id) else {
+            return Ok(None);
+        };
+        self.backend.remove(id)?;
+        // Keys taken over by another peer since are left to it.
+        for address in &record.addresses {
+            if self.addresses.get(address) == Some(&id) {
+                self.addresses.remove(address);
+            }
+        }
+        for hint in &record.hints {
+            if self.hints.get(hint) == Some(&id) {
+                self.hints.remove(hint);
+            }
+        }
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::TinyString;
+    use crate::feature::PeerFeature;
+
+    #[test]
+    fn test_identity_tracking() -> ProtocolResult<()> {
+        let mut store = PeerStore::new();
+        let declared: SocketAddr = "1.1.1.1:9030".parse().unwrap();
+        let reply = HandshakeMessage {
+            declared_address: Some(declared),
+            ..Default::default()
+        };
+
+        let id = store.record("10.0.0.1:9030".parse().unwrap(), reply.clone(), 1)?;
+        // Same declared address from another ip is the same peer.
+        let moved_id = store.record("10.0.0.2:9030".parse().unwrap(), reply, 2)?;
+        assert_eq!(id, moved_id);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(id).unwrap().addresses.len(), 2);
+        assert_eq!(store.get(id).unwrap().first_seen, 1);
+        assert_eq!(store.get(id).unwrap().last_seen, 2);
+
+        // A peer without hints is tracked by address.
+        let other = store.record(
+            "10.0.0.3:9030".parse().unwrap(),
+            HandshakeMessage::default(),
+            3,
+        )?;
+        assert_ne!(other, id);
+        let again = store.record(
+            "10.0.0.3:9030".parse().unwrap(),
+            HandshakeMessage::default(),
+            4,
+        )?;
+        assert_eq!(other, again);
+
+        assert!(store.remove(id)?.is_some());
+        assert!(store
+            .find_by_address(&"10.0.0.1:9030".parse().unwrap())
+            .is_none());
+        assert!(store
+            .find_by_address(&"10.0.0.3:9030".parse().unwrap())
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_and_rest_url_hints() -> ProtocolResult<()> {
+        let reply = HandshakeMessage {
+            agent_name: TinyString::new("ergoref").unwrap(),
+            features: vec![
+                PeerFeature::new(SESSION_FEATURE_ID, vec![1, 0, 2, 4, 42]),
+                PeerFeature::new(REST_API_URL_FEATURE_ID, b"\x05http:".to_vec()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            IdentityHint::from_reply(&reply),
+            vec![
+                IdentityHint::Session {
+                    agent_name: "ergoref".to_string(),
+                    session: vec![1, 0, 2, 4, 42],
+                },
+                IdentityHint::RestApiUrl("http:".to_string()),
+            ]
+        );
+
+        let mut store = PeerStore::new();
+        let id = store.record("10.0.0.1:9030".parse().unwrap(), reply.clone(), 1)?;
+        assert_eq!(
+            store.record("10.0.0.9:9030".parse().unwrap(), reply, 2)?,
+            id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload_from_backend() -> ProtocolResult<()> {
+        let reply = HandshakeMessage {
+            declared_address: Some("1.1.1.1:9030".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut store = PeerStore::new();
+        let id = store.record("10.0.0.1:9030".parse().unwrap(), reply.clone(), 1)?;
+
+        let mut reloaded = PeerStore::with_backend(store.backend().clone())?;
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(
+            reloaded.record("10.0.0.2:9030".parse().unwrap(), reply, 2)?,
+            id
+        );
+        let other = reloaded.record(
+            "10.0.0.3:9030".parse().unwrap(),
+            HandshakeMessage::default(),
+            3,
+        )?;
+        assert_ne!(other, id);
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+//! This module implements the retention policies keeping long running
+//! stores from growing unboundedly.
+//!
+
+use std::time::Duration;
+
+use crate::encoder::get_current_unix_timestamp;
+use crate::error::ProtocolResult;
+
+use super::{PeerId, PeerStore, PeerStoreBackend};
+
+/// Which peers to keep when pruning a store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Peers not seen for longer than this are dropped.
+    pub max_age: Option<Duration>,
+    /// When the store holds more peers, the least recently seen ones are dropped.
+    pub max_entries: Option<usize>,
+}
+
+impl<B: PeerStoreBackend> PeerStore<B> {
+    /// Drops the peers that don't satisfy the policy and returns how many
+    /// were removed.
+    pub fn prune(&mut self, policy: &RetentionPolicy) -> ProtocolResult<usize> {
+        self.prune_at(policy, get_current_unix_timestamp()?)
+    }
+
+    pub(crate) fn prune_at(&mut self, policy: &RetentionPolicy, now: u64) -> ProtocolResult<usize> {
+        // Oldest first, ties broken by id for a deterministic eviction.
+        let mut peers: Vec<(u64, PeerId)> = self
+            .peers()
+            .map(|record| (record.last_seen, record.id))
+            .collect();
+        peers.sort_unstable();
+
+        let mut expired = 0;
+        if let Some(max_age) = policy.max_age {
+            let oldest_allowed = now.saturating_sub(max_age.as_millis() as u64);
+            expired = peers
+                .iter()
+                .take_while(|(last_seen, _)| *last_seen < oldest_allowed)
+                .count();
+        }
+        if let Some(max_entries) = policy.max_entries {
+            expired = expired.max(peers.len().saturating_sub(max_entries));
+        }
+
+        for (_, id) in &peers[..expired] {
+            self.remove(*id)?;
+        }
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::HandshakeMessage;
+
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    #[test]
+    fn test_prune() -> ProtocolResult<()> {
+        let mut store = PeerStore::new();
+        for (index, last_seen) in [DAY, 5 * DAY, 8 * DAY, 9 * DAY, 10 * DAY]
+            .iter()
+            .enumerate()
+        {
+            let address = format!("10.0.0.{index}:9030").parse().unwrap();
+            store.record(address, HandshakeMessage::default(), *last_seen)?;
+        }
+
+        let by_age = RetentionPolicy {
+            max_age: Some(Duration::from_millis(7 * DAY)),
+            max_entries: None,
+        };
+        assert_eq!(store.prune_at(&by_age, 10 * DAY)?, 1);
+        assert_eq!(store.len(), 4);
+        assert!(store
+            .find_by_address(&"10.0.0.0:9030".parse().unwrap())
+            .is_none());
+
+        let by_count = RetentionPolicy {
+            max_age: None,
+            max_entries: Some(2),
+        };
+        assert_eq!(store.prune_at(&by_count, 10 * DAY)?, 2);
+        let mut kept: Vec<u64> = store.peers().map(|record| record.last_seen).collect();
+        kept.sort();
+        assert_eq!(kept, vec![9 * DAY, 10 * DAY]);
+
+        assert_eq!(store.prune_at(&RetentionPolicy::default(), 10 * DAY)?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! A peer store backend persisting the records in a [sled](https://docs.rs/sled) database.
+//!
+
+use std::path::Path;
+
+use crate::error::{ProtocolError, ProtocolResult};
+
+use super::backend::{decode_record, encode_record, PeerStoreBackend};
+use super::{PeerId, PeerRecord};
+
+/// Persists the peer records in a sled tree, keyed by big endian peer id.
+#[derive(Debug, Clone)]
+pub struct SledBackend {
+    tree: sled::Tree,
+}
+
+impl SledBackend {
+    /// Opens (or creates) the database at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> ProtocolResult<Self> {
+        let db = sled::open(path).map_err(storage_error)?;
+        Self::from_tree(db.open_tree("peers").map_err(storage_error)?)
+    }
+
+    /// Uses an already opened tree, for embedders sharing a database.
+    pub fn from_tree(tree: sled::Tree) -> ProtocolResult<Self> {
+        Ok(Self { tree })
+    }
+}
+
+impl PeerStoreBackend for SledBackend {
+    fn load(&self) -> ProtocolResult<Vec<PeerRecord>> {
+        self.tree
+            .iter()
+            .map(|entry| decode_record(&entry.map_err(storage_error)?.1))
+            .collect()
+    }
+
+    fn save(&mut self, record: &PeerRecord) -> ProtocolResult<()> {
+        self.tree
+            .insert(record.id.to_be_bytes(), encode_record(record)?)
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: PeerId) -> ProtocolResult<()> {
+        self.tree.remove(id.to_be_bytes()).map_err(storage_error)?;
+        Ok(())
+    }
+}
+
+fn storage_error(err: sled::Error) -> ProtocolError {
+    ProtocolError::Storage(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::HandshakeMessage;
+    use crate::store::PeerStore;
+
+    #[test]
+    fn test_sled_backend() -> ProtocolResult<()> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(storage_error)?;
+        let backend = SledBackend::from_tree(db.open_tree("peers").map_err(storage_error)?)?;
+        let mut store = PeerStore::with_backend(backend.clone())?;
+        let id = store.record(
+            "1.2.3.4:9030".parse().unwrap(),
+            HandshakeMessage::default(),
+            1,
+        )?;
+
+        let reloaded = PeerStore::with_backend(backend)?;
+        assert_eq!(reloaded.get(id).map(|record| record.last_seen), Some(1));
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! A peer store backend persisting the records in a SQLite database.
+//!
+
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::error::{ProtocolError, ProtocolResult};
+
+use super::backend::{decode_record, encode_record, PeerStoreBackend};
+use super::{PeerId, PeerRecord};
+
+/// Persists the peer records in a `peers` table of a SQLite database.
+#[derive(Debug)]
+pub struct SqliteBackend {
+    connection: Connection,
+}
+
+impl SqliteBackend {
+    /// Opens (or creates) the database at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> ProtocolResult<Self> {
+        Self::from_connection(Connection::open(path).map_err(storage_error)?)
+    }
+
+    /// Opens a database living in memory, mostly useful for tests.
+    pub fn open_in_memory() -> ProtocolResult<Self> {
+        Self::from_connection(Connection::open_in_memory().map_err(storage_error)?)
+    }
+
+    /// Uses an already opened connection, creating the table if needed.
+    pub fn from_connection(connection: Connection) -> ProtocolResult<Self> {
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS peers (id INTEGER PRIMARY KEY, record BLOB NOT NULL)",
+                [],
+            )
+            .map_err(storage_error)?;
+        Ok(Self { connection })
+    }
+
+    /// Rebuilds the database file, giving back to the file system the
+    /// space of the removed records, ex. after pruning the store.
+    pub fn vacuum(&self) -> ProtocolResult<()> {
+        self.connection
+            .execute("VACUUM", [])
+            .map_err(storage_error)?;
+        Ok(())
+    }
+}
+
+impl PeerStoreBackend for SqliteBackend {
+    fn load(&self) -> ProtocolResult<Vec<PeerRecord>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT record FROM peers")
+            .map_err(storage_error)?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, Vec<u8>>(0))
+            .map_err(storage_error)?;
+        rows.map(|row| decode_record(&row.map_err(storage_error)?))
+            .collect()
+    }
+
+    fn save(&mut self, record: &PeerRecord) -> ProtocolResult<()> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO peers (id, record) VALUES (?1, ?2)",
+                params![record.id as i64, encode_record(record)?],
+            )
+            .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: PeerId) -> ProtocolResult<()> {
+        self.connection
+            .execute("DELETE FROM peers WHERE id = ?1", params![id as i64])
+            .map_err(storage_error)?;
+        Ok(())
+    }
+}
+
+fn storage_error(err: rusqlite::Error) -> ProtocolError {
+    ProtocolError::Storage(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::encoder::HandshakeMessage;
+    use crate::store::PeerStore;
+
+    #[test]
+    fn test_sqlite_backend() -> ProtocolResult<()> {
+        let backend = SqliteBackend::open_in_memory()?;
+        let mut store = PeerStore::with_backend(backend)?;
+        let id = store.record(
+            "1.2.3.4:9030".parse().unwrap(),
+            HandshakeMessage::default(),
+            1,
+        )?;
+
+        let records = store.backend().load()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_vacuum() -> ProtocolResult<()> {
+        let mut store = PeerStore::with_backend(SqliteBackend::open_in_memory()?)?;
+        let reply = HandshakeMessage::default();
+        let ids = (0..500)
+            .map(|port| store.record(SocketAddr::from(([1, 2, 3, 4], port)), reply.clone(), 1))
+            .collect::<ProtocolResult<Vec<_>>>()?;
+        let page_count = |store: &PeerStore<SqliteBackend>| -> u64 {
+            let connection = &store.backend().connection;
+            connection
+                .query_row("PRAGMA page_count", [], |row| row.get(0))
+                .unwrap()
+        };
+        let full = page_count(&store);
+        for id in ids {
+            store.remove(id)?;
+        }
+        assert_eq!(page_count(&store), full);
+        store.backend().vacuum()?;
+        assert!(page_count(&store) < full);
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! This module implements the connection to a peer opened by the scans,
+//! plain or, with the `tls` feature, wrapped in the TLS session
+//! configured by `HandshakeBuilder::tls`.
+//!
+//! It reads and writes as the underlying stream, so the messages
+//! following the handshake are exchanged the same way over both.
+//!
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// A connection to a peer, see the [module](self) documentation.
+#[derive(Debug)]
+pub enum PeerStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+impl PeerStream {
+    /// The TCP connection carrying the stream.
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            PeerStream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            PeerStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    /// The TCP connection of a plain stream, `None` over TLS.
+    pub fn into_plain(self) -> Option<TcpStream> {
+        match self {
+            PeerStream::Plain(stream) => Some(stream),
+            #[cfg(feature = "tls")]
+            PeerStream::Tls(_) => None,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, PeerStream::Plain(_))
+    }
+
+    /// Negotiates the TLS session configured by the request, if any.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    pub(crate) async fn negotiate(
+        stream: TcpStream,
+        request: &crate::HandshakeBuilder,
+    ) -> crate::ProtocolResult<Self> {
+        #[cfg(feature = "tls")]
+        if let Some(settings) = request.tls_settings() {
+            let stream = crate::tls::upgrade(stream, settings).await?;
+            return Ok(PeerStream::Tls(Box::new(stream)));
+        }
+        Ok(PeerStream::Plain(stream))
+    }
+}
+
+impl From<TcpStream> for PeerStream {
+    fn from(stream: TcpStream) -> Self {
+        PeerStream::Plain(stream)
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            PeerStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            PeerStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            PeerStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            PeerStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
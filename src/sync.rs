@@ -0,0 +1,11 @@
+//! This module implements the locking shared by the modules keeping state
+//! behind a mutex.
+//!
+
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks the mutex, even when poisoned: a poisoned lock only means another
+/// task panicked, the data is still usable.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
@@ -0,0 +1,91 @@
+//! This module implements recording the handshakes through the
+//! [`metrics`](https://docs.rs/metrics) facade, with the `metrics`
+//! feature.
+//!
+//! Every attempt of the probes made with a [`HandshakeConfig`](crate::HandshakeConfig)
+//! is recorded, whatever recorder the application installs, ex. a
+//! Prometheus exporter. Call [`describe`] once the recorder is installed
+//! to publish the help text of the metrics.
+//!
+
+use std::time::Duration;
+
+use crate::error::ProtocolResult;
+
+/// Counter of the handshake attempts, retries included.
+pub const ATTEMPTS: &str = "ergo_handshake_attempts_total";
+
+/// Counter of the attempts completing the handshake.
+pub const SUCCESSES: &str = "ergo_handshake_successes_total";
+
+/// Counter of the failed attempts, labelled by error `kind`, see
+/// [`ProtocolError::kind`](crate::ProtocolError::kind).
+pub const FAILURES: &str = "ergo_handshake_failures_total";
+
+/// Histogram of the name resolution and connection time, in seconds.
+pub const CONNECT_SECONDS: &str = "ergo_handshake_connect_seconds";
+
+/// Histogram of the time between sending the request and decoding the
+/// reply, in seconds.
+pub const ROUND_TRIP_SECONDS: &str = "ergo_handshake_round_trip_seconds";
+
+/// Describes the metrics to the installed recorder.
+pub fn describe() {
+    metrics::describe_counter!(ATTEMPTS, "Number of handshake attempts, retries included.");
+    metrics::describe_counter!(SUCCESSES, "Number of attempts completing the handshake.");
+    metrics::describe_counter!(FAILURES, "Number of failed attempts, by error kind.");
+    metrics::describe_histogram!(
+        CONNECT_SECONDS,
+        metrics::Unit::Seconds,
+        "Name resolution and connection time."
+    );
+    metrics::describe_histogram!(
+        ROUND_TRIP_SECONDS,
+        metrics::Unit::Seconds,
+        "Time between sending the request and decoding the reply."
+    );
+}
+
+pub(crate) fn record_connect(duration: Duration) {
+    metrics::histogram!(CONNECT_SECONDS).record(duration.as_secs_f64());
+}
+
+pub(crate) fn record_round_trip(duration: Duration) {
+    metrics::histogram!(ROUND_TRIP_SECONDS).record(duration.as_secs_f64());
+}
+
+pub(crate) fn record_attempt<T>(outcome: &ProtocolResult<T>) {
+    metrics::counter!(ATTEMPTS).increment(1);
+    match outcome {
+        Ok(_) => metrics::counter!(SUCCESSES).increment(1),
+        Err(err) => metrics::counter!(FAILURES, "kind" => err.kind()).increment(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::error::ProtocolError;
+
+    #[test]
+    fn test_record() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            describe();
+            record_attempt(&Ok(()));
+            record_attempt::<()>(&Err(ProtocolError::TimedOut));
+            record_attempt::<()>(&Err(ProtocolError::TimedOut));
+            record_connect(Duration::from_millis(20));
+            record_round_trip(Duration::from_millis(30));
+        });
+        let rendered = handle.render();
+        assert!(rendered.contains("ergo_handshake_attempts_total 3"));
+        assert!(rendered.contains("ergo_handshake_successes_total 1"));
+        assert!(rendered.contains("ergo_handshake_failures_total{kind=\"timed_out\"} 2"));
+        assert!(rendered.contains("ergo_handshake_round_trip_seconds"));
+        assert!(rendered.contains("# HELP ergo_handshake_attempts_total"));
+    }
+}
@@ -0,0 +1,580 @@
+//! This module implements a scriptable mock of an Ergo node, to test
+//! multi-step conversations (ex. handshake, `GetPeers`, `Peers`)
+//! deterministically without a real node.
+//!
+//! A script runs on the first accepted connection with
+//! [`MockScript::spawn`], or on every connection with [`MockScript::serve`]
+//! to test applications connecting several times.
+//!
+//! It also provides [`Chaos`], which injects failures in the connections
+//! made with a [`HandshakeConfig`](crate::HandshakeConfig) to soak test
+//! retry and scoring logic.
+//!
+//! ```
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::framing::MAINNET_MAGIC;
+//! use p2p_handshake::peers::{encode_peers, request_peers, GET_PEERS_CODE};
+//! use p2p_handshake::testing::MockErgoNode;
+//! use p2p_handshake::{handshake, HandshakeBuilder, Version};
+//!
+//! let node = MockErgoNode::builder()
+//!     .respond_handshake(HandshakeBuilder::new().agent_name("ergoref").build()?)
+//!     .then_expect_frame(GET_PEERS_CODE)
+//!     .then_send_frame(encode_peers(&[])?)
+//!     .then_close()
+//!     .spawn()
+//!     .await?;
+//!
+//! let mut peers = None;
+//! handshake(node.address(), "paul", Version([5, 0, 0]), |stream, _reply| {
+//!     peers = Some(stream);
+//!     Ok(())
+//! })
+//! .await?;
+//! let mut stream = peers.unwrap();
+//! assert!(request_peers(&mut stream, MAINNET_MAGIC).await?.is_empty());
+//! node.finish().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::batch::handshake_many;
+use crate::builder::HandshakeBuilder;
+use crate::config::HandshakeConfig;
+use crate::encoder::{read_handshake, HandshakeMessage};
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::framing::{
+    read_message, write_message, NetworkMessage, DEFAULT_MAX_BODY_SIZE, MAINNET_MAGIC,
+};
+use crate::rng::SplitMix64;
+use crate::spec;
+use crate::sync::lock;
+
+#[derive(Debug, Clone)]
+enum Step {
+    RespondHandshake(HandshakeMessage),
+    SendFrame(NetworkMessage),
+    SendBytes(Vec<u8>),
+    ExpectFrame(u8),
+    Wait(Duration),
+    Close,
+}
+
+/// What the mock node received during the conversation.
+#[derive(Debug, Default, Clone)]
+pub struct Transcript {
+    /// The handshake request of the client.
+    pub handshake: Option<HandshakeMessage>,
+    /// The frames the client sent, in order.
+    pub frames: Vec<NetworkMessage>,
+}
+
+/// Scripts the behavior of a [`MockErgoNode`], steps run in order on the
+/// first accepted connection.
+#[derive(Debug, Clone)]
+pub struct MockScript {
+    magic: [u8; 4],
+    steps: Vec<Step>,
+}
+
+impl Default for MockScript {
+    fn default() -> Self {
+        Self {
+            magic: MAINNET_MAGIC,
+            steps: Vec::new(),
+        }
+    }
+}
+
+impl MockScript {
+    /// Sets the network of the frames, given as a
+    /// [`Network`](crate::framing::Network) or as magic bytes, mainnet by
+    /// default.
+    pub fn magic<N: Into<[u8; 4]>>(mut self, network: N) -> Self {
+        self.magic = network.into();
+        self
+    }
+
+    /// Reads the client handshake and replies with `reply`, encoded with
+    /// its own timestamp.
+    pub fn respond_handshake(mut self, reply: HandshakeMessage) -> Self {
+        self.steps.push(Step::RespondHandshake(reply));
+        self
+    }
+
+    /// Sends a framed network message.
+    pub fn then_send_frame(mut self, message: NetworkMessage) -> Self {
+        self.steps.push(Step::SendFrame(message));
+        self
+    }
+
+    /// Sends raw bytes, to script malformed messages.
+    pub fn then_send_bytes(mut self, data: Vec<u8>) -> Self {
+        self.steps.push(Step::SendBytes(data));
+        self
+    }
+
+    /// Reads frames until one with the given code is received.
+    pub fn then_expect_frame(mut self, code: u8) -> Self {
+        self.steps.push(Step::ExpectFrame(code));
+        self
+    }
+
+    /// Pauses the conversation.
+    pub fn then_wait(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Closes the connection, remaining steps are ignored.
+    pub fn then_close(mut self) -> Self {
+        self.steps.push(Step::Close);
+        self
+    }
+
+    /// Starts listening on a local port and runs the script on the first
+    /// connection.
+    pub async fn spawn(self) -> ProtocolResult<MockErgoNode> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            self.run(&mut stream).await
+        });
+        Ok(MockErgoNode { address, task })
+    }
+
+    /// Starts listening on a local port and runs the script on every
+    /// connection, until the returned server is dropped.
+    pub async fn serve(self) -> ProtocolResult<MockErgoServer> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let transcripts = Arc::new(Mutex::new(Vec::new()));
+        let completed = transcripts.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let script = self.clone();
+                let completed = completed.clone();
+                tokio::spawn(async move {
+                    let transcript = script.run(&mut stream).await;
+                    lock(&completed).push(transcript);
+                });
+            }
+        });
+        Ok(MockErgoServer {
+            address,
+            transcripts,
+            task,
+        })
+    }
+
+    async fn run(self, stream: &mut TcpStream) -> ProtocolResult<Transcript> {
+        let mut transcript = Transcript::default();
+        for step in self.steps {
+            match step {
+                Step::RespondHandshake(reply) => {
+                    let request = read_handshake(stream, spec::MAX_HANDSHAKE_SIZE).await?;
+                    transcript.handshake = Some(request);
+                    stream
+                        .write_all(&reply.encode_with_timestamp(reply.timestamp)?)
+                        .await?;
+                }
+                Step::SendFrame(message) => write_message(stream, self.magic, &message).await?,
+                Step::SendBytes(data) => stream.write_all(&data).await?,
+                Step::ExpectFrame(code) => loop {
+                    let message = read_message(stream, self.magic, DEFAULT_MAX_BODY_SIZE).await?;
+                    let received = message.code;
+                    transcript.frames.push(message);
+                    if received == code {
+                        break;
+                    }
+                },
+                Step::Wait(duration) => tokio::time::sleep(duration).await,
+                Step::Close => break,
+            }
+        }
+        stream.shutdown().await?;
+        Ok(transcript)
+    }
+}
+
+/// A mock node listening on a local port and running a [`MockScript`].
+#[derive(Debug)]
+pub struct MockErgoNode {
+    address: SocketAddr,
+    task: JoinHandle<ProtocolResult<Transcript>>,
+}
+
+impl MockErgoNode {
+    pub fn builder() -> MockScript {
+        MockScript::default()
+    }
+
+    /// The address to connect to.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Waits for the script to complete and returns what the node received,
+    /// or the error that interrupted the script.
+    pub async fn finish(self) -> ProtocolResult<Transcript> {
+        self.task
+            .await
+            .map_err(|err| ProtocolError::Io(std::io::Error::other(err)))?
+    }
+}
+
+/// A mock node listening on a local port and running a [`MockScript`] on
+/// every connection, it stops listening once dropped.
+#[derive(Debug)]
+pub struct MockErgoServer {
+    address: SocketAddr,
+    transcripts: Arc<Mutex<Vec<ProtocolResult<Transcript>>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockErgoServer {
+    /// The address to connect to.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Takes the outcome of the conversations completed so far, in the
+    /// order they completed.
+    pub fn take_transcripts(&self) -> Vec<ProtocolResult<Transcript>> {
+        std::mem::take(&mut *lock(&self.transcripts))
+    }
+}
+
+impl Drop for MockErgoServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Randomly fails a fraction of the connections and handshakes.
+///
+/// The failures are drawn from a seeded generator, so a given seed
+/// injects the same number of failures on every run.
+#[derive(Debug)]
+pub struct Chaos {
+    connect_failure_rate: f64,
+    handshake_failure_rate: f64,
+    rng: Mutex<SplitMix64>,
+}
+
+impl Chaos {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            connect_failure_rate: 0.0,
+            handshake_failure_rate: 0.0,
+            rng: Mutex::new(SplitMix64::new(seed)),
+        }
+    }
+
+    /// Fraction, between 0 and 1, of the connections failing with a
+    /// refused connection.
+    pub fn connect_failure_rate(mut self, rate: f64) -> Self {
+        self.connect_failure_rate = rate;
+        self
+    }
+
+    /// Fraction, between 0 and 1, of the established connections reset
+    /// before the handshake completes.
+    pub fn handshake_failure_rate(mut self, rate: f64) -> Self {
+        self.handshake_failure_rate = rate;
+        self
+    }
+
+    pub(crate) fn inject_connect_failure(&self) -> ProtocolResult<()> {
+        if self.roll(self.connect_failure_rate) {
+            return Err(injected(io::ErrorKind::ConnectionRefused));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn inject_handshake_failure(&self) -> ProtocolResult<()> {
+        if self.roll(self.handshake_failure_rate) {
+            return Err(injected(io::ErrorKind::ConnectionReset));
+        }
+        Ok(())
+    }
+
+    fn roll(&self, rate: f64) -> bool {
+        lock(&self.rng).next_f64() < rate
+    }
+}
+
+fn injected(kind: io::ErrorKind) -> ProtocolError {
+    ProtocolError::Io(io::Error::new(kind, "injected by chaos testing"))
+}
+
+/// The resources held by the process and its runtime, to detect leaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ResourceUsage {
+    /// Number of open file descriptors, on Linux only.
+    pub open_fds: Option<usize>,
+    /// Number of tasks alive in the current runtime.
+    pub alive_tasks: usize,
+    /// Resident memory of the process in bytes, on Linux only.
+    pub resident_bytes: Option<u64>,
+}
+
+impl ResourceUsage {
+    /// Measures the resources now, it must be called within a runtime.
+    pub fn current() -> Self {
+        let open_fds = std::fs::read_dir("/proc/self/fd")
+            .ok()
+            .map(|entries| entries.count());
+        // The second field of `statm` is the resident set, in pages,
+        // which are 4 KiB on the platforms this is run on.
+        let resident_bytes = std::fs::read_to_string("/proc/self/statm")
+            .ok()
+            .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+            .map(|pages| pages * 4096);
+        Self {
+            open_fds,
+            alive_tasks: tokio::runtime::Handle::current()
+                .metrics()
+                .num_alive_tasks(),
+            resident_bytes,
+        }
+    }
+}
+
+/// The parameters of a [`stress`] run.
+#[derive(Debug, Clone)]
+pub struct StressOptions {
+    /// Number of handshakes to perform.
+    pub handshakes: usize,
+    /// Number of handshakes running at the same time.
+    pub concurrency: usize,
+    /// Maximum time given to the connections and tasks to be released
+    /// once the handshakes complete.
+    pub settle_timeout: Duration,
+}
+
+impl Default for StressOptions {
+    fn default() -> Self {
+        Self {
+            handshakes: 1000,
+            concurrency: 100,
+            settle_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The outcome of a [`stress`] run.
+#[derive(Debug, Clone)]
+pub struct StressReport {
+    pub completed: usize,
+    pub failed: usize,
+    pub elapsed: Duration,
+    pub before: ResourceUsage,
+    pub after: ResourceUsage,
+}
+
+impl StressReport {
+    /// File descriptors still open after the run, `None` when they can't
+    /// be counted.
+    pub fn leaked_fds(&self) -> Option<usize> {
+        Some(self.after.open_fds?.saturating_sub(self.before.open_fds?))
+    }
+
+    /// Tasks still alive after the run.
+    pub fn leaked_tasks(&self) -> usize {
+        self.after
+            .alive_tasks
+            .saturating_sub(self.before.alive_tasks)
+    }
+
+    /// Growth of the resident memory over the run, `None` when it can't
+    /// be measured.
+    pub fn resident_growth(&self) -> Option<u64> {
+        Some(
+            self.after
+                .resident_bytes?
+                .saturating_sub(self.before.resident_bytes?),
+        )
+    }
+}
+
+/// Performs many concurrent handshakes, as configured, against an
+/// in-process mock node, and reports the resources held before and after
+/// them, to guard the resource behavior of the scans.
+///
+/// ```
+/// # async fn run() -> p2p_handshake::ProtocolResult<()> {
+/// use p2p_handshake::testing::{stress, StressOptions};
+/// use p2p_handshake::{HandshakeBuilder, HandshakeConfig};
+///
+/// let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("paul"));
+/// let report = stress(&config, &StressOptions::default()).await?;
+/// assert_eq!(report.completed, 1000);
+/// assert_eq!(report.leaked_tasks(), 0);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn stress(
+    config: &HandshakeConfig,
+    options: &StressOptions,
+) -> ProtocolResult<StressReport> {
+    let before = ResourceUsage::current();
+    let reply = HandshakeBuilder::new().agent_name("ergoref").build()?;
+    let server = MockErgoNode::builder()
+        .respond_handshake(reply)
+        .serve()
+        .await?;
+    let targets = vec![server.address(); options.handshakes];
+    let started = Instant::now();
+    let results = handshake_many(targets, config, options.concurrency).await;
+    let elapsed = started.elapsed();
+    let completed = results
+        .iter()
+        .filter(|result| result.outcome.is_ok())
+        .count();
+    drop(results);
+    drop(server);
+
+    // The server side of the connections and the finished tasks are
+    // released asynchronously.
+    let deadline = Instant::now() + options.settle_timeout;
+    let mut after = ResourceUsage::current();
+    while (after.alive_tasks > before.alive_tasks || after.open_fds > before.open_fds)
+        && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        after = ResourceUsage::current();
+    }
+    Ok(StressReport {
+        completed,
+        failed: options.handshakes - completed,
+        elapsed,
+        before,
+        after,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::{encode_peers, request_peers, PeerSpec, GET_PEERS_CODE};
+    use crate::{handshake, Version};
+
+    #[tokio::test]
+    async fn test_scripted_conversation() -> ProtocolResult<()> {
+        let peers = vec![PeerSpec {
+            declared_address: Some("1.2.3.4:9030".parse().unwrap()),
+            ..Default::default()
+        }];
+        let node = MockErgoNode::builder()
+            .respond_handshake(HandshakeBuilder::new().agent_name("ergoref").build()?)
+            .then_expect_frame(GET_PEERS_CODE)
+            .then_send_frame(NetworkMessage::new(65, vec![1]))
+            .then_send_frame(encode_peers(&peers)?)
+            .then_close()
+            .spawn()
+            .await?;
+
+        let mut connection = None;
+        handshake(
+            node.address(),
+            "paul",
+            Version([5, 0, 0]),
+            |stream, reply| {
+                assert_eq!(reply.agent_name.as_str(), "ergoref");
+                connection = Some(stream);
+                Ok(())
+            },
+        )
+        .await?;
+        let mut stream = connection.unwrap();
+        assert_eq!(request_peers(&mut stream, MAINNET_MAGIC).await?, peers);
+
+        let transcript = node.finish().await?;
+        assert_eq!(transcript.handshake.unwrap().agent_name.as_str(), "paul");
+        assert_eq!(transcript.frames.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_closing_before_handshake() -> ProtocolResult<()> {
+        let node = MockErgoNode::builder().then_close().spawn().await?;
+        let result = handshake(node.address(), "paul", Version([5, 0, 0]), |_, _| Ok(())).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::HandshakeTimedOutByPeer)
+        ));
+        node.finish().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_chaos_rates() {
+        let never = Chaos::new(7);
+        assert!((0..100).all(|_| never.inject_connect_failure().is_ok()));
+
+        let always = Chaos::new(7).handshake_failure_rate(1.0);
+        assert!((0..100).all(|_| always.inject_handshake_failure().is_err()));
+
+        let count_failures = |seed| {
+            let chaos = Chaos::new(seed).connect_failure_rate(0.3);
+            (0..1000)
+                .filter(|_| chaos.inject_connect_failure().is_err())
+                .count()
+        };
+        let failures = count_failures(42);
+        assert!((250..350).contains(&failures), "{failures} failures");
+        assert_eq!(count_failures(42), failures);
+    }
+
+    #[tokio::test]
+    async fn test_serve() -> ProtocolResult<()> {
+        let server = MockErgoNode::builder()
+            .respond_handshake(HandshakeBuilder::new().agent_name("ergoref").build()?)
+            .then_close()
+            .serve()
+            .await?;
+
+        for name in ["paul", "jane"] {
+            let mut agent_name = None;
+            handshake(server.address(), name, Version([5, 0, 0]), |_, reply| {
+                agent_name = Some(reply.agent_name.to_string());
+                Ok(())
+            })
+            .await?;
+            assert_eq!(agent_name.as_deref(), Some("ergoref"));
+        }
+
+        // The conversations complete after the replies are read.
+        let mut transcripts = Vec::new();
+        while transcripts.len() < 2 {
+            tokio::task::yield_now().await;
+            transcripts.extend(server.take_transcripts());
+        }
+        let mut names: Vec<String> = transcripts
+            .into_iter()
+            .map(|transcript| {
+                transcript
+                    .unwrap()
+                    .handshake
+                    .unwrap()
+                    .agent_name
+                    .to_string()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, ["jane", "paul"]);
+        Ok(())
+    }
+}
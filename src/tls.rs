@@ -0,0 +1,244 @@
+//! This module implements running the handshake over TLS, for the nodes
+//! sitting behind a TLS terminating proxy.
+//!
+//! The TLS session is negotiated with [rustls](https://docs.rs/rustls) as
+//! soon as the connection is established, then the protocol runs over it
+//! as over a plain connection. Configure the request with
+//! [`HandshakeBuilder::tls`]: the scans, ex. [`handshake_many`](crate::handshake_many)
+//! and [`handshake_with_config`](crate::handshake_with_config), then
+//! negotiate the session on every connection. The other functions of the
+//! crate root hand a plain `TcpStream` over, they refuse such requests
+//! instead of sending them in the clear.
+//!
+//! ```no_run
+//! # async fn run() -> p2p_handshake::ProtocolResult<()> {
+//! use p2p_handshake::tls::{self, ServerName};
+//! use p2p_handshake::HandshakeBuilder;
+//!
+//! let server_name = ServerName::try_from("node.example.org").unwrap();
+//! let request = HandshakeBuilder::new()
+//!     .agent_name("paul")
+//!     .tls(tls::client_config(), server_name);
+//! tls::handshake_with_builder("node.example.org:443", &request, |_stream, reply| {
+//!     println!("{}", reply.agent_name);
+//!     Ok(())
+//! })
+//! .await
+//! # }
+//! ```
+//!
+
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+pub use tokio_rustls::rustls::pki_types::ServerName;
+pub use tokio_rustls::rustls::ClientConfig;
+
+use crate::builder::HandshakeBuilder;
+use crate::encoder::HandshakeMessage;
+use crate::error::{ProtocolError, ProtocolResult};
+use crate::route::Target;
+use crate::{connect, handshake_with_stream};
+
+/// The TLS configuration of a request, cloning it only bumps reference
+/// counts.
+#[derive(Clone)]
+pub struct TlsSettings {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) server_name: ServerName<'static>,
+}
+
+impl fmt::Debug for TlsSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsSettings")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A client configuration trusting the web PKI roots of Mozilla.
+pub fn client_config() -> Arc<ClientConfig> {
+    let roots = tokio_rustls::rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Same as [`handshake_with_builder`](crate::handshake_with_builder) but
+/// negotiates TLS before running the protocol, as configured by
+/// [`HandshakeBuilder::tls`]. Without a configuration, the web PKI roots
+/// are trusted and the server name is the host of the target.
+pub async fn handshake_with_builder<A: Target, F>(
+    target_address: A,
+    request: &HandshakeBuilder,
+    on_accept: F,
+) -> ProtocolResult<()>
+where
+    F: FnOnce(TlsStream<TcpStream>, HandshakeMessage) -> ProtocolResult<()>,
+{
+    let settings = match request.tls_settings() {
+        Some(settings) => settings.clone(),
+        None => TlsSettings {
+            config: client_config(),
+            server_name: server_name(&target_address)?,
+        },
+    };
+    let stream = connect_tls(target_address, &settings).await?;
+    handshake_with_stream(stream, request, on_accept).await
+}
+
+/// Connects to the target and negotiates the TLS session.
+pub async fn connect_tls<A: Target>(
+    target_address: A,
+    settings: &TlsSettings,
+) -> ProtocolResult<TlsStream<TcpStream>> {
+    upgrade(connect(target_address).await?, settings).await
+}
+
+/// Negotiates the TLS session over the connection.
+pub(crate) async fn upgrade(
+    stream: TcpStream,
+    settings: &TlsSettings,
+) -> ProtocolResult<TlsStream<TcpStream>> {
+    let connector = TlsConnector::from(settings.config.clone());
+    Ok(connector
+        .connect(settings.server_name.clone(), stream)
+        .await?)
+}
+
+/// The server name of the host of the target.
+fn server_name<A: Target>(target_address: &A) -> ProtocolResult<ServerName<'static>> {
+    let no_server_name =
+        || ProtocolError::Tls("the target has no host name, configure the server name".to_string());
+    let (host, _) = target_address.host_port().ok_or_else(no_server_name)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|err| ProtocolError::Tls(err.to_string()))
+}
+
+impl HandshakeBuilder {
+    /// Negotiates TLS with the configuration and the server name before
+    /// running the protocol, see the [`tls`](crate::tls) module.
+    pub fn tls(mut self, config: Arc<ClientConfig>, server_name: ServerName<'static>) -> Self {
+        self.tls = Some(TlsSettings {
+            config,
+            server_name,
+        });
+        self
+    }
+
+    pub(crate) fn tls_settings(&self) -> Option<&TlsSettings> {
+        self.tls.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::{handshake_many, handshake_with_config, respond_handshake, HandshakeConfig};
+
+    const CERTIFICATE: &[u8] = include_bytes!("../data/tls/cert.der");
+    const PRIVATE_KEY: &[u8] = include_bytes!("../data/tls/key.der");
+
+    /// Accepts a TLS connection and answers the handshake.
+    async fn spawn_tls_node() -> ProtocolResult<std::net::SocketAddr> {
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(PRIVATE_KEY.to_vec()));
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![CertificateDer::from(CERTIFICATE.to_vec())], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = acceptor.accept(stream).await?;
+            let reply = HandshakeBuilder::new().agent_name("ergoref");
+            respond_handshake(&mut stream, &reply).await
+        });
+        Ok(address)
+    }
+
+    /// A request trusting the certificate of the test node.
+    fn trusting_request() -> HandshakeBuilder {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(CERTIFICATE.to_vec()))
+            .unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        HandshakeBuilder::new()
+            .agent_name("paul")
+            .tls(Arc::new(config), ServerName::try_from("node.test").unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake() -> ProtocolResult<()> {
+        let address = spawn_tls_node().await?;
+        let request = trusting_request();
+
+        let mut agent_name = None;
+        handshake_with_builder(address, &request, |_stream, reply| {
+            agent_name = Some(reply.agent_name);
+            Ok(())
+        })
+        .await?;
+        assert_eq!(agent_name.unwrap().as_str(), "ergoref");
+
+        // The plain functions refuse to send the request in the clear.
+        let result = crate::handshake_with_builder(address, &request, |_, _| Ok(())).await;
+        assert!(matches!(result, Err(ProtocolError::Tls(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_scans() -> ProtocolResult<()> {
+        let config = HandshakeConfig::new(trusting_request());
+        let address = spawn_tls_node().await?;
+        let mut negotiated = false;
+        handshake_with_config(address, &config, |stream, reply| {
+            negotiated = stream.is_tls();
+            assert_eq!(reply.agent_name.as_str(), "ergoref");
+            Ok(())
+        })
+        .await?;
+        assert!(negotiated);
+
+        let address = spawn_tls_node().await?;
+        let results = handshake_many(vec![address], &config, 1).await;
+        assert_eq!(
+            results[0].outcome.as_ref().unwrap().agent_name.as_str(),
+            "ergoref"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_certificate() -> ProtocolResult<()> {
+        let address = spawn_tls_node().await?;
+        let request = HandshakeBuilder::new()
+            .agent_name("paul")
+            .tls(client_config(), ServerName::try_from("node.test").unwrap());
+        let result = handshake_with_builder(address, &request, |_, _| Ok(())).await;
+        assert!(matches!(result, Err(ProtocolError::Io(_))));
+
+        let result = handshake_with_builder(address, &HandshakeBuilder::new(), |_, _| Ok(())).await;
+        assert!(matches!(result, Err(ProtocolError::Tls(_))));
+        Ok(())
+    }
+}
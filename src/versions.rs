@@ -0,0 +1,161 @@
+//! This module implements checking the versions reported by the peers
+//! against the releases of the reference node.
+//!
+//! The table of known releases is embedded in the library and can be
+//! replaced by a file with the same format, see [`VersionTable::parse`].
+//! A peer reporting an obsolete release is likely left behind by its
+//! operator, one reporting an unknown release, ex. beyond the latest
+//! one, may be spoofing its identity.
+//!
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::encoder::Version;
+
+/// The table embedded in the library.
+const KNOWN_VERSIONS: &str = include_str!("../data/known_versions.txt");
+
+/// The status of a release of the reference node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionStatus {
+    Supported,
+    Obsolete,
+}
+
+/// Why the version reported by a peer deserves attention.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionWarning {
+    /// The version isn't a known release.
+    Unknown(Version),
+    /// The version is a release that should have been upgraded.
+    Obsolete(Version),
+}
+
+impl fmt::Display for VersionWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionWarning::Unknown(version) => write!(f, "unknown version {version}"),
+            VersionWarning::Obsolete(version) => write!(f, "obsolete version {version}"),
+        }
+    }
+}
+
+/// The known releases of the reference node, as inclusive ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionTable {
+    ranges: Vec<(Version, Version, VersionStatus)>,
+}
+
+impl Default for VersionTable {
+    fn default() -> Self {
+        Self::embedded()
+    }
+}
+
+impl VersionTable {
+    /// The table embedded in the library.
+    pub fn embedded() -> Self {
+        // The embedded table is checked by the tests.
+        Self::parse(KNOWN_VERSIONS).unwrap_or(Self { ranges: Vec::new() })
+    }
+
+    /// Reads a table from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Parses a table holding a version, ex. `5.0.21`, or an inclusive
+    /// range of versions, ex. `5.0.0-5.0.21`, followed by `supported` or
+    /// `obsolete` on every line. Empty lines and lines starting with `#`
+    /// are ignored.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| format!("line {}: {reason}", index + 1);
+            let mut words = line.split_whitespace();
+            let (Some(versions), Some(status), None) = (words.next(), words.next(), words.next())
+            else {
+                return Err(invalid("expected a version and a status".to_string()));
+            };
+            let (low, high) = versions.split_once('-').unwrap_or((versions, versions));
+            let low: Version = low.parse().map_err(invalid)?;
+            let high: Version = high.parse().map_err(invalid)?;
+            let status = match status {
+                "supported" => VersionStatus::Supported,
+                "obsolete" => VersionStatus::Obsolete,
+                status => return Err(invalid(format!("unknown status `{status}`"))),
+            };
+            ranges.push((low, high, status));
+        }
+        Ok(Self { ranges })
+    }
+
+    /// The status of the version, `None` if it isn't a known release. The
+    /// first matching line of the table wins.
+    pub fn status(&self, version: &Version) -> Option<VersionStatus> {
+        self.ranges
+            .iter()
+            .find(|(low, high, _)| low <= version && version <= high)
+            .map(|(_, _, status)| *status)
+    }
+
+    /// The warning raised by a peer reporting the version, if any.
+    pub fn check(&self, version: &Version) -> Option<VersionWarning> {
+        match self.status(version) {
+            Some(VersionStatus::Supported) => None,
+            Some(VersionStatus::Obsolete) => Some(VersionWarning::Obsolete(version.clone())),
+            None => Some(VersionWarning::Unknown(version.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_table() {
+        assert!(VersionTable::parse(KNOWN_VERSIONS).is_ok());
+        let table = VersionTable::embedded();
+        assert_eq!(table.check(&Version([5, 0, 21])), None);
+        assert_eq!(
+            table.check(&Version([4, 0, 100])),
+            Some(VersionWarning::Obsolete(Version([4, 0, 100])))
+        );
+        assert_eq!(
+            table
+                .check(&Version([9, 9, 9]))
+                .map(|warning| warning.to_string()),
+            Some("unknown version 9.9.9".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let table =
+            VersionTable::parse("# comment\n\n5.0.21 supported\n5.0.0-5.0.20 obsolete\n").unwrap();
+        assert_eq!(
+            table.status(&Version([5, 0, 21])),
+            Some(VersionStatus::Supported)
+        );
+        assert_eq!(
+            table.status(&Version([5, 0, 3])),
+            Some(VersionStatus::Obsolete)
+        );
+        assert_eq!(table.status(&Version([5, 0, 22])), None);
+
+        assert_eq!(
+            VersionTable::parse("5.0.21 current"),
+            Err("line 1: unknown status `current`".to_string())
+        );
+        assert!(VersionTable::parse("5.0 supported").is_err());
+        assert!(VersionTable::parse("5.0.21").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+//! This module implements capturing the bytes of the handshakes, to
+//! diagnose the interoperability failures with other implementations
+//! without a packet capture.
+//!
+//! [`HandshakeConfig::on_wire`](crate::HandshakeConfig::on_wire) hands
+//! the request and the bytes received in reply, complete or not, to a
+//! [`WireCapture`]. [`hex_dump`] prints them as hexadecimal with the
+//! boundaries of the fields labeled:
+//!
+//! ```text
+//! 0000  b8 ad 9f 97 94 34                                timestamp = 1792123852472
+//! 0006  07                                               agent name length = 7
+//! 0007  65 72 67 6f 72 65 66                             agent name = "ergoref"
+//! 000e  05 00 15                                         version = 5.0.21
+//! ```
+//!
+
+use std::fmt::{self, Write};
+use std::net::IpAddr;
+use std::ops::Range;
+#[cfg(feature = "net")]
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "net")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(feature = "net")]
+use crate::encoder::HandshakeMessage;
+#[cfg(feature = "net")]
+use crate::error::ProtocolResult;
+#[cfg(feature = "net")]
+use crate::observer::{exchange_observed, Observation};
+use crate::spec::{Field, VERSION_LEN};
+
+/// Number of bytes printed on each line of a dump.
+const BYTES_PER_LINE: usize = 16;
+
+/// Whether the bytes were sent to the peer or received from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        })
+    }
+}
+
+/// Receives the bytes of the handshakes.
+///
+/// It is implemented by the functions taking the address of the peer,
+/// the direction and the bytes, see
+/// [`HandshakeConfig::on_wire`](crate::HandshakeConfig::on_wire).
+#[cfg(feature = "net")]
+pub trait WireCapture: Send + Sync {
+    fn on_wire(&self, address: SocketAddr, direction: Direction, data: &[u8]);
+}
+
+#[cfg(feature = "net")]
+impl<F> WireCapture for F
+where
+    F: Fn(SocketAddr, Direction, &[u8]) + Send + Sync,
+{
+    fn on_wire(&self, address: SocketAddr, direction: Direction, data: &[u8]) {
+        self(address, direction, data)
+    }
+}
+
+/// A shared [`WireCapture`], cloning it only bumps a reference count.
+#[cfg(feature = "net")]
+#[derive(Clone)]
+pub struct WireCaptureHook(Arc<dyn WireCapture>);
+
+#[cfg(feature = "net")]
+impl WireCaptureHook {
+    pub fn new<H: WireCapture + 'static>(hook: H) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+#[cfg(feature = "net")]
+impl fmt::Debug for WireCaptureHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireCaptureHook").finish_non_exhaustive()
+    }
+}
+
+/// A labeled field of a captured handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The position of the field in the captured bytes.
+    pub range: Range<usize>,
+    /// The name of the field and its value, ex. `version = 5.0.21`.
+    pub label: String,
+}
+
+/// Splits a captured handshake into its fields, as far as the bytes go.
+///
+/// Unlike the decoding, it never fails: a field cut short is labeled as
+/// truncated, invalid values are shown as such, and the bytes following
+/// the message are labeled as trailing.
+pub fn annotate(data: &[u8]) -> Vec<Segment> {
+    let mut walker = Walker {
+        data,
+        position: 0,
+        pending: 0,
+        segments: vec![],
+    };
+    // Stops at the first field cut short.
+    let _ = walker.walk();
+    if walker.position < data.len() {
+        walker.segments.push(Segment {
+            range: walker.position..data.len(),
+            label: "trailing bytes".to_string(),
+        });
+    }
+    walker.segments
+}
+
+/// Prints the captured bytes as hexadecimal, one field per line, followed
+/// by its label. Long fields span several lines.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for segment in annotate(data) {
+        let bytes = &data[segment.range.clone()];
+        for (index, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let offset = segment.range.start + index * BYTES_PER_LINE;
+            let hex: Vec<String> = line.iter().map(|byte| format!("{byte:02x}")).collect();
+            let label = if index == 0 {
+                segment.label.as_str()
+            } else {
+                ""
+            };
+            let line = format!(
+                "{offset:04x}  {:<width$}  {label}",
+                hex.join(" "),
+                width = BYTES_PER_LINE * 3 - 1
+            );
+            let _ = writeln!(dump, "{}", line.trim_end());
+        }
+    }
+    dump
+}
+
+/// Returned when a field is cut short.
+struct Truncated;
+
+struct Walker<'a> {
+    data: &'a [u8],
+    position: usize,
+    /// Length of the field taken and not labeled yet.
+    pending: usize,
+    segments: Vec<Segment>,
+}
+
+impl<'a> Walker<'a> {
+    fn walk(&mut self) -> Result<(), Truncated> {
+        let timestamp = self.vlq(&Field::Timestamp.to_string())?;
+        self.label(format!("{} = {timestamp}", Field::Timestamp));
+        self.string(Field::AgentName)?;
+        let version = self.take(VERSION_LEN, &Field::Version.to_string())?;
+        let version = format!("{}.{}.{}", version[0], version[1], version[2]);
+        self.label(format!("{} = {version}", Field::Version));
+        self.string(Field::PeerName)?;
+
+        let field = Field::DeclaredAddress;
+        let flag = self.byte(&format!("{field} flag"))?;
+        self.label(format!("{field} flag = {flag}"));
+        if flag != 0 {
+            let size = self.byte(&format!("{field} size"))?;
+            self.label(format!("{field} size = {size}"));
+            let octets = self.take((size as usize).saturating_sub(4), &format!("{field} ip"))?;
+            let ip = match octets.len() {
+                4 => <[u8; 4]>::try_from(octets).map(IpAddr::from).ok(),
+                16 => <[u8; 16]>::try_from(octets).map(IpAddr::from).ok(),
+                _ => None,
+            };
+            match ip {
+                Some(ip) => self.label(format!("{field} ip = {ip}")),
+                None => self.label(format!("{field} ip, invalid size")),
+            }
+            let port = self.vlq(&format!("{field} port"))?;
+            self.label(format!("{field} port = {port}"));
+        }
+
+        let count = self.byte("features count")?;
+        self.label(format!("features count = {count}"));
+        for index in 0..count {
+            let id = self.byte(&format!("feature #{index} id"))?;
+            self.label(format!("feature #{index} id = {id}"));
+            let len = self.vlq(&format!("feature #{index} length"))?;
+            self.label(format!("feature #{index} length = {len}"));
+            let len = usize::try_from(len).unwrap_or(usize::MAX);
+            self.take(len, &format!("feature #{index} payload"))?;
+            self.label(format!("feature #{index} payload"));
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, field: Field) -> Result<(), Truncated> {
+        let len = self.byte(&format!("{field} length"))?;
+        self.label(format!("{field} length = {len}"));
+        let bytes = self.take(len as usize, &field.to_string())?;
+        let value = String::from_utf8_lossy(bytes);
+        self.label(format!("{field} = {value:?}"));
+        Ok(())
+    }
+
+    fn byte(&mut self, name: &str) -> Result<u8, Truncated> {
+        Ok(self.take(1, name)?[0])
+    }
+
+    fn vlq(&mut self, name: &str) -> Result<u64, Truncated> {
+        let mut rest = &self.data[self.position..];
+        let len = rest.len();
+        match leb128::read::unsigned(&mut rest) {
+            Ok(value) => {
+                self.pending = len - rest.len();
+                Ok(value)
+            }
+            Err(_) => Err(self.truncated(name)),
+        }
+    }
+
+    /// Takes the next `len` bytes, labeled by the following call to
+    /// [`Walker::label`].
+    fn take(&mut self, len: usize, name: &str) -> Result<&'a [u8], Truncated> {
+        let start = self.position;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => {
+                self.pending = len;
+                Ok(&self.data[start..end])
+            }
+            _ => Err(self.truncated(name)),
+        }
+    }
+
+    fn label(&mut self, label: String) {
+        let range = self.position..self.position + self.pending;
+        self.position = range.end;
+        self.segments.push(Segment { range, label });
+    }
+
+    fn truncated(&mut self, name: &str) -> Truncated {
+        self.segments.push(Segment {
+            range: self.position..self.data.len(),
+            label: format!("{name}, truncated"),
+        });
+        self.position = self.data.len();
+        Truncated
+    }
+}
+
+/// Records the bytes read from the stream and hands them to the capture
+/// once dropped, so that the replies cut short by an error or a timeout
+/// are captured too.
+#[cfg(feature = "net")]
+struct ReadTap<'a, S> {
+    stream: &'a mut S,
+    received: Vec<u8>,
+    address: SocketAddr,
+    capture: &'a WireCaptureHook,
+}
+
+#[cfg(feature = "net")]
+impl<S: AsyncRead + Unpin> AsyncRead for ReadTap<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut *this.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.received.extend_from_slice(&buf.filled()[filled..]);
+        }
+        poll
+    }
+}
+
+#[cfg(feature = "net")]
+impl<S: AsyncWrite + Unpin> AsyncWrite for ReadTap<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "net")]
+impl<S> Drop for ReadTap<'_, S> {
+    fn drop(&mut self) {
+        let WireCaptureHook(capture) = self.capture;
+        capture.on_wire(self.address, Direction::Received, &self.received);
+    }
+}
+
+/// Same as [`exchange_observed`] handing the bytes sent and received to
+/// the capture.
+#[cfg(feature = "net")]
+pub(crate) async fn exchange_captured<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &[u8],
+    address: SocketAddr,
+    capture: &WireCaptureHook,
+    observation: &mut Observation<'_>,
+) -> ProtocolResult<HandshakeMessage> {
+    let WireCaptureHook(hook) = capture;
+    hook.on_wire(address, Direction::Sent, request);
+    let mut tap = ReadTap {
+        stream,
+        received: vec![],
+        address,
+        capture,
+    };
+    exchange_observed(&mut tap, request, observation).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::HandshakeBuilder;
+    use crate::encoder::Version;
+    use crate::error::ProtocolResult;
+
+    #[test]
+    fn test_annotate() -> ProtocolResult<()> {
+        let data = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .version(Version([5, 0, 21]))
+            .peer_name("node")
+            .declared_address("1.2.3.4:9030".parse().unwrap())
+            .build()?
+            .encode_with_timestamp(42)?;
+        let labels: Vec<String> = annotate(&data)
+            .into_iter()
+            .map(|segment| segment.label)
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "timestamp = 42",
+                "agent name length = 7",
+                "agent name = \"ergoref\"",
+                "version = 5.0.21",
+                "peer name length = 4",
+                "peer name = \"node\"",
+                "declared address flag = 1",
+                "declared address size = 8",
+                "declared address ip = 1.2.3.4",
+                "declared address port = 9030",
+                "features count = 0",
+            ]
+        );
+
+        let segments = annotate(&data[..6]);
+        let last = segments.last().unwrap();
+        assert_eq!(last.range, 2..6);
+        assert_eq!(last.label, "agent name, truncated");
+
+        let mut trailing = data.clone();
+        trailing.push(0xff);
+        let last = annotate(&trailing).pop().unwrap();
+        assert_eq!(last.range, data.len()..data.len() + 1);
+        assert_eq!(last.label, "trailing bytes");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hex_dump() -> ProtocolResult<()> {
+        let data = HandshakeBuilder::new()
+            .agent_name("ergoref")
+            .build()?
+            .encode_with_timestamp(1)?;
+        let dump = hex_dump(&data);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert!(lines[0].starts_with("0000  01  "));
+        assert!(lines[0].ends_with("timestamp = 1"));
+        assert!(lines[2].starts_with("0002  65 72 67 6f 72 65 66  "));
+        assert!(hex_dump(&[0u8; 40]).lines().count() > 1);
+        Ok(())
+    }
+}
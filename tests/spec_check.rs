@@ -0,0 +1,219 @@
+//! Checks the encoder output against the documented handshake layout.
+
+use p2p_handshake::conformance::TestVector;
+use p2p_handshake::core::{CodecError, HandshakeDecoder};
+use p2p_handshake::feature::{Feature, ModeFeature, SessionFeature, StateType};
+use p2p_handshake::spec::{self, Field};
+use p2p_handshake::{
+    DecodeLimits, HandshakeBuilder, HandshakeMessage, PeerFeature, ProtocolError, ProtocolResult,
+    Version,
+};
+
+fn field_bytes<'a>(
+    data: &'a [u8],
+    fields: &[(Field, std::ops::Range<usize>)],
+    field: Field,
+) -> &'a [u8] {
+    let (_, range) = fields
+        .iter()
+        .find(|(f, _)| *f == field)
+        .expect("field should be present");
+    &data[range.clone()]
+}
+
+#[test]
+fn test_encoder_follows_field_order() -> ProtocolResult<()> {
+    let data = HandshakeBuilder::new()
+        .agent_name("ergoref")
+        .peer_name("ergo-testnet")
+        .version(Version([5, 0, 21]))
+        .timestamp_provider(|| 1_718_000_000_000)
+        .encode()?;
+
+    let fields = spec::layout(&data)?;
+    let order: Vec<Field> = fields.iter().map(|(field, _)| *field).collect();
+    assert_eq!(order, spec::FIELD_ORDER.to_vec());
+
+    assert_eq!(
+        field_bytes(&data, &fields, Field::AgentName),
+        b"\x07ergoref"
+    );
+    assert_eq!(field_bytes(&data, &fields, Field::Version), &[5, 0, 21]);
+    assert_eq!(
+        field_bytes(&data, &fields, Field::PeerName),
+        b"\x0cergo-testnet"
+    );
+    assert_eq!(field_bytes(&data, &fields, Field::DeclaredAddress), &[0]);
+    assert_eq!(field_bytes(&data, &fields, Field::Features), &[0]);
+    Ok(())
+}
+
+#[test]
+fn test_encoder_declared_addresses_follow_spec() -> ProtocolResult<()> {
+    for (address, size) in [
+        ("1.2.3.4:9030", spec::IPV4_ADDRESS_SIZE),
+        ("[2001:db8::1]:9030", spec::IPV6_ADDRESS_SIZE),
+    ] {
+        let data = HandshakeBuilder::new()
+            .declared_address(address.parse().unwrap())
+            .encode()?;
+        let fields = spec::layout(&data)?;
+        let declared = field_bytes(&data, &fields, Field::DeclaredAddress);
+        assert_eq!(declared[0], 1);
+        assert_eq!(declared[1], size);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_encoder_respects_limits() -> ProtocolResult<()> {
+    let mut builder = HandshakeBuilder::new().agent_name("x".repeat(spec::MAX_SHORT_STRING_LEN));
+    for id in 0..spec::MAX_FEATURES {
+        builder = builder.feature(PeerFeature::new(id as u8, vec![1]));
+    }
+    let data = builder.clone().encode()?;
+    spec::layout(&data)?;
+
+    assert!(builder
+        .clone()
+        .feature(PeerFeature::new(0, vec![]))
+        .encode()
+        .is_err());
+    assert!(HandshakeBuilder::new()
+        .feature(PeerFeature::new(0, vec![0; spec::MAX_FEATURE_LEN + 1]))
+        .encode()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_encoder_respects_message_size() -> ProtocolResult<()> {
+    // A feature of several KiB fits within the message size.
+    let builder = HandshakeBuilder::new().feature(PeerFeature::new(64, vec![7; 6 * 1024]));
+    let data = builder.encode()?;
+    spec::layout(&data)?;
+    assert!(data.len() <= spec::MAX_HANDSHAKE_SIZE);
+
+    // Each feature is within its limit but the message exceeds the size
+    // the reference node accepts.
+    let builder = builder.feature(PeerFeature::new(65, vec![7; 4 * 1024]));
+    for outcome in [
+        builder.encode().map(|_| ()),
+        builder.encode_into(&mut Vec::new()),
+        builder.encode_to_slice(&mut [0u8; 16 * 1024]).map(|_| ()),
+        spec::check_message(&builder.build()?),
+    ] {
+        assert!(matches!(
+            outcome,
+            Err(ProtocolError::MessageTooLarge(spec::MAX_HANDSHAKE_SIZE))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_decode_limits_are_capped() -> ProtocolResult<()> {
+    // The encoder refuses it, the message is built from the features of
+    // two smaller ones.
+    let empty = HandshakeBuilder::new().build()?.encode_with_timestamp(1)?;
+    let single = HandshakeBuilder::new()
+        .feature(PeerFeature::new(64, vec![7; 5 * 1024]))
+        .build()?
+        .encode_with_timestamp(1)?;
+    // The features end the message, after their count.
+    let feature = &single[empty.len()..];
+    let data = [&empty[..empty.len() - 1], &[2], feature, feature].concat();
+    assert!(data.len() > spec::MAX_HANDSHAKE_SIZE);
+
+    let loose = DecodeLimits {
+        max_message: usize::MAX,
+        ..Default::default()
+    };
+    assert_eq!(
+        DecodeLimits::with_max_message(usize::MAX),
+        DecodeLimits::default()
+    );
+    assert!(matches!(
+        HandshakeMessage::decode_with_limits(&data, &loose),
+        Err(ProtocolError::MessageTooLarge(spec::MAX_HANDSHAKE_SIZE))
+    ));
+    let mut decoder = HandshakeDecoder::with_limits(loose);
+    assert!(matches!(
+        decoder.feed(&data),
+        Err(CodecError::MessageTooLarge(spec::MAX_HANDSHAKE_SIZE))
+    ));
+    Ok(())
+}
+
+fn vector(name: &str) -> HandshakeMessage {
+    TestVector::embedded()
+        .into_iter()
+        .find(|vector| vector.name == name)
+        .expect("vector should be embedded")
+        .decode()
+        .expect("vector should decode")
+}
+
+#[test]
+fn test_vectors_round_trip() -> ProtocolResult<()> {
+    // The replies captured from reference nodes, along with the vectors
+    // written from the spec.
+    for vector in [TestVector::embedded(), TestVector::captured()].concat() {
+        let message = vector.decode()?;
+        let encoded = message.encode_with_timestamp(message.timestamp)?;
+        assert_eq!(encoded, vector.bytes, "{}", vector.name);
+        assert_eq!(spec::layout(&vector.bytes)?.len(), spec::FIELD_ORDER.len());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_vectors_decode() -> ProtocolResult<()> {
+    let message = vector("minimal");
+    assert_eq!(message.timestamp, 1_718_000_000_000);
+    assert_eq!(message.agent_name.as_str(), "ergoref");
+    assert_eq!(message.version, Version([5, 0, 21]));
+    assert_eq!(message.peer_name.as_str(), "ergo-mainnet-5.0.21");
+    assert_eq!(message.declared_address, None);
+    assert!(message.features.is_empty());
+
+    let message = vector("declared-ipv4-mode-session");
+    assert_eq!(
+        message.declared_address,
+        Some("213.239.193.208:9030".parse().unwrap())
+    );
+    assert_eq!(ModeFeature::find(&message), Some(ModeFeature::archive()));
+    assert_eq!(
+        SessionFeature::find(&message),
+        Some(SessionFeature::new(
+            [1, 0, 2, 4],
+            -3_530_241_478_911_735_808
+        ))
+    );
+
+    let message = vector("declared-ipv6-pruned-digest");
+    assert_eq!(
+        message.declared_address,
+        Some("[2001:db8::1]:9020".parse().unwrap())
+    );
+    let mode = ModeFeature::find(&message).unwrap();
+    assert_eq!(mode.state_type, StateType::Digest);
+    assert_eq!(mode.nipopow_bootstrapped, Some(2));
+    assert_eq!(mode.blocks_to_keep, 1440);
+
+    let features = Feature::parse_all(&vector("local-address-rest-api"))?;
+    assert_eq!(
+        features[..2],
+        [
+            Feature::LocalAddress("192.168.1.10:9030".parse().unwrap()),
+            Feature::RestApiUrl("http://node.example.org:9053".to_string()),
+        ]
+    );
+
+    let message = vector("unknown-feature");
+    assert_eq!(
+        message.features[1],
+        PeerFeature::new(200, vec![0xde, 0xad, 0xbe, 0xef])
+    );
+    Ok(())
+}
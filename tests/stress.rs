@@ -0,0 +1,57 @@
+//! Runs thousands of concurrent handshakes against the mock node and
+//! checks the scanner releases what it acquires.
+
+use std::time::Duration;
+
+use p2p_handshake::testing::{stress, StressOptions};
+use p2p_handshake::{HandshakeBuilder, HandshakeConfig, ProtocolResult};
+
+/// Growth of the resident memory tolerated over a run, the allocator keeps
+/// some of the freed memory.
+const MAX_RESIDENT_GROWTH: u64 = 32 * 1024 * 1024;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_handshakes_release_resources() -> ProtocolResult<()> {
+    let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("stress"))
+        .timeout(Duration::from_secs(10));
+    let options = StressOptions {
+        handshakes: 4000,
+        concurrency: 256,
+        ..Default::default()
+    };
+
+    // The first run warms up the allocator and the runtime, the second
+    // one must not hold more than the first released.
+    let warmup = stress(&config, &options).await?;
+    assert_eq!(warmup.completed, options.handshakes, "{warmup:?}");
+    let report = stress(&config, &options).await?;
+    assert_eq!(report.completed, options.handshakes, "{report:?}");
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.leaked_tasks(), 0, "{report:?}");
+    if let Some(leaked) = report.leaked_fds() {
+        assert_eq!(leaked, 0, "{report:?}");
+    }
+    if let Some(growth) = report.resident_growth() {
+        assert!(growth < MAX_RESIDENT_GROWTH, "{report:?}");
+    }
+
+    // The file descriptors are counted for the whole process, so the runs
+    // follow each other rather than running as separate tests.
+    //
+    // A timeout shorter than a loopback handshake under load fails some
+    // of them midway, they must be released too.
+    let config = HandshakeConfig::new(HandshakeBuilder::new().agent_name("stress"))
+        .timeout(Duration::from_micros(200));
+    let options = StressOptions {
+        handshakes: 1000,
+        concurrency: 128,
+        ..Default::default()
+    };
+    let report = stress(&config, &options).await?;
+    assert_eq!(report.completed + report.failed, options.handshakes);
+    assert_eq!(report.leaked_tasks(), 0, "{report:?}");
+    if let Some(leaked) = report.leaked_fds() {
+        assert_eq!(leaked, 0, "{report:?}");
+    }
+    Ok(())
+}